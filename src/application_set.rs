@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_yaml::{Mapping, Value};
+
+/// The Applications an ApplicationSet generates, as far as they can be told
+/// from the repository alone.
+pub struct Expansion {
+    pub apps: Vec<HashMap<String, Value>>,
+    /// Why some of the generators couldn't be expanded.
+    pub unexpanded: Vec<String>,
+}
+
+pub fn is_application_set(value: &HashMap<String, Value>) -> bool {
+    let api_version = value.get("apiVersion").and_then(|v| v.as_str());
+    let kind = value.get("kind").and_then(|v| v.as_str());
+
+    kind == Some("ApplicationSet") && api_version == Some("argoproj.io/v1alpha1")
+}
+
+/// Renders `spec.template` once for each element of the `list` generators.
+/// The others need a cluster, or another repository, to know what they
+/// generate.
+pub fn expand(set: &HashMap<String, Value>) -> Result<Expansion, String> {
+    let spec = set
+        .get("spec")
+        .and_then(Value::as_mapping)
+        .ok_or("No `spec`")?;
+    let template = spec
+        .get("template")
+        .and_then(Value::as_mapping)
+        .ok_or("No `spec.template`")?;
+    let generators = spec
+        .get("generators")
+        .and_then(Value::as_sequence)
+        .ok_or("No `spec.generators`")?;
+    let go_template = spec.get("goTemplate").and_then(Value::as_bool) == Some(true);
+
+    let mut expansion = Expansion {
+        apps: vec![],
+        unexpanded: vec![],
+    };
+    for generator in generators {
+        let Some(generator) = generator.as_mapping() else {
+            continue;
+        };
+        let elements = match generator.get("list").and_then(|list| list.get("elements")) {
+            Some(elements) => elements.as_sequence().into_iter().flatten(),
+            None => {
+                let kind = generator
+                    .keys()
+                    .filter_map(Value::as_str)
+                    .find(|key| *key != "selector" && *key != "template")
+                    .unwrap_or("unknown");
+                expansion.unexpanded.push(format!(
+                    "Can't expand its `{}` generator, only the `list` ones",
+                    kind
+                ));
+                continue;
+            }
+        };
+
+        for element in elements {
+            let mut parameters = BTreeMap::new();
+            flatten("", element, &mut parameters);
+            let rendered = render(&Value::Mapping(template.clone()), &parameters, go_template);
+            expansion.apps.push(HashMap::from([
+                ("apiVersion".to_string(), "argoproj.io/v1alpha1".into()),
+                ("kind".to_string(), "Application".into()),
+                (
+                    "metadata".to_string(),
+                    rendered.get("metadata").cloned().unwrap_or_default(),
+                ),
+                (
+                    "spec".to_string(),
+                    rendered.get("spec").cloned().unwrap_or_default(),
+                ),
+            ]));
+        }
+    }
+
+    Ok(expansion)
+}
+
+/// The parameters of a list element, nested ones being joined with dots like
+/// `values.env`.
+fn flatten(prefix: &str, value: &Value, parameters: &mut BTreeMap<String, String>) {
+    let value = match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping {
+                let Some(key) = key.as_str() else {
+                    continue;
+                };
+                let key = match prefix.is_empty() {
+                    true => key.to_string(),
+                    false => format!("{}.{}", prefix, key),
+                };
+                flatten(&key, value, parameters);
+            }
+            return;
+        }
+        Value::String(value) => value.clone(),
+        Value::Number(value) => value.to_string(),
+        Value::Bool(value) => value.to_string(),
+        _ => return,
+    };
+    parameters.insert(prefix.to_string(), value);
+}
+
+/// Substitutes the parameters in every string of the template.
+fn render(template: &Value, parameters: &BTreeMap<String, String>, go_template: bool) -> Value {
+    match template {
+        Value::String(text) => Value::String(substitute(text, parameters, go_template)),
+        Value::Mapping(mapping) => Value::Mapping(
+            mapping
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        render(key, parameters, go_template),
+                        render(value, parameters, go_template),
+                    )
+                })
+                .collect::<Mapping>(),
+        ),
+        Value::Sequence(values) => Value::Sequence(
+            values
+                .iter()
+                .map(|value| render(value, parameters, go_template))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// Replaces `{{name}}`, or `{{.name}}` for the Go templates. Anything else,
+/// like pipelines or parameters the element doesn't have, is left as is.
+fn substitute(text: &str, parameters: &BTreeMap<String, String>, go_template: bool) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end + 2) else {
+            break;
+        };
+        let name = rest[start + 2..end - 2].trim();
+        let name = match go_template {
+            true => name.strip_prefix('.'),
+            false => Some(name),
+        };
+
+        rendered.push_str(&rest[..start]);
+        match name.and_then(|name| parameters.get(name)) {
+            Some(value) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::config_file;
+
+/// A change that got pushed, as the audit log records it.
+#[derive(Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    /// The job of the run, the `once` runs having none.
+    pub run_id: Option<u64>,
+    pub trigger: String,
+    pub repo: String,
+    pub app: String,
+    pub image: Option<String>,
+    /// For the stale parameters that got removed rather than an image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameter: Option<String>,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    /// The file that got written, relative to the root of the repository.
+    pub path: String,
+    pub commit: Option<String>,
+}
+
+/// Appends every change that got pushed to `AUDIT_LOG_PATH`, one JSON object
+/// per line.
+pub struct AuditLog {
+    path: PathBuf,
+    /// Keeps the records of concurrent appends from interleaving.
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    /// Enabled with `AUDIT_LOG_PATH`, which gets created if it doesn't exist.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(path) = config_file::var("AUDIT_LOG_PATH") else {
+            return Ok(None);
+        };
+
+        let audit_log = Self {
+            path: PathBuf::from(&path),
+            lock: Mutex::new(()),
+        };
+        audit_log
+            .open()
+            .with_context(|| format!("AUDIT_LOG_PATH {} can't be written", path))?;
+
+        Ok(Some(audit_log))
+    }
+
+    /// Appends the records, each of them getting to the disk before the next
+    /// one is written.
+    pub fn append(&self, records: &[AuditRecord]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        // Opened again every time, a rotated file gets replaced by a new one
+        let mut file = self.open()?;
+        for record in records {
+            let mut line = serde_json::to_string(record)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// The last `limit` records, the oldest first. Lines that aren't JSON,
+    /// like the one cut short by a crash, are skipped.
+    pub fn tail(&self, limit: usize) -> Result<Vec<serde_json::Value>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            // Rotated away, and nothing got written since
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = VecDeque::with_capacity(limit);
+        for line in BufReader::new(file).lines() {
+            let Ok(record) = serde_json::from_str(&line?) else {
+                continue;
+            };
+            if records.len() == limit {
+                records.pop_front();
+            }
+            if limit > 0 {
+                records.push_back(record);
+            }
+        }
+
+        Ok(records.into())
+    }
+
+    fn open(&self) -> std::io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use git2::Oid;
+
+use crate::Discovery;
+
+/// Tag listings keyed by image URL, shared between runs so that a burst of
+/// webhooks doesn't list every repository again each time.
+pub struct TagCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+}
+
+impl TagCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        let (fetched_at, tags) = entries.get(url)?;
+        if fetched_at.elapsed() > self.ttl {
+            return None;
+        }
+
+        Some(tags.clone())
+    }
+
+    pub fn insert(&self, url: &str, tags: Vec<String>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (Instant::now(), tags));
+    }
+
+    pub fn invalidate(&self, url: &str) {
+        self.entries.lock().unwrap().remove(url);
+    }
+}
+
+/// What each file of a checkout holds, by its path in the checkout.
+pub type DiscoveredFiles = BTreeMap<PathBuf, Discovery>;
+
+/// What each file of the checkouts holds, keyed by repository, along with the
+/// commit it was found at. Only the files a new commit changes have to be read
+/// again.
+#[derive(Default)]
+pub struct DiscoveryCache {
+    entries: Mutex<HashMap<String, (Oid, DiscoveredFiles)>>,
+}
+
+impl DiscoveryCache {
+    pub fn get(&self, repo: &str) -> Option<(Oid, DiscoveredFiles)> {
+        self.entries.lock().unwrap().get(repo).cloned()
+    }
+
+    pub fn insert(&self, repo: &str, head: Oid, files: DiscoveredFiles) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(repo.to_string(), (head, files));
+    }
+}
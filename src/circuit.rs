@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Stops asking the registry hosts that keep failing, so that their candidates
+/// don't hold the others up timing out. After `threshold` failures in a row the
+/// circuit of a host opens and its candidates get skipped for `cooldown`, then
+/// a single resolution probes whether it's back. It lasts across runs.
+pub struct Circuits {
+    /// Never opening when 0.
+    threshold: u32,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, Circuit>>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    /// When it opened, as long as it's open.
+    opened_at: Option<Instant>,
+    /// Whether the probe is on its way, the others still being skipped.
+    probing: bool,
+}
+
+/// The circuit of a host that failed lately, for `/status`.
+#[derive(Serialize)]
+pub struct CircuitState {
+    pub host: String,
+    /// `closed`, `open` or `probing`.
+    pub state: &'static str,
+    pub failures: u32,
+    /// How long until the next probe, when it's open.
+    pub retry_in_secs: Option<u64>,
+}
+
+/// The circuit of the host is open, its candidates are skipped without asking.
+#[derive(Clone, Debug)]
+pub struct RegistryUnavailable {
+    pub host: String,
+    pub retry_in: Duration,
+}
+
+impl std::fmt::Display for RegistryUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Registry {} unavailable, trying again in {}s",
+            self.host,
+            self.retry_in.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for RegistryUnavailable {}
+
+impl Circuits {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// Whether `host` can be asked at `now`, letting a single probe through
+    /// once the cooldown is over.
+    pub fn admit(&self, host: &str, now: Instant) -> Result<(), RegistryUnavailable> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(circuit) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(opened_at) = circuit.opened_at else {
+            return Ok(());
+        };
+
+        let elapsed = now.saturating_duration_since(opened_at);
+        if elapsed < self.cooldown || circuit.probing {
+            return Err(RegistryUnavailable {
+                host: host.to_string(),
+                retry_in: self.cooldown.saturating_sub(elapsed),
+            });
+        }
+
+        log::info!(
+            "Probing {}, its circuit has been open for {:?}",
+            host,
+            elapsed
+        );
+        circuit.probing = true;
+        Ok(())
+    }
+
+    /// Records whether asking `host` worked, returning whether its circuit is
+    /// open afterwards.
+    pub fn record(&self, host: &str, available: bool, now: Instant) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        if available {
+            if let Some(Circuit {
+                opened_at: Some(_), ..
+            }) = hosts.remove(host)
+            {
+                log::info!("{} is back, closing its circuit", host);
+            }
+            return false;
+        }
+
+        let circuit = hosts.entry(host.to_string()).or_default();
+        circuit.failures += 1;
+        if circuit.probing {
+            log::warn!("{} is still unavailable, keeping its circuit open", host);
+            circuit.opened_at = Some(now);
+            circuit.probing = false;
+        } else if self.threshold > 0
+            && circuit.failures >= self.threshold
+            && circuit.opened_at.is_none()
+        {
+            log::warn!(
+                "{} failed {} times in a row, skipping its candidates for {:?}",
+                host,
+                circuit.failures,
+                self.cooldown
+            );
+            circuit.opened_at = Some(now);
+        }
+
+        circuit.opened_at.is_some()
+    }
+
+    /// The hosts that failed since they last worked.
+    pub fn states(&self, now: Instant) -> Vec<CircuitState> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut states = hosts
+            .iter()
+            .map(|(host, circuit)| CircuitState {
+                host: host.clone(),
+                state: match (circuit.opened_at, circuit.probing) {
+                    (None, _) => "closed",
+                    (Some(_), false) => "open",
+                    (Some(_), true) => "probing",
+                },
+                failures: circuit.failures,
+                retry_in_secs: circuit.opened_at.map(|opened_at| {
+                    self.cooldown
+                        .saturating_sub(now.saturating_duration_since(opened_at))
+                        .as_secs()
+                }),
+            })
+            .collect::<Vec<_>>();
+        states.sort_by(|a, b| a.host.cmp(&b.host));
+
+        states
+    }
+}
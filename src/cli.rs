@@ -0,0 +1,366 @@
+use std::{path::Path, time::Instant};
+
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::Instrument;
+
+use crate::{
+    await_deliveries, candidate_entries, checks,
+    config::{Config, RunMode},
+    config_file, fetch_checkout, notify, plan_entries, record_audit, run_record, update,
+    CandidateFilter, Readiness,
+};
+
+/// Updates the images of Argo CD applications in their GitOps repositories.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Same as the `once` command.
+    #[arg(long, hide = true)]
+    once: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serve the webhooks, the default unless `RUN_MODE=once` is set.
+    Serve,
+    /// Run a single update and exit.
+    Once {
+        /// Print the summary as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print what an update would change, without writing anything.
+    Plan {
+        /// Print the changes as JSON, like `/plan` does.
+        #[arg(long)]
+        json: bool,
+        /// Only look at this repository.
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// List the candidates, and why the other apps and images aren't.
+    ListCandidates {
+        /// Print them as JSON, like `/candidates` does.
+        #[arg(long)]
+        json: bool,
+        /// Only look at this repository.
+        #[arg(long)]
+        repo: Option<String>,
+    },
+}
+
+impl Cli {
+    /// The command to run, `RUN_MODE` deciding when none is given.
+    pub fn command(self) -> Result<Command> {
+        if let Some(command) = self.command {
+            return Ok(command);
+        }
+        if self.once {
+            return Ok(Command::Once { json: false });
+        }
+
+        match config_file::var("RUN_MODE").as_deref() {
+            Err(_) | Ok("serve") => Ok(Command::Serve),
+            Ok("once") => Ok(Command::Once { json: false }),
+            Ok(mode) => bail!("Unknown RUN_MODE {}, expected serve or once", mode),
+        }
+    }
+}
+
+/// Turns the outcome of a command into an exit code, 2 meaning it couldn't
+/// run at all.
+pub fn exit_code(result: Result<i32>) -> i32 {
+    result.unwrap_or_else(|e| {
+        log::error!("{:#}", e);
+        2
+    })
+}
+
+/// Runs a single update, returning the exit code: 0 when it went fine, even
+/// with nothing to update, 1 when some candidates failed, 2 when a repository
+/// couldn't be updated at all.
+pub async fn once(repo_tmpdir: &Path, json: bool) -> Result<i32> {
+    let config = Config::from_env(repo_tmpdir.to_path_buf(), RunMode::Once)?;
+    config_file::log_effective();
+    checks::run(&config).await?;
+
+    let started_at = chrono::Utc::now();
+    let start = Instant::now();
+    let filter = CandidateFilter::default();
+    let readiness = Readiness::default();
+    let run = tokio::time::timeout(
+        config.run_timeout,
+        update(&config, None, &readiness, &filter)
+            .instrument(tracing::info_span!("run", trigger = "once")),
+    );
+    tokio::pin!(run);
+    let result = tokio::select! {
+        result = &mut run => result,
+        () = shutdown_signal() => {
+            log::info!(
+                "Shutting down, waiting up to {:?} for the run to finish",
+                config.shutdown_grace
+            );
+            match tokio::time::timeout(config.shutdown_grace, run).await {
+                Ok(result) => {
+                    log::info!("Shut down, the last run completed");
+                    result
+                }
+                Err(_) => {
+                    log::warn!("Shut down before the run completed, the remote may be partially updated");
+                    return Ok(2);
+                }
+            }
+        }
+    };
+    let result = result.unwrap_or_else(|_| {
+        Err(anyhow::anyhow!(
+            "Run timed out after {:?}",
+            config.run_timeout
+        ))
+    });
+    // The process is about to exit, the notifications have to be out before
+    let duration = start.elapsed();
+    let run = run_record("once".to_string(), started_at, duration, &result);
+    await_deliveries(notify(&config, &run, &result, duration).await).await;
+    record_audit(&config, None, &run.trigger, &result);
+    let summary = result?;
+    match json {
+        true => println!("{}", summary.to_json(start.elapsed())),
+        false => println!("{}", summary),
+    }
+
+    if summary.repos.iter().any(|(_, summary)| summary.is_err()) {
+        return Ok(2);
+    }
+
+    Ok(match summary.succeeded() {
+        true => 0,
+        false => 1,
+    })
+}
+
+/// Prints what an update would change, failing with 1 when some tags couldn't
+/// be resolved.
+pub async fn plan(repo_tmpdir: &Path, json: bool, repo: Option<String>) -> Result<i32> {
+    let config = Config::from_env(repo_tmpdir.to_path_buf(), RunMode::Inspect)?;
+    let filter = repo_filter(&config, repo)?;
+
+    let entries = plan_entries(&config, None, &Readiness::default(), &filter).await?;
+    if json {
+        println!("{}", serde_json::Value::from(entries.clone()));
+    } else {
+        let rows = entries
+            .iter()
+            .map(|entry| {
+                let change = match (&entry["error"], entry["change"].as_bool()) {
+                    (serde_json::Value::String(error), _) => format!("error: {}", error),
+                    _ if entry["paused"] == true => "paused".to_string(),
+                    _ if entry["soaking"].is_string() => "soaking".to_string(),
+                    _ if entry["missing_platforms"].is_string() => "missing platforms".to_string(),
+                    (_, Some(true)) => "update".to_string(),
+                    _ => "none".to_string(),
+                };
+                vec![
+                    text(&entry["repo"]),
+                    text(&entry["app"]),
+                    text(&entry["image"]),
+                    text(&entry["current"]),
+                    text(&entry["selected"]),
+                    change,
+                ]
+            })
+            .collect::<Vec<_>>();
+        print_table(
+            &["REPO", "APP", "IMAGE", "CURRENT", "SELECTED", "CHANGE"],
+            &rows,
+        );
+
+        let anchoring_changes = entries
+            .iter()
+            .filter(|entry| entry["anchoring_changes"] == true);
+        for (i, entry) in anchoring_changes.enumerate() {
+            if i == 0 {
+                println!("\nOnly partly matching `allow-tags`, another tag with ANCHOR_TAG_REGEX:");
+            }
+            println!(
+                "  {} {} {}",
+                text(&entry["app"]),
+                text(&entry["image"]),
+                text(&entry["selected"])
+            );
+        }
+
+        let skipped_prereleases = entries
+            .iter()
+            .filter(|entry| !entry["skipped_prerelease"].is_null());
+        for (i, entry) in skipped_prereleases.enumerate() {
+            if i == 0 {
+                println!("\nPassed over prereleases, without `allow-prerelease`:");
+            }
+            println!(
+                "  {} {} {}",
+                text(&entry["app"]),
+                text(&entry["image"]),
+                text(&entry["skipped_prerelease"])
+            );
+        }
+
+        let without_platforms = entries
+            .iter()
+            .filter(|entry| !entry["without_platforms"].is_null());
+        for (i, entry) in without_platforms.enumerate() {
+            if i == 0 {
+                println!("\nPassed over, not built for all of `platforms`:");
+            }
+            let tags = entry["without_platforms"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(text)
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!(
+                "  {} {} {}",
+                text(&entry["app"]),
+                text(&entry["image"]),
+                tags
+            );
+        }
+    }
+
+    let failed = entries.iter().any(|entry| !entry["error"].is_null());
+    Ok(if failed { 1 } else { 0 })
+}
+
+/// Prints the candidates of the repositories and what got skipped, without
+/// talking to any registry.
+pub async fn list_candidates(repo_tmpdir: &Path, json: bool, repo: Option<String>) -> Result<i32> {
+    let config = Config::from_env(repo_tmpdir.to_path_buf(), RunMode::Inspect)?;
+    let filter = repo_filter(&config, repo)?;
+
+    let readiness = Readiness::default();
+    for repo in config
+        .repositories
+        .iter()
+        .filter(|repo| filter.matches_repo(repo))
+    {
+        fetch_checkout(&config, repo, &readiness)?;
+    }
+    let body = candidate_entries(&config, &filter)?;
+    if json {
+        println!("{}", body);
+        return Ok(0);
+    }
+
+    let rows = body["candidates"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|candidate| {
+            vec![
+                text(&candidate["repo"]),
+                text(&candidate["app"]),
+                text(&candidate["image"]),
+                text(&candidate["allow_tags"]),
+                text(&candidate["manifest"]),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(&["REPO", "APP", "IMAGE", "ALLOW TAGS", "MANIFEST"], &rows);
+
+    let skipped = body["skipped"].as_array().into_iter().flatten();
+    for (i, skipped) in skipped.enumerate() {
+        if i == 0 {
+            println!("\nSkipped:");
+        }
+        let what = [
+            &skipped["repo"],
+            &skipped["manifest"],
+            &skipped["app"],
+            &skipped["image"],
+        ]
+        .into_iter()
+        .filter(|value| !value.is_null())
+        .map(text)
+        .collect::<Vec<_>>()
+        .join(" ");
+        println!("  {}: {}", what, text(&skipped["reason"]));
+    }
+
+    Ok(0)
+}
+
+/// Resolves on SIGTERM or Ctrl-C, the signals rocket shuts down on.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::warn!("Can't listen for SIGTERM: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+fn repo_filter(config: &Config, repo: Option<String>) -> Result<CandidateFilter> {
+    if let Some(repo) = &repo {
+        if !config.repositories.iter().any(|known| known.name == *repo) {
+            let known = config
+                .repositories
+                .iter()
+                .map(|known| known.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!("Unknown repository {}, known repositories: {}", repo, known);
+        }
+    }
+
+    Ok(CandidateFilter {
+        repo,
+        ..Default::default()
+    })
+}
+
+fn text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(value) => value.clone(),
+        serde_json::Value::Null => "-".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Prints the rows in columns as wide as their widest cell.
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths = headers
+        .iter()
+        .map(|header| header.len())
+        .collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
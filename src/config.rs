@@ -1,10 +1,465 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    audit::AuditLog,
+    cache::DiscoveryCache,
+    circuit::Circuits,
+    config_file::{self, RepositoryEntry},
+    email::Email,
+    events::Events,
+    git::{
+        self, commit_trailers, CommitGranularity, CommitIdentity, CommitMode, CommitSigner,
+        GitCredentials, SshKey,
+    },
+    github::PullRequests,
+    known_hosts::HostKeyCheck,
+    metrics::Metrics,
+    notify_webhook::NotifyWebhook,
+    registry::{ProxySettings, RegistryCredentials, TlsSettings},
+    scan::ScanFilter,
+    slack::Slack,
+};
 
 pub struct Config {
-    pub repository_url: String,
-    pub ssh_key_path: String,
-    pub github_username: String,
-    pub github_key: String,
-    pub repo_tmpdir: PathBuf,
-    pub secret: String,
+    pub repositories: Vec<RepoConfig>,
+    /// At least 2 when amending, see [`CommitMode::fetch_depth`].
+    pub git_fetch_depth: i32,
+    pub commit_message_template: Option<String>,
+    pub commit_mode: CommitMode,
+    pub commit_granularity: CommitGranularity,
+    pub commit_trailers: Vec<String>,
+    pub commit_identity: CommitIdentity,
+    pub commit_signer: Option<CommitSigner>,
+    pub registry_credentials: RegistryCredentials,
+    pub registry_proxy: ProxySettings,
+    pub registry_tls: TlsSettings,
+    pub secrets: Vec<String>,
+    pub fail_fast: bool,
+    pub prune_stale_parameters: bool,
+    /// Whether the manifests of the selected tags get checked before they're
+    /// written, with `VERIFY_MANIFESTS`.
+    pub verify_manifests: bool,
+    /// What the checkouts hold, unless `DISABLE_DISCOVERY_CACHE` is set.
+    pub discovery_cache: Option<DiscoveryCache>,
+    /// How many files get read at a time when looking for candidates.
+    pub discovery_parallelism: usize,
+    pub max_tags_per_repo: usize,
+    pub tag_cache_ttl: Duration,
+    pub registry_concurrency: usize,
+    pub registry_timeout: Duration,
+    /// The registry hosts failing lately, with `REGISTRY_CIRCUIT_THRESHOLD`
+    /// and `REGISTRY_CIRCUIT_COOLDOWN_SECS`.
+    pub circuits: Circuits,
+    pub run_timeout: Duration,
+    /// How long the run in progress gets to finish when shutting down.
+    pub shutdown_grace: Duration,
+    /// How often to run without waiting for a webhook, if at all.
+    pub poll_interval: Option<Duration>,
+    pub ready_max_fetch_age: Option<Duration>,
+    pub status_history: usize,
+    pub metrics: Metrics,
+    pub events: Events,
+    pub metrics_require_secret: bool,
+    pub max_triggers_per_minute: Option<u32>,
+    pub delivery_id_ttl: Duration,
+    pub skip_startup_checks: bool,
+    pub slack: Option<Slack>,
+    pub notify_webhook: Option<NotifyWebhook>,
+    pub email: Option<Email>,
+    pub audit_log: Option<AuditLog>,
+    /// An image whose tags get listed at startup, to check the registry
+    /// credentials.
+    pub startup_check_image: Option<String>,
+    #[cfg(feature = "ecr")]
+    pub ecr_tokens: crate::ecr::EcrTokens,
+}
+
+/// A GitOps repository being watched, checked out in its own directory.
+pub struct RepoConfig {
+    pub name: String,
+    pub url: String,
+    pub git_credentials: GitCredentials,
+    pub host_key_check: HostKeyCheck,
+    pub branch: Option<String>,
+    /// Only the manifests under this directory get looked at.
+    pub path: Option<String>,
+    pub scan: ScanFilter,
+    /// Whether the `allow-tags` regexes have to match whole tags, with
+    /// `ANCHOR_TAG_REGEX`, unless the `anchor-tags` annotation of an image
+    /// says otherwise.
+    pub anchor_tag_regex: bool,
+    pub pull_requests: Option<PullRequests>,
+    pub checkout: PathBuf,
+    /// Whether it's one of the configuration file's `repositories`, rather than
+    /// `REPOSITORY_URL`.
+    pub listed: bool,
+}
+
+impl RepoConfig {
+    fn new(
+        entry: RepositoryEntry,
+        repo_tmpdir: &Path,
+        listed: bool,
+        run_mode: RunMode,
+    ) -> Result<Self> {
+        let name = match entry.name {
+            Some(name) => name,
+            None => default_name(&entry.url)?,
+        };
+        // The name is a directory of the checkouts
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            bail!("Invalid repository name {:?}", name);
+        }
+
+        let key = match (entry.ssh_key, entry.ssh_key_path) {
+            (Some(private_key), _) => Some(SshKey::Memory {
+                private_key,
+                public_key: entry.ssh_public_key,
+            }),
+            (None, Some(key_path)) => Some(SshKey::Path(key_path.into())),
+            (None, None) => None,
+        };
+
+        let git_credentials = match GitCredentials::from_env(&entry.url, key) {
+            // Reading public or local repositories doesn't need any
+            Err(_)
+                if run_mode == RunMode::Inspect
+                    && (git::is_https(&entry.url) || git::is_local(&entry.url)) =>
+            {
+                GitCredentials::Anonymous
+            }
+            credentials => credentials?,
+        };
+
+        Ok(Self {
+            pull_requests: match run_mode {
+                RunMode::Inspect => None,
+                _ => PullRequests::from_env(&entry.url).context("PUSH_MODE")?,
+            },
+            git_credentials,
+            host_key_check: HostKeyCheck::from_env(&entry.url)?,
+            checkout: repo_tmpdir.join(&name),
+            name,
+            url: entry.url,
+            branch: entry.branch.or_else(|| config_file::var("BRANCH").ok()),
+            path: entry.path.filter(|path| !path.is_empty()),
+            scan: ScanFilter::from_env()?,
+            anchor_tag_regex: env_flag("ANCHOR_TAG_REGEX"),
+            listed,
+        })
+    }
+}
+
+/// The last component of the repository's URL, without `.git`.
+fn default_name(url: &str) -> Result<String> {
+    let name = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default();
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if name.is_empty() {
+        bail!("Can't name the repository {}, give it a name", url);
+    }
+
+    Ok(name.to_string())
+}
+
+/// The repositories from the configuration file, or the one from
+/// `REPOSITORY_URL`.
+fn repositories(repo_tmpdir: &Path, run_mode: RunMode) -> Result<Vec<RepoConfig>> {
+    let mut entries = config_file::repositories();
+    let listed = !entries.is_empty();
+    if !listed {
+        entries.push(RepositoryEntry {
+            name: None,
+            url: config_file::required("REPOSITORY_URL")?,
+            branch: None,
+            path: None,
+            ssh_key: None,
+            ssh_key_path: None,
+            ssh_public_key: None,
+        });
+    } else if config_file::var("REPOSITORY_URL").is_ok() {
+        bail!("REPOSITORY_URL can't be set along with `repositories`");
+    }
+
+    let mut repositories = Vec::<RepoConfig>::new();
+    for entry in entries {
+        let url = entry.url.clone();
+        let repository = RepoConfig::new(entry, repo_tmpdir, listed, run_mode)
+            .with_context(|| format!("Repository {}", url))?;
+        if repositories
+            .iter()
+            .any(|other| other.name == repository.name)
+        {
+            bail!(
+                "Several repositories are named {}, give them distinct names",
+                repository.name
+            );
+        }
+        repositories.push(repository);
+    }
+
+    Ok(repositories)
+}
+
+/// What the configuration is loaded for, which decides what has to be set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunMode {
+    /// Serving the webhooks, which needs a secret.
+    Serve,
+    /// Running a single update.
+    Once,
+    /// Only looking at the repositories, which doesn't need any credentials
+    /// to push.
+    Inspect,
+}
+
+impl Config {
+    /// Only needs a secret when serving.
+    pub fn from_env(repo_tmpdir: PathBuf, run_mode: RunMode) -> Result<Self> {
+        // Explicitly configured credentials win over the ones from the docker config
+        let mut registry_credentials = match config_file::var("DOCKER_CONFIG") {
+            Ok(path) => RegistryCredentials::from_docker_config(Path::new(&path))
+                .context("DOCKER_CONFIG")?,
+            Err(_) => RegistryCredentials::default(),
+        };
+        if let (Ok(username), Ok(key)) = (
+            config_file::var("GITHUB_USERNAME"),
+            config_file::var("GITHUB_KEY"),
+        ) {
+            let mut github = RegistryCredentials::default();
+            github.insert("ghcr.io", &username, &key);
+            registry_credentials.extend(github);
+        }
+        if let Ok(credentials) = config_file::var("REGISTRY_CREDENTIALS") {
+            registry_credentials
+                .extend(RegistryCredentials::parse(&credentials).context("REGISTRY_CREDENTIALS")?);
+        }
+
+        let commit_mode = CommitMode::from_env()?;
+        Ok(Self {
+            repositories: repositories(&repo_tmpdir, run_mode)?,
+            git_fetch_depth: commit_mode.fetch_depth(env_or("GIT_FETCH_DEPTH", 1)?),
+            commit_message_template: config_file::var("COMMIT_MESSAGE_TEMPLATE").ok(),
+            commit_mode,
+            commit_granularity: CommitGranularity::from_env()?,
+            commit_trailers: commit_trailers(),
+            commit_identity: CommitIdentity::from_env(),
+            commit_signer: CommitSigner::from_env().context("GPG_SIGNING_KEY")?,
+            registry_credentials,
+            registry_proxy: ProxySettings::from_env(),
+            registry_tls: TlsSettings::from_env()?,
+            secrets: match run_mode {
+                RunMode::Serve => {
+                    parse_secrets(&config_file::required("SECRET")?).context("SECRET")?
+                }
+                RunMode::Once | RunMode::Inspect => vec![],
+            },
+            fail_fast: env_flag("FAIL_FAST"),
+            prune_stale_parameters: env_flag("PRUNE_STALE_PARAMETERS"),
+            verify_manifests: env_flag("VERIFY_MANIFESTS"),
+            discovery_cache: (!env_flag("DISABLE_DISCOVERY_CACHE")).then(DiscoveryCache::default),
+            discovery_parallelism: env_or(
+                "DISCOVERY_PARALLELISM",
+                std::thread::available_parallelism().map_or(1, |n| n.get()),
+            )?
+            .max(1),
+            max_tags_per_repo: env_or("MAX_TAGS_PER_REPO", 5000)?,
+            tag_cache_ttl: Duration::from_secs(env_or("TAG_CACHE_TTL", 60)?),
+            registry_concurrency: env_or("REGISTRY_CONCURRENCY", 8)?.max(1),
+            registry_timeout: Duration::from_secs(env_or("REGISTRY_TIMEOUT_SECS", 30)?),
+            circuits: Circuits::new(
+                env_or("REGISTRY_CIRCUIT_THRESHOLD", 5)?,
+                Duration::from_secs(env_or("REGISTRY_CIRCUIT_COOLDOWN_SECS", 300)?),
+            ),
+            run_timeout: Duration::from_secs(env_or("RUN_TIMEOUT_SECS", 600)?),
+            shutdown_grace: Duration::from_secs(env_or("SHUTDOWN_GRACE_SECS", 25)?),
+            poll_interval: config_file::var("POLL_INTERVAL")
+                .ok()
+                .map(|interval| parse_duration(&interval))
+                .transpose()
+                .context("POLL_INTERVAL")?,
+            ready_max_fetch_age: config_file::var("READY_MAX_FETCH_AGE_SECS")
+                .ok()
+                .map(|secs| secs.parse().map(Duration::from_secs))
+                .transpose()
+                .context("READY_MAX_FETCH_AGE_SECS")?,
+            status_history: env_or("STATUS_HISTORY", 20)?,
+            metrics: Metrics::new(env_or("MAX_CANDIDATE_METRICS", 500)?)?,
+            events: Events::new(),
+            metrics_require_secret: env_flag("METRICS_REQUIRE_SECRET"),
+            max_triggers_per_minute: config_file::var("MAX_TRIGGERS_PER_MINUTE")
+                .ok()
+                .map(|max| max.parse())
+                .transpose()
+                .context("MAX_TRIGGERS_PER_MINUTE")?,
+            delivery_id_ttl: Duration::from_secs(env_or("DELIVERY_ID_TTL_SECS", 600)?),
+            skip_startup_checks: env_flag("SKIP_STARTUP_CHECKS"),
+            slack: Slack::from_env()?,
+            notify_webhook: NotifyWebhook::from_env()?,
+            email: Email::from_env()?,
+            audit_log: AuditLog::from_env()?,
+            startup_check_image: config_file::var("STARTUP_CHECK_IMAGE").ok(),
+            #[cfg(feature = "ecr")]
+            ecr_tokens: Default::default(),
+        })
+    }
+}
+
+/// Splits a comma separated list of secrets, several of them being accepted at
+/// once while rotating.
+fn parse_secrets(secrets: &str) -> Result<Vec<String>> {
+    secrets
+        .split(',')
+        .enumerate()
+        .map(|(index, secret)| {
+            // An empty secret would let anyone in
+            if secret.trim().is_empty() {
+                anyhow::bail!("Secret #{} is empty", index + 1);
+            }
+            Ok(secret.to_string())
+        })
+        .collect()
+}
+
+/// Returns whether the given environment variable is set to `true`.
+fn env_flag(name: &str) -> bool {
+    config_file::var(name).is_ok_and(|v| v == "true")
+}
+
+/// Parses the given environment variable, falling back to `default` when it's
+/// not set.
+fn env_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match config_file::var(name) {
+        Ok(value) => value.parse().context(name.to_string()),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parses durations like `90s`, `5m` or `1h30m`, a bare number being seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+    if let Ok(secs) = duration.parse::<u64>() {
+        return match secs {
+            0 => bail!("The duration can't be 0"),
+            secs => Ok(Duration::from_secs(secs)),
+        };
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let value: u64 = rest[..digits].parse().with_context(|| {
+            format!(
+                "Invalid duration {:?}, expected something like 5m",
+                duration
+            )
+        })?;
+        rest = &rest[digits..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let secs = match &rest[..unit] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            unit => bail!(
+                "Invalid duration unit {:?} in {:?}, expected s, m, h or d",
+                unit,
+                duration
+            ),
+        };
+        rest = &rest[unit..];
+        total += Duration::from_secs(value.saturating_mul(secs));
+    }
+
+    if total.is_zero() {
+        bail!("The duration can't be 0");
+    }
+
+    Ok(total)
+}
+
+/// The configuration the tests of the other modules run with, only setting the
+/// repository.
+#[cfg(test)]
+pub fn test_config() -> Config {
+    static ENV: std::sync::Once = std::sync::Once::new();
+    ENV.call_once(|| {
+        std::env::set_var("REPOSITORY_URL", "file:///nonexistent");
+        std::env::set_var("SSH_AUTH_SOCK", "/nonexistent");
+        std::env::set_var("GIT_SSH_INSECURE_ACCEPT_ANY", "true");
+    });
+
+    Config::from_env(std::env::temp_dir(), RunMode::Once).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        for (duration, secs) in [
+            ("90", 90),
+            ("90s", 90),
+            (" 5m ", 5 * 60),
+            ("1h30m", 90 * 60),
+            ("2d", 2 * 24 * 60 * 60),
+            ("1h0m", 60 * 60),
+        ] {
+            assert_eq!(
+                parse_duration(duration).unwrap(),
+                Duration::from_secs(secs),
+                "{duration}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for (duration, error) in [
+            ("0", "The duration can't be 0"),
+            ("0m", "The duration can't be 0"),
+            ("", "The duration can't be 0"),
+            (
+                "5w",
+                r#"Invalid duration unit "w" in "5w", expected s, m, h or d"#,
+            ),
+            (
+                "5 m",
+                r#"Invalid duration unit " m" in "5 m", expected s, m, h or d"#,
+            ),
+            ("m", r#"Invalid duration "m", expected something like 5m"#),
+            (
+                "-5m",
+                r#"Invalid duration "-5m", expected something like 5m"#,
+            ),
+        ] {
+            let e = parse_duration(duration).unwrap_err();
+            assert!(e.to_string().starts_with(error), "{duration}: {e:#}");
+        }
+    }
 }
@@ -0,0 +1,694 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env::VarError,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+static LOADED: OnceLock<Loaded> = OnceLock::new();
+/// The secrets read from the file their `*_FILE` variable points to.
+static SECRET_FILES: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+
+/// The file at `CONFIG_FILE`.
+struct Loaded {
+    path: PathBuf,
+    /// Keyed by the environment variable they stand for.
+    values: HashMap<&'static str, String>,
+    repositories: Vec<RepositoryEntry>,
+}
+
+/// The configuration file, every setting being optional as the environment
+/// can provide or override any of them.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    repository_url: Option<String>,
+    secret: Option<OneOrMany>,
+    fail_fast: Option<bool>,
+    prune_stale_parameters: Option<bool>,
+    anchor_tag_regex: Option<bool>,
+    verify_manifests: Option<bool>,
+    disable_discovery_cache: Option<bool>,
+    discovery_parallelism: Option<usize>,
+    run_timeout_secs: Option<u64>,
+    shutdown_grace_secs: Option<u64>,
+    /// Like `5m`.
+    poll_interval: Option<String>,
+    /// `serve` or `once`.
+    run_mode: Option<String>,
+    skip_startup_checks: Option<bool>,
+    /// Replaces `repository_url` to watch several repositories, which have
+    /// no environment variable equivalent.
+    repositories: Option<Vec<RepositoryEntry>>,
+    git: GitSection,
+    registries: RegistriesSection,
+    server: ServerSection,
+    slack: SlackSection,
+    notify: NotifySection,
+    smtp: SmtpSection,
+    audit: AuditSection,
+    sentry: SentrySection,
+    scan: ScanSection,
+}
+
+/// One of the GitOps repositories to watch, the rest of the git settings
+/// being shared by all of them.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryEntry {
+    /// Defaults to the last component of the URL.
+    pub name: Option<String>,
+    pub url: String,
+    pub branch: Option<String>,
+    /// Only the manifests under this directory of the repository are looked at.
+    pub path: Option<String>,
+    pub ssh_key: Option<String>,
+    pub ssh_key_path: Option<String>,
+    pub ssh_public_key: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GitSection {
+    branch: Option<String>,
+    fetch_depth: Option<i32>,
+    ssh_key: Option<String>,
+    ssh_key_path: Option<String>,
+    ssh_public_key: Option<String>,
+    ssh_key_passphrase: Option<String>,
+    ssh_key_passphrase_file: Option<String>,
+    known_hosts: Option<String>,
+    insecure_accept_any_host_key: Option<bool>,
+    https_username: Option<String>,
+    https_token: Option<String>,
+    push_mode: Option<String>,
+    github_api_url: Option<String>,
+    github_api_token: Option<String>,
+    commit: CommitSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CommitSection {
+    message_template: Option<String>,
+    mode: Option<String>,
+    granularity: Option<String>,
+    trailers: Option<Vec<String>>,
+    skip_ci: Option<bool>,
+    author_name: Option<String>,
+    author_email: Option<String>,
+    committer_name: Option<String>,
+    committer_email: Option<String>,
+    gpg_signing_key: Option<String>,
+    gpg_signing_key_passphrase: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RegistriesSection {
+    /// Keyed by registry host.
+    credentials: Option<BTreeMap<String, RegistryCredential>>,
+    docker_config: Option<String>,
+    github_username: Option<String>,
+    github_key: Option<String>,
+    /// CA bundle paths keyed by registry host.
+    ca_certs: Option<BTreeMap<String, String>>,
+    insecure: Option<Vec<String>>,
+    https_proxy: Option<String>,
+    http_proxy: Option<String>,
+    no_proxy: Option<Vec<String>>,
+    concurrency: Option<usize>,
+    timeout_secs: Option<u64>,
+    circuit_threshold: Option<u32>,
+    circuit_cooldown_secs: Option<u64>,
+    max_tags_per_repo: Option<usize>,
+    tag_cache_ttl: Option<u64>,
+    /// An image to list the tags of at startup.
+    check_image: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryCredential {
+    username: String,
+    password: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    prefix: Option<String>,
+    ready_max_fetch_age_secs: Option<u64>,
+    status_history: Option<usize>,
+    metrics_require_secret: Option<bool>,
+    /// Past that many candidates, the new ones don't get their own series.
+    max_candidate_metrics: Option<usize>,
+    max_triggers_per_minute: Option<u32>,
+    delivery_id_ttl_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SlackSection {
+    webhook_url: Option<String>,
+    notify_always: Option<bool>,
+}
+
+/// Where to post a report of each run.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NotifySection {
+    url: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SmtpSection {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    from: Option<String>,
+    to: Option<Vec<String>>,
+    notify_success: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuditSection {
+    /// The JSON lines file every change that got pushed is appended to.
+    log_path: Option<String>,
+}
+
+/// Which files of the repositories get looked at, excluding winning over
+/// including.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ScanSection {
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+}
+
+/// Only used by the builds with the `sentry` feature.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SentrySection {
+    dsn: Option<String>,
+    environment: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// A setting of the file, and the environment variable it stands for.
+struct Setting {
+    var: &'static str,
+    key: &'static str,
+    value: Option<String>,
+    secret: bool,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the configuration from {}", path.display()))?;
+
+        let file = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(anyhow::Error::from),
+            _ => serde_yaml::from_str(&content).map_err(anyhow::Error::from),
+        };
+
+        file.with_context(|| format!("Invalid configuration in {}", path.display()))
+    }
+
+    /// Every setting, in the format of its environment variable.
+    fn settings(self) -> Vec<Setting> {
+        fn list(values: Option<Vec<String>>, separator: &str) -> Option<String> {
+            values.map(|values| values.join(separator))
+        }
+        fn text<T: ToString>(value: Option<T>) -> Option<String> {
+            value.map(|value| value.to_string())
+        }
+
+        let Self {
+            repository_url,
+            secret,
+            fail_fast,
+            prune_stale_parameters,
+            anchor_tag_regex,
+            verify_manifests,
+            disable_discovery_cache,
+            discovery_parallelism,
+            run_timeout_secs,
+            shutdown_grace_secs,
+            poll_interval,
+            run_mode,
+            skip_startup_checks,
+            repositories: _,
+            git,
+            registries,
+            server,
+            slack,
+            notify,
+            smtp,
+            audit,
+            sentry,
+            scan,
+        } = self;
+        let commit = git.commit;
+        let secret = secret.map(|secret| match secret {
+            OneOrMany::One(secret) => secret,
+            OneOrMany::Many(secrets) => secrets.join(","),
+        });
+        let credentials = registries.credentials.map(|credentials| {
+            credentials
+                .into_iter()
+                .map(|(host, credential)| {
+                    format!("{}={}:{}", host, credential.username, credential.password)
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let ca_certs = registries.ca_certs.map(|ca_certs| {
+            ca_certs
+                .into_iter()
+                .map(|(host, path)| format!("{}={}", host, path))
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+
+        let setting = |var, key, value| Setting {
+            var,
+            key,
+            value,
+            secret: false,
+        };
+        let secret_setting = |var, key, value| Setting {
+            var,
+            key,
+            value,
+            secret: true,
+        };
+
+        vec![
+            setting("REPOSITORY_URL", "repository_url", repository_url),
+            secret_setting("SECRET", "secret", secret),
+            setting("FAIL_FAST", "fail_fast", text(fail_fast)),
+            setting(
+                "PRUNE_STALE_PARAMETERS",
+                "prune_stale_parameters",
+                text(prune_stale_parameters),
+            ),
+            setting(
+                "ANCHOR_TAG_REGEX",
+                "anchor_tag_regex",
+                text(anchor_tag_regex),
+            ),
+            setting(
+                "VERIFY_MANIFESTS",
+                "verify_manifests",
+                text(verify_manifests),
+            ),
+            setting(
+                "DISABLE_DISCOVERY_CACHE",
+                "disable_discovery_cache",
+                text(disable_discovery_cache),
+            ),
+            setting(
+                "DISCOVERY_PARALLELISM",
+                "discovery_parallelism",
+                text(discovery_parallelism),
+            ),
+            setting(
+                "RUN_TIMEOUT_SECS",
+                "run_timeout_secs",
+                text(run_timeout_secs),
+            ),
+            setting(
+                "SHUTDOWN_GRACE_SECS",
+                "shutdown_grace_secs",
+                text(shutdown_grace_secs),
+            ),
+            setting("POLL_INTERVAL", "poll_interval", poll_interval),
+            setting("RUN_MODE", "run_mode", run_mode),
+            setting(
+                "SKIP_STARTUP_CHECKS",
+                "skip_startup_checks",
+                text(skip_startup_checks),
+            ),
+            setting("BRANCH", "git.branch", git.branch),
+            setting("GIT_FETCH_DEPTH", "git.fetch_depth", text(git.fetch_depth)),
+            secret_setting("SSH_KEY", "git.ssh_key", git.ssh_key),
+            setting("SSH_KEY_PATH", "git.ssh_key_path", git.ssh_key_path),
+            setting("SSH_PUBLIC_KEY", "git.ssh_public_key", git.ssh_public_key),
+            secret_setting(
+                "SSH_KEY_PASSPHRASE",
+                "git.ssh_key_passphrase",
+                git.ssh_key_passphrase,
+            ),
+            setting(
+                "SSH_KEY_PASSPHRASE_FILE",
+                "git.ssh_key_passphrase_file",
+                git.ssh_key_passphrase_file,
+            ),
+            setting("SSH_KNOWN_HOSTS", "git.known_hosts", git.known_hosts),
+            setting(
+                "GIT_SSH_INSECURE_ACCEPT_ANY",
+                "git.insecure_accept_any_host_key",
+                text(git.insecure_accept_any_host_key),
+            ),
+            setting(
+                "GIT_HTTPS_USERNAME",
+                "git.https_username",
+                git.https_username,
+            ),
+            secret_setting("GIT_HTTPS_TOKEN", "git.https_token", git.https_token),
+            setting("PUSH_MODE", "git.push_mode", git.push_mode),
+            setting("GITHUB_API_URL", "git.github_api_url", git.github_api_url),
+            secret_setting(
+                "GITHUB_API_TOKEN",
+                "git.github_api_token",
+                git.github_api_token,
+            ),
+            setting(
+                "COMMIT_MESSAGE_TEMPLATE",
+                "git.commit.message_template",
+                commit.message_template,
+            ),
+            setting("COMMIT_MODE", "git.commit.mode", commit.mode),
+            setting(
+                "COMMIT_GRANULARITY",
+                "git.commit.granularity",
+                commit.granularity,
+            ),
+            setting(
+                "COMMIT_TRAILERS",
+                "git.commit.trailers",
+                list(commit.trailers, "\n"),
+            ),
+            setting("COMMIT_SKIP_CI", "git.commit.skip_ci", text(commit.skip_ci)),
+            setting(
+                "GIT_AUTHOR_NAME",
+                "git.commit.author_name",
+                commit.author_name,
+            ),
+            setting(
+                "GIT_AUTHOR_EMAIL",
+                "git.commit.author_email",
+                commit.author_email,
+            ),
+            setting(
+                "GIT_COMMITTER_NAME",
+                "git.commit.committer_name",
+                commit.committer_name,
+            ),
+            setting(
+                "GIT_COMMITTER_EMAIL",
+                "git.commit.committer_email",
+                commit.committer_email,
+            ),
+            secret_setting(
+                "GPG_SIGNING_KEY",
+                "git.commit.gpg_signing_key",
+                commit.gpg_signing_key,
+            ),
+            secret_setting(
+                "GPG_SIGNING_KEY_PASSPHRASE",
+                "git.commit.gpg_signing_key_passphrase",
+                commit.gpg_signing_key_passphrase,
+            ),
+            secret_setting(
+                "REGISTRY_CREDENTIALS",
+                "registries.credentials",
+                credentials,
+            ),
+            setting(
+                "DOCKER_CONFIG",
+                "registries.docker_config",
+                registries.docker_config,
+            ),
+            setting(
+                "GITHUB_USERNAME",
+                "registries.github_username",
+                registries.github_username,
+            ),
+            secret_setting("GITHUB_KEY", "registries.github_key", registries.github_key),
+            setting("REGISTRY_CA_CERTS", "registries.ca_certs", ca_certs),
+            setting(
+                "INSECURE_REGISTRIES",
+                "registries.insecure",
+                list(registries.insecure, ","),
+            ),
+            setting(
+                "REGISTRY_HTTPS_PROXY",
+                "registries.https_proxy",
+                registries.https_proxy,
+            ),
+            setting(
+                "REGISTRY_HTTP_PROXY",
+                "registries.http_proxy",
+                registries.http_proxy,
+            ),
+            setting(
+                "REGISTRY_NO_PROXY",
+                "registries.no_proxy",
+                list(registries.no_proxy, ","),
+            ),
+            setting(
+                "REGISTRY_CONCURRENCY",
+                "registries.concurrency",
+                text(registries.concurrency),
+            ),
+            setting(
+                "REGISTRY_TIMEOUT_SECS",
+                "registries.timeout_secs",
+                text(registries.timeout_secs),
+            ),
+            setting(
+                "REGISTRY_CIRCUIT_THRESHOLD",
+                "registries.circuit_threshold",
+                text(registries.circuit_threshold),
+            ),
+            setting(
+                "REGISTRY_CIRCUIT_COOLDOWN_SECS",
+                "registries.circuit_cooldown_secs",
+                text(registries.circuit_cooldown_secs),
+            ),
+            setting(
+                "MAX_TAGS_PER_REPO",
+                "registries.max_tags_per_repo",
+                text(registries.max_tags_per_repo),
+            ),
+            setting(
+                "TAG_CACHE_TTL",
+                "registries.tag_cache_ttl",
+                text(registries.tag_cache_ttl),
+            ),
+            setting(
+                "STARTUP_CHECK_IMAGE",
+                "registries.check_image",
+                registries.check_image,
+            ),
+            setting("PREFIX", "server.prefix", server.prefix),
+            setting(
+                "READY_MAX_FETCH_AGE_SECS",
+                "server.ready_max_fetch_age_secs",
+                text(server.ready_max_fetch_age_secs),
+            ),
+            setting(
+                "STATUS_HISTORY",
+                "server.status_history",
+                text(server.status_history),
+            ),
+            setting(
+                "METRICS_REQUIRE_SECRET",
+                "server.metrics_require_secret",
+                text(server.metrics_require_secret),
+            ),
+            setting(
+                "MAX_CANDIDATE_METRICS",
+                "server.max_candidate_metrics",
+                text(server.max_candidate_metrics),
+            ),
+            setting(
+                "MAX_TRIGGERS_PER_MINUTE",
+                "server.max_triggers_per_minute",
+                text(server.max_triggers_per_minute),
+            ),
+            setting(
+                "DELIVERY_ID_TTL_SECS",
+                "server.delivery_id_ttl_secs",
+                text(server.delivery_id_ttl_secs),
+            ),
+            secret_setting("SLACK_WEBHOOK_URL", "slack.webhook_url", slack.webhook_url),
+            setting(
+                "SLACK_NOTIFY_ALWAYS",
+                "slack.notify_always",
+                text(slack.notify_always),
+            ),
+            secret_setting("NOTIFY_URL", "notify.url", notify.url),
+            secret_setting("NOTIFY_SECRET", "notify.secret", notify.secret),
+            setting("SMTP_HOST", "smtp.host", smtp.host),
+            setting("SMTP_PORT", "smtp.port", text(smtp.port)),
+            setting("SMTP_USERNAME", "smtp.username", smtp.username),
+            secret_setting("SMTP_PASSWORD", "smtp.password", smtp.password),
+            setting("SMTP_FROM", "smtp.from", smtp.from),
+            setting("SMTP_TO", "smtp.to", list(smtp.to, ",")),
+            setting(
+                "SMTP_NOTIFY_SUCCESS",
+                "smtp.notify_success",
+                text(smtp.notify_success),
+            ),
+            setting("AUDIT_LOG_PATH", "audit.log_path", audit.log_path),
+            setting("SCAN_INCLUDE", "scan.include", list(scan.include, ",")),
+            setting("SCAN_EXCLUDE", "scan.exclude", list(scan.exclude, ",")),
+            secret_setting("SENTRY_DSN", "sentry.dsn", sentry.dsn),
+            setting(
+                "SENTRY_ENVIRONMENT",
+                "sentry.environment",
+                sentry.environment,
+            ),
+        ]
+    }
+}
+
+/// Loads the YAML, or TOML, file at `CONFIG_FILE` if it's set, then the
+/// secrets given as files.
+pub fn load() -> Result<()> {
+    if let Ok(path) = std::env::var("CONFIG_FILE") {
+        load_file(PathBuf::from(path))?;
+    }
+
+    let _ = SECRET_FILES.set(read_secret_files()?);
+    Ok(())
+}
+
+fn load_file(path: PathBuf) -> Result<()> {
+    let mut file = ConfigFile::read(&path)?;
+    let repositories = file.repositories.take().unwrap_or_default();
+    let values = file
+        .settings()
+        .into_iter()
+        .filter_map(|setting| Some((setting.var, setting.value?)))
+        .collect();
+    log::info!("Loaded the configuration from {}", path.display());
+
+    let _ = LOADED.set(Loaded {
+        path,
+        values,
+        repositories,
+    });
+    Ok(())
+}
+
+/// Reads the secrets whose `*_FILE` variable is set, unless the environment
+/// has the secret itself. The file's trailing newlines aren't part of the
+/// secret.
+fn read_secret_files() -> Result<HashMap<&'static str, String>> {
+    let mut secrets = HashMap::new();
+    for setting in ConfigFile::default().settings() {
+        if !setting.secret || std::env::var(setting.var).is_ok() {
+            continue;
+        }
+        let file_var = format!("{}_FILE", setting.var);
+        let Ok(path) = var(&file_var) else {
+            continue;
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {} {}", file_var, path))?;
+        let secret = contents.trim_end_matches(['\r', '\n']);
+        if secret.is_empty() {
+            anyhow::bail!("{} {} is empty", file_var, path);
+        }
+        secrets.insert(setting.var, secret.to_string());
+    }
+
+    Ok(secrets)
+}
+
+/// Reads a setting from the environment, falling back to the file its
+/// `*_FILE` variable points to for secrets, then to the configuration file.
+pub fn var(name: &str) -> Result<String, VarError> {
+    match std::env::var(name) {
+        Err(VarError::NotPresent) => source_value(name).ok_or(VarError::NotPresent),
+        result => result,
+    }
+}
+
+fn source_value(name: &str) -> Option<String> {
+    if let Some(secret) = SECRET_FILES.get().and_then(|secrets| secrets.get(name)) {
+        return Some(secret.clone());
+    }
+
+    LOADED
+        .get()
+        .and_then(|loaded| loaded.values.get(name).cloned())
+}
+
+/// Reads a setting that has to be set, saying where to set it otherwise.
+pub fn required(name: &str) -> Result<String> {
+    if let Ok(value) = var(name) {
+        return Ok(value);
+    }
+
+    let key = ConfigFile::default()
+        .settings()
+        .into_iter()
+        .find(|setting| setting.var == name)
+        .map_or("", |setting| setting.key);
+    match LOADED.get() {
+        Some(loaded) => anyhow::bail!(
+            "{} isn't set, set it or `{}` in {}",
+            name,
+            key,
+            loaded.path.display()
+        ),
+        None => anyhow::bail!("{} isn't set, set it or `{}` in a CONFIG_FILE", name, key),
+    }
+}
+
+/// The repositories listed in the file, if any.
+pub fn repositories() -> Vec<RepositoryEntry> {
+    LOADED
+        .get()
+        .map(|loaded| loaded.repositories.clone())
+        .unwrap_or_default()
+}
+
+/// Logs the settings that are set and where they come from, without the
+/// secrets.
+pub fn log_effective() {
+    let mut lines = vec![];
+    for setting in ConfigFile::default().settings() {
+        let Ok(value) = var(setting.var) else {
+            continue;
+        };
+        let source = match std::env::var(setting.var) {
+            Ok(_) => "environment".to_string(),
+            Err(_)
+                if SECRET_FILES
+                    .get()
+                    .is_some_and(|secrets| secrets.contains_key(setting.var)) =>
+            {
+                format!("{}_FILE", setting.var)
+            }
+            Err(_) => "config file".to_string(),
+        };
+        let value = match setting.secret {
+            true => "<redacted>".to_string(),
+            false => value.replace('\n', "\\n"),
+        };
+        lines.push(format!("  {}={} ({})", setting.var, value, source));
+    }
+
+    log::info!("Effective configuration:\n{}", lines.join("\n"));
+}
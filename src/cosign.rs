@@ -0,0 +1,128 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use oci_client::{client, secrets::RegistryAuth};
+use sigstore::{
+    cosign::{
+        verification_constraint::{
+            cert_subject_email_verifier::StringVerifier, CertSubjectEmailVerifier,
+            CertSubjectUrlVerifier, PublicKeyVerifier, VerificationConstraint,
+        },
+        verify_constraints, ClientBuilder, CosignCapabilities,
+    },
+    errors::SigstoreError,
+    registry::{
+        Auth, Certificate, CertificateEncoding, ClientConfig, ClientProtocol, OciReference,
+    },
+    trust::sigstore::SigstoreTrustRoot,
+};
+use tokio::sync::OnceCell;
+
+use crate::registry::SignatureRequirement;
+
+/// Verifies the cosign signatures of the selected tags. The Fulcio
+/// certificates and the Rekor key keyless signatures are checked against are
+/// fetched from the Sigstore TUF repository the first time they're needed.
+#[derive(Default)]
+pub struct Signatures {
+    trust_root: OnceCell<SigstoreTrustRoot>,
+}
+
+impl Signatures {
+    /// Returns why the image at `reference` isn't signed as `requirement`
+    /// wants, if it isn't.
+    pub async fn verify(
+        &self,
+        client_config: &client::ClientConfig,
+        auth: &RegistryAuth,
+        reference: &str,
+        requirement: &SignatureRequirement,
+    ) -> Result<Option<String>> {
+        let reference = OciReference::from_str(reference)?;
+        let auth = match auth {
+            RegistryAuth::Anonymous => Auth::Anonymous,
+            RegistryAuth::Basic(username, password) => {
+                Auth::Basic(username.clone(), password.clone())
+            }
+            RegistryAuth::Bearer(token) => Auth::Bearer(token.clone()),
+        };
+
+        let mut builder = ClientBuilder::default().with_oci_client_config(convert(client_config));
+        if let SignatureRequirement::Keyless { .. } = requirement {
+            let trust_root = self
+                .trust_root
+                .get_or_try_init(|| SigstoreTrustRoot::new(None))
+                .await
+                .context("Couldn't fetch the Sigstore trust root")?;
+            builder = builder.with_trust_repository(trust_root)?;
+        }
+        let mut client = builder.build()?;
+
+        let (signatures, digest) = client.triangulate(&reference, &auth).await?;
+        let layers = match client
+            .trusted_signature_layers(&auth, &digest, &signatures)
+            .await
+        {
+            Ok(layers) => layers,
+            Err(
+                SigstoreError::RegistryPullManifestError { error, .. }
+                | SigstoreError::RegistryPullError { error, .. },
+            ) => return Ok(Some(format!("No signature found: {}", error))),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(
+            match verify_constraints(&layers, [constraint(requirement)?].iter()) {
+                Ok(()) => None,
+                Err(_) => Some(format!("No signature by {}", requirement)),
+            },
+        )
+    }
+}
+
+fn constraint(requirement: &SignatureRequirement) -> Result<Box<dyn VerificationConstraint>> {
+    Ok(match requirement {
+        SignatureRequirement::PublicKey(key) => {
+            Box::new(PublicKeyVerifier::try_from(key.as_bytes()).context("Invalid public key")?)
+        }
+        // Workflows sign with a URI identity, people with their email
+        SignatureRequirement::Keyless { identity, issuer } => match identity.contains("://") {
+            true => Box::new(CertSubjectUrlVerifier {
+                url: identity.clone(),
+                issuer: issuer.clone(),
+            }),
+            false => Box::new(CertSubjectEmailVerifier {
+                email: StringVerifier::ExactMatch(identity.clone()),
+                issuer: Some(StringVerifier::ExactMatch(issuer.clone())),
+            }),
+        },
+    })
+}
+
+/// The sigstore client embeds an older OCI client, with its own settings.
+fn convert(client_config: &client::ClientConfig) -> ClientConfig {
+    ClientConfig {
+        protocol: match &client_config.protocol {
+            client::ClientProtocol::Http => ClientProtocol::Http,
+            client::ClientProtocol::Https => ClientProtocol::Https,
+            client::ClientProtocol::HttpsExcept(hosts) => {
+                ClientProtocol::HttpsExcept(hosts.clone())
+            }
+        },
+        accept_invalid_certificates: client_config.accept_invalid_certificates,
+        extra_root_certificates: client_config
+            .extra_root_certificates
+            .iter()
+            .map(|certificate| Certificate {
+                encoding: match certificate.encoding {
+                    client::CertificateEncoding::Der => CertificateEncoding::Der,
+                    client::CertificateEncoding::Pem => CertificateEncoding::Pem,
+                },
+                data: certificate.data.clone(),
+            })
+            .collect(),
+        https_proxy: client_config.https_proxy.clone(),
+        http_proxy: client_config.http_proxy.clone(),
+        no_proxy: client_config.no_proxy.clone(),
+    }
+}
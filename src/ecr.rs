@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use oci_client::secrets::RegistryAuth;
+
+/// Tokens are refreshed this long before ECR says they expire.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Returns the AWS region of an ECR registry host such as
+/// `123456789012.dkr.ecr.eu-west-1.amazonaws.com`.
+pub fn ecr_region(host: &str) -> Option<&str> {
+    let hostname = host.split(':').next().unwrap_or(host);
+    let mut parts = hostname.split('.');
+    let _account = parts.next()?;
+    if parts.next()? != "dkr" || parts.next()? != "ecr" {
+        return None;
+    }
+    let region = parts.next()?;
+    if parts.next()? != "amazonaws" {
+        return None;
+    }
+
+    Some(region)
+}
+
+/// Short lived ECR passwords keyed by registry host.
+#[derive(Default)]
+pub struct EcrTokens {
+    tokens: Mutex<HashMap<String, (String, SystemTime)>>,
+}
+
+impl EcrTokens {
+    /// Returns the auth for an ECR `host`, asking AWS for a new token when
+    /// there's no cached one or when it's about to expire.
+    pub async fn auth_for(&self, host: &str, region: &str) -> Result<RegistryAuth> {
+        let cached = self
+            .tokens
+            .lock()
+            .unwrap()
+            .get(host)
+            .filter(|(_, expires_at)| SystemTime::now() + EXPIRY_MARGIN < *expires_at)
+            .map(|(password, _)| password.clone());
+
+        let password = match cached {
+            Some(password) => password,
+            None => {
+                let (password, expires_at) = fetch_token(region)
+                    .await
+                    .with_context(|| format!("Failed to get an ECR token for {}", host))?;
+                self.tokens
+                    .lock()
+                    .unwrap()
+                    .insert(host.to_string(), (password.clone(), expires_at));
+                password
+            }
+        };
+
+        Ok(RegistryAuth::Basic("AWS".to_string(), password))
+    }
+
+    /// Forgets the token for `host`, returning whether there was one.
+    pub fn invalidate(&self, host: &str) -> bool {
+        self.tokens.lock().unwrap().remove(host).is_some()
+    }
+}
+
+async fn fetch_token(region: &str) -> Result<(String, SystemTime)> {
+    log::info!("Requesting a new ECR authorization token for {}", region);
+    let sdk_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .region(aws_config::Region::new(region.to_string()))
+        .load()
+        .await;
+    let client = aws_sdk_ecr::Client::new(&sdk_config);
+
+    let output = client.get_authorization_token().send().await?;
+    let data = output
+        .authorization_data()
+        .first()
+        .context("ECR returned no authorization data")?;
+    let token = data
+        .authorization_token()
+        .context("ECR returned no authorization token")?;
+    let expires_at = data
+        .expires_at()
+        .and_then(|expires_at| SystemTime::try_from(*expires_at).ok())
+        .unwrap_or_else(|| SystemTime::now() + Duration::from_secs(60 * 60));
+
+    let decoded = String::from_utf8(base64::engine::general_purpose::STANDARD.decode(token)?)?;
+    let (_, password) = decoded
+        .split_once(':')
+        .context("Malformed ECR authorization token")?;
+
+    Ok((password.to_string(), expires_at))
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::{config_file, status::Run};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Emails how the runs went through an SMTP server, using STARTTLS.
+#[derive(Clone)]
+pub struct Email {
+    host: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    /// Whether the runs that went fine get an email too, not only the failed
+    /// ones.
+    notify_success: bool,
+}
+
+impl Email {
+    /// Enabled with `SMTP_HOST`, which then needs `SMTP_FROM` and `SMTP_TO`,
+    /// a comma separated list of addresses. Authenticates with `SMTP_USERNAME`
+    /// and `SMTP_PASSWORD` when they're set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(host) = config_file::var("SMTP_HOST") else {
+            for var in ["SMTP_FROM", "SMTP_TO"] {
+                if config_file::var(var).is_ok() {
+                    bail!("{} is set but SMTP_HOST isn't", var);
+                }
+            }
+            return Ok(None);
+        };
+
+        let from = config_file::var("SMTP_FROM")
+            .context("SMTP_FROM needs to be set along with SMTP_HOST")?;
+        let from = from
+            .parse()
+            .with_context(|| format!("SMTP_FROM {} isn't a valid address", from))?;
+        let to = config_file::var("SMTP_TO")
+            .context("SMTP_TO needs to be set along with SMTP_HOST")?
+            .split(',')
+            .map(str::trim)
+            .filter(|to| !to.is_empty())
+            .map(|to| {
+                to.parse()
+                    .with_context(|| format!("SMTP_TO {} isn't a valid address", to))
+            })
+            .collect::<Result<Vec<Mailbox>>>()?;
+        if to.is_empty() {
+            bail!("SMTP_TO is empty");
+        }
+
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+            .with_context(|| format!("SMTP_HOST {} isn't a valid host", host))?
+            .timeout(Some(TIMEOUT));
+        if let Ok(port) = config_file::var("SMTP_PORT") {
+            transport = transport.port(port.parse().context("SMTP_PORT")?);
+        }
+        match (
+            config_file::var("SMTP_USERNAME"),
+            config_file::var("SMTP_PASSWORD"),
+        ) {
+            (Ok(username), Ok(password)) => {
+                transport = transport.credentials(Credentials::new(username, password));
+            }
+            (Err(_), Err(_)) => {}
+            (Ok(_), Err(_)) => bail!("SMTP_USERNAME is set but SMTP_PASSWORD isn't"),
+            (Err(_), Ok(_)) => bail!("SMTP_PASSWORD is set but SMTP_USERNAME isn't"),
+        }
+
+        Ok(Some(Self {
+            host,
+            transport: transport.build(),
+            from,
+            to,
+            notify_success: config_file::var("SMTP_NOTIFY_SUCCESS").is_ok_and(|v| v == "true"),
+        }))
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Connects to the server and logs in, without sending anything.
+    pub async fn test_connection(&self) -> Result<()> {
+        self.transport.test_connection().await?;
+
+        Ok(())
+    }
+
+    /// Emails the run in the background when it failed, or when the ones that
+    /// went fine get one too. Failing to send it is only logged.
+    pub fn send(&self, run: &Run) -> Option<tokio::task::JoinHandle<()>> {
+        let failures = run.errors.len()
+            + run.repos.iter().filter(|repo| repo.error.is_some()).count()
+            + usize::from(run.error.is_some());
+        if failures == 0 && !self.notify_success {
+            return None;
+        }
+
+        let subject = match failures {
+            0 => format!("Image update run succeeded, updated {}", run.updated.len()),
+            1 => "Image update run failed".to_string(),
+            failures => format!("Image update run failed, {} failures", failures),
+        };
+        let email = self.clone();
+        let body = body(run);
+
+        Some(tokio::spawn(async move {
+            if let Err(e) = email.post(&subject, body).await {
+                log::warn!("Failed to email the run summary: {:#}", e);
+            }
+        }))
+    }
+
+    async fn post(&self, subject: &str, body: String) -> Result<()> {
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        self.transport.send(message.body(body)?).await?;
+
+        Ok(())
+    }
+}
+
+/// What `/status` says about the run, in plain text.
+fn body(run: &Run) -> String {
+    let mut lines = vec![
+        format!("Triggered by: {}", run.trigger),
+        format!("Started at: {}", run.started_at),
+        format!("Duration: {:.1}s", run.duration_secs),
+        format!("Candidates: {}", run.candidates),
+    ];
+    if let Some(error) = &run.error {
+        lines.push(format!("Error: {}", error));
+    }
+    lines.push(match run.updated.is_empty() {
+        true => "Updated: none".to_string(),
+        false => format!("Updated: {}", run.updated.join(", ")),
+    });
+
+    if !run.repos.is_empty() {
+        lines.push(String::new());
+        lines.push("Repositories:".to_string());
+    }
+    for repo in &run.repos {
+        lines.push(match (&repo.error, &repo.commit) {
+            (Some(error), _) => format!("  {}: failed: {}", repo.repo, error),
+            (None, Some(commit)) => format!("  {}: pushed {}", repo.repo, commit),
+            (None, None) => format!("  {}: nothing pushed", repo.repo),
+        });
+    }
+
+    if !run.errors.is_empty() {
+        lines.push(String::new());
+        lines.push("Failed candidates:".to_string());
+    }
+    for error in &run.errors {
+        lines.push(format!("  {} {}: {}", error.repo, error.app, error.error));
+    }
+
+    lines.join("\n") + "\n"
+}
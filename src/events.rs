@@ -0,0 +1,146 @@
+use std::sync::Mutex;
+
+use rocket::response::stream::Event;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber can lag behind before missing some.
+const CAPACITY: usize = 256;
+
+/// What happens during a run, as it happens.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunEvent {
+    Started {
+        job: u64,
+        trigger: String,
+    },
+    Checking {
+        app: String,
+        image: String,
+    },
+    Updated {
+        app: String,
+        image: String,
+        old: Option<String>,
+        new: String,
+    },
+    Skipped {
+        app: String,
+        image: String,
+        reason: String,
+    },
+    Failed {
+        app: String,
+        error: String,
+    },
+    Committed {
+        repo: String,
+        commit: String,
+    },
+    Pushed {
+        repo: String,
+        commit: String,
+    },
+    Finished {
+        job: u64,
+        succeeded: bool,
+        summary: String,
+    },
+}
+
+impl RunEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Started { .. } => "started",
+            Self::Checking { .. } => "checking",
+            Self::Updated { .. } => "updated",
+            Self::Skipped { .. } => "skipped",
+            Self::Failed { .. } => "failed",
+            Self::Committed { .. } => "committed",
+            Self::Pushed { .. } => "pushed",
+            Self::Finished { .. } => "finished",
+        }
+    }
+
+    pub fn to_sse(&self) -> Event {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Event::data(data).event(self.name())
+    }
+}
+
+impl std::fmt::Display for RunEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Started { job, trigger } => write!(f, "Job {} started by {}", job, trigger),
+            Self::Checking { app, image } => write!(f, "{}: checking {}", app, image),
+            Self::Updated {
+                app,
+                image,
+                old,
+                new,
+            } => write!(
+                f,
+                "{}: updated {} {} -> {}",
+                app,
+                image,
+                old.as_deref().unwrap_or("absent"),
+                new
+            ),
+            Self::Skipped { app, image, reason } => {
+                write!(f, "{}: skipped {}: {}", app, image, reason)
+            }
+            Self::Failed { app, error } => write!(f, "{}: failed: {}", app, error),
+            Self::Committed { repo, commit } => write!(f, "{}: committed {}", repo, commit),
+            Self::Pushed { repo, commit } => write!(f, "{}: pushed {}", repo, commit),
+            Self::Finished {
+                job,
+                succeeded,
+                summary,
+            } => write!(
+                f,
+                "Job {} {}: {}",
+                job,
+                if *succeeded { "succeeded" } else { "failed" },
+                summary
+            ),
+        }
+    }
+}
+
+/// Broadcasts the events of the runs to whoever listens, remembering how the
+/// last one ended for those coming in between runs.
+pub struct Events {
+    sender: broadcast::Sender<RunEvent>,
+    /// The last `finished` event, unless a run is in progress.
+    last_finished: Mutex<Option<RunEvent>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            last_finished: Mutex::new(None),
+        }
+    }
+
+    pub fn emit(&self, event: RunEvent) {
+        match &event {
+            RunEvent::Started { .. } => *self.last_finished.lock().unwrap() = None,
+            RunEvent::Finished { .. } => {
+                *self.last_finished.lock().unwrap() = Some(event.clone());
+            }
+            _ => {}
+        }
+
+        // Nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to the coming events, along with how the last run ended
+    /// when none is in progress.
+    pub fn subscribe(&self) -> (Option<RunEvent>, broadcast::Receiver<RunEvent>) {
+        let last_finished = self.last_finished.lock().unwrap();
+        (last_finished.clone(), self.sender.subscribe())
+    }
+}
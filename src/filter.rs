@@ -0,0 +1,225 @@
+use anyhow::{bail, Context, Result};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use semver::VersionReq;
+
+use crate::strategy::parse_version;
+
+/// Parsed form of an `allow-tags` annotation.
+#[derive(Clone, Debug)]
+pub enum TagFilter {
+    Regexp(Regex),
+    Semver(VersionReq),
+    /// Without a prefix, a comma separated list like `v1.*, 1.0.?`.
+    Globs(Vec<GlobMatcher>),
+}
+
+impl TagFilter {
+    /// `anchored` makes a regex have to match whole tags, as if it were
+    /// wrapped in `^(?:...)$`.
+    pub fn parse(allow_tags: &str, anchored: bool) -> Result<Self> {
+        if let Some(constraint) = allow_tags.strip_prefix("semver:") {
+            let req = VersionReq::parse(constraint.trim())
+                .with_context(|| format!("Invalid semver constraint: {}", constraint))?;
+            return Ok(Self::Semver(req));
+        }
+        if let Some(re) = allow_tags.strip_prefix("regexp:") {
+            let re = match anchored {
+                true => Regex::new(&format!("^(?:{})$", re))?,
+                false => Regex::new(re)?,
+            };
+            return Ok(Self::Regexp(re));
+        }
+
+        // These used to be read as regexes, which would now silently match
+        // nothing
+        if allow_tags.contains(['^', '$', '\\', '+', '(', ')', '|']) {
+            bail!(
+                "{} is a regex rather than a list of globs, prefix it with `regexp:`",
+                allow_tags
+            );
+        }
+
+        allow_tags
+            .split(',')
+            .map(str::trim)
+            .filter(|glob| !glob.is_empty())
+            .map(|glob| {
+                Glob::new(glob)
+                    .map(|glob| glob.compile_matcher())
+                    .with_context(|| format!("Invalid glob: {}", glob))
+            })
+            .collect::<Result<_>>()
+            .map(Self::Globs)
+    }
+
+    /// The version embedded in `tag`, when the regex captures it: in a group
+    /// named `version`, or else the first one.
+    pub fn version<'a>(&self, tag: &'a str) -> Option<&'a str> {
+        let Self::Regexp(re) = self else {
+            return None;
+        };
+        let captures = re.captures(tag)?;
+
+        captures
+            .name("version")
+            .or_else(|| captures.get(1))
+            .map(|version| version.as_str())
+    }
+
+    pub fn matches(&self, tag: &str) -> bool {
+        match self {
+            Self::Regexp(re) => re.is_match(tag),
+            Self::Semver(req) => parse_version(tag).is_some_and(|version| req.matches(&version)),
+            // Like an empty regex, as the digest strategy has no `allow-tags`
+            Self::Globs(globs) => globs.is_empty() || globs.iter().any(|glob| glob.is_match(tag)),
+        }
+    }
+}
+
+impl std::fmt::Display for TagFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Regexp(re) => write!(f, "the regex {}", re),
+            Self::Semver(req) => write!(f, "the semver constraint {}", req),
+            Self::Globs(globs) => {
+                let globs = globs
+                    .iter()
+                    .map(|glob| glob.glob().glob())
+                    .collect::<Vec<_>>();
+                write!(f, "the globs {}", globs.join(", "))
+            }
+        }
+    }
+}
+
+/// One entry of an `ignore-tags` annotation.
+#[derive(Clone, Debug)]
+pub enum IgnoredTag {
+    Exact(String),
+    Regexp(Regex),
+}
+
+impl IgnoredTag {
+    /// Parses a comma separated list of exact tags and `regexp:` patterns.
+    pub fn parse_list(ignore_tags: &str) -> Result<Vec<Self>> {
+        ignore_tags
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.strip_prefix("regexp:") {
+                Some(pattern) => Ok(Self::Regexp(Regex::new(pattern)?)),
+                None => Ok(Self::Exact(entry.to_string())),
+            })
+            .collect()
+    }
+
+    pub fn matches(&self, tag: &str) -> bool {
+        match self {
+            Self::Exact(exact) => exact == tag,
+            Self::Regexp(re) => re.is_match(tag),
+        }
+    }
+}
+
+impl std::fmt::Display for IgnoredTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(exact) => write!(f, "{}", exact),
+            Self::Regexp(re) => write!(f, "regexp:{}", re),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matching<'a>(allow_tags: &str, tags: &[&'a str]) -> Vec<&'a str> {
+        let filter = TagFilter::parse(allow_tags, false).unwrap();
+        tags.iter()
+            .copied()
+            .filter(|tag| filter.matches(tag))
+            .collect()
+    }
+
+    #[test]
+    fn globs_and_regexes_differ() {
+        let tags = [
+            "v1",
+            "v1.5",
+            "v10",
+            "release-v1.5",
+            "1.0.",
+            "1.0.1",
+            "1.0.10",
+            "1x0",
+        ];
+
+        assert_eq!(matching("v1.*", &tags), ["v1.5"]);
+        assert_eq!(
+            matching("regexp:v1.*", &tags),
+            ["v1", "v1.5", "v10", "release-v1.5"]
+        );
+
+        assert_eq!(matching("1.0.?", &tags), ["1.0.1"]);
+        assert_eq!(
+            matching("regexp:1.0.?", &tags),
+            ["1.0.", "1.0.1", "1.0.10", "1x0"]
+        );
+    }
+
+    #[test]
+    fn globs_are_a_list() {
+        let tags = ["v1.5", "v2.0", "1.0.1", "latest"];
+
+        assert_eq!(matching("v1.*, 1.0.?", &tags), ["v1.5", "1.0.1"]);
+        assert_eq!(matching("*", &tags), tags);
+    }
+
+    #[test]
+    fn rejects_regexes_without_a_prefix() {
+        for allow_tags in [r"^v\d+$", "v1.(0|1)", "1.0.[0-9]+"] {
+            let e = TagFilter::parse(allow_tags, false).unwrap_err();
+            assert!(e.to_string().contains("regexp:"), "{}: {}", allow_tags, e);
+        }
+    }
+
+    #[test]
+    fn anchors_regexes() {
+        let tags = ["1.0", "1.0-rc1", "v1.0"];
+
+        assert_eq!(matching(r"regexp:1\.0", &tags), tags);
+        let filter = TagFilter::parse(r"regexp:1\.0", true).unwrap();
+        assert!(filter.matches("1.0") && !filter.matches("1.0-rc1") && !filter.matches("v1.0"));
+    }
+
+    #[test]
+    fn semver() {
+        let tags = ["1.2.3", "v1.4.0", "2.0.0", "latest"];
+
+        assert_eq!(matching("semver:^1.2", &tags), ["1.2.3", "v1.4.0"]);
+        assert!(TagFilter::parse("semver:nope", false).is_err());
+    }
+
+    #[test]
+    fn captures_the_version() {
+        let filter = TagFilter::parse(r"regexp:^build-(?<version>\d+)-\w+$", false).unwrap();
+        assert_eq!(filter.version("build-42-abc"), Some("42"));
+
+        let filter = TagFilter::parse(r"regexp:^v(\d+\.\d+)$", false).unwrap();
+        assert_eq!(filter.version("v1.2"), Some("1.2"));
+        assert_eq!(filter.version("latest"), None);
+    }
+
+    #[test]
+    fn ignores_exact_tags_and_regexes() {
+        let ignored = IgnoredTag::parse_list(" 1.2.0, regexp:-rc\\d+$ ,").unwrap();
+        let is_ignored = |tag| ignored.iter().any(|ignored| ignored.matches(tag));
+
+        assert_eq!(ignored.len(), 2);
+        assert!(is_ignored("1.2.0") && is_ignored("1.3.0-rc1"));
+        assert!(!is_ignored("1.2.0.1") && !is_ignored("1.3.0"));
+        assert!(IgnoredTag::parse_list("regexp:(").is_err());
+    }
+}
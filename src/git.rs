@@ -0,0 +1,968 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use git2::{
+    Cred, Direction, FetchOptions, Oid, PushOptions, RemoteCallbacks, Repository,
+    RepositoryInitOptions, ResetType, Signature,
+};
+use pgp::{
+    composed::{ArmorOptions, Deserializable, DetachedSignature, SignedSecretKey},
+    crypto::hash::HashAlgorithm,
+    types::Password,
+};
+
+use crate::config_file;
+use crate::known_hosts::HostKeyCheck;
+use crate::writeback::Change;
+
+/// How to authenticate against the git remote.
+pub enum GitCredentials {
+    /// A private key, optionally protected by a passphrase.
+    Ssh {
+        key: SshKey,
+        passphrase: Option<String>,
+    },
+    /// Whatever key the agent listening on `SSH_AUTH_SOCK` has.
+    SshAgent,
+    /// A token from `GIT_HTTPS_TOKEN` for `https://` remotes.
+    Https { username: String, token: String },
+    /// Nothing, for the `https://` remotes that can be read without a token.
+    Anonymous,
+}
+
+pub enum SshKey {
+    /// The key file `SSH_KEY_PATH` points to.
+    Path(PathBuf),
+    /// The key's contents from `SSH_KEY`, along with the public key from
+    /// `SSH_PUBLIC_KEY` for the formats that can't derive it.
+    Memory {
+        private_key: String,
+        public_key: Option<String>,
+    },
+}
+
+impl GitCredentials {
+    /// Uses the repository's own key when it has one, or the environment's.
+    pub fn from_env(repository_url: &str, repository_key: Option<SshKey>) -> Result<Self> {
+        if !is_https(repository_url) {
+            // The key's contents win over a path to it
+            let key = match (
+                repository_key,
+                config_file::var("SSH_KEY"),
+                config_file::var("SSH_KEY_PATH"),
+            ) {
+                (Some(key), _, _) => key,
+                (None, Ok(private_key), _) => SshKey::Memory {
+                    private_key,
+                    public_key: config_file::var("SSH_PUBLIC_KEY").ok(),
+                },
+                (None, Err(_), Ok(key_path)) => SshKey::Path(key_path.into()),
+                (None, Err(_), Err(_)) if std::env::var("SSH_AUTH_SOCK").is_ok() => {
+                    return Ok(Self::SshAgent);
+                }
+                (None, Err(_), Err(_)) => {
+                    bail!("Either SSH_KEY or SSH_KEY_PATH needs to be set when not using an ssh agent")
+                }
+            };
+
+            return Ok(Self::Ssh {
+                key,
+                passphrase: config_file::var("SSH_KEY_PASSPHRASE").ok(),
+            });
+        }
+
+        let token = config_file::var("GIT_HTTPS_TOKEN").with_context(|| {
+            format!(
+                "{} is an https remote, GIT_HTTPS_TOKEN needs to be set",
+                repository_url
+            )
+        })?;
+
+        Ok(Self::Https {
+            // Forges like GitHub don't care about the username when using a token
+            username: config_file::var("GIT_HTTPS_USERNAME")
+                .unwrap_or_else(|_| "x-access-token".to_string()),
+            token,
+        })
+    }
+
+    /// Callbacks answering the remote's authentication requests and verifying
+    /// its host key, shared by fetching and pushing.
+    pub fn remote_callbacks<'a>(&'a self, host_keys: &'a HostKeyCheck) -> RemoteCallbacks<'a> {
+        let mut cb = RemoteCallbacks::new();
+        cb.certificate_check(|cert, host| host_keys.check(cert, host));
+        cb.credentials(move |_, username, _| match self {
+            Self::Ssh {
+                key: SshKey::Path(key_path),
+                passphrase,
+            } => Cred::ssh_key(
+                username.unwrap_or("git"),
+                None,
+                key_path,
+                passphrase.as_deref(),
+            ),
+            Self::Ssh {
+                key:
+                    SshKey::Memory {
+                        private_key,
+                        public_key,
+                    },
+                passphrase,
+            } => Cred::ssh_key_from_memory(
+                username.unwrap_or("git"),
+                public_key.as_deref(),
+                private_key,
+                passphrase.as_deref(),
+            ),
+            Self::SshAgent => Cred::ssh_key_from_agent(username.unwrap_or("git")),
+            Self::Https { username, token } => Cred::userpass_plaintext(username, token),
+            Self::Anonymous => Err(git2::Error::from_str(
+                "The remote needs credentials, set GIT_HTTPS_TOKEN",
+            )),
+        });
+
+        cb
+    }
+}
+
+pub fn is_https(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+pub fn is_local(repo_url: &str) -> bool {
+    repo_url.starts_with("file://") || Path::new(repo_url).exists()
+}
+
+/// The branches of the remote, connecting to it without fetching anything.
+pub fn remote_branches(
+    repo_url: &str,
+    credentials: &GitCredentials,
+    host_keys: &HostKeyCheck,
+) -> Result<Vec<String>> {
+    let mut remote = git2::Remote::create_detached(repo_url)?;
+    let connection = remote.connect_auth(
+        Direction::Fetch,
+        Some(credentials.remote_callbacks(host_keys)),
+        None,
+    )?;
+
+    Ok(connection
+        .list()?
+        .iter()
+        .filter_map(|head| head.name().strip_prefix("refs/heads/"))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Fetches `branch`, or the remote's default branch when it's not set, and
+/// resets the checkout to it. Returns the branch that was checked out.
+///
+/// Only the last `depth` commits get fetched, the fetched commit being all
+/// that's needed as a parent for the update. A depth of 0 fetches everything.
+pub fn clone_or_reset(
+    repo_url: &str,
+    repo_path: &Path,
+    credentials: &GitCredentials,
+    host_keys: &HostKeyCheck,
+    branch: Option<&str>,
+    depth: i32,
+) -> Result<(Repository, String)> {
+    log::info!("Resetting upstream repo");
+
+    let mut init_opts = RepositoryInitOptions::new();
+    init_opts.initial_head(branch.unwrap_or("main"));
+
+    let repo = Repository::init_opts(repo_path, &init_opts)?;
+    let branch = {
+        let mut remote = repo
+            .find_remote("origin")
+            .or_else(|_| repo.remote("origin", repo_url))?;
+
+        let mut connection = remote.connect_auth(
+            Direction::Fetch,
+            Some(credentials.remote_callbacks(host_keys)),
+            None,
+        )?;
+        let branch = match branch {
+            Some(branch) => branch.to_string(),
+            None => {
+                let default_branch = connection.default_branch()?;
+                let default_branch = default_branch
+                    .as_str()
+                    .context("The remote's default branch isn't valid UTF-8")?;
+                default_branch
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(default_branch)
+                    .to_string()
+            }
+        };
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(credentials.remote_callbacks(host_keys));
+        // libgit2 can't fetch shallowly from a repository on disk.
+        if depth > 0 && !is_local(repo_url) {
+            fetch_options.depth(depth);
+        }
+        connection
+            .remote()
+            .fetch(&[&branch], Some(&mut fetch_options), None)
+            .with_context(|| format!("Failed to fetch branch {}", branch))?;
+
+        let fetch_head = repo.find_reference("FETCH_HEAD")?;
+        let commit = fetch_head.peel_to_commit()?;
+
+        // Make sure the local branch is the one being pushed later on, even if
+        // a previous run checked out another one.
+        let reference = format!("refs/heads/{}", branch);
+        repo.reference(&reference, commit.id(), true, "Reset to the remote")?;
+        repo.set_head(&reference)?;
+        repo.reset(commit.as_object(), ResetType::Hard, None)?;
+
+        branch
+    };
+
+    Ok((repo, branch))
+}
+
+/// Who the automatic commits are attributed to.
+pub struct CommitIdentity {
+    pub author_name: String,
+    pub author_email: String,
+    pub committer_name: String,
+    pub committer_email: String,
+}
+
+impl CommitIdentity {
+    /// Reads `GIT_AUTHOR_NAME`/`GIT_AUTHOR_EMAIL`, the committer defaulting to
+    /// the author unless `GIT_COMMITTER_NAME`/`GIT_COMMITTER_EMAIL` are set.
+    pub fn from_env() -> Self {
+        let author_name = config_file::var("GIT_AUTHOR_NAME")
+            .unwrap_or_else(|_| "Automatic image updater".to_string());
+        let author_email = config_file::var("GIT_AUTHOR_EMAIL")
+            .unwrap_or_else(|_| "nobody@bananium.fr".to_string());
+
+        Self {
+            committer_name: config_file::var("GIT_COMMITTER_NAME")
+                .unwrap_or_else(|_| author_name.clone()),
+            committer_email: config_file::var("GIT_COMMITTER_EMAIL")
+                .unwrap_or_else(|_| author_email.clone()),
+            author_name,
+            author_email,
+        }
+    }
+}
+
+/// Signs the automatic commits with a PGP key.
+pub struct CommitSigner {
+    key: SignedSecretKey,
+    passphrase: Password,
+}
+
+impl CommitSigner {
+    /// Reads the armored private key from `GPG_SIGNING_KEY`, either directly or
+    /// from the file it points to, unlocked with `GPG_SIGNING_KEY_PASSPHRASE`.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(key) = config_file::var("GPG_SIGNING_KEY") else {
+            return Ok(None);
+        };
+
+        let armored = if key.trim_start().starts_with("-----BEGIN") {
+            key
+        } else {
+            std::fs::read_to_string(&key)
+                .with_context(|| format!("Failed to read the signing key from {}", key))?
+        };
+        let (key, _) =
+            SignedSecretKey::from_string(&armored).context("Failed to parse the signing key")?;
+        let passphrase = config_file::var("GPG_SIGNING_KEY_PASSPHRASE")
+            .map(Password::from)
+            .unwrap_or_else(|_| Password::empty());
+
+        Ok(Some(Self { key, passphrase }))
+    }
+
+    /// Returns the armored detached signature of `data`.
+    fn sign(&self, data: &[u8]) -> Result<String> {
+        let signature = DetachedSignature::sign_binary_data(
+            rand::thread_rng(),
+            &self.key.primary_key,
+            &self.passphrase,
+            HashAlgorithm::Sha256,
+            data,
+        )?;
+
+        Ok(signature.to_armored_string(ArmorOptions::default())?)
+    }
+}
+
+/// Whether the changes of a run get committed together or one app at a time,
+/// from `COMMIT_GRANULARITY`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitGranularity {
+    #[default]
+    Combined,
+    /// One commit per app, in the order of their names.
+    PerApp,
+}
+
+impl CommitGranularity {
+    pub fn from_env() -> Result<Self> {
+        match config_file::var("COMMIT_GRANULARITY").as_deref() {
+            Err(_) | Ok("combined") => Ok(Self::Combined),
+            Ok("per-app") => Ok(Self::PerApp),
+            Ok(granularity) => bail!("Unknown commit granularity: {}", granularity),
+        }
+    }
+}
+
+/// Whether each run gets its own commit, or folds its changes into the
+/// updater's previous commit, from `COMMIT_MODE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitMode {
+    #[default]
+    Stack,
+    /// Amends the tip of the branch when the updater wrote it. Only applies
+    /// when pushing a single commit directly, pull requests get force-pushed
+    /// anyway.
+    Amend,
+}
+
+impl CommitMode {
+    pub fn from_env() -> Result<Self> {
+        match config_file::var("COMMIT_MODE").as_deref() {
+            Err(_) | Ok("stack") => Ok(Self::Stack),
+            Ok("amend") => Ok(Self::Amend),
+            Ok(mode) => bail!("Unknown commit mode: {}", mode),
+        }
+    }
+
+    /// The depth to fetch the checkouts at given `GIT_FETCH_DEPTH`. The commit
+    /// at the tip of a depth 1 checkout has no parent to amend it on top of,
+    /// so amending fetches 2 commits at least.
+    pub fn fetch_depth(self, depth: i32) -> i32 {
+        match self {
+            Self::Amend if depth == 1 => 2,
+            _ => depth,
+        }
+    }
+}
+
+/// The updater's own commit at the tip of the branch, which the new changes
+/// get folded into.
+pub struct Amend {
+    pub commit: Oid,
+    pub changes: Vec<Change>,
+}
+
+impl Amend {
+    /// Returns the tip of the checked out branch if the updater wrote it, going
+    /// by its author and subject, so that a commit pushed by someone else in
+    /// between is never rewritten.
+    pub fn find(
+        repo: &Repository,
+        identity: &CommitIdentity,
+        template: Option<&str>,
+    ) -> Result<Option<Self>> {
+        let head = repo.head()?.peel_to_commit()?;
+        let author = head.author();
+        if author.name() != Some(identity.author_name.as_str())
+            || author.email() != Some(identity.author_email.as_str())
+        {
+            log::info!("{} isn't the updater's, not amending it", head.id());
+            return Ok(None);
+        }
+
+        let message = head.message().unwrap_or_default();
+        let subject = message.lines().next().unwrap_or_default();
+        if head.parent_count() == 0 && repo.is_shallow() {
+            log::warn!(
+                "{} is the oldest commit of the shallow checkout, GIT_FETCH_DEPTH has to be at least 2 to amend it",
+                head.id()
+            );
+            return Ok(None);
+        }
+        if head.parent_count() != 1 || !is_updater_subject(template, subject) {
+            log::info!("{} doesn't look like an update, not amending it", head.id());
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            commit: head.id(),
+            changes: message.lines().skip(1).filter_map(Change::parse).collect(),
+        }))
+    }
+
+    /// Combines the amended commit's changes with `changes`. An image updated
+    /// again keeps the tag it had before the amended commit.
+    pub fn merge(&self, changes: &[Change]) -> Vec<Change> {
+        let mut merged = self.changes.clone();
+        for change in changes {
+            let previous = merged
+                .iter_mut()
+                .find(|previous| match (&**previous, change) {
+                    (
+                        Change::Tag {
+                            app_name, image, ..
+                        },
+                        Change::Tag {
+                            app_name: new_app_name,
+                            image: new_image,
+                            ..
+                        },
+                    ) => app_name == new_app_name && image == new_image,
+                    (
+                        Change::Pruned {
+                            app_name,
+                            parameter,
+                            ..
+                        },
+                        Change::Pruned {
+                            app_name: new_app_name,
+                            parameter: new_parameter,
+                            ..
+                        },
+                    ) => app_name == new_app_name && parameter == new_parameter,
+                    _ => false,
+                });
+
+            match (previous, change) {
+                (
+                    Some(Change::Tag { new_tag, path, .. }),
+                    Change::Tag {
+                        new_tag: newer_tag,
+                        path: newer_path,
+                        ..
+                    },
+                ) => {
+                    *new_tag = newer_tag.clone();
+                    *path = newer_path.clone();
+                }
+                (Some(_), _) => {}
+                (None, change) => merged.push(change.clone()),
+            }
+        }
+
+        merged
+    }
+}
+
+/// Whether `subject` is one the updater writes, with `template` or by default.
+fn is_updater_subject(template: Option<&str>, subject: &str) -> bool {
+    let pattern = match template {
+        Some(template) => regex::escape(template.lines().next().unwrap_or_default())
+            .replace(r"\{count\}", r"\d+")
+            .replace(r"\{changes\}", ".*")
+            .replace(r"\{app\}", ".*"),
+        None => r"(?:Update \d+ images?|Prune stale parameters)(?: of \S+)?".to_string(),
+    };
+
+    regex::Regex::new(&format!("^(?:{})$", pattern)).is_ok_and(|re| re.is_match(subject))
+}
+
+/// Commits the files the changes were written to, returning whether there was
+/// anything to commit. With `amend`, the commit replaces the tip of the branch
+/// instead of being added on top of it.
+pub fn add_and_commit(
+    repo: &Repository,
+    identity: &CommitIdentity,
+    signer: Option<&CommitSigner>,
+    changes: &[Change],
+    amend: Option<&Amend>,
+    message: &str,
+    trailers: &[String],
+) -> Result<bool> {
+    // Only stage what the updater wrote, anything else lying around in the
+    // checkout has no business being pushed
+    let paths = changes.iter().map(Change::path).collect::<BTreeSet<_>>();
+    if paths.is_empty() {
+        bail!("Refusing to commit, no file was written");
+    }
+
+    let mut index = repo.index()?;
+    for path in paths {
+        index
+            .add_path(path)
+            .with_context(|| format!("Failed to stage {:?}", path))?;
+    }
+    index.write()?;
+
+    let oid = index.write_tree()?;
+    let head = repo.head()?.peel_to_commit()?;
+    if head.tree_id() == oid {
+        log::info!("Nothing changed once staged, skipping commit");
+        return Ok(false);
+    }
+
+    let author = Signature::now(&identity.author_name, &identity.author_email)?;
+    let committer = Signature::now(&identity.committer_name, &identity.committer_email)?;
+    let tree = repo.find_tree(oid).unwrap();
+    let parents = match amend {
+        Some(amend) => {
+            log::info!("Amending {}", amend.commit);
+            head.parents().collect()
+        }
+        None => vec![head],
+    };
+    let parents = parents.iter().collect::<Vec<_>>();
+    let message = &with_trailers(message, trailers);
+
+    // The commit is created without updating any reference, as neither signed
+    // nor amended commits can be, HEAD gets moved to it afterwards.
+    let commit = match signer {
+        Some(signer) => {
+            let buffer =
+                repo.commit_create_buffer(&author, &committer, message, &tree, &parents)?;
+            let buffer = buffer
+                .as_str()
+                .context("The commit to sign isn't valid UTF-8")?;
+            let signature = signer
+                .sign(buffer.as_bytes())
+                .context("Failed to sign the commit")?;
+            repo.commit_signed(buffer, &signature, None)?
+        }
+        None => repo.commit(None, &author, &committer, message, &tree, &parents)?,
+    };
+    repo.head()?.set_target(commit, message)?;
+
+    Ok(true)
+}
+
+/// How many times pushing is attempted when the remote moved in the meantime.
+pub const PUSH_ATTEMPTS: usize = 3;
+
+/// The remote refused to update a reference.
+#[derive(Debug)]
+pub struct PushRejected {
+    pub reference: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for PushRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The remote rejected {}: {}", self.reference, self.reason)
+    }
+}
+
+impl std::error::Error for PushRejected {}
+
+/// Every push attempt was rejected because the remote kept moving.
+#[derive(Debug)]
+pub struct RemoteKeptMoving {
+    pub attempts: usize,
+}
+
+impl std::fmt::Display for RemoteKeptMoving {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Gave up pushing after {} attempts, the remote kept moving",
+            self.attempts
+        )
+    }
+}
+
+impl std::error::Error for RemoteKeptMoving {}
+
+/// Pushes `refspec`. With a `lease`, the remote reference must still point to
+/// that commit for the push to go through, like `git push --force-with-lease`.
+pub fn push(
+    repo: &Repository,
+    credentials: &GitCredentials,
+    host_keys: &HostKeyCheck,
+    refspec: &str,
+    lease: Option<Oid>,
+) -> Result<()> {
+    let mut remote = repo.find_remote("origin")?;
+    let mut connection = remote.connect_auth(
+        Direction::Push,
+        Some(credentials.remote_callbacks(host_keys)),
+        None,
+    )?;
+
+    if let Some(lease) = lease {
+        let refspec = refspec.trim_start_matches('+');
+        let destination = refspec.split_once(':').map_or(refspec, |(_, dst)| dst);
+        let current = connection
+            .list()?
+            .iter()
+            .find(|head| head.name() == destination)
+            .map(|head| head.oid());
+        if current != Some(lease) {
+            return Err(PushRejected {
+                reference: destination.to_string(),
+                reason: "stale info".to_string(),
+            }
+            .into());
+        }
+    }
+
+    // Rejections by the server don't fail the push itself, they're only
+    // reported through this callback
+    let mut rejected = None;
+    let mut push_cb = RemoteCallbacks::new();
+    push_cb.push_update_reference(|reference, status| {
+        if let Some(status) = status {
+            rejected = Some(PushRejected {
+                reference: reference.to_string(),
+                reason: status.to_string(),
+            });
+        }
+        Ok(())
+    });
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(push_cb);
+
+    connection
+        .remote()
+        .push(&[refspec], Some(&mut push_options))
+        .with_context(|| format!("Failed to push {}", refspec))?;
+    drop(push_options);
+
+    match rejected {
+        Some(rejected) => Err(rejected.into()),
+        None => Ok(()),
+    }
+}
+
+/// Whether a push failed because the remote has commits that aren't in the
+/// checkout.
+pub fn is_non_fast_forward(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<git2::Error>() {
+            return e.code() == git2::ErrorCode::NotFastForward;
+        }
+        if let Some(rejected) = cause.downcast_ref::<PushRejected>() {
+            return ["non-fast-forward", "fetch first", "stale info"]
+                .iter()
+                .any(|reason| rejected.reason.contains(reason));
+        }
+        false
+    })
+}
+
+/// Appends `trailers` to the message, separated from its body by a blank line.
+fn with_trailers(message: &str, trailers: &[String]) -> String {
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+
+    format!("{}\n\n{}", message.trim_end(), trailers.join("\n"))
+}
+
+/// The lines to end commit messages with, from `COMMIT_TRAILERS`, plus the
+/// `[skip ci]` marker with `COMMIT_SKIP_CI=true`.
+pub fn commit_trailers() -> Vec<String> {
+    let mut trailers = config_file::var("COMMIT_TRAILERS")
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|trailer| !trailer.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if config_file::var("COMMIT_SKIP_CI").as_deref() == Ok("true")
+        && !trailers.iter().any(|trailer| trailer == "[skip ci]")
+    {
+        trailers.push("[skip ci]".to_string());
+    }
+
+    trailers
+}
+
+/// Builds the commit message listing `changes`, one per line, for all apps or
+/// only `app_name`'s. `template` can use `{count}` for the number of updated
+/// images, `{changes}` for the list and `{app}` for the app.
+pub fn commit_message(
+    template: Option<&str>,
+    app_name: Option<&str>,
+    changes: &[Change],
+) -> String {
+    let count = changes
+        .iter()
+        .filter(|change| matches!(change, Change::Tag { .. }))
+        .count();
+    let list = changes
+        .iter()
+        .map(|change| change.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    match template {
+        Some(template) => template
+            .replace("{count}", &count.to_string())
+            .replace("{changes}", &list)
+            .replace("{app}", app_name.unwrap_or_default()),
+        None => {
+            let mut subject = match count {
+                0 => "Prune stale parameters".to_string(),
+                1 => "Update 1 image".to_string(),
+                count => format!("Update {} images", count),
+            };
+            if let Some(app_name) = app_name {
+                subject = format!("{} of {}", subject, app_name);
+            }
+            format!("{}\n\n{}", subject, list)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> CommitIdentity {
+        CommitIdentity {
+            author_name: "updater".to_string(),
+            author_email: "updater@example.com".to_string(),
+            committer_name: "updater".to_string(),
+            committer_email: "updater@example.com".to_string(),
+        }
+    }
+
+    fn commit_file(repo: &Repository, author: &str, content: &str, message: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join("values.yaml"), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("values.yaml")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now(author, &format!("{}@example.com", author)).unwrap();
+        let parents = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit().unwrap()],
+            Err(_) => vec![],
+        };
+        let parents = parents.iter().collect::<Vec<_>>();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    /// Someone's commit with the updater's on top of it, in a checkout cut
+    /// `depth` commits deep the way a shallow fetch leaves it.
+    fn checkout(depth: i32) -> (tempfile::TempDir, Repository, Oid) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let base = commit_file(&repo, "someone", "tag: 1.0.0\n", "Add web");
+        let update = commit_file(
+            &repo,
+            "updater",
+            "tag: 1.1.0\n",
+            "Update 1 image\n\nweb: example.com/web 1.0.0 -> 1.1.0",
+        );
+        let boundary = match depth {
+            1 => Some(update),
+            2 => Some(base),
+            _ => None,
+        };
+        if let Some(boundary) = boundary {
+            std::fs::write(dir.path().join(".git/shallow"), format!("{}\n", boundary)).unwrap();
+        }
+
+        // The grafts are only read when opening the repository
+        let repo = Repository::open(dir.path()).unwrap();
+        (dir, repo, base)
+    }
+
+    #[test]
+    fn amending_fetches_the_parent_of_the_tip() {
+        assert_eq!(CommitMode::Amend.fetch_depth(1), 2);
+        assert_eq!(CommitMode::Amend.fetch_depth(5), 5);
+        assert_eq!(CommitMode::Amend.fetch_depth(0), 0);
+        assert_eq!(CommitMode::Stack.fetch_depth(1), 1);
+    }
+
+    #[test]
+    fn amends_with_the_default_fetch_depth() {
+        let (dir, repo, base) = checkout(CommitMode::Amend.fetch_depth(1));
+        assert!(repo.is_shallow());
+
+        let amend = Amend::find(&repo, &identity(), None).unwrap().unwrap();
+        std::fs::write(dir.path().join("values.yaml"), "tag: 1.2.0\n").unwrap();
+        let changes = amend.merge(&[Change::Tag {
+            app_name: "web".to_string(),
+            image: "example.com/web".to_string(),
+            old_tag: Some("1.1.0".to_string()),
+            new_tag: "1.2.0".to_string(),
+            path: PathBuf::from("values.yaml"),
+        }]);
+        let message = commit_message(None, None, &changes);
+        let committed = add_and_commit(
+            &repo,
+            &identity(),
+            None,
+            &changes,
+            Some(&amend),
+            &message,
+            &[],
+        )
+        .unwrap();
+
+        assert!(committed);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_ids().collect::<Vec<_>>(), vec![base]);
+        assert_eq!(
+            head.message(),
+            Some("Update 1 image\n\nweb: example.com/web 1.0.0 -> 1.2.0")
+        );
+    }
+
+    #[test]
+    fn doesnt_amend_the_oldest_commit_of_a_depth_1_checkout() {
+        let (_dir, repo, _) = checkout(1);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_count(), 0);
+
+        assert!(Amend::find(&repo, &identity(), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn doesnt_amend_someone_elses_commit() {
+        let (_dir, repo, _) = checkout(0);
+        commit_file(&repo, "someone", "tag: 1.1.1\n", "Update 1 image");
+
+        assert!(Amend::find(&repo, &identity(), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn detects_https_remotes() {
+        for url in [
+            "https://github.com/org/ops.git",
+            "HTTPS://github.com/org/ops",
+            "http://gitea.internal:3000/org/ops.git",
+        ] {
+            assert!(is_https(url), "{}", url);
+        }
+        for url in [
+            "git@github.com:org/ops.git",
+            "ssh://git@github.com/org/ops.git",
+            "file:///srv/git/ops.git",
+            "github.com/org/ops",
+        ] {
+            assert!(!is_https(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn needs_a_token_for_https_remotes() {
+        // GIT_HTTPS_TOKEN isn't set when testing
+        let Err(e) = GitCredentials::from_env("https://github.com/org/ops.git", None) else {
+            panic!("Got credentials without a token");
+        };
+        assert_eq!(
+            e.to_string(),
+            "https://github.com/org/ops.git is an https remote, GIT_HTTPS_TOKEN needs to be set"
+        );
+    }
+
+    #[test]
+    fn uses_the_ssh_key_of_the_repository() {
+        let key = SshKey::Path("/keys/ops".into());
+        let credentials = GitCredentials::from_env("git@github.com:org/ops.git", Some(key));
+
+        assert!(matches!(
+            credentials.unwrap(),
+            GitCredentials::Ssh { key: SshKey::Path(path), .. } if path == Path::new("/keys/ops")
+        ));
+    }
+
+    fn tag_change(path: &str) -> Change {
+        Change::Tag {
+            app_name: "web".to_string(),
+            image: "example.com/web".to_string(),
+            old_tag: Some("1.0.0".to_string()),
+            new_tag: "1.1.0".to_string(),
+            path: PathBuf::from(path),
+        }
+    }
+
+    #[test]
+    fn only_commits_the_files_written() {
+        let (dir, repo, _) = checkout(0);
+        std::fs::create_dir_all(dir.path().join("apps/web")).unwrap();
+        std::fs::write(
+            dir.path().join("apps/web/.argocd-source-web.yaml"),
+            "helm: {}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("values.yaml"), "tag: 1.2.0\n").unwrap();
+        std::fs::write(dir.path().join("values.yaml~"), "tag: 1.1.0\n").unwrap();
+        std::fs::write(dir.path().join("apps/web/.tmp-overrides"), "helm:").unwrap();
+
+        let changes = [tag_change("apps/web/.argocd-source-web.yaml")];
+        assert!(add_and_commit(&repo, &identity(), None, &changes, None, "Update", &[]).unwrap());
+
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let mut paths = vec![];
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                paths.push(format!("{}{}", root, entry.name().unwrap()));
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .unwrap();
+        assert_eq!(paths, ["apps/web/.argocd-source-web.yaml", "values.yaml"]);
+        // Neither the stray files nor the changes to the others got committed
+        let values = tree.get_path(Path::new("values.yaml")).unwrap();
+        let values = repo.find_blob(values.id()).unwrap();
+        assert_eq!(values.content(), b"tag: 1.1.0\n");
+    }
+
+    #[test]
+    fn refuses_to_commit_nothing() {
+        let (dir, repo, _) = checkout(0);
+        std::fs::write(dir.path().join("stray.yaml"), "tag: 1.2.0\n").unwrap();
+
+        assert!(add_and_commit(&repo, &identity(), None, &[], None, "Update", &[]).is_err());
+    }
+
+    #[test]
+    fn appends_the_trailers_after_the_changes() {
+        let changes = [tag_change("values.yaml")];
+        let message = commit_message(None, None, &changes);
+        let trailers = [
+            "[skip ci]".to_string(),
+            "Signed-off-by: updater <updater@example.com>".to_string(),
+        ];
+
+        assert_eq!(
+            with_trailers(&message, &trailers),
+            "Update 1 image\n\nweb: example.com/web 1.0.0 -> 1.1.0\n\n[skip ci]\nSigned-off-by: updater <updater@example.com>"
+        );
+        assert_eq!(
+            with_trailers("Update 1 image\n\n", &trailers[..1]),
+            "Update 1 image\n\n[skip ci]"
+        );
+        assert_eq!(with_trailers(&message, &[]), message);
+    }
+
+    #[test]
+    fn commits_with_the_trailers() {
+        let (dir, repo, _) = checkout(0);
+        std::fs::write(dir.path().join("values.yaml"), "tag: 1.2.0\n").unwrap();
+
+        let changes = [tag_change("values.yaml")];
+        let message = commit_message(None, None, &changes);
+        let trailers = ["[skip ci]".to_string()];
+        add_and_commit(
+            &repo,
+            &identity(),
+            None,
+            &changes,
+            None,
+            &message,
+            &trailers,
+        )
+        .unwrap();
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message().unwrap(), format!("{}\n\n[skip ci]", message));
+    }
+}
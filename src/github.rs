@@ -0,0 +1,278 @@
+use anyhow::{bail, Context, Result};
+use git2::Oid;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config_file;
+
+/// Branches the updater opens pull requests from.
+const BRANCH_PREFIX: &str = "image-updater/";
+
+/// The `owner/name` of a GitHub repository.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GithubRepo {
+    pub owner: String,
+    pub name: String,
+}
+
+impl GithubRepo {
+    /// Extracts the repository from its clone URL, either
+    /// `git@github.com:owner/name.git`, `ssh://git@github.com/owner/name.git` or
+    /// `https://github.com/owner/name`.
+    pub fn from_url(url: &str) -> Result<Self> {
+        let trimmed = url.trim_end_matches('/');
+        let trimmed = trimmed.strip_suffix(".git").unwrap_or(trimmed);
+        let path = match trimmed.split_once("://") {
+            Some((_, rest)) => rest.split_once('/').map(|(_, path)| path),
+            None => trimmed.split_once(':').map(|(_, path)| path),
+        };
+
+        let Some((owner, name)) = path.and_then(|path| path.rsplit_once('/')) else {
+            bail!("Couldn't find the GitHub repository in {}", url);
+        };
+        let owner = owner.rsplit('/').next().unwrap_or(owner);
+        if owner.is_empty() || name.is_empty() {
+            bail!("Couldn't find the GitHub repository in {}", url);
+        }
+
+        Ok(Self {
+            owner: owner.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+/// Opens pull requests with the updates instead of pushing them to the branch.
+pub struct PullRequests {
+    repo: GithubRepo,
+    api_url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+pub struct PullRequest {
+    pub number: u64,
+    pub html_url: String,
+    pub head: PullRequestHead,
+}
+
+#[derive(Deserialize)]
+pub struct PullRequestHead {
+    #[serde(rename = "ref")]
+    pub branch: String,
+}
+
+impl PullRequests {
+    /// Enabled with `PUSH_MODE=pull-request`, authenticating with
+    /// `GITHUB_API_TOKEN` or `GITHUB_KEY`.
+    pub fn from_env(repository_url: &str) -> Result<Option<Self>> {
+        match config_file::var("PUSH_MODE").as_deref() {
+            Err(_) | Ok("direct") => return Ok(None),
+            Ok("pull-request") => {}
+            Ok(mode) => bail!("Unknown push mode: {}", mode),
+        }
+
+        let token = config_file::var("GITHUB_API_TOKEN")
+            .or_else(|_| config_file::var("GITHUB_KEY"))
+            .context("Pull requests need GITHUB_API_TOKEN or GITHUB_KEY")?;
+
+        Ok(Some(Self {
+            repo: GithubRepo::from_url(repository_url)?,
+            api_url: config_file::var("GITHUB_API_URL")
+                .unwrap_or_else(|_| "https://api.github.com".to_string()),
+            token,
+            client: reqwest::Client::new(),
+        }))
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(
+                method,
+                format!(
+                    "{}/repos/{}/{}/{}",
+                    self.api_url, self.repo.owner, self.repo.name, path
+                ),
+            )
+            .bearer_auth(&self.token)
+            .header(reqwest::header::USER_AGENT, "image-updater")
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+    }
+
+    /// Finds a pull request from a previous run that's still open against
+    /// `base`.
+    pub async fn find_open(&self, base: &str) -> Result<Option<PullRequest>> {
+        let pulls: Vec<PullRequest> = self
+            .request(reqwest::Method::GET, "pulls")
+            .query(&[("state", "open"), ("base", base), ("per_page", "100")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(pulls
+            .into_iter()
+            .find(|pull| pull.head.branch.starts_with(BRANCH_PREFIX)))
+    }
+
+    /// Opens a pull request merging `head` into `base`, the first line of
+    /// `message` being its title and the rest its body.
+    pub async fn create(&self, base: &str, head: &str, message: &str) -> Result<PullRequest> {
+        let (title, body) = split_message(message);
+        let pull = self
+            .request(reqwest::Method::POST, "pulls")
+            .json(&json!({
+                "title": title,
+                "body": body,
+                "head": head,
+                "base": base,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(pull)
+    }
+
+    /// Refreshes the description of an existing pull request after its branch
+    /// was updated.
+    pub async fn update(&self, pull: &PullRequest, message: &str) -> Result<()> {
+        let (title, body) = split_message(message);
+        self.request(reqwest::Method::PATCH, &format!("pulls/{}", pull.number))
+            .json(&json!({
+                "title": title,
+                "body": body,
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Name of the branch to open a new pull request from.
+pub fn branch_name(commit: Oid) -> String {
+    format!(
+        "{}{}-{:.7}",
+        BRANCH_PREFIX,
+        chrono::Utc::now().format("%Y%m%d"),
+        commit.to_string()
+    )
+}
+
+fn split_message(message: &str) -> (&str, &str) {
+    match message.split_once('\n') {
+        Some((title, body)) => (title, body.trim()),
+        None => (message, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(owner: &str, name: &str) -> GithubRepo {
+        GithubRepo {
+            owner: owner.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn finds_the_repository_in_ssh_urls() {
+        for url in [
+            "git@github.com:org/ops.git",
+            "git@github.com:org/ops",
+            "ssh://git@github.com/org/ops.git",
+            "ssh://git@github.com:22/org/ops.git/",
+        ] {
+            assert_eq!(
+                GithubRepo::from_url(url).unwrap(),
+                repo("org", "ops"),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_repository_in_https_urls() {
+        for url in [
+            "https://github.com/org/ops",
+            "https://github.com/org/ops.git",
+            "https://github.com/org/ops/",
+            "https://x-access-token@github.example.com:8443/org/ops.git",
+        ] {
+            assert_eq!(
+                GithubRepo::from_url(url).unwrap(),
+                repo("org", "ops"),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_urls_without_a_repository() {
+        for url in [
+            "https://github.com/ops",
+            "https://github.com/",
+            "git@github.com:ops.git",
+            "github.com",
+        ] {
+            assert!(GithubRepo::from_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn names_the_branches_after_the_commit() {
+        let commit = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let branch = branch_name(commit);
+
+        assert!(branch.starts_with(BRANCH_PREFIX));
+        assert!(branch.ends_with("-0123456"));
+        assert_eq!(branch.len(), BRANCH_PREFIX.len() + "20240618-0123456".len());
+    }
+
+    #[test]
+    fn splits_the_title_from_the_body() {
+        assert_eq!(
+            split_message("Update web\n\nweb: 1.0 -> 1.1\n"),
+            ("Update web", "web: 1.0 -> 1.1")
+        );
+        assert_eq!(split_message("Update web"), ("Update web", ""));
+    }
+
+    #[tokio::test]
+    async fn finds_the_open_pull_request_of_a_previous_run() {
+        let address = crate::registry::tests::serve(|target| {
+            assert!(target.starts_with("/repos/org/ops/pulls?state=open&base=main"));
+            let pull = |number, branch| {
+                json!({
+                    "number": number,
+                    "html_url": format!("https://github.com/org/ops/pull/{}", number),
+                    "head": { "ref": branch },
+                })
+            };
+            let pulls = json!([
+                pull(1, "someone/feature"),
+                pull(2, "image-updater/20240618-0123456"),
+            ]);
+            (200, pulls.to_string())
+        });
+        let pull_requests = PullRequests {
+            repo: repo("org", "ops"),
+            api_url: format!("http://{}", address),
+            token: "token".to_string(),
+            client: reqwest::Client::new(),
+        };
+
+        let pull = pull_requests.find_open("main").await.unwrap().unwrap();
+        assert_eq!(pull.number, 2);
+    }
+}
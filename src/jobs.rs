@@ -0,0 +1,200 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, Notify};
+
+use crate::Trigger;
+
+/// How many finished jobs are remembered for `/jobs/<id>`.
+const FINISHED_JOBS: usize = 100;
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded {
+        summary: serde_json::Value,
+    },
+    Failed {
+        error: String,
+        /// Set when the run went through, some candidates having failed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        summary: Option<serde_json::Value>,
+    },
+}
+
+impl JobState {
+    fn is_finished(&self) -> bool {
+        matches!(self, Self::Succeeded { .. } | Self::Failed { .. })
+    }
+}
+
+struct Job {
+    trigger: Trigger,
+    state: JobState,
+}
+
+/// The runs waiting for the worker, and the state of the recent ones.
+pub struct Jobs {
+    sender: mpsc::UnboundedSender<u64>,
+    jobs: Mutex<(u64, BTreeMap<u64, Job>)>,
+    finished: Notify,
+    /// Set when shutting down, no job starting anymore.
+    closed: AtomicBool,
+}
+
+impl Jobs {
+    /// Returns the queue, and the receiving end the worker takes the ids of
+    /// the jobs to run from.
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<u64>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let jobs = Self {
+            sender,
+            jobs: Mutex::new((0, BTreeMap::new())),
+            finished: Notify::new(),
+            closed: AtomicBool::new(false),
+        };
+
+        (jobs, receiver)
+    }
+
+    /// Queues a run and returns its id. The id of the job already waiting for
+    /// the same run is returned instead if there's one, so a burst of webhooks
+    /// only queues a single run.
+    pub fn enqueue(&self, trigger: Trigger) -> u64 {
+        let mut guard = self.jobs.lock().unwrap();
+        let (next_id, jobs) = &mut *guard;
+        let pending = jobs.iter().find(|(_, job)| {
+            matches!(job.state, JobState::Queued) && job.trigger.same_run(&trigger)
+        });
+        if let Some((&id, _)) = pending {
+            log::info!("Run already queued as job {}", id);
+            return id;
+        }
+
+        *next_id += 1;
+        let id = *next_id;
+        jobs.insert(
+            id,
+            Job {
+                trigger,
+                state: JobState::Queued,
+            },
+        );
+        // The worker lives as long as the server does
+        let _ = self.sender.send(id);
+
+        id
+    }
+
+    /// Marks a job as running, returning what it should run, or nothing when
+    /// it isn't queued anymore.
+    pub fn start(&self, id: u64) -> Option<Trigger> {
+        let mut guard = self.jobs.lock().unwrap();
+        let job = guard.1.get_mut(&id)?;
+        if !matches!(job.state, JobState::Queued) || self.is_closed() {
+            return None;
+        }
+        job.state = JobState::Running;
+
+        Some(job.trigger.clone())
+    }
+
+    /// Records how a job went, forgetting the oldest finished ones.
+    pub fn finish(&self, id: u64, state: JobState) {
+        let mut guard = self.jobs.lock().unwrap();
+        let jobs = &mut guard.1;
+        if let Some(job) = jobs.get_mut(&id) {
+            job.state = state;
+        }
+
+        let finished = jobs
+            .iter()
+            .filter(|(_, job)| job.state.is_finished())
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(FINISHED_JOBS))
+        {
+            jobs.remove(id);
+        }
+        drop(guard);
+
+        self.finished.notify_waiters();
+    }
+
+    /// Waits for a job to finish, returning how it went.
+    pub async fn wait(&self, id: u64) -> Option<JobState> {
+        loop {
+            // Registered before looking at the state, not to miss the job
+            // finishing in between
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+
+            match self.state(id)? {
+                state if state.is_finished() => return Some(state),
+                _ => finished.await,
+            }
+        }
+    }
+
+    /// Stops running jobs, failing the queued ones. The one running, if any,
+    /// goes on.
+    pub fn close(&self) {
+        let mut guard = self.jobs.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        for job in guard.1.values_mut() {
+            if matches!(job.state, JobState::Queued) {
+                job.state = JobState::Failed {
+                    error: "Shut down before it ran".to_string(),
+                    summary: None,
+                };
+            }
+        }
+        drop(guard);
+
+        self.finished.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// The id of the job being run, if any.
+    pub fn running(&self) -> Option<u64> {
+        let guard = self.jobs.lock().unwrap();
+        guard
+            .1
+            .iter()
+            .find(|(_, job)| matches!(job.state, JobState::Running))
+            .map(|(&id, _)| id)
+    }
+
+    /// Waits for the job being run, if any, to finish.
+    pub async fn idle(&self) {
+        loop {
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+
+            match self.running() {
+                Some(_) => finished.await,
+                None => return,
+            }
+        }
+    }
+
+    pub fn state(&self, id: u64) -> Option<JobState> {
+        let guard = self.jobs.lock().unwrap();
+        guard.1.get(&id).map(|job| job.state.clone())
+    }
+}
@@ -0,0 +1,291 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use git2::{cert::Cert, CertificateCheckStatus};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::config_file;
+
+/// How the SSH server's host key gets verified.
+pub enum HostKeyCheck {
+    /// Against the entries of a known_hosts file.
+    KnownHosts { hosts: KnownHosts, port: u16 },
+    /// Not at all, with `GIT_SSH_INSECURE_ACCEPT_ANY=true`.
+    AcceptAny,
+}
+
+/// The entries of an OpenSSH known_hosts file.
+#[derive(Default)]
+pub struct KnownHosts {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    hosts: Hosts,
+    key_type: String,
+    key: Vec<u8>,
+    revoked: bool,
+}
+
+enum Hosts {
+    /// Comma separated patterns, `!` negating the ones it prefixes.
+    Patterns(Vec<String>),
+    /// `|1|salt|hash`, the HMAC-SHA1 of the host name keyed with the salt.
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+impl HostKeyCheck {
+    /// Reads the known_hosts file at `SSH_KNOWN_HOSTS`, or
+    /// `~/.ssh/known_hosts` by default.
+    pub fn from_env(repository_url: &str) -> Result<Self> {
+        if config_file::var("GIT_SSH_INSECURE_ACCEPT_ANY").as_deref() == Ok("true") {
+            log::warn!("Accepting any SSH host key, the remote isn't verified");
+            return Ok(Self::AcceptAny);
+        }
+
+        let port = ssh_port(repository_url);
+        if crate::git::is_https(repository_url) {
+            // There's no host key to check against, the TLS certificate is
+            // verified by libgit2 as usual
+            return Ok(Self::KnownHosts {
+                hosts: KnownHosts::default(),
+                port,
+            });
+        }
+
+        let path = match config_file::var("SSH_KNOWN_HOSTS") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let home = std::env::var("HOME")
+                    .context("HOME isn't set, set SSH_KNOWN_HOSTS to a known_hosts file")?;
+                PathBuf::from(home).join(".ssh/known_hosts")
+            }
+        };
+
+        Ok(Self::KnownHosts {
+            hosts: KnownHosts::load(path)?,
+            port,
+        })
+    }
+
+    /// Accepts the host key `host` presented if it's in the known_hosts file,
+    /// leaving other kind of certificates to libgit2.
+    pub fn check(
+        &self,
+        cert: &Cert<'_>,
+        host: &str,
+    ) -> Result<CertificateCheckStatus, git2::Error> {
+        let Some(hostkey) = cert.as_hostkey() else {
+            return Ok(CertificateCheckStatus::CertificatePassthrough);
+        };
+        let Self::KnownHosts { hosts, port } = self else {
+            return Ok(CertificateCheckStatus::CertificateOk);
+        };
+
+        let Some(key) = hostkey.hostkey() else {
+            return Err(git2::Error::from_str(
+                "The SSH server's host key couldn't be read",
+            ));
+        };
+        let key_type = hostkey
+            .hostkey_type()
+            .map_or("unknown", |key_type| key_type.name());
+
+        hosts.verify(host, *port, key_type, key)?;
+
+        Ok(CertificateCheckStatus::CertificateOk)
+    }
+}
+
+impl KnownHosts {
+    fn load(path: PathBuf) -> Result<Self> {
+        let content = std::fs::read_to_string(&path).with_context(|| {
+            format!(
+                "Failed to read the known hosts from {}, set GIT_SSH_INSECURE_ACCEPT_ANY=true to skip host key verification",
+                path.display()
+            )
+        })?;
+
+        let mut entries = vec![];
+        for (number, line) in content.lines().enumerate() {
+            match Entry::parse(line) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {}
+                Err(e) => log::warn!(
+                    "Ignoring line {} of {}: {:#}",
+                    number + 1,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        Ok(Self { path, entries })
+    }
+
+    fn verify(&self, host: &str, port: u16, key_type: &str, key: &[u8]) -> Result<(), git2::Error> {
+        // Hosts on another port than ssh's are recorded as `[host]:port`
+        let name = match port {
+            22 => host.to_string(),
+            port => format!("[{}]:{}", host, port),
+        };
+
+        let mut known = false;
+        for entry in self
+            .entries
+            .iter()
+            .filter(|entry| entry.hosts.matches(&name))
+        {
+            if entry.key != key {
+                known |= !entry.revoked;
+                continue;
+            }
+            if entry.revoked {
+                return Err(git2::Error::from_str(&format!(
+                    "The {} host key of {} is revoked in {}",
+                    key_type,
+                    name,
+                    self.path.display()
+                )));
+            }
+
+            log::debug!("Verified the {} host key of {}", entry.key_type, name);
+            return Ok(());
+        }
+
+        let message = if known {
+            format!(
+                "The {} host key of {} doesn't match the ones in {}",
+                key_type,
+                name,
+                self.path.display()
+            )
+        } else {
+            format!("{} isn't a known host in {}", name, self.path.display())
+        };
+
+        Err(git2::Error::from_str(&message))
+    }
+}
+
+impl Entry {
+    fn parse(line: &str) -> Result<Option<Self>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let mut fields = line.split_whitespace();
+        let mut hosts = fields.next().unwrap_or_default();
+        let mut revoked = false;
+        if let Some(marker) = hosts.strip_prefix('@') {
+            match marker {
+                "revoked" => revoked = true,
+                // Certificates aren't something libgit2 hands over
+                "cert-authority" => return Ok(None),
+                _ => anyhow::bail!("Unknown marker @{}", marker),
+            }
+            hosts = fields.next().unwrap_or_default();
+        }
+
+        let (Some(key_type), Some(key)) = (fields.next(), fields.next()) else {
+            anyhow::bail!("Missing the host key");
+        };
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        let hosts = match hosts.strip_prefix("|1|") {
+            Some(hashed) => {
+                let (salt, hash) = hashed
+                    .split_once('|')
+                    .context("Hashed host names need a salt and a hash")?;
+                Hosts::Hashed {
+                    salt: engine.decode(salt).context("Invalid salt")?,
+                    hash: engine.decode(hash).context("Invalid hash")?,
+                }
+            }
+            None => Hosts::Patterns(hosts.split(',').map(str::to_string).collect()),
+        };
+
+        Ok(Some(Self {
+            hosts,
+            key_type: key_type.to_string(),
+            key: engine.decode(key).context("Invalid host key")?,
+            revoked,
+        }))
+    }
+}
+
+impl Hosts {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Patterns(patterns) => {
+                let mut matched = false;
+                for pattern in patterns {
+                    match pattern.strip_prefix('!') {
+                        Some(negated) if glob_matches(negated, name) => return false,
+                        Some(_) => {}
+                        None => matched |= glob_matches(pattern, name),
+                    }
+                }
+                matched
+            }
+            Self::Hashed { salt, hash } => {
+                let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(salt) else {
+                    return false;
+                };
+                mac.update(name.as_bytes());
+                mac.verify_slice(hash).is_ok()
+            }
+        }
+    }
+}
+
+/// Matches `name` case insensitively against a pattern where `*` stands for
+/// any number of characters and `?` for exactly one.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase().into_bytes();
+    let name = name.to_ascii_lowercase().into_bytes();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// The port an `ssh://` remote is reached on, scp-like ones always using 22.
+fn ssh_port(repository_url: &str) -> u16 {
+    let Some((_, rest)) = repository_url.split_once("://") else {
+        return 22;
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    host.rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(22)
+}
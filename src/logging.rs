@@ -0,0 +1,235 @@
+use std::{fmt, io::IsTerminal};
+
+use anyhow::{bail, Result};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    field::RecordFields,
+    filter::{filter_fn, FilterExt, Targets},
+    fmt::{
+        format::{DefaultFields, FmtSpan, JsonFields, Writer},
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+/// Keeps the spans going out to the OTLP collector, until
+/// [`Tracing::shutdown`] flushes the last ones.
+pub struct Tracing {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Tracing {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("Failed to export the last spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Sets up the logger `LOG_FORMAT` asks for, `text` or `json`, both filtered
+/// by `RUST_LOG` and logging how long the spans of the runs took. The spans
+/// get exported over OTLP too when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init() -> Result<Tracing> {
+    let json = match std::env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => false,
+        Ok("json") => true,
+        Ok(format) => bail!("Unknown LOG_FORMAT {}, expected text or json", format),
+    };
+    let provider = otlp_provider()?;
+
+    // The `log` records go through tracing, picking up the fields of the spans
+    // they're emitted in. The spans get timed by a layer of their own, which
+    // only sees them. Like `env_logger` did, everything goes to stderr,
+    // colored when it's a terminal, stdout being for what the commands print.
+    let ansi = std::io::stderr().is_terminal();
+    let events = EnvFilter::from_default_env;
+    let spans = || {
+        EnvFilter::from_default_env().and(filter_fn(|metadata| {
+            // The finer spans carry the fields of the candidate ones
+            metadata.is_span()
+                && metadata.target().starts_with("image_updater")
+                && metadata.name() != "candidate"
+        }))
+    };
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = match json {
+        true => vec![
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .fmt_fields(JsonFields::new())
+                .event_format(JsonLines)
+                .with_filter(events())
+                .boxed(),
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_span_events(FmtSpan::CLOSE)
+                .fmt_fields(JsonFields::new())
+                .event_format(JsonLines)
+                .with_filter(spans())
+                .boxed(),
+        ],
+        false => vec![
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_ansi(ansi)
+                .with_filter(events())
+                .boxed(),
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_ansi(ansi)
+                .with_span_events(FmtSpan::CLOSE)
+                .fmt_fields(SpanFields::default())
+                .with_filter(spans())
+                .boxed(),
+        ],
+    };
+    if let Some(provider) = &provider {
+        // Not the spans of the libraries, the exporter's HTTP client included
+        layers.push(
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("image-updater"))
+                .with_filter(Targets::new().with_target("image_updater", Level::INFO))
+                .boxed(),
+        );
+    }
+    tracing_subscriber::registry().with(layers).init();
+
+    Ok(Tracing { provider })
+}
+
+/// The OTLP over HTTP exporter, configured by the standard `OTEL_*`
+/// variables, when there's an endpoint to export to.
+fn otlp_provider() -> Result<Option<SdkTracerProvider>> {
+    let endpoint = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .into_iter()
+    .any(|var| std::env::var(var).is_ok_and(|v| !v.is_empty()));
+    if !endpoint {
+        return Ok(None);
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let mut resource = Resource::builder();
+    if std::env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name("image-updater");
+    }
+
+    Ok(Some(
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build(),
+    ))
+}
+
+/// A span covering the processing of a candidate, for its logs to carry the
+/// app and the image.
+pub fn candidate_span(app: &str, image: &str) -> tracing::Span {
+    tracing::info_span!("candidate", app, image)
+}
+
+/// The fields of the spans as the timings show them, stored apart from the
+/// ones the logs show so that the fields recorded once the span exists aren't
+/// added to both. The JSON ones get merged, they can be shared.
+#[derive(Default)]
+struct SpanFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for SpanFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+/// One JSON object per line, with the fields of the spans the event is in
+/// next to its own rather than nested under them.
+struct JsonLines;
+
+impl<S> FormatEvent<S, JsonFields> for JsonLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut line = Map::new();
+        line.insert("timestamp".into(), chrono::Utc::now().to_rfc3339().into());
+        line.insert("level".into(), metadata.level().as_str().into());
+        line.insert("target".into(), metadata.target().into());
+        // The span being closed, for the timings
+        if let Some(span) = ctx.event_scope().and_then(|mut scope| scope.next()) {
+            line.insert("span".into(), span.name().into());
+        }
+        // Inner spans win over the outer ones
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            let extensions = span.extensions();
+            let Some(fields) = extensions.get::<FormattedFields<JsonFields>>() else {
+                continue;
+            };
+            if let Ok(Value::Object(fields)) = serde_json::from_str(&fields.fields) {
+                line.extend(fields);
+            }
+        }
+        event.record(&mut Fields(&mut line));
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl Fields<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // What the `log` records carry about where they come from
+        if field.name().starts_with("log.") {
+            return;
+        }
+        self.0.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+}
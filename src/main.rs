@@ -1,60 +1,250 @@
-use std::{collections::HashMap, io::BufReader, path::Path, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
-use config::Config;
-use git2::{Cred, Direction, IndexAddOption, RemoteCallbacks, Repository, RepositoryInitOptions, ResetType, Signature};
-use oci_distribution::{client::ClientConfig, secrets::RegistryAuth, Client, Reference};
-use overrides::Overrides;
-use regex::Regex;
+use audit::AuditRecord;
+use cache::{DiscoveredFiles, TagCache};
+use circuit::RegistryUnavailable;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{Config, RepoConfig, RunMode};
+use events::RunEvent;
+use filter::{IgnoredTag, TagFilter};
+use futures::StreamExt;
+use git::{Amend, CommitGranularity, CommitMode};
+use git2::{Oid, Repository};
+use hmac::{Hmac, Mac};
+use jobs::{JobState, Jobs};
+use metrics::CandidateVersion;
+use oci_client::Reference;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use registry::{
+    get_latest_tag_for_candidate, is_rate_limited, is_unavailable, select_auth, ManifestChecks,
+    MissingPlatforms, Platform, PlatformChecks, PullSecret, RateLimited, RateLimits,
+    RegistryTimeout, Selection, SignatureRequirement, Soaking,
+};
 use rocket::{
+    data::{self, FromData, Limits},
+    fairing::AdHoc,
+    http::{ContentType, Status},
     request::{FromRequest, Outcome},
-    routes, Request, State,
+    response::stream::EventStream,
+    routes, Data, Request, Shutdown, State,
 };
-use serde_yaml::Value;
+use serde_yaml::{Mapping, Value};
+use sha2::Sha256;
+use status::{RepoRun, Run, RunError, RunHistory};
+use strategy::{validate_date_format, UpdateStrategy, DEFAULT_DATE_FORMAT};
+use subtle::ConstantTimeEq;
 use tempfile::TempDir;
+use throttle::{Admission, Throttle};
+use tokio::sync::{broadcast, mpsc};
+use tracing::Instrument;
 use walkdir::WalkDir;
+use webhook::PushedImage;
+use writeback::{prune_parameters, update_tag_for_candidate, Change, WriteBackTarget, WriteTarget};
 
+mod application_set;
+mod audit;
+mod cache;
+mod checks;
+mod circuit;
+mod cli;
 mod config;
+mod config_file;
+#[cfg(feature = "cosign")]
+mod cosign;
+#[cfg(feature = "ecr")]
+mod ecr;
+mod email;
+mod events;
+mod filter;
+mod git;
+mod github;
+mod jobs;
+mod known_hosts;
+mod logging;
+mod metrics;
+mod notify_webhook;
 mod overrides;
+mod registry;
+#[cfg(feature = "sentry")]
+mod reporting;
+mod scan;
+mod slack;
+mod status;
+mod strategy;
+mod throttle;
+mod webhook;
+mod writeback;
+mod yaml_edit;
 
 #[rocket::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
     let _ = dotenvy::dotenv();
 
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
-    env_logger::init();
+    let tracing = logging::init()?;
+    config_file::load()?;
+    #[cfg(feature = "sentry")]
+    let sentry = reporting::init()?;
+    #[cfg(not(feature = "sentry"))]
+    if config_file::var("SENTRY_DSN").is_ok() {
+        log::warn!(
+            "SENTRY_DSN is set but this build has no Sentry support, enable the sentry feature"
+        );
+    }
+
+    let command = cli.command()?;
 
     let temp_dir = TempDir::with_prefix("image-updater")?;
-    let prefix = std::env::var("PREFIX").unwrap_or_else(|_| "/".to_string());
+    let repo_tmpdir = temp_dir.path();
+    let result = match command {
+        Command::Serve => {
+            let result = serve(repo_tmpdir).await;
+            tracing.shutdown();
+            return result;
+        }
+        Command::Once { json } => cli::once(repo_tmpdir, json).await,
+        Command::Plan { json, repo } => cli::plan(repo_tmpdir, json, repo).await,
+        Command::ListCandidates { json, repo } => {
+            cli::list_candidates(repo_tmpdir, json, repo).await
+        }
+    };
+
+    let code = cli::exit_code(result);
+    // Exiting skips the destructors
+    drop(temp_dir);
+    #[cfg(feature = "sentry")]
+    drop(sentry);
+    tracing.shutdown();
+    std::process::exit(code);
+}
+
+async fn serve(repo_tmpdir: &Path) -> Result<()> {
+    let prefix = config_file::var("PREFIX").unwrap_or_else(|_| "/".to_string());
+
+    let config = Arc::new(Config::from_env(repo_tmpdir.to_path_buf(), RunMode::Serve)?);
+    config_file::log_effective();
+    checks::run(&config).await?;
+
+    let readiness = Arc::new(Readiness::default());
+    for repo in &config.repositories {
+        log::info!("Watching {} ({})", repo.name, repo.url);
+        fetch_checkout(&config, repo, &readiness)
+            .with_context(|| format!("Failed to fetch {}", repo.name))?;
+    }
+
+    let tag_cache = Arc::new(TagCache::new(config.tag_cache_ttl));
+    let run_lock = Arc::new(RunLock::default());
+    let history = Arc::new(RunHistory::new(config.status_history));
+    let throttle = Throttle::new(
+        config.max_triggers_per_minute,
+        config.delivery_id_ttl,
+        Instant::now(),
+    );
+    let (jobs, receiver) = Jobs::new();
+    let jobs = Arc::new(jobs);
 
-    let config = Config {
-        repository_url: std::env::var("REPOSITORY_URL").context("REPOSITORY_URL")?,
-        ssh_key_path: std::env::var("SSH_KEY_PATH").context("SSH_KEY_PATH")?,
-        github_username: std::env::var("GITHUB_USERNAME").context("GITHUB_KEY")?,
-        github_key: std::env::var("GITHUB_KEY").context("GITHUB_KEY")?,
-        repo_tmpdir: temp_dir.path().to_path_buf(),
-        secret: std::env::var("SECRET").context("SECRET")?,
+    let worker = Worker {
+        config: config.clone(),
+        tag_cache: tag_cache.clone(),
+        run_lock: run_lock.clone(),
+        readiness: readiness.clone(),
+        history: history.clone(),
+        jobs: jobs.clone(),
     };
+    tokio::spawn(worker.run(receiver));
+    if let Some(interval) = config.poll_interval {
+        log::info!("Polling every {:?}", interval);
+        tokio::spawn(poll(jobs.clone(), interval));
+    }
 
-    clone_or_reset(
-        &config.repository_url,
-        &config.repo_tmpdir,
-        Path::new(&config.ssh_key_path),
-    )?;
+    let (_, mut events) = config.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => log::debug!("{}", event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::debug!("Missed {} run events", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
 
     log::info!("Starting rocket");
 
-    rocket::build()
-        .mount(&prefix, routes![root])
+    let launched = rocket::build()
+        .mount(
+            &prefix,
+            routes![
+                trigger,
+                trigger_app,
+                root,
+                job,
+                dry_run,
+                list_candidates,
+                run_status,
+                audit_tail,
+                stream_events,
+                export_metrics,
+                healthz,
+                readyz
+            ],
+        )
         .manage(config)
+        .manage(tag_cache)
+        .manage(run_lock)
+        .manage(readiness)
+        .manage(history)
+        .manage(jobs.clone())
+        .manage(throttle)
+        .attach(AdHoc::on_shutdown("Finish the run in progress", |rocket| {
+            Box::pin(async move {
+                if let (Some(config), Some(jobs)) =
+                    (rocket.state::<Arc<Config>>(), rocket.state::<Arc<Jobs>>())
+                {
+                    drain(jobs, config.shutdown_grace).await;
+                }
+            })
+        }))
         .launch()
-        .await?;
+        .await;
+
+    match jobs.running() {
+        Some(id) => log::warn!(
+            "Shut down before job {} completed, the remote may be ahead of its status",
+            id
+        ),
+        None => log::info!("Shut down, the last run completed"),
+    }
+    launched?;
 
     Ok(())
 }
 
+/// Stops taking jobs, and gives the one running `grace` to finish.
+async fn drain(jobs: &Jobs, grace: Duration) {
+    jobs.close();
+    if let Some(id) = jobs.running() {
+        log::info!(
+            "Shutting down, waiting up to {:?} for job {} to finish",
+            grace,
+            id
+        );
+        let _ = tokio::time::timeout(grace, jobs.idle()).await;
+    }
+}
+
 pub struct SecretGuard;
 
 #[rocket::async_trait]
@@ -62,305 +252,4694 @@ impl<'r> FromRequest<'r> for SecretGuard {
     type Error = ();
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let secret = req.headers().get_one("X-Secret");
-        let Some(config) = req.rocket().state::<Config>() else {
-            return Outcome::Error((rocket::http::Status::InternalServerError, ()));
+        let Some(config) = req.rocket().state::<Arc<Config>>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
         };
 
-        if secret == Some(&config.secret) {
-            return Outcome::Success(SecretGuard);
+        match check_secret(req, config) {
+            Some(true) => Outcome::Success(SecretGuard),
+            _ => Outcome::Error((Status::Unauthorized, ())),
         }
-
-        Outcome::Error((rocket::http::Status::Unauthorized, ()))
     }
 }
 
-#[rocket::get("/")]
-async fn root(config: &State<Config>, _secret: SecretGuard) {
-    log::info!("Update triggered by webhook");
+/// Compares the secret given with `X-Secret` or `Authorization: Bearer` in
+/// constant time, `None` meaning there was none.
+fn check_secret(req: &Request<'_>, config: &Config) -> Option<bool> {
+    let provided = req.headers().get_one("X-Secret").or_else(|| {
+        req.headers()
+            .get_one("Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+    })?;
+
+    // Compare against all of them so the time taken doesn't tell which matched
+    let mut matched = None;
+    for (index, secret) in config.secrets.iter().enumerate() {
+        if bool::from(provided.as_bytes().ct_eq(secret.as_bytes())) {
+            matched.get_or_insert(index);
+        }
+    }
 
-    if let Err(e) = update(config).await {
-        log::error!("Error while updating: {}", e);
+    match matched {
+        Some(index) => log_secret_used(index),
+        None => log::warn!(
+            "Rejecting a request from {} with a wrong secret",
+            client_address(req)
+        ),
     }
 
-    log::info!("Update complete");
+    Some(matched.is_some())
 }
 
-async fn update(config: &State<Config>) -> Result<()> {
-    let repo = clone_or_reset(
-        &config.repository_url,
-        &config.repo_tmpdir,
-        Path::new(&config.ssh_key_path),
-    )?;
-    let candidates = find_candidates(&config.repo_tmpdir)?;
+/// Telling which secret got used shows when an old one can be removed.
+fn log_secret_used(index: usize) {
+    log::info!("Authenticated with secret #{}", index + 1);
+}
+
+fn client_address(req: &Request<'_>) -> String {
+    req.client_ip()
+        .map_or_else(|| "an unknown address".to_string(), |ip| ip.to_string())
+}
+
+/// The body of a trigger request, authenticated like with [`SecretGuard`] or
+/// with the GitHub signature of the body in `X-Hub-Signature-256`.
+pub struct Webhook {
+    /// The `X-GitHub-Event` header.
+    github_event: Option<String>,
+    is_json: bool,
+    body: Vec<u8>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for Webhook {
+    type Error = ();
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let Some(config) = req.rocket().state::<Arc<Config>>() else {
+            return data::Outcome::Error((Status::InternalServerError, ()));
+        };
 
-    let mut has_changed = false;
-    for candidate in candidates {
-        let tag =
-            get_latest_tag_for_candidate(&candidate, &config.github_username, &config.github_key)
-                .await?;
-        has_changed |= update_tag_for_candidate(&config.repo_tmpdir, &candidate, &tag)?;
+        let limit = req.limits().get("bytes").unwrap_or(Limits::BYTES);
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => return data::Outcome::Error((Status::PayloadTooLarge, ())),
+            Err(_) => return data::Outcome::Error((Status::BadRequest, ())),
+        };
+        let webhook = Webhook {
+            github_event: req.headers().get_one("X-GitHub-Event").map(str::to_string),
+            is_json: req
+                .content_type()
+                .is_some_and(|content_type| content_type.is_json()),
+            body,
+        };
+
+        match check_secret(req, config) {
+            Some(true) => return data::Outcome::Success(webhook),
+            Some(false) => return data::Outcome::Error((Status::Unauthorized, ())),
+            None => {}
+        }
+        let Some(signature) = req.headers().get_one("X-Hub-Signature-256") else {
+            return data::Outcome::Error((Status::Unauthorized, ()));
+        };
+        let signed_with = config
+            .secrets
+            .iter()
+            .position(|secret| is_signed(secret, &webhook.body, signature));
+        if let Some(index) = signed_with {
+            log_secret_used(index);
+            return data::Outcome::Success(webhook);
+        }
+
+        log::warn!(
+            "Rejecting a webhook from {} with an invalid signature",
+            client_address(req)
+        );
+        data::Outcome::Error((Status::Unauthorized, ()))
     }
+}
 
-    if !has_changed {
-        log::info!("No image changes, skipping commit and push");
-        return Ok(());
+/// Checks `signature`, the `sha256=` prefixed hex HMAC-SHA256 of the body keyed
+/// with the secret, in constant time.
+fn is_signed(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(signature) = signature
+        .strip_prefix("sha256=")
+        .and_then(|hex| decode_hex(hex.trim()))
+    else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(body);
+
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
     }
 
-    add_and_commit(&repo)?;
-    let mut remote = repo.find_remote("origin")?;
-    let mut cb = RemoteCallbacks::new();
-    cb.credentials(|_, username, _| {
-        Cred::ssh_key(
-            username.unwrap_or("git"),
-            None,
-            Path::new(&config.ssh_key_path),
-            None,
-        )
-    });
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
 
-    let mut connection = remote.connect_auth(Direction::Push, Some(cb), None)?;
-    connection.remote().push(&["refs/heads/main"], None)?;
+/// Set when the trigger request asked to bypass the tag cache with
+/// `X-No-Cache: true`.
+pub struct NoCache(bool);
 
-    Ok(())
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NoCache {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(NoCache(req.headers().get_one("X-No-Cache") == Some("true")))
+    }
 }
 
-fn add_and_commit(repo: &Repository) -> Result<()> {
-    let mut index = repo.index()?;
-    index.add_all(["."], IndexAddOption::DEFAULT, None)?;
-    index.write()?;
-
-    let oid = index.write_tree()?;
-    let signature = Signature::now("Automatic image updater", "nobody@bananium.fr")?;
-    let parent_commit = repo.head()?.peel_to_commit()?;
-    let tree = repo.find_tree(oid).unwrap();
-    repo.commit(
-        Some("HEAD"),
-        &signature,
-        &signature,
-        "Updated images",
-        &tree,
-        &[&parent_commit],
-    )?;
+/// The `X-Delivery-Id` of a webhook, for senders that deliver the same one more
+/// than once.
+pub struct DeliveryId(Option<String>);
 
-    Ok(())
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DeliveryId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let delivery = req.headers().get_one("X-Delivery-Id").map(str::to_string);
+        Outcome::Success(DeliveryId(delivery))
+    }
 }
 
-fn update_tag_for_candidate(repo_path: &Path, candidate: &Candidate, tag: &str) -> Result<bool> {
-    let overrides_path = repo_path
-        .join(&candidate.path)
-        .join(format!(".argocd-source-{}.yaml", candidate.app_name));
-    let mut current_overrides = if overrides_path.exists() {
-        serde_yaml::from_str(&std::fs::read_to_string(&overrides_path)?)?
-    } else {
-        Overrides::default()
-    };
+/// Held for the duration of a run, as they all share the same checkouts.
+#[derive(Default)]
+pub struct RunLock(tokio::sync::Mutex<()>);
 
-    let mut has_changed = false;
+/// What a run got triggered by, and what it should update.
+#[derive(Clone)]
+pub struct Trigger {
+    source: String,
+    filter: CandidateFilter,
+    no_cache: bool,
+}
+
+impl Trigger {
+    /// Whether both would run the same update, whatever triggered them.
+    fn same_run(&self, other: &Trigger) -> bool {
+        self.filter == other.filter && self.no_cache == other.no_cache
+    }
+}
+
+/// Only updates the pushed image when the body is a JSON webhook payload we
+/// know about, everything otherwise. Only looks at the `repo` repository when
+/// it's set. Nothing runs when none of the candidates would take the pushed
+/// tag.
+#[rocket::post("/update?<wait>&<repo>", data = "<webhook>")]
+#[allow(clippy::too_many_arguments)]
+async fn trigger(
+    wait: bool,
+    repo: Option<&str>,
+    config: &State<Arc<Config>>,
+    jobs: &State<Arc<Jobs>>,
+    throttle: &State<Throttle>,
+    run_lock: &State<Arc<RunLock>>,
+    no_cache: NoCache,
+    delivery: DeliveryId,
+    webhook: Webhook,
+) -> (Status, (ContentType, String)) {
+    if let Some(unknown) = unknown_repo(config, repo) {
+        return unknown;
+    }
+
+    let pushed = webhook
+        .is_json
+        .then(|| PushedImage::parse(webhook.github_event.as_deref(), &webhook.body))
+        .flatten();
+    let source = match &pushed {
+        Some(pushed) => {
+            log::info!("Update of {} triggered by webhook", pushed);
+            format!("POST /update for {}", pushed)
+        }
+        None => {
+            log::info!("Update triggered by webhook");
+            "POST /update".to_string()
+        }
+    };
 
-    for parameters in &mut current_overrides.helm.parameters.0 {
-        if parameters.name == candidate.helm_image_tag && parameters.value != tag {
-            log::info!("Updating {} to {}", candidate.url, tag);
-            parameters.value = tag.to_string();
-            has_changed = true;
+    let filter = CandidateFilter {
+        repo: repo.map(str::to_string),
+        pushed,
+        ..Default::default()
+    };
+    if let Some(pushed) = &filter.pushed {
+        // The checkouts are being updated while a run is in progress
+        let has_candidates = match run_lock.0.try_lock() {
+            Ok(_guard) => has_candidates(config, &filter),
+            Err(_) => None,
+        };
+        if has_candidates == Some(false) {
+            log::info!("No candidate takes {}, not running", pushed);
+            return (
+                Status::Ok,
+                (ContentType::Text, "no matching candidates".to_string()),
+            );
         }
     }
 
-    if has_changed {
-        std::fs::write(overrides_path, serde_yaml::to_string(&current_overrides)?)?;
+    enqueue(
+        jobs,
+        throttle,
+        delivery,
+        Trigger {
+            source,
+            filter,
+            no_cache: no_cache.0,
+        },
+        wait,
+    )
+    .await
+}
+
+/// Whether the checkouts have candidates matching `filter`, as of their last
+/// fetch. `None` when that can't be told, like for a checkout that was never
+/// fetched.
+fn has_candidates(config: &Config, filter: &CandidateFilter) -> Option<bool> {
+    let mut found = false;
+    for repo in &config.repositories {
+        if !filter.matches_repo(repo) {
+            continue;
+        }
+        if Repository::open(&repo.checkout).is_err() {
+            return None;
+        }
+
+        match discover(config, repo) {
+            Ok(discovery) => {
+                found |= discovery
+                    .candidates
+                    .iter()
+                    .any(|candidate| filter.matches(candidate));
+            }
+            Err(e) => {
+                log::warn!("Couldn't look at the candidates of {}: {:#}", repo.name, e);
+                return None;
+            }
+        }
     }
 
-    Ok(has_changed)
+    Some(found)
 }
 
-async fn get_latest_tag_for_candidate(
-    candidate: &Candidate,
-    github_username: &str,
-    github_key: &str,
-) -> Result<String> {
-    log::info!("Getting latest tag for candidate: {}", candidate.url);
-    let tags_re = Regex::new(candidate.allow_tags.trim_start_matches("regexp:"))?;
-    let config = ClientConfig::default();
-    let client = Client::new(config);
-
-    let auth = RegistryAuth::Basic(github_username.to_string(), github_key.to_string());
-    let reference = Reference::from_str(&candidate.url)?;
-
-    let mut tags = client
-        .list_tags(&reference, &auth, None, None)
-        .await?
-        .tags
-        .into_iter()
-        .filter(|name| tags_re.is_match(name))
-        .collect::<Vec<_>>();
+/// Only updates the candidates of `app_name`, and only the `image` one when
+/// it's set, in the `repo` repository when that's set.
+#[rocket::post("/update/<app_name>?<image>&<wait>&<repo>", data = "<_webhook>")]
+#[allow(clippy::too_many_arguments)]
+async fn trigger_app(
+    app_name: &str,
+    image: Option<&str>,
+    wait: bool,
+    repo: Option<&str>,
+    config: &State<Arc<Config>>,
+    jobs: &State<Arc<Jobs>>,
+    throttle: &State<Throttle>,
+    no_cache: NoCache,
+    delivery: DeliveryId,
+    _webhook: Webhook,
+) -> (Status, (ContentType, String)) {
+    if let Some(unknown) = unknown_repo(config, repo) {
+        return unknown;
+    }
 
-    tags.sort_by(|a, b| alphanumeric_sort::compare_path(a, b));
+    log::info!("Update of {} triggered by webhook", app_name);
 
-    Ok(tags
-        .last()
-        .with_context(|| format!("No tags matched the regex for {}", candidate.app_name))?
-        .to_string())
+    enqueue(
+        jobs,
+        throttle,
+        delivery,
+        Trigger {
+            source: format!("POST /update/{}", app_name),
+            filter: CandidateFilter {
+                repo: repo.map(str::to_string),
+                app_name: Some(app_name.to_string()),
+                image: image.map(str::to_string),
+                pushed: None,
+            },
+            no_cache: no_cache.0,
+        },
+        wait,
+    )
+    .await
 }
 
-#[derive(Clone, Debug)]
-pub struct Candidate {
-    app_name: String,
-    url: String,
-    allow_tags: String,
-    helm_image_tag: String,
-    path: String,
+#[rocket::get("/")]
+async fn root(
+    jobs: &State<Arc<Jobs>>,
+    throttle: &State<Throttle>,
+    no_cache: NoCache,
+    delivery: DeliveryId,
+    _secret: SecretGuard,
+) -> (Status, (ContentType, String)) {
+    log::warn!("Update triggered with GET /, which is deprecated in favor of POST /update");
+
+    // Callers of the old endpoint expect it to answer once the run is over
+    enqueue(
+        jobs,
+        throttle,
+        delivery,
+        Trigger {
+            source: "GET /".to_string(),
+            filter: CandidateFilter::default(),
+            no_cache: no_cache.0,
+        },
+        true,
+    )
+    .await
 }
 
-fn find_candidates(repo_path: &Path) -> Result<Vec<Candidate>> {
-    log::info!("Extracting candidates");
-    let mut candidates = vec![];
+/// Answers with a 404 when `repo` isn't one of the configured repositories.
+fn unknown_repo(config: &Config, repo: Option<&str>) -> Option<(Status, (ContentType, String))> {
+    let repo = repo?;
+    if config.repositories.iter().any(|known| known.name == repo) {
+        return None;
+    }
 
-    for entry in WalkDir::new(repo_path).into_iter().filter_map(|e| e.ok()) {
-        if !entry.file_type().is_file() {
-            continue;
+    let known = config
+        .repositories
+        .iter()
+        .map(|known| known.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some((
+        Status::NotFound,
+        (
+            ContentType::Text,
+            format!("Unknown repository {}, known repositories: {}", repo, known),
+        ),
+    ))
+}
+
+/// Queues a run for the worker, answering with the id of its job, which is the
+/// `run_id` of its logs, or with the job once it's finished when waiting.
+/// Deliveries seen already get the id of the job they got the first time
+/// around.
+async fn enqueue(
+    jobs: &Jobs,
+    throttle: &Throttle,
+    delivery: DeliveryId,
+    trigger: Trigger,
+    wait: bool,
+) -> (Status, (ContentType, String)) {
+    if jobs.is_closed() {
+        return (
+            Status::ServiceUnavailable,
+            (
+                ContentType::Text,
+                "Shutting down, not accepting triggers".to_string(),
+            ),
+        );
+    }
+
+    let admission = throttle.admit(delivery.0.as_deref(), Instant::now(), || {
+        jobs.enqueue(trigger)
+    });
+
+    let (status, body) = match admission {
+        Admission::Queued(id) if wait => return job_response(id, jobs.wait(id).await),
+        Admission::Queued(id) => (
+            Status::Accepted,
+            serde_json::json!({ "id": id, "run_id": id }),
+        ),
+        Admission::Duplicate(id) => {
+            log::info!("Delivery already seen, it got job {}", id);
+            (
+                Status::Ok,
+                serde_json::json!({ "id": id, "run_id": id, "duplicate": true }),
+            )
+        }
+        Admission::Limited => {
+            log::warn!("Too many triggers, rejecting this one");
+            return (
+                Status::TooManyRequests,
+                (
+                    ContentType::Text,
+                    "Too many triggers, try again later".to_string(),
+                ),
+            );
         }
+    };
 
-        let Some(file_name) = entry.file_name().to_str() else {
-            continue;
-        };
+    (status, (ContentType::JSON, body.to_string()))
+}
 
-        if !file_name.ends_with(".yaml") && !file_name.ends_with(".yml") {
-            continue;
+#[rocket::get("/jobs/<id>")]
+fn job(id: u64, jobs: &State<Arc<Jobs>>, _secret: SecretGuard) -> (Status, (ContentType, String)) {
+    match jobs.state(id) {
+        Some(state) => (Status::Ok, job_body(id, state)),
+        None => job_response(id, None),
+    }
+}
+
+/// Answers with a finished job, failing when it did.
+fn job_response(id: u64, state: Option<JobState>) -> (Status, (ContentType, String)) {
+    match state {
+        Some(state @ JobState::Failed { .. }) => (Status::InternalServerError, job_body(id, state)),
+        Some(state) => (Status::Ok, job_body(id, state)),
+        None => (
+            Status::NotFound,
+            (ContentType::Text, format!("Unknown job {}", id)),
+        ),
+    }
+}
+
+fn job_body(id: u64, state: JobState) -> (ContentType, String) {
+    let mut body = serde_json::to_value(state).unwrap();
+    body["id"] = id.into();
+    body["run_id"] = id.into();
+
+    (ContentType::JSON, body.to_string())
+}
+
+/// Queues a run every `interval`, plus up to 10% of jitter so that replicas
+/// don't all hit the registries at the same time. Going through the queue
+/// keeps polls from overlapping with the runs triggered by webhooks.
+async fn poll(jobs: Arc<Jobs>, interval: Duration) {
+    const JITTER: f64 = 0.1;
+
+    loop {
+        let jitter = interval.mul_f64(rand::random::<f64>() * JITTER);
+        tokio::time::sleep(interval + jitter).await;
+        if jobs.is_closed() {
+            return;
         }
 
-        let candidates_from_file = get_candidates_from(entry.path())?;
-        candidates.extend(candidates_from_file.into_iter());
+        let id = jobs.enqueue(Trigger {
+            source: "poll".to_string(),
+            filter: CandidateFilter::default(),
+            no_cache: false,
+        });
+        log::info!("Queued job {} to poll the registries", id);
     }
+}
 
-    Ok(candidates)
+/// Runs the queued jobs one after the other, the checkout being theirs for the
+/// duration of the run.
+struct Worker {
+    config: Arc<Config>,
+    tag_cache: Arc<TagCache>,
+    run_lock: Arc<RunLock>,
+    readiness: Arc<Readiness>,
+    history: Arc<RunHistory>,
+    jobs: Arc<Jobs>,
 }
 
-fn get_candidates_from(file_path: &Path) -> Result<Vec<Candidate>> {
-    log::trace!("Looking at {:?}", file_path);
-    let content = std::fs::read_to_string(file_path)?;
+impl Worker {
+    async fn run(self, mut receiver: mpsc::UnboundedReceiver<u64>) {
+        while let Some(id) = receiver.recv().await {
+            // Dry runs use the checkout too
+            let _guard = self.run_lock.0.lock().await;
+            let Some(trigger) = self.jobs.start(id) else {
+                continue;
+            };
 
-    let yaml = content
-        .trim()
-        .trim_start_matches("---")
-        .trim_end_matches("---");
+            log::info!("Running job {} triggered by {}", id, trigger.source);
+            self.config.events.emit(RunEvent::Started {
+                job: id,
+                trigger: trigger.source.clone(),
+            });
+            let span = tracing::info_span!("run", run_id = id, trigger = %trigger.source);
+            let state = self.run_job(id, trigger).instrument(span).await;
+            self.jobs.finish(id, state);
+        }
+    }
 
-    let reader = BufReader::new(yaml.as_bytes());
-    let documents = yaml_split::DocumentIterator::new(reader);
-    let mut candidates = vec![];
-    for document in documents {
-        let Ok(parsed) = serde_yaml::from_str::<HashMap<String, Value>>(&document?) else {
-            log::debug!("Couldn't parse {:?}. Ignoring it.", file_path);
-            continue;
+    async fn run_job(&self, id: u64, trigger: Trigger) -> JobState {
+        let config = &*self.config;
+        config.metrics.run_started();
+        let started_at = chrono::Utc::now();
+        let start = Instant::now();
+        let cache = (!trigger.no_cache).then_some(&*self.tag_cache);
+        let result = tokio::time::timeout(
+            config.run_timeout,
+            update(config, cache, &self.readiness, &trigger.filter),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow::anyhow!(
+                "Run timed out after {:?}",
+                config.run_timeout
+            ))
+        });
+
+        let duration = start.elapsed();
+        let run = run_record(trigger.source, started_at, duration, &result);
+        let state = match &result {
+            Ok(summary) => {
+                log::info!("Update complete: {}", summary);
+                if summary.succeeded() {
+                    JobState::Succeeded {
+                        summary: summary.to_json(duration),
+                    }
+                } else {
+                    JobState::Failed {
+                        error: summary.to_string(),
+                        summary: Some(summary.to_json(duration)),
+                    }
+                }
+            }
+            Err(e) => {
+                if e.is::<NoMatchingCandidate>() {
+                    log::warn!("{}", e);
+                } else {
+                    log::error!("Error while updating: {:#}", e);
+                }
+                JobState::Failed {
+                    error: format!("{:#}", e),
+                    summary: None,
+                }
+            }
         };
+        await_deliveries(notify(config, &run, &result, duration).await).await;
+        record_audit(config, Some(id), &run.trigger, &result);
+        self.history.record(run);
+        let succeeded = matches!(state, JobState::Succeeded { .. });
+        config.metrics.run_finished(succeeded);
+        config.events.emit(RunEvent::Finished {
+            job: id,
+            succeeded,
+            summary: match &result {
+                Ok(summary) => summary.to_string(),
+                Err(e) => format!("{:#}", e),
+            },
+        });
 
-        if !is_argo_app(&parsed) {
-            continue;
+        state
+    }
+}
+
+/// What `/status` remembers about a run.
+fn run_record(
+    trigger: String,
+    started_at: chrono::DateTime<chrono::Utc>,
+    duration: Duration,
+    result: &Result<RunSummary>,
+) -> Run {
+    let mut run = Run {
+        started_at: started_at.to_rfc3339(),
+        duration_secs: duration.as_secs_f64(),
+        trigger,
+        candidates: 0,
+        updated: vec![],
+        commit: None,
+        repos: vec![],
+        errors: vec![],
+        error: None,
+    };
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => {
+            run.error = Some(format!("{:#}", e));
+            return run;
         }
+    };
 
-        let Some(metadata) = parsed.get("metadata").and_then(Value::as_mapping) else {
-            log::debug!("No metadata, ignoring");
-            continue;
-        };
-        let Some(annotations) = metadata.get("annotations").and_then(Value::as_mapping) else {
-            log::debug!("No annotations, ignoring");
-            continue;
-        };
-        let Some(image_list) = annotations
-            .get("argocd-image-updater.argoproj.io/image-list")
-            .and_then(Value::as_str)
-        else {
-            log::debug!("No image_list, ignoring");
-            continue;
+    run.candidates = summary.candidates();
+    run.commit = summary.commit().map(|commit| commit.to_string());
+    for (repo, repo_summary) in &summary.repos {
+        let mut repo_run = RepoRun {
+            repo: repo.clone(),
+            commit: None,
+            error: None,
         };
+        match repo_summary {
+            Ok(repo_summary) => {
+                run.updated.extend(repo_summary.updated.iter().cloned());
+                run.errors
+                    .extend(repo_summary.failed.iter().map(|(app_name, e)| RunError {
+                        repo: repo.clone(),
+                        app: app_name.clone(),
+                        error: format!("{:#}", e),
+                    }));
+                repo_run.commit = repo_summary.commit.map(|commit| commit.to_string());
+            }
+            Err(e) => repo_run.error = Some(format!("{:#}", e)),
+        }
+        run.repos.push(repo_run);
+    }
 
-        let Some(spec) = parsed.get("spec").and_then(Value::as_mapping) else {
-            continue;
-        };
-        let Some(source) = spec.get("source").and_then(Value::as_mapping) else {
-            continue;
-        };
-        let Some(path) = source.get("path").and_then(Value::as_str) else {
-            continue;
-        };
-        let Some(app_name) = metadata.get("name").and_then(Value::as_str) else {
+    run
+}
+
+/// Tells Slack, `NOTIFY_URL` and the SMTP recipients how a run went, when
+/// they're set up. Returns the deliveries going on in the background.
+async fn notify(
+    config: &Config,
+    run: &Run,
+    result: &Result<RunSummary>,
+    duration: Duration,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    // A run that matched nothing was asked for something that doesn't exist,
+    // which isn't worth an alert
+    let no_match = matches!(result, Err(e) if e.is::<NoMatchingCandidate>());
+    if let Some(slack) = &config.slack {
+        match result {
+            Ok(summary) => {
+                let summary = summary.to_json(duration);
+                slack.notify(&config.repositories, Ok(&summary)).await;
+            }
+            Err(_) if no_match => {}
+            Err(e) => {
+                slack
+                    .notify(&config.repositories, Err(format!("{:#}", e)))
+                    .await
+            }
+        }
+    }
+
+    #[cfg(feature = "sentry")]
+    if !no_match {
+        reporting::run_failures(run);
+    }
+
+    let mut deliveries = vec![];
+    if let Some(email) = config.email.as_ref().filter(|_| !no_match) {
+        deliveries.extend(email.send(run));
+    }
+
+    let Some(webhook) = &config.notify_webhook else {
+        return deliveries;
+    };
+    let mut report = match result {
+        Ok(summary) => summary.to_json(duration),
+        Err(e) => serde_json::json!({
+            "status": "failed",
+            "duration_secs": duration.as_secs_f64(),
+            "error": truncated_error(e),
+        }),
+    };
+    report["trigger"] = run.trigger.clone().into();
+    report["started_at"] = run.started_at.clone().into();
+    deliveries.push(webhook.send(report));
+
+    deliveries
+}
+
+/// How long a run waits for its notifications to be out, the webhook retrying
+/// a few times.
+const DELIVERIES_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Waits for the deliveries of the notifications of a run, which would be lost
+/// to the process exiting otherwise.
+async fn await_deliveries(deliveries: Vec<tokio::task::JoinHandle<()>>) {
+    let delivered = futures::future::join_all(deliveries);
+    if tokio::time::timeout(DELIVERIES_TIMEOUT, delivered)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Gave up on the notifications of the run after {:?}",
+            DELIVERIES_TIMEOUT
+        );
+    }
+}
+
+/// Appends the changes the run pushed to the audit log. Failing to is only
+/// logged, they're in the remote already.
+fn record_audit(config: &Config, run_id: Option<u64>, trigger: &str, result: &Result<RunSummary>) {
+    let (Some(audit_log), Ok(summary)) = (&config.audit_log, result) else {
+        return;
+    };
+
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let mut records = vec![];
+    for (name, repo_summary) in &summary.repos {
+        let Ok(repo_summary) = repo_summary else {
             continue;
         };
+        let checkout = config
+            .repositories
+            .iter()
+            .find(|repo| repo.name == *name)
+            .map(|repo| repo.checkout.as_path());
+        for change in &repo_summary.changes {
+            let (app_name, image, parameter, old, new, path) = match change {
+                Change::Tag {
+                    app_name,
+                    image,
+                    old_tag,
+                    new_tag,
+                    path,
+                } => (
+                    app_name,
+                    Some(image.clone()),
+                    None,
+                    old_tag.clone(),
+                    Some(new_tag.clone()),
+                    path,
+                ),
+                Change::Pruned {
+                    app_name,
+                    parameter,
+                    path,
+                } => (app_name, None, Some(parameter.clone()), None, None, path),
+            };
+            let path = checkout
+                .and_then(|checkout| path.strip_prefix(checkout).ok())
+                .unwrap_or(path);
+            records.push(AuditRecord {
+                timestamp: timestamp.clone(),
+                run_id,
+                trigger: trigger.to_string(),
+                repo: name.clone(),
+                app: app_name.clone(),
+                image,
+                parameter,
+                old,
+                new,
+                path: path.display().to_string(),
+                commit: repo_summary.commit.map(|commit| commit.to_string()),
+            });
+        }
+    }
 
-        let images = image_list.split(',');
-        for image in images {
-            let image = image.trim();
-            let Some((name, url)) = image.split_once('=') else {
-                continue;
+    if let Err(e) = audit_log.append(&records) {
+        log::error!(
+            "Failed to write {} change(s) to the audit log: {:#}",
+            records.len(),
+            e
+        );
+    }
+}
+
+/// Streams the events of the runs as they happen, starting with how the last
+/// one ended when none is in progress.
+#[rocket::get("/events")]
+fn stream_events(
+    config: &State<Arc<Config>>,
+    mut shutdown: Shutdown,
+    _secret: SecretGuard,
+) -> EventStream![] {
+    let (last_finished, mut events) = config.events.subscribe();
+
+    EventStream! {
+        if let Some(last_finished) = last_finished {
+            yield last_finished.to_sse();
+        }
+
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = &mut shutdown => break,
             };
+            match event {
+                Ok(event) => yield event.to_sse(),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
 
-            let Some(allow_tags) = annotations
-                .get(format!(
-                    "argocd-image-updater.argoproj.io/{}.allow-tags",
-                    name
-                ))
-                .and_then(Value::as_str)
-            else {
-                log::warn!("Found image {} without `allow-tags`. Ignoring.", name);
-                continue;
+/// The last changes written to `AUDIT_LOG_PATH`, 100 unless `limit` says
+/// otherwise.
+#[rocket::get("/audit?<limit>")]
+fn audit_tail(
+    limit: Option<usize>,
+    config: &State<Arc<Config>>,
+    _secret: SecretGuard,
+) -> (Status, (ContentType, String)) {
+    let Some(audit_log) = &config.audit_log else {
+        return (
+            Status::NotFound,
+            (ContentType::Text, "AUDIT_LOG_PATH isn't set".to_string()),
+        );
+    };
+
+    match audit_log.tail(limit.unwrap_or(100)) {
+        Ok(records) => (
+            Status::Ok,
+            (
+                ContentType::JSON,
+                serde_json::json!({ "records": records }).to_string(),
+            ),
+        ),
+        Err(e) => {
+            log::error!("Failed to read the audit log: {:#}", e);
+            (
+                Status::InternalServerError,
+                (
+                    ContentType::Text,
+                    "Failed to read the audit log".to_string(),
+                ),
+            )
+        }
+    }
+}
+
+/// The outcome of the last runs, most recent first.
+#[rocket::get("/status")]
+fn run_status(
+    history: &State<Arc<RunHistory>>,
+    config: &State<Arc<Config>>,
+    _secret: SecretGuard,
+) -> (ContentType, String) {
+    let runs = serde_json::json!({
+        "runs": history.runs(),
+        "circuits": config.circuits.states(Instant::now()),
+    });
+
+    (ContentType::JSON, runs.to_string())
+}
+
+/// Reports what an update would do, without writing, committing or pushing
+/// anything, in the `repo` repository or all of them.
+#[rocket::get("/plan?<repo>")]
+#[allow(clippy::too_many_arguments)]
+async fn dry_run(
+    repo: Option<&str>,
+    config: &State<Arc<Config>>,
+    tag_cache: &State<Arc<TagCache>>,
+    run_lock: &State<Arc<RunLock>>,
+    readiness: &State<Arc<Readiness>>,
+    no_cache: NoCache,
+    _secret: SecretGuard,
+) -> (Status, (ContentType, String)) {
+    let Ok(_guard) = run_lock.0.try_lock() else {
+        return (
+            Status::Conflict,
+            (
+                ContentType::Text,
+                "An update is already running".to_string(),
+            ),
+        );
+    };
+
+    if let Some(unknown) = unknown_repo(config, repo) {
+        return unknown;
+    }
+
+    let cache = (!no_cache.0).then_some(&**tag_cache.inner());
+    let filter = CandidateFilter {
+        repo: repo.map(str::to_string),
+        ..Default::default()
+    };
+    match plan_entries(config, cache, readiness, &filter).await {
+        Ok(entries) => (
+            Status::Ok,
+            (
+                ContentType::JSON,
+                serde_json::Value::from(entries).to_string(),
+            ),
+        ),
+        Err(e) => {
+            log::error!("Error while planning: {:#}", e);
+            (
+                Status::InternalServerError,
+                (ContentType::Text, format!("{:#}", e)),
+            )
+        }
+    }
+}
+
+/// Fetches the repositories matching `filter` and describes what an update
+/// would do to each of their candidates.
+async fn plan_entries(
+    config: &Config,
+    cache: Option<&TagCache>,
+    readiness: &Readiness,
+    filter: &CandidateFilter,
+) -> Result<Vec<serde_json::Value>> {
+    let mut entries = vec![];
+    for repo in &config.repositories {
+        if !filter.matches_repo(repo) {
+            continue;
+        }
+
+        fetch_checkout(config, repo, readiness).context(repo.name.clone())?;
+        let plan = plan(config, repo, cache, filter)
+            .await
+            .context(repo.name.clone())?;
+
+        entries.extend(plan.resolved.iter().map(|(candidate, tag)| {
+            let current = writeback::current_tag(&repo.checkout, candidate);
+            let soaking = tag
+                .as_ref()
+                .err()
+                .filter(|e| e.is::<Soaking>())
+                .map(|e| e.to_string());
+            let missing_platforms = tag
+                .as_ref()
+                .err()
+                .filter(|e| e.is::<MissingPlatforms>())
+                .map(|e| e.to_string());
+            let error = match (&current, tag) {
+                (_, Err(_)) if soaking.is_some() || missing_platforms.is_some() => None,
+                (_, Err(e)) | (Err(e), _) => Some(format!("{:#}", e)),
+                _ => None,
             };
-            let Some(helm_image_tag) = annotations
-                .get(format!(
-                    "argocd-image-updater.argoproj.io/{}.helm.image-tag",
-                    name
-                ))
-                .and_then(Value::as_str)
-            else {
-                log::warn!("Found image {} without `helm.image-tag`. Ignoring.", name);
-                continue;
+            let current = current.ok().flatten();
+            let change = match tag {
+                Ok(tag) => writeback::would_change(candidate, current.as_deref(), tag),
+                Err(_) => false,
+            };
+            // The tags matching whole are among the ones matching at all, the
+            // selection only changes when it isn't one of them
+            let anchoring_changes = match (tag, candidate.anchor_tags, &candidate.pinned_tag) {
+                (Ok(tag), false, None) => TagFilter::parse(&candidate.allow_tags, true)
+                    .is_ok_and(|anchored| !anchored.matches(tag)),
+                _ => false,
             };
 
-            candidates.push(Candidate {
-                app_name: app_name.to_string(),
-                url: url.to_string(),
-                allow_tags: allow_tags.to_string(),
-                helm_image_tag: helm_image_tag.to_string(),
-                path: path.to_string(),
-            });
-        }
+            serde_json::json!({
+                "repo": repo.name,
+                "app": candidate.app_name,
+                "image": split_tag(&candidate.url).0,
+                "current": current.as_deref().unwrap_or("absent"),
+                "selected": tag.as_ref().ok(),
+                "change": change && error.is_none() && !candidate.paused,
+                "paused": candidate.paused,
+                "soaking": soaking,
+                "missing_platforms": missing_platforms,
+                "anchoring_changes": anchoring_changes,
+                "skipped_prerelease": plan
+                    .skipped_prereleases
+                    .get(&(candidate.app_name.clone(), candidate.url.clone())),
+                "without_platforms": plan
+                    .without_platforms
+                    .get(&(candidate.app_name.clone(), candidate.url.clone())),
+                "error": error,
+            })
+        }));
     }
 
-    Ok(candidates)
+    Ok(entries)
 }
 
-fn is_argo_app(value: &HashMap<String, Value>) -> bool {
-    let api_version = value.get("apiVersion").and_then(|v| v.as_str());
-    let kind = value.get("kind").and_then(|v| v.as_str());
+/// Lists the candidates found in the checkouts as they currently are, along
+/// with the apps and images that got skipped and why.
+#[rocket::get("/candidates?<repo>")]
+async fn list_candidates(
+    repo: Option<&str>,
+    config: &State<Arc<Config>>,
+    run_lock: &State<Arc<RunLock>>,
+    _secret: SecretGuard,
+) -> (Status, (ContentType, String)) {
+    let Ok(_guard) = run_lock.0.try_lock() else {
+        return (
+            Status::Conflict,
+            (
+                ContentType::Text,
+                "An update is already running".to_string(),
+            ),
+        );
+    };
 
-    kind == Some("Application") && api_version == Some("argoproj.io/v1alpha1")
+    if let Some(unknown) = unknown_repo(config, repo) {
+        return unknown;
+    }
+
+    let filter = CandidateFilter {
+        repo: repo.map(str::to_string),
+        ..Default::default()
+    };
+    match candidate_entries(config, &filter) {
+        Ok(body) => (Status::Ok, (ContentType::JSON, body.to_string())),
+        Err(e) => {
+            log::error!("Error while looking for candidates: {:#}", e);
+            (
+                Status::InternalServerError,
+                (ContentType::Text, format!("{:#}", e)),
+            )
+        }
+    }
 }
 
-fn clone_or_reset(repo_url: &str, repo_path: &Path, ssh_key_path: &Path) -> Result<Repository> {
-    log::info!("Resetting upstream repo");
+/// The candidates found in the checkouts of the repositories matching
+/// `filter`, and what got skipped.
+fn candidate_entries(config: &Config, filter: &CandidateFilter) -> Result<serde_json::Value> {
+    let mut candidates = vec![];
+    let mut skipped = vec![];
+    for repo in &config.repositories {
+        if !filter.matches_repo(repo) {
+            continue;
+        }
 
-    let mut init_opts = RepositoryInitOptions::new();
-    init_opts.initial_head("main");
+        let discovery = discover(config, repo).context(repo.name.clone())?;
+        candidates.extend(discovery.candidates.iter().map(|candidate| {
+            let helm_image_tag = match &candidate.target {
+                WriteTarget::Helm { image_tag, .. } => Some(image_tag),
+                _ => None,
+            };
 
-    let repo = Repository::init_opts(repo_path, &init_opts)?;
-    {
-        let mut remote = repo
-            .find_remote("origin")
-            .or_else(|_| repo.remote("origin", repo_url))?;
+            serde_json::json!({
+                "repo": repo.name,
+                "app": candidate.app_name,
+                "image": candidate.url,
+                "allow_tags": candidate.allow_tags,
+                "helm_image_tag": helm_image_tag,
+                "path": candidate.path,
+                "manifest": candidate.manifest,
+                "paused": candidate.paused,
+            })
+        }));
+        skipped.extend(discovery.skipped.iter().map(|skipped| {
+            serde_json::json!({
+                "repo": repo.name,
+                "app": skipped.app_name,
+                "image": skipped.image,
+                "manifest": skipped.manifest,
+                "kind": skipped.reason.kind(),
+                "reason": skipped.reason.to_string(),
+            })
+        }));
+    }
 
-        let mut cb = RemoteCallbacks::new();
-        cb.credentials(|_, username, _| {
-            Cred::ssh_key(username.unwrap_or("git"), None, ssh_key_path, None)
-        });
+    Ok(serde_json::json!({ "candidates": candidates, "skipped": skipped }))
+}
 
-        let mut connection = remote.connect_auth(Direction::Fetch, Some(cb), None)?;
-        connection.remote().fetch(&["main"], None, None)?;
+/// When each checkout was last fetched successfully, by repository name.
+#[derive(Default)]
+pub struct Readiness {
+    last_fetch: std::sync::Mutex<HashMap<String, Instant>>,
+}
 
-        let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        repo.reset(
-            &fetch_head.peel(git2::ObjectType::Commit)?,
-            ResetType::Hard,
-            None,
-        )?;
+/// Unauthenticated unless `METRICS_REQUIRE_SECRET` is set.
+#[rocket::get("/metrics")]
+fn export_metrics(
+    config: &State<Arc<Config>>,
+    secret: Option<SecretGuard>,
+) -> (Status, (ContentType, String)) {
+    if config.metrics_require_secret && secret.is_none() {
+        return (Status::Unauthorized, (ContentType::Text, String::new()));
     }
 
-    Ok(repo)
+    match config.metrics.encode() {
+        Ok(metrics) => (
+            Status::Ok,
+            (
+                ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+                metrics,
+            ),
+        ),
+        Err(e) => (
+            Status::InternalServerError,
+            (ContentType::Text, format!("{:#}", e)),
+        ),
+    }
+}
+
+#[rocket::get("/healthz")]
+fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Ready once every repository got fetched, and recently enough when
+/// `READY_MAX_FETCH_AGE_SECS` is set.
+#[rocket::get("/readyz")]
+fn readyz(
+    config: &State<Arc<Config>>,
+    readiness: &State<Arc<Readiness>>,
+) -> (Status, (ContentType, String)) {
+    let last_fetch = readiness.last_fetch.lock().unwrap();
+    let reason = config.repositories.iter().find_map(|repo| {
+        match (last_fetch.get(&repo.name), config.ready_max_fetch_age) {
+            (None, _) => Some(format!(
+                "The repository {} hasn't been fetched yet",
+                repo.name
+            )),
+            (Some(last_fetch), Some(max_age)) if last_fetch.elapsed() > max_age => Some(format!(
+                "The repository {} was last fetched {}s ago, more than the {}s allowed",
+                repo.name,
+                last_fetch.elapsed().as_secs(),
+                max_age.as_secs()
+            )),
+            _ => None,
+        }
+    });
+    drop(last_fetch);
+
+    let (status, body) = match reason {
+        Some(reason) => (
+            Status::ServiceUnavailable,
+            serde_json::json!({ "ready": false, "reason": reason }),
+        ),
+        None => (Status::Ok, serde_json::json!({ "ready": true })),
+    };
+
+    (status, (ContentType::JSON, body.to_string()))
+}
+
+/// Restricts a run to some of the candidates, all of them by default.
+#[derive(Clone, Default, PartialEq)]
+pub struct CandidateFilter {
+    /// The name of the only repository to look at.
+    repo: Option<String>,
+    app_name: Option<String>,
+    /// The image, without its tag.
+    image: Option<String>,
+    /// Only the candidates of the image that got pushed, if they'd accept the
+    /// tag.
+    pushed: Option<PushedImage>,
+}
+
+impl CandidateFilter {
+    fn matches_repo(&self, repo: &RepoConfig) -> bool {
+        self.repo.as_ref().is_none_or(|name| *name == repo.name)
+    }
+
+    fn matches_app(&self, candidate: &Candidate) -> bool {
+        self.app_name
+            .as_ref()
+            .is_none_or(|app_name| *app_name == candidate.app_name)
+    }
+
+    fn matches(&self, candidate: &Candidate) -> bool {
+        self.matches_app(candidate)
+            && self
+                .image
+                .as_ref()
+                .is_none_or(|image| image == split_tag(&candidate.url).0)
+            && self.pushed.as_ref().is_none_or(|pushed| {
+                pushed.is_image(&candidate.url)
+                    && pushed
+                        .tags
+                        .iter()
+                        .any(|tag| candidate.tag_filter.matches(tag))
+            })
+    }
+
+    /// Keeps the matching candidates, failing when a filter is set and none
+    /// matched.
+    fn apply(&self, candidates: Vec<Candidate>) -> Result<Vec<Candidate>> {
+        let (matching, others): (Vec<_>, Vec<_>) = candidates
+            .into_iter()
+            .partition(|candidate| self.matches(candidate));
+        if !matching.is_empty() || (self.app_name.is_none() && self.image.is_none()) {
+            return Ok(matching);
+        }
+
+        Err(NoMatchingCandidate {
+            app_name: self.app_name.clone().unwrap_or_default(),
+            image: self.image.clone(),
+            app_known: others.iter().any(|candidate| self.matches_app(candidate)),
+            known_apps: others
+                .into_iter()
+                .map(|candidate| candidate.app_name)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+        }
+        .into())
+    }
+}
+
+/// Nothing matched the candidate filter of a run.
+#[derive(Debug)]
+pub struct NoMatchingCandidate {
+    app_name: String,
+    image: Option<String>,
+    /// Whether the app has candidates, only the image didn't match.
+    app_known: bool,
+    known_apps: Vec<String>,
+}
+
+impl NoMatchingCandidate {
+    /// Combines what the repositories that had no matching candidate know
+    /// about.
+    fn merge(&mut self, other: NoMatchingCandidate) {
+        self.app_known |= other.app_known;
+        self.known_apps.extend(other.known_apps);
+        self.known_apps.sort();
+        self.known_apps.dedup();
+    }
+}
+
+impl std::fmt::Display for NoMatchingCandidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const SHOWN: usize = 20;
+
+        if let (true, Some(image)) = (self.app_known, &self.image) {
+            return write!(f, "{} has no image {}", self.app_name, image);
+        }
+
+        write!(
+            f,
+            "Unknown app {}, known apps: {}",
+            self.app_name,
+            self.known_apps
+                .iter()
+                .take(SHOWN)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        if self.known_apps.len() > SHOWN {
+            write!(f, " and {} more", self.known_apps.len() - SHOWN)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for NoMatchingCandidate {}
+
+#[derive(Default, Debug)]
+pub struct UpdateSummary {
+    candidates: usize,
+    updated: Vec<String>,
+    failed: Vec<(String, anyhow::Error)>,
+    timed_out: usize,
+    rate_limited: usize,
+    /// Skipped, their registry's circuit being open.
+    unavailable: usize,
+    /// The commit that got pushed, if any.
+    commit: Option<Oid>,
+    changes: Vec<Change>,
+    skipped: Vec<Unchanged>,
+    /// The apps, or images, found in the checkout that aren't candidates.
+    ignored: Vec<Skipped>,
+}
+
+/// A candidate that was left alone, and why.
+#[derive(Debug)]
+struct Unchanged {
+    app_name: String,
+    image: String,
+    reason: String,
+}
+
+impl UpdateSummary {
+    /// What happened in the repository, each entry saying which one it was.
+    fn to_json(&self, repo: &str) -> serde_json::Value {
+        let mut updated = vec![];
+        let mut pruned = vec![];
+        for change in &self.changes {
+            match change {
+                Change::Tag {
+                    app_name,
+                    image,
+                    old_tag,
+                    new_tag,
+                    ..
+                } => updated.push(serde_json::json!({
+                    "repo": repo,
+                    "app": app_name,
+                    "image": image,
+                    "old": old_tag,
+                    "new": new_tag,
+                })),
+                Change::Pruned {
+                    app_name,
+                    parameter,
+                    ..
+                } => pruned.push(serde_json::json!({
+                    "repo": repo,
+                    "app": app_name,
+                    "parameter": parameter,
+                })),
+            }
+        }
+        let skipped = self
+            .skipped
+            .iter()
+            .map(|skipped| {
+                serde_json::json!({
+                    "repo": repo,
+                    "app": skipped.app_name,
+                    "image": skipped.image,
+                    "reason": skipped.reason,
+                })
+            })
+            .collect::<Vec<_>>();
+        let ignored = self
+            .ignored
+            .iter()
+            .map(|ignored| {
+                serde_json::json!({
+                    "repo": repo,
+                    "app": ignored.app_name,
+                    "image": ignored.image,
+                    "manifest": ignored.manifest,
+                    "kind": ignored.reason.kind(),
+                    "reason": ignored.reason.to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let failed = self
+            .failed
+            .iter()
+            .map(|(app_name, e)| {
+                serde_json::json!({ "repo": repo, "app": app_name, "error": truncated_error(e) })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "updated": updated,
+            "pruned": pruned,
+            "skipped": skipped,
+            "ignored": ignored,
+            "failed": failed,
+        })
+    }
+}
+
+/// The error for the caller that triggered the run, truncated so that a single
+/// failing registry can't blow up the body.
+fn truncated_error(e: &anyhow::Error) -> String {
+    const MAX_ERROR_LENGTH: usize = 500;
+
+    let mut error = format!("{:#}", e);
+    if let Some((end, _)) = error.char_indices().nth(MAX_ERROR_LENGTH) {
+        error.truncate(end);
+        error.push('…');
+    }
+
+    error
+}
+
+impl std::fmt::Display for UpdateSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.candidates == 0 {
+            write!(f, "no matching candidates")?;
+            if !self.ignored.is_empty() {
+                write!(f, ", ignored {}", self.ignored.len())?;
+            }
+            return Ok(());
+        }
+
+        write!(
+            f,
+            "updated {}, failed {}",
+            self.updated.len(),
+            self.failed.len()
+        )?;
+
+        if self.timed_out > 0 {
+            write!(f, ", timed out {}", self.timed_out)?;
+        }
+        if self.rate_limited > 0 {
+            write!(f, ", rate limited {}", self.rate_limited)?;
+        }
+        if self.unavailable > 0 {
+            write!(f, ", registry unavailable {}", self.unavailable)?;
+        }
+        if !self.ignored.is_empty() {
+            write!(f, ", ignored {}", self.ignored.len())?;
+        }
+
+        if !self.failed.is_empty() {
+            let failures = self
+                .failed
+                .iter()
+                .map(|(app_name, e)| format!("{}: {:#}", app_name, e))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(f, " ({})", failures)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The outcome of a run, repository by repository.
+#[derive(Default, Debug)]
+pub struct RunSummary {
+    repos: Vec<(String, Result<UpdateSummary>)>,
+}
+
+impl RunSummary {
+    fn summaries(&self) -> impl Iterator<Item = &UpdateSummary> {
+        self.repos
+            .iter()
+            .filter_map(|(_, summary)| summary.as_ref().ok())
+    }
+
+    fn candidates(&self) -> usize {
+        self.summaries().map(|summary| summary.candidates).sum()
+    }
+
+    /// Whether every repository got updated without failures.
+    fn succeeded(&self) -> bool {
+        self.repos.iter().all(|(_, summary)| {
+            summary
+                .as_ref()
+                .is_ok_and(|summary| summary.failed.is_empty())
+        })
+    }
+
+    /// The commit that got pushed, when there's a single repository to push to.
+    fn commit(&self) -> Option<Oid> {
+        match self.repos.as_slice() {
+            [(_, Ok(summary))] => summary.commit,
+            _ => None,
+        }
+    }
+
+    fn to_json(&self, duration: Duration) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "status": if self.succeeded() { "succeeded" } else { "failed" },
+            "duration_secs": duration.as_secs_f64(),
+            "candidates": self.candidates(),
+            "updated": [],
+            "pruned": [],
+            "skipped": [],
+            "ignored": [],
+            "failed": [],
+            "commit": self.commit().map(|commit| commit.to_string()),
+        });
+
+        let mut repos = vec![];
+        for (repo, summary) in &self.repos {
+            let (status, commit, error) = match summary {
+                Ok(summary) => {
+                    let entries = summary.to_json(repo);
+                    for key in ["updated", "pruned", "skipped", "ignored", "failed"] {
+                        if let (Some(all), Some(entries)) =
+                            (body[key].as_array_mut(), entries[key].as_array())
+                        {
+                            all.extend(entries.iter().cloned());
+                        }
+                    }
+                    let status = match summary.failed.is_empty() {
+                        true => "succeeded",
+                        false => "failed",
+                    };
+                    (
+                        status,
+                        summary.commit.map(|commit| commit.to_string()),
+                        None,
+                    )
+                }
+                Err(e) => ("failed", None, Some(truncated_error(e))),
+            };
+            repos.push(serde_json::json!({
+                "repo": repo,
+                "status": status,
+                "commit": commit,
+                "error": error,
+            }));
+        }
+        body["repos"] = repos.into();
+
+        body
+    }
+}
+
+impl std::fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe = |summary: &Result<UpdateSummary>| match summary {
+            Ok(summary) => summary.to_string(),
+            Err(e) => format!("{:#}", e),
+        };
+
+        match self.repos.as_slice() {
+            [] => write!(f, "no matching candidates"),
+            [(_, summary)] => write!(f, "{}", describe(summary)),
+            repos => {
+                let repos = repos
+                    .iter()
+                    .map(|(repo, summary)| format!("{}: {}", repo, describe(summary)))
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                write!(f, "{}", repos)
+            }
+        }
+    }
+}
+
+/// Resets the repository's checkout to the remote branch, keeping track of
+/// when it last worked for `/readyz`.
+fn fetch_checkout(
+    config: &Config,
+    repo: &RepoConfig,
+    readiness: &Readiness,
+) -> Result<(Repository, String)> {
+    let _span = tracing::info_span!("fetch", repo = repo.name.as_str()).entered();
+    let checkout = git::clone_or_reset(
+        &repo.url,
+        &repo.checkout,
+        &repo.git_credentials,
+        &repo.host_key_check,
+        repo.branch.as_deref(),
+        config.git_fetch_depth,
+    )?;
+    readiness
+        .last_fetch
+        .lock()
+        .unwrap()
+        .insert(repo.name.clone(), Instant::now());
+
+    Ok(checkout)
+}
+
+/// Updates the repositories one after the other, a failure in one of them not
+/// keeping the others from being updated. Only fails when none of them has a
+/// candidate matching the filter.
+async fn update(
+    config: &Config,
+    tag_cache: Option<&TagCache>,
+    readiness: &Readiness,
+    filter: &CandidateFilter,
+) -> Result<RunSummary> {
+    let mut summary = RunSummary::default();
+    let mut no_match: Option<NoMatchingCandidate> = None;
+    let manifests = ManifestChecks::default();
+    for repo in &config.repositories {
+        if !filter.matches_repo(repo) {
+            continue;
+        }
+
+        let result = update_repo(config, repo, tag_cache, readiness, &manifests, filter)
+            .instrument(tracing::info_span!("repo", repo = repo.name.as_str()))
+            .await;
+        let result = match result {
+            Err(e) if e.is::<NoMatchingCandidate>() => {
+                let e = e.downcast::<NoMatchingCandidate>().unwrap();
+                match &mut no_match {
+                    Some(no_match) => no_match.merge(e),
+                    None => no_match = Some(e),
+                }
+                continue;
+            }
+            Err(e) => {
+                log::error!("Error while updating {}: {:#}", repo.name, e);
+                Err(e)
+            }
+            Ok(repo_summary) => Ok(repo_summary),
+        };
+        summary.repos.push((repo.name.clone(), result));
+    }
+
+    match no_match {
+        Some(no_match) if summary.repos.is_empty() => Err(no_match.into()),
+        _ => Ok(summary),
+    }
+}
+
+async fn update_repo(
+    config: &Config,
+    repo: &RepoConfig,
+    tag_cache: Option<&TagCache>,
+    readiness: &Readiness,
+    manifests: &ManifestChecks,
+    filter: &CandidateFilter,
+) -> Result<UpdateSummary> {
+    let checkout = fetch_checkout(config, repo, readiness)?;
+    let plan = plan(config, repo, tag_cache, filter).await?;
+    let processed = plan
+        .resolved
+        .iter()
+        .map(|(candidate, _)| candidate.app_name.clone())
+        .collect::<Vec<_>>();
+
+    let summary = apply(
+        config, repo, tag_cache, readiness, manifests, checkout, plan,
+    )
+    .await?;
+    config.metrics.candidates(
+        processed.iter().map(String::as_str),
+        summary.updated.iter().map(String::as_str),
+        summary.failed.iter().map(|(app_name, _)| app_name.as_str()),
+    );
+
+    Ok(summary)
+}
+
+/// The tag resolved for each candidate found in the checkout, before anything
+/// gets written.
+pub struct Plan {
+    resolved: Vec<(Candidate, Result<String>)>,
+    managed_parameters: BTreeMap<(String, String), HashSet<String>>,
+    /// The newer prereleases passed over, by app and image.
+    skipped_prereleases: HashMap<(String, String), String>,
+    /// The newer tags passed over for their missing `platforms`, by app and
+    /// image.
+    without_platforms: HashMap<(String, String), Vec<String>>,
+    /// The apps, or images, that can't be candidates as they are.
+    ignored: Vec<Skipped>,
+}
+
+/// Finds the candidates in the repository's checkout matching `filter` and
+/// resolves their tags.
+async fn plan(
+    config: &Config,
+    repo: &RepoConfig,
+    tag_cache: Option<&TagCache>,
+    filter: &CandidateFilter,
+) -> Result<Plan> {
+    let Discovery {
+        candidates,
+        skipped,
+    } = tracing::info_span!("discover").in_scope(|| discover(config, repo))?;
+
+    // The mistakes in the apps are only worth reporting when the run isn't
+    // about a given image
+    let ignored = match filter.image.is_none() && filter.pushed.is_none() {
+        true => skipped
+            .into_iter()
+            .filter(|skipped| {
+                skipped.reason.is_actionable()
+                    && filter
+                        .app_name
+                        .as_ref()
+                        .is_none_or(|app_name| skipped.app_name.as_ref() == Some(app_name))
+            })
+            .collect(),
+        false => vec![],
+    };
+
+    // Keep track of the parameters still belonging to a candidate in the apps
+    // that want stale ones pruned. All of an app's candidates count, even the
+    // ones filtered out by image.
+    let mut managed_parameters = BTreeMap::<_, HashSet<String>>::new();
+    for candidate in &candidates {
+        if !filter.matches_app(candidate)
+            || !(config.prune_stale_parameters || candidate.prune_parameters)
+            || !matches!(candidate.write_back, WriteBackTarget::Overrides)
+        {
+            continue;
+        }
+
+        let names = managed_parameters
+            .entry((candidate.app_name.clone(), candidate.path.clone()))
+            .or_default();
+        if let WriteTarget::Helm {
+            image_tag,
+            image_name,
+        } = &candidate.target
+        {
+            names.insert(image_tag.clone());
+            names.extend(image_name.clone());
+        }
+    }
+    // Pruning would write the overrides of the paused candidates too
+    managed_parameters.retain(|(app_name, path), _| {
+        !candidates.iter().any(|candidate| {
+            candidate.paused && candidate.app_name == *app_name && candidate.path == *path
+        })
+    });
+
+    // Candidates sharing the same image and tag selection rules only need to
+    // hit the registry once.
+    let mut groups = HashMap::<_, Vec<Candidate>>::new();
+    for candidate in filter.apply(candidates)? {
+        groups
+            .entry(candidate.lookup_key())
+            .or_default()
+            .push(candidate);
+    }
+
+    let rate_limits = &RateLimits::default();
+    let platforms = &PlatformChecks::default();
+    let resolved = futures::stream::iter(groups.into_values())
+        .map(|group| {
+            let apps = group
+                .iter()
+                .map(|candidate| candidate.app_name.as_str())
+                .collect::<Vec<_>>()
+                .join(",");
+            let span = logging::candidate_span(&apps, split_tag(&group[0].url).0);
+            async move {
+                for candidate in &group {
+                    config.events.emit(RunEvent::Checking {
+                        app: candidate.app_name.clone(),
+                        image: split_tag(&candidate.url).0.to_string(),
+                    });
+                }
+                let span = tracing::info_span!(
+                    "registry",
+                    host = group[0].registry_host().unwrap_or_default(),
+                    image = split_tag(&group[0].url).0,
+                    tag = tracing::field::Empty,
+                );
+                let (tag, passed_over) =
+                    match resolve_tag(config, &group[0], tag_cache, rate_limits, platforms)
+                        .instrument(span.clone())
+                        .await
+                    {
+                        Ok(selection) => (
+                            Ok(selection.tag),
+                            (selection.skipped_prerelease, selection.without_platforms),
+                        ),
+                        Err(e) => (Err(e), (None, vec![])),
+                    };
+                if let Ok(tag) = &tag {
+                    span.record("tag", tag.as_str());
+                }
+                group
+                    .into_iter()
+                    .map(|candidate| {
+                        let tag = match &tag {
+                            Ok(tag) => Ok(tag.clone()),
+                            Err(e) => Err(duplicate_error(e)),
+                        };
+                        (candidate, tag, passed_over.clone())
+                    })
+                    .collect::<Vec<_>>()
+            }
+            .instrument(span)
+        })
+        .buffer_unordered(config.registry_concurrency)
+        .flat_map(futures::stream::iter)
+        .collect::<Vec<_>>()
+        .await;
+    let mut skipped_prereleases = HashMap::new();
+    let mut without_platforms = HashMap::new();
+    let mut resolved = resolved
+        .into_iter()
+        .map(|(candidate, tag, (skipped_prerelease, without))| {
+            let key = (candidate.app_name.clone(), candidate.url.clone());
+            if let Some(skipped_prerelease) = skipped_prerelease {
+                skipped_prereleases.insert(key.clone(), skipped_prerelease);
+            }
+            if !without.is_empty() {
+                without_platforms.insert(key, without);
+            }
+            (candidate, tag)
+        })
+        .collect::<Vec<_>>();
+    resolved.sort_by(|(a, _), (b, _)| (&a.app_name, &a.url).cmp(&(&b.app_name, &b.url)));
+
+    // Dry runs included, the metrics say which candidates are behind
+    let versions = resolved
+        .iter()
+        .filter_map(|(candidate, tag)| {
+            let current = writeback::current_tag(&repo.checkout, candidate).ok()?;
+            Some(CandidateVersion {
+                app: candidate.app_name.clone(),
+                image: split_tag(&candidate.url).0.to_string(),
+                update_available: tag
+                    .as_ref()
+                    .ok()
+                    .map(|tag| writeback::would_change(candidate, current.as_deref(), tag)),
+                current: current.unwrap_or_else(|| "absent".to_string()),
+            })
+        })
+        .collect();
+    let complete = filter.app_name.is_none() && filter.image.is_none() && filter.pushed.is_none();
+    config
+        .metrics
+        .candidate_versions(&repo.name, versions, complete);
+
+    Ok(Plan {
+        resolved,
+        managed_parameters,
+        skipped_prereleases,
+        without_platforms,
+        ignored,
+    })
+}
+
+/// Writes the planned tags, then commits and pushes them, starting over from a
+/// fresh checkout when the remote moved in the meantime.
+async fn apply(
+    config: &Config,
+    repo: &RepoConfig,
+    tag_cache: Option<&TagCache>,
+    readiness: &Readiness,
+    manifests: &ManifestChecks,
+    checkout: (Repository, String),
+    plan: Plan,
+) -> Result<UpdateSummary> {
+    // Registry failures are only recorded once, the rest of the run can be
+    // repeated on top of a fresh checkout when the push gets rejected.
+    let mut summary = UpdateSummary {
+        candidates: plan.resolved.len(),
+        ignored: plan.ignored,
+        ..Default::default()
+    };
+    let mut selected = vec![];
+    for (candidate, tag) in plan.resolved {
+        let _span =
+            logging::candidate_span(&candidate.app_name, split_tag(&candidate.url).0).entered();
+        match tag {
+            Ok(tag) => selected.push((candidate, tag)),
+            // Not a failure, there's just nothing to update to yet
+            Err(e) if e.is::<Soaking>() || e.is::<MissingPlatforms>() => {
+                record_skipped(config, &mut summary, &candidate, e.to_string())
+            }
+            Err(e) => record_failure(
+                config,
+                &mut summary,
+                candidate.app_name.clone(),
+                Some(&candidate),
+                e,
+            )?,
+        }
+    }
+    if config.verify_manifests {
+        selected = verify_manifests(config, repo, manifests, selected, &mut summary).await?;
+    }
+    selected = verify_signatures(config, repo, manifests, selected, &mut summary).await?;
+    let registry_failures = summary.failed.len();
+    let soaking = summary.skipped.len();
+
+    let mut checkout = Some(checkout);
+    for attempt in 1..=git::PUSH_ATTEMPTS {
+        let (repository, branch) = match checkout.take() {
+            Some(checkout) => checkout,
+            None => fetch_checkout(config, repo, readiness)?,
+        };
+        summary.updated.clear();
+        summary.changes.clear();
+        summary.skipped.truncate(soaking);
+        summary.failed.truncate(registry_failures);
+
+        let changes = apply_updates(
+            config,
+            repo,
+            &selected,
+            &plan.managed_parameters,
+            tag_cache,
+            &mut summary,
+        )?;
+        if summary.updated.is_empty() {
+            log::info!("No image changes, skipping commit and push");
+            return Ok(summary);
+        }
+
+        let committed = tracing::info_span!("commit")
+            .in_scope(|| commit_changes(config, repo, &repository, &changes))?;
+        let Some((message, lease)) = committed else {
+            return Ok(summary);
+        };
+        let commit = repository.head()?.peel_to_commit()?.id();
+        config.events.emit(RunEvent::Committed {
+            repo: repo.name.clone(),
+            commit: commit.to_string(),
+        });
+        let published = publish(repo, repository, &branch, &message, lease)
+            .instrument(tracing::info_span!("push", attempt))
+            .await;
+        config.metrics.pushed(published.is_ok());
+        match published {
+            Ok(()) => {
+                config.events.emit(RunEvent::Pushed {
+                    repo: repo.name.clone(),
+                    commit: commit.to_string(),
+                });
+                let versions = summary
+                    .changes
+                    .iter()
+                    .filter_map(|change| match change {
+                        Change::Tag {
+                            app_name,
+                            image,
+                            new_tag,
+                            ..
+                        } => Some(CandidateVersion {
+                            app: app_name.clone(),
+                            image: image.clone(),
+                            current: new_tag.clone(),
+                            update_available: Some(false),
+                        }),
+                        Change::Pruned { .. } => None,
+                    })
+                    .collect();
+                config
+                    .metrics
+                    .candidate_versions(&repo.name, versions, false);
+                summary.commit = Some(commit);
+                return Ok(summary);
+            }
+            Err(e) if git::is_non_fast_forward(&e) => {
+                log::warn!(
+                    "The remote moved while updating ({}/{}), retrying on top of it: {:#}",
+                    attempt,
+                    git::PUSH_ATTEMPTS,
+                    e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(git::RemoteKeptMoving {
+        attempts: git::PUSH_ATTEMPTS,
+    }
+    .into())
+}
+
+/// Commits the changes, together or one app at a time. Returns the message
+/// describing all of them, along with the commit being replaced when amending,
+/// or nothing when there was nothing to commit.
+fn commit_changes(
+    config: &Config,
+    gitops_repo: &RepoConfig,
+    repo: &Repository,
+    changes: &[Change],
+) -> Result<Option<(String, Option<Oid>)>> {
+    let template = config.commit_message_template.as_deref();
+    if config.commit_granularity == CommitGranularity::PerApp {
+        let mut by_app = BTreeMap::<&str, Vec<Change>>::new();
+        for change in changes {
+            by_app
+                .entry(change.app_name())
+                .or_default()
+                .push(change.clone());
+        }
+
+        // Each commit stacks on top of the previous one, HEAD moving along
+        let mut committed = false;
+        for (app_name, changes) in by_app {
+            committed |= git::add_and_commit(
+                repo,
+                &config.commit_identity,
+                config.commit_signer.as_ref(),
+                &changes,
+                None,
+                &git::commit_message(template, Some(app_name), &changes),
+                &config.commit_trailers,
+            )?;
+        }
+
+        return Ok(committed.then(|| (git::commit_message(template, None, changes), None)));
+    }
+
+    let amend = match config.commit_mode {
+        CommitMode::Amend if gitops_repo.pull_requests.is_none() => {
+            Amend::find(repo, &config.commit_identity, template)?
+        }
+        _ => None,
+    };
+    let message = match &amend {
+        Some(amend) => git::commit_message(template, None, &amend.merge(changes)),
+        None => git::commit_message(template, None, changes),
+    };
+    if !git::add_and_commit(
+        repo,
+        &config.commit_identity,
+        config.commit_signer.as_ref(),
+        changes,
+        amend.as_ref(),
+        &message,
+        &config.commit_trailers,
+    )? {
+        return Ok(None);
+    }
+
+    Ok(Some((message, amend.map(|amend| amend.commit))))
+}
+
+/// Writes the selected tags and prunes stale parameters, returning what
+/// changed.
+fn apply_updates(
+    config: &Config,
+    repo: &RepoConfig,
+    selected: &[(Candidate, String)],
+    managed_parameters: &BTreeMap<(String, String), HashSet<String>>,
+    tag_cache: Option<&TagCache>,
+    summary: &mut UpdateSummary,
+) -> Result<Vec<Change>> {
+    let mut changes = vec![];
+    for (candidate, tag) in selected {
+        let _span =
+            logging::candidate_span(&candidate.app_name, split_tag(&candidate.url).0).entered();
+        if candidate.paused {
+            let reason = format!("Paused, would select {}", tag);
+            record_skipped(config, summary, candidate, reason);
+            continue;
+        }
+
+        let span = tracing::info_span!(
+            "write_back",
+            app = candidate.app_name.as_str(),
+            image = split_tag(&candidate.url).0,
+            tag = tag.as_str(),
+            change = tracing::field::Empty,
+        );
+        let result = span.in_scope(|| update_tag_for_candidate(&repo.checkout, candidate, tag));
+        span.record("change", matches!(result, Ok(Some(_))));
+        match result {
+            Ok(Some(change)) => {
+                if let Some(tag_cache) = tag_cache {
+                    tag_cache.invalidate(&candidate.image);
+                }
+                if let Change::Tag {
+                    app_name,
+                    image,
+                    old_tag,
+                    new_tag,
+                    ..
+                } = &change
+                {
+                    config.events.emit(RunEvent::Updated {
+                        app: app_name.clone(),
+                        image: image.clone(),
+                        old: old_tag.clone(),
+                        new: new_tag.clone(),
+                    });
+                }
+                summary.updated.push(candidate.app_name.clone());
+                changes.push(change);
+            }
+            Ok(None) => {
+                let skipped = Unchanged {
+                    app_name: candidate.app_name.clone(),
+                    image: split_tag(&candidate.url).0.to_string(),
+                    reason: format!("Already at {}", tag),
+                };
+                config.events.emit(RunEvent::Skipped {
+                    app: skipped.app_name.clone(),
+                    image: skipped.image.clone(),
+                    reason: skipped.reason.clone(),
+                });
+                summary.skipped.push(skipped);
+            }
+            Err(e) => record_failure(
+                config,
+                summary,
+                candidate.app_name.clone(),
+                Some(candidate),
+                e,
+            )?,
+        }
+    }
+
+    for ((app_name, path), names) in managed_parameters {
+        match prune_parameters(&repo.checkout, app_name, path, names) {
+            Ok(pruned) if pruned.is_empty() => {}
+            Ok(pruned) => {
+                summary.updated.push(app_name.clone());
+                changes.extend(pruned);
+            }
+            Err(e) => record_failure(
+                config,
+                summary,
+                app_name.clone(),
+                None,
+                e.context("Failed to prune parameters"),
+            )?,
+        }
+    }
+
+    summary.changes.clone_from(&changes);
+
+    Ok(changes)
+}
+
+/// Drops the selected tags whose manifest is missing from the registry, like
+/// when it got garbage-collected after listing the tags. Only the tags that
+/// would be written get checked.
+async fn verify_manifests(
+    config: &Config,
+    repo: &RepoConfig,
+    manifests: &ManifestChecks,
+    selected: Vec<(Candidate, String)>,
+    summary: &mut UpdateSummary,
+) -> Result<Vec<(Candidate, String)>> {
+    let mut verified = vec![];
+    for (candidate, tag) in selected {
+        let current = writeback::current_tag(&repo.checkout, &candidate)
+            .ok()
+            .flatten();
+        if candidate.paused || !writeback::would_change(&candidate, current.as_deref(), &tag) {
+            verified.push((candidate, tag));
+            continue;
+        }
+
+        let span = logging::candidate_span(&candidate.app_name, split_tag(&candidate.url).0);
+        let exists = manifests
+            .exists(config, &candidate, &tag)
+            .instrument(span.clone())
+            .await;
+        let _span = span.entered();
+        match exists {
+            Ok(true) => verified.push((candidate, tag)),
+            Ok(false) => {
+                log::warn!("The manifest of {}:{} is missing", candidate.image, tag);
+                let reason = format!("Manifest missing for {}", tag);
+                record_skipped(config, summary, &candidate, reason);
+            }
+            Err(e) => record_failure(
+                config,
+                summary,
+                candidate.app_name.clone(),
+                Some(&candidate),
+                e.context(format!("Couldn't verify the manifest of {}", tag)),
+            )?,
+        }
+    }
+
+    Ok(verified)
+}
+
+/// Drops the selected tags that aren't signed like their `require-signature`
+/// wants. Only the tags that would be written get checked.
+async fn verify_signatures(
+    config: &Config,
+    repo: &RepoConfig,
+    manifests: &ManifestChecks,
+    selected: Vec<(Candidate, String)>,
+    summary: &mut UpdateSummary,
+) -> Result<Vec<(Candidate, String)>> {
+    let mut verified = vec![];
+    for (candidate, tag) in selected {
+        let Some(requirement) = &candidate.require_signature else {
+            verified.push((candidate, tag));
+            continue;
+        };
+        let current = writeback::current_tag(&repo.checkout, &candidate)
+            .ok()
+            .flatten();
+        if candidate.paused || !writeback::would_change(&candidate, current.as_deref(), &tag) {
+            verified.push((candidate, tag));
+            continue;
+        }
+
+        let span = logging::candidate_span(&candidate.app_name, split_tag(&candidate.url).0);
+        let unsigned = manifests
+            .unsigned(config, &candidate, &tag, requirement)
+            .instrument(span.clone())
+            .await;
+        let _span = span.entered();
+        match unsigned {
+            Ok(None) => verified.push((candidate, tag)),
+            Ok(Some(reason)) => {
+                log::warn!("{}:{} isn't signed: {}", candidate.image, tag, reason);
+                let reason = format!("Unsigned {}: {}", tag, reason);
+                record_skipped(config, summary, &candidate, reason);
+            }
+            Err(e) => record_failure(
+                config,
+                summary,
+                candidate.app_name.clone(),
+                Some(&candidate),
+                e.context(format!("Couldn't verify the signature of {}", tag)),
+            )?,
+        }
+    }
+
+    Ok(verified)
+}
+
+/// Adds a candidate that's left alone to the summary.
+fn record_skipped(
+    config: &Config,
+    summary: &mut UpdateSummary,
+    candidate: &Candidate,
+    reason: String,
+) {
+    let skipped = Unchanged {
+        app_name: candidate.app_name.clone(),
+        image: split_tag(&candidate.url).0.to_string(),
+        reason,
+    };
+    log::info!("Not updating {}: {}", skipped.app_name, skipped.reason);
+    config.events.emit(RunEvent::Skipped {
+        app: skipped.app_name.clone(),
+        image: skipped.image.clone(),
+        reason: skipped.reason.clone(),
+    });
+    summary.skipped.push(skipped);
+}
+
+/// Adds a failure to the summary, or returns it when failing fast.
+#[cfg_attr(not(feature = "sentry"), allow(unused_variables))]
+fn record_failure(
+    config: &Config,
+    summary: &mut UpdateSummary,
+    app_name: String,
+    candidate: Option<&Candidate>,
+    e: anyhow::Error,
+) -> Result<()> {
+    if config.fail_fast {
+        return Err(e.context(app_name));
+    }
+
+    log::warn!("Failed to update {}: {:#}", app_name, e);
+    #[cfg(feature = "sentry")]
+    reporting::candidate_failure(
+        &app_name,
+        candidate.map(|candidate| split_tag(&candidate.url).0),
+        candidate
+            .and_then(|candidate| candidate.registry_host().ok())
+            .as_deref(),
+        &e,
+    );
+    config.events.emit(RunEvent::Failed {
+        app: app_name.clone(),
+        error: format!("{:#}", e),
+    });
+    if e.is::<RegistryTimeout>() {
+        summary.timed_out += 1;
+    }
+    if e.is::<RateLimited>() {
+        summary.rate_limited += 1;
+    }
+    if e.is::<RegistryUnavailable>() {
+        summary.unavailable += 1;
+    }
+    summary.failed.push((app_name, e));
+
+    Ok(())
+}
+
+/// Pushes the commit to the branch, or through a pull request. Takes the
+/// repository by value as it isn't `Sync` and can't be borrowed across awaits.
+/// An amended commit is force-pushed, as long as the branch still points to
+/// `lease`.
+async fn publish(
+    gitops_repo: &RepoConfig,
+    repo: Repository,
+    branch: &str,
+    message: &str,
+    lease: Option<Oid>,
+) -> Result<()> {
+    let credentials = &gitops_repo.git_credentials;
+    let host_keys = &gitops_repo.host_key_check;
+    let Some(pull_requests) = &gitops_repo.pull_requests else {
+        let refspec = match lease {
+            Some(_) => format!("+refs/heads/{}", branch),
+            None => format!("refs/heads/{}", branch),
+        };
+        return git::push(&repo, credentials, host_keys, &refspec, lease);
+    };
+
+    // Reuse the pull request of a previous run that's still open
+    let head_commit = repo.head()?.peel_to_commit()?.id();
+    drop(repo);
+    let existing = pull_requests.find_open(branch).await?;
+    let head = match &existing {
+        Some(pull) => pull.head.branch.clone(),
+        None => github::branch_name(head_commit),
+    };
+    git::push(
+        &Repository::open(&gitops_repo.checkout)?,
+        credentials,
+        host_keys,
+        &format!("+refs/heads/{}:refs/heads/{}", branch, head),
+        None,
+    )?;
+
+    match existing {
+        Some(pull) => {
+            pull_requests.update(&pull, message).await?;
+            log::info!("Updated pull request {}", pull.html_url);
+        }
+        None => {
+            let pull = pull_requests.create(branch, &head, message).await?;
+            log::info!("Opened pull request {}", pull.html_url);
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies a resolution error for every candidate sharing the same lookup,
+/// keeping the error types the summary cares about.
+fn duplicate_error(e: &anyhow::Error) -> anyhow::Error {
+    if let Some(timeout) = e.downcast_ref::<RegistryTimeout>() {
+        return timeout.clone().into();
+    }
+    if let Some(rate_limited) = e.downcast_ref::<RateLimited>() {
+        return rate_limited.clone().into();
+    }
+    if let Some(unavailable) = e.downcast_ref::<RegistryUnavailable>() {
+        return unavailable.clone().into();
+    }
+    if let Some(soaking) = e.downcast_ref::<Soaking>() {
+        return soaking.clone().into();
+    }
+    if let Some(missing) = e.downcast_ref::<MissingPlatforms>() {
+        return missing.clone().into();
+    }
+
+    anyhow::anyhow!("{:#}", e)
+}
+
+async fn resolve_tag(
+    config: &Config,
+    candidate: &Candidate,
+    tag_cache: Option<&TagCache>,
+    rate_limits: &RateLimits,
+    platforms: &PlatformChecks,
+) -> Result<Selection> {
+    let host = candidate.registry_host()?;
+    if rate_limits.is_limited(&host) {
+        return Err(RateLimited { host }.into());
+    }
+
+    let auth = select_auth(config, candidate).await?;
+    config.circuits.admit(&host, Instant::now())?;
+    let result = get_latest_tag_for_candidate(config, candidate, &auth, tag_cache, platforms).await;
+
+    // ECR tokens can be revoked before they expire, get a fresh one and retry once
+    #[cfg(feature = "ecr")]
+    let result = match result {
+        Err(e) if registry::is_unauthorized(&e) && config.ecr_tokens.invalidate(&host) => {
+            log::info!("ECR token for {} was rejected, refreshing it", host);
+            let auth = select_auth(config, candidate).await?;
+            get_latest_tag_for_candidate(config, candidate, &auth, tag_cache, platforms).await
+        }
+        result => result,
+    };
+
+    let available = result.as_ref().err().is_none_or(|e| !is_unavailable(e));
+    let open = config.circuits.record(&host, available, Instant::now());
+    config.metrics.circuit(&host, open);
+
+    match result {
+        Err(e) if is_rate_limited(&e) => {
+            log::warn!(
+                "Rate limited by {}, skipping its candidates for this run",
+                host
+            );
+            rate_limits.mark(&host);
+            Err(RateLimited { host }.into())
+        }
+        result => result,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    app_name: String,
+    /// The image as the `image-list` names it, which is what gets written.
+    url: String,
+    /// The one its tags are listed for, see `normalize_image`.
+    image: String,
+    allow_tags: String,
+    /// `allow_tags` compiled, once when the candidate is found.
+    tag_filter: TagFilter,
+    /// Whether `tag_filter` only matches whole tags.
+    anchor_tags: bool,
+    /// How the `calver` strategy reads the tags, from `tag-date-format`.
+    tag_date_format: String,
+    /// Whether the tags can be semver prereleases, with `allow-prerelease`.
+    allow_prerelease: bool,
+    /// How long ago a tag has to have been built to be selected, with
+    /// `min-age`.
+    min_age: Option<Duration>,
+    /// What the selected tag has to be built for, with `platforms`.
+    platforms: Vec<Platform>,
+    target: WriteTarget,
+    path: String,
+    strategy: UpdateStrategy,
+    pinned_tag: Option<String>,
+    ignore_tags: Vec<IgnoredTag>,
+    allow_downgrade: bool,
+    pull_secret: Option<PullSecret>,
+    /// Who has to have signed the selected tag for it to be written, with
+    /// `require-signature`.
+    require_signature: Option<SignatureRequirement>,
+    write_back: WriteBackTarget,
+    prune_parameters: bool,
+    /// Still resolved and reported, but never written, with the `pause`
+    /// annotation of the app or the image.
+    paused: bool,
+    /// The file the candidate was found in, relative to the root of the
+    /// repository.
+    manifest: PathBuf,
+}
+
+/// Everything that influences which tag gets selected for a candidate.
+#[derive(PartialEq, Eq, Hash)]
+struct LookupKey {
+    url: String,
+    allow_tags: String,
+    anchor_tags: bool,
+    strategy: UpdateStrategy,
+    tag_date_format: String,
+    allow_prerelease: bool,
+    min_age: Option<Duration>,
+    platforms: Vec<Platform>,
+    pinned_tag: Option<String>,
+    ignore_tags: Vec<String>,
+    pull_secret: Option<PullSecret>,
+}
+
+impl Candidate {
+    fn registry_host(&self) -> Result<String> {
+        Ok(Reference::from_str(&self.image)?.registry().to_string())
+    }
+
+    fn lookup_key(&self) -> LookupKey {
+        LookupKey {
+            url: self.image.clone(),
+            allow_tags: self.allow_tags.clone(),
+            anchor_tags: self.anchor_tags,
+            strategy: self.strategy,
+            tag_date_format: self.tag_date_format.clone(),
+            allow_prerelease: self.allow_prerelease,
+            min_age: self.min_age,
+            platforms: self.platforms.clone(),
+            pinned_tag: self.pinned_tag.clone(),
+            ignore_tags: self
+                .ignore_tags
+                .iter()
+                .map(|ignored| ignored.to_string())
+                .collect(),
+            pull_secret: self.pull_secret.clone(),
+        }
+    }
+}
+
+/// The candidates found in the checkout, and the apps or images that were
+/// skipped along the way.
+#[derive(Clone, Default)]
+struct Discovery {
+    candidates: Vec<Candidate>,
+    skipped: Vec<Skipped>,
+}
+
+/// An Application, or one of its images, that isn't a candidate.
+#[derive(Clone, Debug)]
+struct Skipped {
+    manifest: PathBuf,
+    app_name: Option<String>,
+    image: Option<String>,
+    reason: SkipReason,
+}
+
+/// Why something found in the checkout isn't a candidate.
+#[derive(Clone, Debug)]
+enum SkipReason {
+    /// The whole file, that couldn't be read.
+    Unreadable(String),
+    TooLarge {
+        size: u64,
+    },
+    /// A document that isn't valid YAML, with the error.
+    Unparsable(String),
+    /// A document that's something else, of this `kind` when it has one.
+    NotAnApplication {
+        kind: Option<String>,
+    },
+    MissingMetadata,
+    MissingAnnotations,
+    MissingImageList,
+    MissingName,
+    MissingSpec,
+    /// There's no source to write to, or it has no `path`.
+    MissingPath(String),
+    MissingAllowTags {
+        alias: String,
+    },
+    MissingHelmImageTag {
+        alias: String,
+    },
+    InvalidAnnotation {
+        annotation: String,
+        error: String,
+    },
+    /// An entry of `image-list` that doesn't name an image.
+    InvalidImage(String),
+    /// A generated app still having parameters in its name or path.
+    UnsubstitutedParameters,
+    /// An ApplicationSet that can't be expanded, or only partly.
+    UnsupportedApplicationSet(String),
+    InvalidChartUpdate(String),
+}
+
+impl SkipReason {
+    fn invalid(annotation: &str, error: impl std::fmt::Display) -> Self {
+        Self::InvalidAnnotation {
+            annotation: annotation.to_string(),
+            error: format!("{:#}", error),
+        }
+    }
+
+    /// The variant, for the API.
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Unreadable(_) => "unreadable",
+            Self::TooLarge { .. } => "too_large",
+            Self::Unparsable(_) => "unparsable",
+            Self::NotAnApplication { .. } => "not_an_application",
+            Self::MissingMetadata => "missing_metadata",
+            Self::MissingAnnotations => "missing_annotations",
+            Self::MissingImageList => "missing_image_list",
+            Self::MissingName => "missing_name",
+            Self::MissingSpec => "missing_spec",
+            Self::MissingPath(_) => "missing_path",
+            Self::MissingAllowTags { .. } => "missing_allow_tags",
+            Self::MissingHelmImageTag { .. } => "missing_helm_image_tag",
+            Self::InvalidAnnotation { .. } => "invalid_annotation",
+            Self::InvalidImage(_) => "invalid_image",
+            Self::UnsubstitutedParameters => "unsubstituted_parameters",
+            Self::UnsupportedApplicationSet(_) => "unsupported_application_set",
+            Self::InvalidChartUpdate(_) => "invalid_chart_update",
+        }
+    }
+
+    /// Whether it's a mistake to fix rather than an app that isn't managed at
+    /// all, the ones worth a warning on every run.
+    fn is_actionable(&self) -> bool {
+        !matches!(
+            self,
+            Self::NotAnApplication { .. }
+                | Self::MissingMetadata
+                | Self::MissingAnnotations
+                | Self::MissingImageList
+        )
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreadable(e) => write!(f, "Couldn't read it: {}", e),
+            Self::TooLarge { size } => write!(
+                f,
+                "It's {} bytes, more than the {} manifests can be",
+                size, MAX_MANIFEST_SIZE
+            ),
+            Self::Unparsable(e) => write!(f, "It isn't valid YAML: {}", e),
+            Self::NotAnApplication { kind: Some(kind) } => {
+                write!(f, "It's a {}, not an Application", kind)
+            }
+            Self::NotAnApplication { kind: None } => write!(f, "It isn't an Application"),
+            Self::MissingMetadata => write!(f, "No `metadata`"),
+            Self::MissingAnnotations => write!(f, "No annotations"),
+            Self::MissingImageList => write!(f, "No `image-list` annotation"),
+            Self::MissingName => write!(f, "No `metadata.name`"),
+            Self::MissingSpec => write!(f, "No `spec`"),
+            Self::MissingAllowTags { alias } => write!(f, "No `{}.allow-tags`", alias),
+            Self::MissingHelmImageTag { alias } => write!(
+                f,
+                "No `{}.helm.image-tag` or `{}.kustomize.image-name`",
+                alias, alias
+            ),
+            Self::InvalidAnnotation { annotation, error } => {
+                write!(f, "Invalid `{}`: {}", annotation, error)
+            }
+            Self::UnsubstitutedParameters => write!(
+                f,
+                "Its name or path has parameters that can't be substituted"
+            ),
+            Self::MissingPath(reason)
+            | Self::InvalidImage(reason)
+            | Self::UnsupportedApplicationSet(reason)
+            | Self::InvalidChartUpdate(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Discovery {
+    /// Records why something isn't a candidate. Only the actionable reasons
+    /// get a warning, the apps that aren't managed at all aren't worth one on
+    /// every run.
+    fn skip(
+        &mut self,
+        manifest: &Path,
+        app_name: Option<&str>,
+        image: Option<&str>,
+        reason: SkipReason,
+    ) {
+        let what = match (app_name, image) {
+            (Some(app_name), Some(image)) => format!("image {} of app {}", image, app_name),
+            (Some(app_name), None) => format!("app {}", app_name),
+            _ => "an app".to_string(),
+        };
+        let level = match reason.is_actionable() {
+            true => log::Level::Warn,
+            false => log::Level::Debug,
+        };
+        log::log!(
+            level,
+            "Ignoring {} in {}: {}",
+            what,
+            manifest.display(),
+            reason
+        );
+
+        self.skipped.push(Skipped {
+            manifest: manifest.to_path_buf(),
+            app_name: app_name.map(str::to_string),
+            image: image.map(str::to_string),
+            reason,
+        });
+    }
+
+    /// Records why a whole file isn't looked at.
+    fn skip_file(&mut self, manifest: &Path, reason: SkipReason) {
+        log::warn!("Ignoring {}: {}", manifest.display(), reason);
+
+        self.skipped.push(Skipped {
+            manifest: manifest.to_path_buf(),
+            app_name: None,
+            image: None,
+            reason,
+        });
+    }
+}
+
+/// Finds the candidates in the checkout, only reading the files that changed
+/// since the cached discovery when there's one.
+fn discover(config: &Config, repo: &RepoConfig) -> Result<Discovery> {
+    let head = Repository::open(&repo.checkout)
+        .and_then(|repository| Ok(repository.head()?.peel_to_commit()?.id()));
+    let cache = config.discovery_cache.as_ref();
+    let cached = cache.and_then(|cache| cache.get(&repo.name));
+
+    let files = match (cached, &head) {
+        (Some((cached_head, files)), Ok(head)) if cached_head == *head => {
+            log::info!("{} is still at {}, reusing its candidates", repo.name, head);
+            files
+        }
+        (Some((cached_head, files)), Ok(head)) => {
+            match rediscover(config, repo, cached_head, *head, files) {
+                Ok(files) => files,
+                Err(e) => {
+                    log::warn!(
+                        "Couldn't tell what changed in {} since {}, reading everything: {:#}",
+                        repo.name,
+                        cached_head,
+                        e
+                    );
+                    discover_files(config, repo)?
+                }
+            }
+        }
+        _ => discover_files(config, repo)?,
+    };
+    if let (Some(cache), Ok(head)) = (cache, head) {
+        cache.insert(&repo.name, head, files.clone());
+    }
+
+    let mut discovery = Discovery::default();
+    for from_file in files.into_values() {
+        discovery.candidates.extend(from_file.candidates);
+        discovery.skipped.extend(from_file.skipped);
+    }
+
+    Ok(discovery)
+}
+
+/// Reads the files `old` and `new` differ in again, on top of what `files`
+/// held at `old`.
+fn rediscover(
+    config: &Config,
+    repo: &RepoConfig,
+    old: Oid,
+    new: Oid,
+    mut files: DiscoveredFiles,
+) -> Result<DiscoveredFiles> {
+    let repository = Repository::open(&repo.checkout)?;
+    let old_tree = repository.find_commit(old)?.tree()?;
+    let new_tree = repository.find_commit(new)?.tree()?;
+    let diff = repository.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+    let mut changed = BTreeSet::new();
+    for delta in diff.deltas() {
+        changed.extend(delta.old_file().path().map(Path::to_path_buf));
+        changed.extend(delta.new_file().path().map(Path::to_path_buf));
+    }
+    log::info!(
+        "{} moved from {} to {}, reading the {} files that changed",
+        repo.name,
+        old,
+        new,
+        changed.len()
+    );
+
+    let mut to_read = vec![];
+    for path in changed {
+        files.remove(&path);
+        let is_file = std::fs::symlink_metadata(repo.checkout.join(&path))
+            .is_ok_and(|metadata| metadata.is_file());
+        if is_file && is_scanned(repo, &path) {
+            to_read.push(path);
+        }
+    }
+    files.extend(read_files(config, repo, to_read)?);
+
+    Ok(files)
+}
+
+/// Whether walking the checkout reads the file at `path`, relative to its
+/// root.
+fn is_scanned(repo: &RepoConfig, path: &Path) -> bool {
+    let root = Path::new(repo.path.as_deref().unwrap_or_default().trim_matches('/'));
+    let Ok(below_root) = path.strip_prefix(root) else {
+        return false;
+    };
+    let is_yaml = path
+        .extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml");
+
+    is_yaml
+        && repo.scan.matches(path)
+        && below_root
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .all(|ancestor| repo.scan.walks(&root.join(ancestor)))
+}
+
+/// Looks at the manifests of the repository's checkout, only the ones under its
+/// path when it has one.
+fn discover_files(config: &Config, repo: &RepoConfig) -> Result<DiscoveredFiles> {
+    log::info!("Extracting candidates from {}", repo.name);
+    let mut to_read = vec![];
+    let repo_path = &repo.checkout;
+    let root = match &repo.path {
+        Some(path) => repo_path.join(path.trim_start_matches('/')),
+        None => repo_path.clone(),
+    };
+
+    let relative = |path: &Path| path.strip_prefix(repo_path).unwrap_or(path).to_path_buf();
+    let entries = WalkDir::new(root).into_iter().filter_entry(|entry| {
+        entry.depth() == 0
+            || !entry.file_type().is_dir()
+            || repo.scan.walks(&relative(entry.path()))
+    });
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let Some(file_name) = entry.file_name().to_str() else {
+            continue;
+        };
+
+        if !file_name.ends_with(".yaml") && !file_name.ends_with(".yml") {
+            continue;
+        }
+        if !repo.scan.matches(&relative(entry.path())) {
+            log::trace!("Not scanning {:?}", entry.path());
+            continue;
+        }
+
+        to_read.push(relative(entry.path()));
+    }
+
+    read_files(config, repo, to_read)
+}
+
+/// Reads the files at `paths` in the checkout, `DISCOVERY_PARALLELISM` at a
+/// time. When several can't be read, the error is about the first of them.
+fn read_files(config: &Config, repo: &RepoConfig, paths: Vec<PathBuf>) -> Result<DiscoveredFiles> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.discovery_parallelism)
+        .build()?;
+    let read = pool.install(|| {
+        paths
+            .into_par_iter()
+            .map(|path| {
+                let from_file = get_candidates_from(
+                    &repo.checkout,
+                    &repo.checkout.join(&path),
+                    repo.anchor_tag_regex,
+                );
+                (path, from_file)
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut files = BTreeMap::new();
+    for (path, from_file) in read {
+        let from_file = from_file.with_context(|| format!("Couldn't read {}", path.display()))?;
+        files.insert(path, from_file);
+    }
+
+    Ok(files)
+}
+
+/// Rendered manifests can get huge, and they're read whole.
+const MAX_MANIFEST_SIZE: u64 = 2 * 1024 * 1024;
+
+fn get_candidates_from(
+    repo_path: &Path,
+    file_path: &Path,
+    anchor_tag_regex: bool,
+) -> Result<Discovery> {
+    log::trace!("Looking at {:?}", file_path);
+    let manifest = file_path.strip_prefix(repo_path).unwrap_or(file_path);
+    let mut discovery = Discovery::default();
+
+    let read = match std::fs::metadata(file_path) {
+        Ok(metadata) if metadata.len() > MAX_MANIFEST_SIZE => Err(SkipReason::TooLarge {
+            size: metadata.len(),
+        }),
+        _ => std::fs::read_to_string(file_path).map_err(|e| SkipReason::Unreadable(e.to_string())),
+    };
+    let content = match read {
+        Ok(content) => content,
+        Err(reason) => {
+            discovery.skip_file(manifest, reason);
+            return Ok(discovery);
+        }
+    };
+
+    // Split on the separators rather than parsing the whole stream, so that a
+    // document that isn't valid YAML doesn't hide the ones after it
+    let documents = yaml_split::DocumentIterator::new(content.as_bytes());
+    for document in documents {
+        let parsed = match serde_yaml::from_str::<Value>(&document?) {
+            // Nothing but comments, or the empty document of a lone `---`
+            Ok(Value::Null) => continue,
+            Ok(parsed) => parsed,
+            Err(e) => {
+                discovery.skip(manifest, None, None, SkipReason::Unparsable(e.to_string()));
+                continue;
+            }
+        };
+        let kind = parsed
+            .get("kind")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let Ok(parsed) = serde_yaml::from_value::<HashMap<String, Value>>(parsed) else {
+            discovery.skip(manifest, None, None, SkipReason::NotAnApplication { kind });
+            continue;
+        };
+
+        let apps = if is_argo_app(&parsed) {
+            vec![(parsed, false)]
+        } else if application_set::is_application_set(&parsed) {
+            let set_name = parsed
+                .get("metadata")
+                .and_then(|metadata| metadata.get("name"))
+                .and_then(Value::as_str);
+            match application_set::expand(&parsed) {
+                Ok(expansion) => {
+                    for reason in expansion.unexpanded {
+                        let reason = SkipReason::UnsupportedApplicationSet(reason);
+                        discovery.skip(manifest, set_name, None, reason);
+                    }
+                    expansion.apps.into_iter().map(|app| (app, true)).collect()
+                }
+                Err(reason) => {
+                    let reason = SkipReason::UnsupportedApplicationSet(reason);
+                    discovery.skip(manifest, set_name, None, reason);
+                    continue;
+                }
+            }
+        } else {
+            discovery.skip(manifest, None, None, SkipReason::NotAnApplication { kind });
+            continue;
+        };
+
+        // The apps an ApplicationSet generates are only in the repository as
+        // its template
+        for (parsed, generated) in apps {
+            let Some(metadata) = parsed.get("metadata").and_then(Value::as_mapping) else {
+                discovery.skip(manifest, None, None, SkipReason::MissingMetadata);
+                continue;
+            };
+            let name = metadata.get("name").and_then(Value::as_str);
+            let Some(annotations) = metadata.get("annotations").and_then(Value::as_mapping) else {
+                discovery.skip(manifest, name, None, SkipReason::MissingAnnotations);
+                continue;
+            };
+            if annotations
+                .get("argocd-image-updater.argoproj.io/chart-update")
+                .and_then(Value::as_str)
+                == Some("true")
+            {
+                // Its `targetRevision` isn't in the manifest, only the template's
+                let candidate = match generated {
+                    true => {
+                        Err("Has `chart-update` but is generated by an ApplicationSet".to_string())
+                    }
+                    false => get_chart_candidate(manifest, &parsed, annotations, anchor_tag_regex),
+                };
+                match candidate {
+                    Ok(candidate) => discovery.candidates.push(candidate),
+                    Err(reason) => {
+                        let reason = SkipReason::InvalidChartUpdate(reason);
+                        discovery.skip(manifest, name, None, reason)
+                    }
+                }
+            }
+
+            let Some(image_list) = annotations
+                .get("argocd-image-updater.argoproj.io/image-list")
+                .and_then(Value::as_str)
+            else {
+                discovery.skip(manifest, name, None, SkipReason::MissingImageList);
+                continue;
+            };
+
+            let Some(app_name) = name else {
+                discovery.skip(manifest, None, None, SkipReason::MissingName);
+                continue;
+            };
+            let Some(spec) = parsed.get("spec").and_then(Value::as_mapping) else {
+                discovery.skip(manifest, name, None, SkipReason::MissingSpec);
+                continue;
+            };
+            let path = match source_path(spec, annotations, app_name) {
+                Ok(path) => path,
+                Err(reason) => {
+                    discovery.skip(manifest, name, None, SkipReason::MissingPath(reason));
+                    continue;
+                }
+            };
+            if generated && (app_name.contains("{{") || path.contains("{{")) {
+                let reason = SkipReason::UnsubstitutedParameters;
+                discovery.skip(manifest, name, None, reason);
+                continue;
+            }
+
+            let write_back = match annotations
+                .get("argocd-image-updater.argoproj.io/write-back-target")
+                .and_then(Value::as_str)
+                .map(WriteBackTarget::from_str)
+                .transpose()
+            {
+                Ok(write_back) => write_back.unwrap_or_default(),
+                Err(e) => {
+                    let reason = SkipReason::invalid("write-back-target", e);
+                    discovery.skip(manifest, name, None, reason);
+                    continue;
+                }
+            };
+
+            let prune_parameters = annotations
+                .get("argocd-image-updater.argoproj.io/prune-parameters")
+                .and_then(Value::as_str)
+                == Some("true");
+            let app_paused = is_paused(annotations);
+
+            let images = image_list.split(',');
+            for image in images {
+                let image = image.trim();
+                let (name, url) = match image.split_once('=') {
+                    Some((name, _)) if name.trim().is_empty() => {
+                        let reason = SkipReason::InvalidImage(format!(
+                            "`{}` in `image-list` has an empty alias",
+                            image
+                        ));
+                        discovery.skip(manifest, Some(app_name), None, reason);
+                        continue;
+                    }
+                    Some((name, url)) => (name.trim(), url.trim()),
+                    // The alias defaults to the name of the image, the way
+                    // argocd-image-updater does
+                    None => {
+                        let name = image.split_once('@').map_or(image, |(name, _)| name);
+                        let alias = split_tag(name).0.rsplit('/').next().unwrap_or_default();
+                        if alias.is_empty() {
+                            let reason = SkipReason::InvalidImage(format!(
+                                "`{}` in `image-list` has no alias and isn't an image to name one after",
+                                image
+                            ));
+                            discovery.skip(manifest, Some(app_name), None, reason);
+                            continue;
+                        }
+                        log::warn!(
+                            "{} in the `image-list` of app {} has no alias, using {}: its annotations are the `{}.` ones",
+                            image,
+                            app_name,
+                            alias,
+                            alias
+                        );
+                        (alias, image)
+                    }
+                };
+                let mut skip = |reason: SkipReason| {
+                    discovery.skip(manifest, Some(app_name), Some(name), reason)
+                };
+
+                let strategy = match get_image_annotation(annotations, name, "update-strategy")
+                    .map(UpdateStrategy::from_str)
+                    .transpose()
+                {
+                    Ok(strategy) => strategy.unwrap_or_default(),
+                    Err(e) => {
+                        skip(SkipReason::invalid("update-strategy", e));
+                        continue;
+                    }
+                };
+
+                let tag_date_format = get_image_annotation(annotations, name, "tag-date-format")
+                    .unwrap_or(DEFAULT_DATE_FORMAT);
+                if let Err(e) = validate_date_format(tag_date_format) {
+                    skip(SkipReason::invalid("tag-date-format", e));
+                    continue;
+                }
+
+                // The digest strategy tracks a single mutable tag, there's nothing to filter
+                let allow_tags = match get_image_annotation(annotations, name, "allow-tags") {
+                    Some(allow_tags) => allow_tags,
+                    None if strategy == UpdateStrategy::Digest => "",
+                    None => {
+                        skip(SkipReason::MissingAllowTags {
+                            alias: name.to_string(),
+                        });
+                        continue;
+                    }
+                };
+                let anchor_tags = match get_image_annotation(annotations, name, "anchor-tags") {
+                    Some(anchor_tags) => anchor_tags == "true",
+                    None => anchor_tag_regex,
+                };
+                let tag_filter = match TagFilter::parse(allow_tags, anchor_tags) {
+                    Ok(tag_filter) => tag_filter,
+                    Err(e) => {
+                        skip(SkipReason::invalid("allow-tags", e));
+                        continue;
+                    }
+                };
+                let target = match (
+                    get_image_annotation(annotations, name, "helm.image-tag"),
+                    get_image_annotation(annotations, name, "kustomize.image-name"),
+                ) {
+                    (Some(image_tag), _) => WriteTarget::Helm {
+                        image_tag: image_tag.to_string(),
+                        image_name: get_image_annotation(annotations, name, "helm.image-name")
+                            .map(str::to_string),
+                    },
+                    (None, Some(image_name)) => WriteTarget::Kustomize {
+                        image_name: image_name.to_string(),
+                    },
+                    (None, None) => {
+                        skip(SkipReason::MissingHelmImageTag {
+                            alias: name.to_string(),
+                        });
+                        continue;
+                    }
+                };
+
+                let ignore_tags = match get_image_annotation(annotations, name, "ignore-tags")
+                    .map(IgnoredTag::parse_list)
+                    .transpose()
+                {
+                    Ok(ignore_tags) => ignore_tags.unwrap_or_default(),
+                    Err(e) => {
+                        skip(SkipReason::invalid("ignore-tags", e));
+                        continue;
+                    }
+                };
+
+                let allow_downgrade =
+                    get_image_annotation(annotations, name, "allow-downgrade") == Some("true");
+                let allow_prerelease =
+                    get_image_annotation(annotations, name, "allow-prerelease") == Some("true");
+                let min_age = match get_image_annotation(annotations, name, "min-age")
+                    .map(config::parse_duration)
+                    .transpose()
+                {
+                    Ok(min_age) => min_age,
+                    Err(e) => {
+                        skip(SkipReason::invalid("min-age", e));
+                        continue;
+                    }
+                };
+                let platforms = match get_image_annotation(annotations, name, "platforms")
+                    .map(Platform::parse_list)
+                    .transpose()
+                {
+                    Ok(platforms) => platforms.unwrap_or_default(),
+                    Err(e) => {
+                        skip(SkipReason::invalid("platforms", e));
+                        continue;
+                    }
+                };
+                let paused =
+                    app_paused || get_image_annotation(annotations, name, "pause") == Some("true");
+
+                let pull_secret = match get_image_annotation(annotations, name, "pull-secret")
+                    .map(PullSecret::from_str)
+                    .transpose()
+                {
+                    Ok(pull_secret) => pull_secret,
+                    Err(e) => {
+                        skip(SkipReason::invalid("pull-secret", e));
+                        continue;
+                    }
+                };
+                let require_signature =
+                    match get_image_annotation(annotations, name, "require-signature")
+                        .map(|value| {
+                            let issuer =
+                                get_image_annotation(annotations, name, "signature-issuer");
+                            SignatureRequirement::parse(value, issuer)
+                        })
+                        .transpose()
+                    {
+                        Ok(require_signature) => require_signature,
+                        Err(e) => {
+                            skip(SkipReason::invalid("require-signature", e));
+                            continue;
+                        }
+                    };
+
+                // Only the digest strategy makes something of a tag, the one it tracks
+                let (url, digest) = match url.split_once('@') {
+                    Some((url, digest)) => (url, Some(digest)),
+                    None => (url, None),
+                };
+                let (url, tag) = split_tag(url);
+                let pinned_tag = match strategy {
+                    UpdateStrategy::Digest => Some(tag.unwrap_or("latest").to_string()),
+                    _ => None,
+                };
+                let ignored = match (digest, tag) {
+                    (Some(digest), _) => Some(digest),
+                    (None, Some(tag)) if pinned_tag.is_none() => Some(tag),
+                    _ => None,
+                };
+                match ignored {
+                    // argocd-image-updater reads what can't be a tag, like
+                    // `~1.0`, as a version constraint
+                    Some(constraint) if digest.is_none() && !is_tag(constraint) => log::warn!(
+                        "{} in the `image-list` of app {} has the constraint {}, which is discarded: only `allow-tags` picks its tags",
+                        image,
+                        app_name,
+                        constraint
+                    ),
+                    Some(ignored) => log::warn!(
+                        "{} in the `image-list` of app {} is pinned to {}, ignoring it",
+                        image,
+                        app_name,
+                        ignored
+                    ),
+                    None => {}
+                }
+                let image = match normalize_image(url) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        skip(SkipReason::InvalidImage(format!(
+                            "Invalid image `{}`: {}",
+                            url, e
+                        )));
+                        continue;
+                    }
+                };
+
+                discovery.candidates.push(Candidate {
+                    app_name: app_name.to_string(),
+                    url: url.to_string(),
+                    image,
+                    allow_tags: allow_tags.to_string(),
+                    tag_filter,
+                    anchor_tags,
+                    tag_date_format: tag_date_format.to_string(),
+                    allow_prerelease,
+                    min_age,
+                    platforms,
+                    target,
+                    path: path.to_string(),
+                    strategy,
+                    pinned_tag,
+                    ignore_tags,
+                    allow_downgrade,
+                    pull_secret,
+                    require_signature,
+                    write_back: write_back.clone(),
+                    prune_parameters,
+                    paused,
+                    manifest: manifest.to_path_buf(),
+                });
+            }
+        }
+    }
+
+    Ok(discovery)
+}
+
+/// Builds the candidate bumping the `targetRevision` of an app deploying a helm
+/// chart from an OCI registry, or says why it can't.
+fn get_chart_candidate(
+    manifest: &Path,
+    parsed: &HashMap<String, Value>,
+    annotations: &Mapping,
+    anchor_tags: bool,
+) -> Result<Candidate, String> {
+    let app_name = parsed
+        .get("metadata")
+        .and_then(|metadata| metadata.get("name"))
+        .and_then(Value::as_str)
+        .ok_or("No `metadata.name`")?;
+    let source = parsed
+        .get("spec")
+        .and_then(|spec| spec.get("source"))
+        .and_then(Value::as_mapping)
+        .ok_or("Has `chart-update` but no `spec.source`")?;
+    let (Some(repo_url), Some(chart)) = (
+        source.get("repoURL").and_then(Value::as_str),
+        source.get("chart").and_then(Value::as_str),
+    ) else {
+        return Err("Has `chart-update` but doesn't deploy a chart".to_string());
+    };
+
+    // OCI repositories don't have a scheme in ArgoCD, or an `oci://` one
+    let repo_url = repo_url.strip_prefix("oci://").unwrap_or(repo_url);
+    if repo_url.contains("://") {
+        return Err(format!(
+            "Deploys chart {} from {}, which isn't an OCI registry",
+            chart, repo_url
+        ));
+    }
+
+    let allow_tags = annotations
+        .get("argocd-image-updater.argoproj.io/chart-allow-tags")
+        .and_then(Value::as_str)
+        .ok_or("Has `chart-update` without `chart-allow-tags`")?;
+    let tag_filter = TagFilter::parse(allow_tags, anchor_tags)
+        .map_err(|e| format!("Invalid `chart-allow-tags`: {:#}", e))?;
+
+    let url = format!("{}/{}", repo_url.trim_end_matches('/'), chart);
+    Ok(Candidate {
+        app_name: app_name.to_string(),
+        image: url.clone(),
+        url,
+        allow_tags: allow_tags.to_string(),
+        tag_filter,
+        anchor_tags,
+        tag_date_format: DEFAULT_DATE_FORMAT.to_string(),
+        allow_prerelease: false,
+        min_age: None,
+        platforms: vec![],
+        target: WriteTarget::ChartRevision,
+        path: manifest
+            .parent()
+            .map(|parent| parent.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        strategy: UpdateStrategy::default(),
+        pinned_tag: None,
+        ignore_tags: vec![],
+        allow_downgrade: false,
+        pull_secret: None,
+        require_signature: None,
+        write_back: WriteBackTarget::default(),
+        prune_parameters: false,
+        paused: is_paused(annotations),
+        manifest: manifest.to_path_buf(),
+    })
+}
+
+/// Finds the path of the source the overrides should be written next to, either
+/// `spec.source` or one of the multiple `spec.sources`.
+fn source_path<'a>(
+    spec: &'a Mapping,
+    annotations: &Mapping,
+    app_name: &str,
+) -> Result<&'a str, String> {
+    if let Some(source) = spec.get("source").and_then(Value::as_mapping) {
+        return source
+            .get("path")
+            .and_then(Value::as_str)
+            .ok_or_else(|| "No `spec.source.path`".to_string());
+    }
+
+    let sources = spec
+        .get("sources")
+        .and_then(Value::as_sequence)
+        .ok_or("No `spec.source` or `spec.sources`")?
+        .iter()
+        .filter_map(Value::as_mapping)
+        .filter(|source| source.get("path").and_then(Value::as_str).is_some())
+        .collect::<Vec<_>>();
+
+    let selected = annotations
+        .get("argocd-image-updater.argoproj.io/write-back-source")
+        .and_then(Value::as_str);
+    let source = match selected {
+        // Sources don't have a name, match on their `ref` or their `path`
+        Some(selected) => sources
+            .iter()
+            .find(|source| {
+                ["ref", "path"]
+                    .iter()
+                    .any(|key| source.get(*key).and_then(Value::as_str) == Some(selected))
+            })
+            .ok_or_else(|| format!("No source matching `write-back-source` {}", selected))?,
+        None => {
+            if sources.len() > 1 {
+                log::warn!(
+                    "App {} has multiple sources with a path and no `write-back-source`, using the first one",
+                    app_name
+                );
+            }
+            sources.first().ok_or("No source with a `path`")?
+        }
+    };
+
+    Ok(source
+        .get("path")
+        .and_then(Value::as_str)
+        .unwrap_or_default())
+}
+
+/// Whether the app's updates are paused as a whole.
+fn is_paused(annotations: &Mapping) -> bool {
+    annotations
+        .get("argocd-image-updater.argoproj.io/pause")
+        .and_then(Value::as_str)
+        == Some("true")
+}
+
+fn get_image_annotation<'a>(annotations: &'a Mapping, alias: &str, key: &str) -> Option<&'a str> {
+    annotations
+        .get(format!(
+            "argocd-image-updater.argoproj.io/{}.{}",
+            alias, key
+        ))
+        .and_then(Value::as_str)
+}
+
+/// Splits `ghcr.io/org/app:tag` into `ghcr.io/org/app` and `tag`, making sure
+/// not to mistake a registry port for a tag.
+pub fn split_tag(url: &str) -> (&str, Option<&str>) {
+    let name_start = url.rfind('/').map(|idx| idx + 1).unwrap_or(0);
+    match url[name_start..].rfind(':') {
+        Some(idx) => (&url[..name_start + idx], Some(&url[name_start + idx + 1..])),
+        None => (url, None),
+    }
+}
+
+/// Whether `tag` is something a registry could hold as a tag.
+fn is_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 128
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        && !tag.starts_with(['.', '-'])
+}
+
+/// The name ArgoCD pulls an image by, without its tag or digest: `redis` and
+/// `library/redis` are `registry-1.docker.io/library/redis`, like the other
+/// names of Docker Hub.
+pub fn normalize_image(image: &str) -> Result<String> {
+    let reference = Reference::from_str(image)?;
+    let registry = registry::normalize_host(reference.registry());
+    let repository = reference.repository();
+
+    Ok(
+        match registry == "registry-1.docker.io" && !repository.contains('/') {
+            true => format!("{}/library/{}", registry, repository),
+            false => format!("{}/{}", registry, repository),
+        },
+    )
+}
+
+fn is_argo_app(value: &HashMap<String, Value>) -> bool {
+    let api_version = value.get("apiVersion").and_then(|v| v.as_str());
+    let kind = value.get("kind").and_then(|v| v.as_str());
+
+    kind == Some("Application") && api_version == Some("argoproj.io/v1alpha1")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{self, AtomicUsize},
+        Arc,
+    };
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn normalizes_images() {
+        let digest = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let cases = [
+            ("redis", "registry-1.docker.io/library/redis"),
+            ("redis:alpine", "registry-1.docker.io/library/redis"),
+            ("library/redis", "registry-1.docker.io/library/redis"),
+            ("docker.io/nginx", "registry-1.docker.io/library/nginx"),
+            (
+                "docker.io/library/nginx:1.27",
+                "registry-1.docker.io/library/nginx",
+            ),
+            (
+                "index.docker.io/library/nginx",
+                "registry-1.docker.io/library/nginx",
+            ),
+            (
+                "registry-1.docker.io/nginx",
+                "registry-1.docker.io/library/nginx",
+            ),
+            ("myorg/app", "registry-1.docker.io/myorg/app"),
+            ("ghcr.io/myorg/app:v1", "ghcr.io/myorg/app"),
+            ("host:5000/team/app", "host:5000/team/app"),
+            ("host:5000/team/app:1.0", "host:5000/team/app"),
+            ("localhost/app", "localhost/app"),
+            ("localhost:5000/app:latest", "localhost:5000/app"),
+            (
+                &format!("redis@{}", digest),
+                "registry-1.docker.io/library/redis",
+            ),
+            (
+                &format!("quay.io/team/app:1.0@{}", digest),
+                "quay.io/team/app",
+            ),
+        ];
+
+        for (image, normalized) in cases {
+            assert_eq!(normalize_image(image).unwrap(), normalized, "{}", image);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        assert!(normalize_image("Redis").is_err());
+        assert!(normalize_image("redis:").is_err());
+    }
+
+    #[test]
+    fn summarizes_the_failures() {
+        let summary = UpdateSummary {
+            candidates: 6,
+            updated: ["app-c", "app-d", "app-e", "app-f"]
+                .map(String::from)
+                .to_vec(),
+            failed: vec![
+                ("app-a".to_string(), anyhow!("no tags matched")),
+                ("app-b".to_string(), anyhow!("401 from ghcr.io")),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            summary.to_string(),
+            "updated 4, failed 2 (app-a: no tags matched, app-b: 401 from ghcr.io)"
+        );
+        assert_eq!(
+            summary.to_json("ops")["failed"],
+            serde_json::json!([
+                { "repo": "ops", "app": "app-a", "error": "no tags matched" },
+                { "repo": "ops", "app": "app-b", "error": "401 from ghcr.io" },
+            ])
+        );
+    }
+
+    #[test]
+    fn keeps_going_after_a_failure() {
+        let config = config::test_config();
+        let mut summary = UpdateSummary::default();
+
+        record_failure(
+            &config,
+            &mut summary,
+            "app-a".into(),
+            None,
+            anyhow!("no tags"),
+        )
+        .unwrap();
+        record_failure(&config, &mut summary, "app-b".into(), None, anyhow!("401")).unwrap();
+        assert_eq!(summary.failed.len(), 2);
+    }
+
+    #[test]
+    fn fails_fast() {
+        let mut config = config::test_config();
+        config.fail_fast = true;
+        let mut summary = UpdateSummary::default();
+
+        let e = record_failure(
+            &config,
+            &mut summary,
+            "app-a".into(),
+            None,
+            anyhow!("no tags"),
+        )
+        .unwrap_err();
+        assert_eq!(format!("{:#}", e), "app-a: no tags");
+        assert!(summary.failed.is_empty());
+    }
+
+    impl Candidate {
+        /// The `web` candidate of `url`, written to its helm `image.tag`, with
+        /// the defaults of all the other annotations.
+        pub(crate) fn test(url: &str, allow_tags: &str) -> Self {
+            Self {
+                app_name: "web".to_string(),
+                url: url.to_string(),
+                image: normalize_image(url).unwrap(),
+                allow_tags: allow_tags.to_string(),
+                tag_filter: TagFilter::parse(allow_tags, false).unwrap(),
+                anchor_tags: false,
+                tag_date_format: DEFAULT_DATE_FORMAT.to_string(),
+                allow_prerelease: false,
+                min_age: None,
+                platforms: vec![],
+                target: WriteTarget::Helm {
+                    image_tag: "image.tag".to_string(),
+                    image_name: None,
+                },
+                path: "apps/web".to_string(),
+                strategy: UpdateStrategy::Name,
+                pinned_tag: None,
+                ignore_tags: vec![],
+                allow_downgrade: false,
+                pull_secret: None,
+                require_signature: None,
+                write_back: WriteBackTarget::Overrides,
+                prune_parameters: false,
+                paused: false,
+                manifest: PathBuf::from("apps.yaml"),
+            }
+        }
+    }
+
+    /// An Application with a `web` image from ghcr.io written to its helm
+    /// `image.tag`, and the extra `annotations`.
+    fn application(annotations: &[(&str, &str)]) -> String {
+        app("web", "ghcr.io/org/web", annotations)
+    }
+
+    /// The Application `name` with a `web` alias for `image`, in `apps/<name>`.
+    fn app(name: &str, image: &str, annotations: &[(&str, &str)]) -> String {
+        let mut manifest = format!(
+            "\
+apiVersion: argoproj.io/v1alpha1
+kind: Application
+metadata:
+  name: {name}
+  annotations:
+    argocd-image-updater.argoproj.io/image-list: web={image}
+    argocd-image-updater.argoproj.io/web.helm.image-tag: image.tag
+"
+        );
+        for (key, value) in annotations {
+            manifest += &format!(
+                "    argocd-image-updater.argoproj.io/{}: '{}'\n",
+                key, value
+            );
+        }
+        manifest + &format!("spec:\n  source:\n    path: apps/{}\n", name)
+    }
+
+    /// Writes the manifests in a checkout and finds the candidates in
+    /// `apps.yaml`.
+    fn discover_in(files: &[(&str, &[u8])]) -> Discovery {
+        let checkout = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = checkout.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        get_candidates_from(checkout.path(), &checkout.path().join("apps.yaml"), true).unwrap()
+    }
+
+    fn discover_manifest(manifest: &str) -> Discovery {
+        discover_in(&[("apps.yaml", manifest.as_bytes())])
+    }
+
+    /// The Application `web` whose `image-list` is just `image`, named `app`.
+    fn aliasless_application(image: &str, annotations: &[(&str, &str)]) -> String {
+        application(annotations)
+            .replace(
+                "image-list: web=ghcr.io/org/web",
+                &format!("image-list: {}", image),
+            )
+            .replace("web.helm.image-tag", "app.helm.image-tag")
+    }
+
+    #[test]
+    fn names_the_aliasless_images_after_themselves() {
+        let discovery = discover_manifest(&aliasless_application(
+            "ghcr.io/org/app:1.0",
+            &[("app.allow-tags", "regexp:.*")],
+        ));
+        // Read from the `app.` annotations
+        assert_eq!(discovery.candidates[0].allow_tags, "regexp:.*");
+        assert_eq!(discovery.candidates[0].image, "ghcr.io/org/app");
+    }
+
+    #[test]
+    fn names_the_aliasless_images_after_themselves_without_their_digest() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let discovery = discover_manifest(&aliasless_application(
+            &format!("ghcr.io/org/app@{}", digest),
+            &[("app.allow-tags", "regexp:.*")],
+        ));
+        // Read from the `app.` annotations
+        assert_eq!(discovery.candidates[0].allow_tags, "regexp:.*");
+        assert_eq!(discovery.candidates[0].image, "ghcr.io/org/app");
+
+        let discovery = discover_manifest(&aliasless_application(
+            &format!("ghcr.io/org/app:1.0@{}", digest),
+            &[("app.allow-tags", "regexp:.*")],
+        ));
+        assert_eq!(discovery.candidates[0].allow_tags, "regexp:.*");
+        assert_eq!(discovery.candidates[0].url, "ghcr.io/org/app");
+    }
+
+    #[test]
+    fn tells_tags_from_constraints() {
+        assert!(is_tag("1.0"));
+        assert!(is_tag("1.25.3-alpine"));
+        assert!(is_tag("latest"));
+        assert!(!is_tag("~1.0"));
+        assert!(!is_tag("^1.2"));
+        assert!(!is_tag(">=1.0 <2.0"));
+        assert!(!is_tag(""));
+    }
+
+    #[test]
+    fn reads_the_update_strategy() {
+        let discovery = discover_manifest(&application(&[
+            ("web.allow-tags", "regexp:.*"),
+            ("web.update-strategy", "semver"),
+        ]));
+        assert_eq!(discovery.candidates[0].strategy, UpdateStrategy::Semver);
+
+        let discovery = discover_manifest(&application(&[("web.allow-tags", "regexp:.*")]));
+        assert_eq!(discovery.candidates[0].strategy, UpdateStrategy::Name);
+    }
+
+    #[test]
+    fn skips_unknown_update_strategies() {
+        let discovery = discover_manifest(&application(&[
+            ("web.allow-tags", "regexp:.*"),
+            ("web.update-strategy", "highest"),
+        ]));
+        assert!(discovery.candidates.is_empty());
+        assert_eq!(discovery.skipped[0].reason.kind(), "invalid_annotation");
+    }
+
+    #[test]
+    fn pins_the_tag_of_the_digest_strategy() {
+        let manifest = application(&[("web.update-strategy", "digest")]);
+        let discovery = discover_manifest(&manifest.replace("org/web", "org/web:stable"));
+        assert_eq!(
+            discovery.candidates[0].pinned_tag.as_deref(),
+            Some("stable")
+        );
+
+        let discovery = discover_manifest(&manifest);
+        assert_eq!(
+            discovery.candidates[0].pinned_tag.as_deref(),
+            Some("latest")
+        );
+    }
+
+    #[test]
+    fn reads_the_ignored_tags() {
+        let discovery = discover_manifest(&application(&[
+            ("web.allow-tags", "regexp:.*"),
+            ("web.ignore-tags", "1.2.0, regexp:^nightly-"),
+        ]));
+        let ignored = &discovery.candidates[0].ignore_tags;
+        assert_eq!(
+            ignored.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["1.2.0", "regexp:^nightly-"]
+        );
+        assert!(
+            discover_manifest(&application(&[("web.allow-tags", "regexp:.*")])).candidates[0]
+                .ignore_tags
+                .is_empty()
+        );
+    }
+
+    /// Plans the run of a checkout holding `files`.
+    async fn plan_in(config: &mut Config, files: &[(String, String)]) -> Plan {
+        let checkout = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = checkout.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        config.repositories[0].checkout = checkout.path().to_path_buf();
+
+        plan(
+            config,
+            &config.repositories[0],
+            None,
+            &CandidateFilter::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn resolved(plan: &Plan) -> Vec<(&str, &str)> {
+        plan.resolved
+            .iter()
+            .map(|(candidate, tag)| {
+                let tag = tag.as_ref().map(String::as_str).unwrap_or("error");
+                (candidate.app_name.as_str(), tag)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn bounds_the_concurrent_lookups() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let address = registry::tests::serve({
+            let (in_flight, most) = (in_flight.clone(), most.clone());
+            move |target| {
+                let Some(image) = target.strip_prefix("/v2/team/") else {
+                    return (404, "{}".to_string());
+                };
+                let current = in_flight.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+                most.fetch_max(current, atomic::Ordering::SeqCst);
+                // The first apps by name answer last
+                let delay = match image.as_bytes()[0] {
+                    b'a' => 400,
+                    b'b' => 300,
+                    _ => 100,
+                };
+                std::thread::sleep(Duration::from_millis(delay));
+                in_flight.fetch_sub(1, atomic::Ordering::SeqCst);
+                registry::tests::page(&["1.0.0", "1.1.0"])
+            }
+        });
+
+        let mut config = registry::tests::config_for(&address);
+        config.registry_concurrency = 2;
+        let files = ["e", "d", "c", "b", "a"]
+            .map(|name| {
+                let image = format!("{}/team/{}", address, name);
+                let manifest = app(name, &image, &[("web.allow-tags", "regexp:.*")]);
+                (format!("apps/{}.yaml", name), manifest)
+            })
+            .to_vec();
+        let plan = plan_in(&mut config, &files).await;
+
+        assert_eq!(
+            resolved(&plan),
+            ["a", "b", "c", "d", "e"].map(|name| (name, "1.1.0"))
+        );
+        assert_eq!(most.load(atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn lists_the_tags_of_an_image_once() {
+        let listings = Arc::new(AtomicUsize::new(0));
+        let address = registry::tests::serve({
+            let listings = listings.clone();
+            move |target| match target {
+                "/v2/team/web/tags/list" => {
+                    listings.fetch_add(1, atomic::Ordering::SeqCst);
+                    registry::tests::page(&["1.0.0", "1.1.0", "2.0.0"])
+                }
+                _ => (404, "{}".to_string()),
+            }
+        });
+
+        let image = format!("{}/team/web", address);
+        let mut files = ["a", "b", "c"]
+            .map(|name| {
+                let manifest = app(name, &image, &[("web.allow-tags", "regexp:.*")]);
+                (format!("apps/{}.yaml", name), manifest)
+            })
+            .to_vec();
+        let manifest = app("d", &image, &[("web.allow-tags", "regexp:^1\\.")]);
+        files.push(("apps/d.yaml".to_string(), manifest));
+        let plan = plan_in(&mut registry::tests::config_for(&address), &files).await;
+
+        assert_eq!(
+            resolved(&plan),
+            [
+                ("a", "2.0.0"),
+                ("b", "2.0.0"),
+                ("c", "2.0.0"),
+                ("d", "1.1.0")
+            ]
+        );
+        // Once for the three sharing their `allow-tags`, once for the other
+        assert_eq!(listings.load(atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn finds_helm_and_kustomize_images_side_by_side() {
+        let discovery = discover_manifest(
+            "\
+apiVersion: argoproj.io/v1alpha1
+kind: Application
+metadata:
+  name: web
+  annotations:
+    argocd-image-updater.argoproj.io/image-list: web=ghcr.io/org/web, worker=ghcr.io/org/worker
+    argocd-image-updater.argoproj.io/web.allow-tags: regexp:.*
+    argocd-image-updater.argoproj.io/web.helm.image-tag: image.tag
+    argocd-image-updater.argoproj.io/worker.allow-tags: regexp:.*
+    argocd-image-updater.argoproj.io/worker.kustomize.image-name: worker
+spec:
+  source:
+    path: apps/web
+",
+        );
+
+        let targets = discovery
+            .candidates
+            .iter()
+            .map(|candidate| (candidate.url.as_str(), &candidate.target))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            targets[..],
+            [
+                ("ghcr.io/org/web", WriteTarget::Helm { image_tag, .. }),
+                ("ghcr.io/org/worker", WriteTarget::Kustomize { image_name }),
+            ] if image_tag == "image.tag" && image_name == "worker"
+        ));
+    }
+
+    /// The `web` Application, with the `sources` list as its `spec.sources`.
+    fn multi_source(annotations: &[(&str, &str)], sources: &str) -> String {
+        let manifest = application(&[&[("web.allow-tags", "regexp:.*")], annotations].concat());
+        let spec = manifest.find("spec:\n").unwrap();
+        let sources = sources
+            .lines()
+            .map(|line| format!("  {}\n", line))
+            .collect::<String>();
+        format!("{}spec:\n  sources:\n{}", &manifest[..spec], sources)
+    }
+
+    fn paths(discovery: &Discovery) -> Vec<&str> {
+        discovery
+            .candidates
+            .iter()
+            .map(|candidate| candidate.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn writes_to_the_source_with_a_path() {
+        let sources = "\
+- repoURL: https://charts.example.com
+  chart: web
+  targetRevision: 1.2.0
+  helm:
+    valueFiles:
+    - $values/apps/web/values.yaml
+- repoURL: https://github.com/org/ops
+  targetRevision: main
+  ref: values
+- repoURL: https://github.com/org/ops
+  targetRevision: main
+  path: apps/web
+";
+        let discovery = discover_manifest(&multi_source(&[], sources));
+        assert_eq!(paths(&discovery), ["apps/web"]);
+    }
+
+    #[test]
+    fn selects_the_write_back_source() {
+        let sources = "\
+- repoURL: https://github.com/org/ops
+  path: base/web
+- repoURL: https://github.com/org/ops
+  path: overlays/prod/web
+  ref: prod
+";
+        let discovery = discover_manifest(&multi_source(&[], sources));
+        assert_eq!(paths(&discovery), ["base/web"]);
+
+        let by_ref = [("write-back-source", "prod")];
+        let discovery = discover_manifest(&multi_source(&by_ref, sources));
+        assert_eq!(paths(&discovery), ["overlays/prod/web"]);
+
+        let by_path = [("write-back-source", "overlays/prod/web")];
+        let discovery = discover_manifest(&multi_source(&by_path, sources));
+        assert_eq!(paths(&discovery), ["overlays/prod/web"]);
+
+        let missing = [("write-back-source", "staging")];
+        let discovery = discover_manifest(&multi_source(&missing, sources));
+        assert!(discovery.candidates.is_empty());
+        assert_eq!(discovery.skipped[0].reason.kind(), "missing_path");
+    }
+
+    #[test]
+    fn skips_the_apps_without_a_source_with_a_path() {
+        let sources = "- repoURL: https://charts.example.com\n  chart: web\n";
+        let discovery = discover_manifest(&multi_source(&[], sources));
+        assert!(discovery.candidates.is_empty());
+        assert_eq!(discovery.skipped[0].reason.kind(), "missing_path");
+    }
+
+    /// GitHub's example of a signed webhook, from its documentation.
+    const GITHUB_SECRET: &str = "It's a Secret to Everybody";
+    const GITHUB_PAYLOAD: &str = "Hello, World!";
+    const GITHUB_SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[rocket::post("/webhook", data = "<webhook>")]
+    fn webhook_body(webhook: Webhook) -> Vec<u8> {
+        webhook.body
+    }
+
+    #[rocket::get("/guarded")]
+    fn secret_guarded(_secret: SecretGuard) {}
+
+    /// A server checking the requests against `secrets`.
+    async fn guarded_client(secrets: &[&str]) -> rocket::local::asynchronous::Client {
+        let mut config = config::test_config();
+        config.secrets = secrets.iter().map(|secret| secret.to_string()).collect();
+        let rocket = rocket::build()
+            .manage(Arc::new(config))
+            .mount("/", routes![webhook_body, secret_guarded]);
+
+        rocket::local::asynchronous::Client::untracked(rocket)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn checks_the_github_signatures() {
+        assert!(is_signed(
+            GITHUB_SECRET,
+            GITHUB_PAYLOAD.as_bytes(),
+            GITHUB_SIGNATURE
+        ));
+        assert!(!is_signed(
+            "another secret",
+            GITHUB_PAYLOAD.as_bytes(),
+            GITHUB_SIGNATURE
+        ));
+        assert!(!is_signed(
+            GITHUB_SECRET,
+            b"Hello, World?",
+            GITHUB_SIGNATURE
+        ));
+        assert!(!is_signed(
+            GITHUB_SECRET,
+            GITHUB_PAYLOAD.as_bytes(),
+            &GITHUB_SIGNATURE[7..]
+        ));
+        assert!(!is_signed(
+            GITHUB_SECRET,
+            GITHUB_PAYLOAD.as_bytes(),
+            "sha256=75710"
+        ));
+        assert!(!is_signed(
+            GITHUB_SECRET,
+            GITHUB_PAYLOAD.as_bytes(),
+            "sha256=zz"
+        ));
+    }
+
+    #[tokio::test]
+    async fn accepts_signed_webhooks() {
+        let client = guarded_client(&["old secret", GITHUB_SECRET]).await;
+
+        let signed = client
+            .post("/webhook")
+            .header(rocket::http::Header::new(
+                "X-Hub-Signature-256",
+                GITHUB_SIGNATURE,
+            ))
+            .body(GITHUB_PAYLOAD)
+            .dispatch()
+            .await;
+        assert_eq!(signed.status(), Status::Ok);
+        assert_eq!(signed.into_string().await.unwrap(), GITHUB_PAYLOAD);
+
+        let tampered = client
+            .post("/webhook")
+            .header(rocket::http::Header::new(
+                "X-Hub-Signature-256",
+                GITHUB_SIGNATURE,
+            ))
+            .body("Hello, World?")
+            .dispatch()
+            .await;
+        assert_eq!(tampered.status(), Status::Unauthorized);
+        let unsigned = client
+            .post("/webhook")
+            .body(GITHUB_PAYLOAD)
+            .dispatch()
+            .await;
+        assert_eq!(unsigned.status(), Status::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn still_accepts_the_secret_with_webhooks() {
+        let client = guarded_client(&[GITHUB_SECRET]).await;
+
+        let response = client
+            .post("/webhook")
+            .header(rocket::http::Header::new("X-Secret", GITHUB_SECRET))
+            .body("{}")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // A wrong secret isn't made up for by a right signature
+        let response = client
+            .post("/webhook")
+            .header(rocket::http::Header::new("X-Secret", "wrong"))
+            .header(rocket::http::Header::new(
+                "X-Hub-Signature-256",
+                GITHUB_SIGNATURE,
+            ))
+            .body(GITHUB_PAYLOAD)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn only_runs_for_the_candidates_of_the_pushed_image() {
+        let candidates = || {
+            vec![
+                Candidate::test("ghcr.io/org/web:1.0.0", "regexp:^1\\."),
+                Candidate {
+                    app_name: "web-canary".to_string(),
+                    ..Candidate::test("ghcr.io/org/web", "regexp:^2\\.")
+                },
+                Candidate {
+                    app_name: "worker".to_string(),
+                    ..Candidate::test("ghcr.io/org/worker", "regexp:.*")
+                },
+            ]
+        };
+        let filter = |tag: &str| CandidateFilter {
+            pushed: Some(PushedImage {
+                image: "ghcr.io/org/web".to_string(),
+                tags: vec![tag.to_string()],
+            }),
+            ..Default::default()
+        };
+        let apps = |filter: CandidateFilter| {
+            filter
+                .apply(candidates())
+                .unwrap()
+                .into_iter()
+                .map(|candidate| candidate.app_name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(apps(filter("1.2.0")), ["web"]);
+        assert_eq!(apps(filter("2.0.0")), ["web-canary"]);
+        // Nothing to run, rather than an error
+        assert!(apps(filter("nightly")).is_empty());
+    }
+
+    async fn metrics_client(require_secret: bool) -> rocket::local::asynchronous::Client {
+        let mut config = config::test_config();
+        config.secrets = vec!["secret".to_string()];
+        config.metrics_require_secret = require_secret;
+        config.metrics.run_started();
+        let rocket = rocket::build()
+            .manage(Arc::new(config))
+            .mount("/", routes![export_metrics]);
+
+        rocket::local::asynchronous::Client::untracked(rocket)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn exports_the_metrics() {
+        let client = metrics_client(false).await;
+        let response = client.get("/metrics").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type().unwrap().to_string(),
+            "text/plain; version=0.0.4"
+        );
+        let metrics = response.into_string().await.unwrap();
+        assert!(metrics.contains("image_updater_runs_started_total 1"));
+    }
+
+    #[tokio::test]
+    async fn can_require_the_secret_for_the_metrics() {
+        let client = metrics_client(true).await;
+        let anonymous = client.get("/metrics").dispatch().await;
+        assert_eq!(anonymous.status(), Status::Unauthorized);
+
+        let authorized = client
+            .get("/metrics")
+            .header(rocket::http::Header::new("X-Secret", "secret"))
+            .dispatch()
+            .await;
+        assert_eq!(authorized.status(), Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn checks_the_secret() {
+        let client = guarded_client(&["old secret", "new secret"]).await;
+        let status = |header: Option<(&'static str, &'static str)>| {
+            let mut request = client.get("/guarded");
+            if let Some((name, value)) = header {
+                request = request.header(rocket::http::Header::new(name, value));
+            }
+            async move { request.dispatch().await.status() }
+        };
+
+        assert_eq!(status(Some(("X-Secret", "new secret"))).await, Status::Ok);
+        assert_eq!(status(Some(("X-Secret", "old secret"))).await, Status::Ok);
+        assert_eq!(
+            status(Some(("Authorization", "Bearer new secret"))).await,
+            Status::Ok
+        );
+
+        for rejected in [
+            None,
+            Some(("X-Secret", "wrong secret")),
+            Some(("X-Secret", "new secre")),
+            Some(("X-Secret", "new secret!")),
+            Some(("X-Secret", "")),
+            Some(("Authorization", "Bearer wrong secret")),
+            Some(("Authorization", "new secret")),
+            Some(("Authorization", "Basic bmV3IHNlY3JldA==")),
+        ] {
+            assert_eq!(status(rejected).await, Status::Unauthorized, "{rejected:?}");
+        }
+    }
+
+    #[test]
+    fn serializes_the_run_report() {
+        let commit = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let deploy = UpdateSummary {
+            candidates: 3,
+            updated: vec!["web".to_string()],
+            failed: vec![("worker".to_string(), anyhow!("no tags matched"))],
+            commit: Some(commit),
+            changes: vec![Change::Tag {
+                app_name: "web".to_string(),
+                image: "ghcr.io/org/web".to_string(),
+                old_tag: Some("1.0.0".to_string()),
+                new_tag: "1.1.0".to_string(),
+                path: "apps/web/.argocd-source-web.yaml".into(),
+            }],
+            skipped: vec![Unchanged {
+                app_name: "api".to_string(),
+                image: "ghcr.io/org/api".to_string(),
+                reason: "already at 2.0.0".to_string(),
+            }],
+            ..Default::default()
+        };
+        let summary = RunSummary {
+            repos: vec![
+                ("deploy".to_string(), Ok(deploy)),
+                ("infra".to_string(), Err(anyhow!("can't clone"))),
+            ],
+        };
+
+        assert_eq!(
+            summary.to_json(Duration::from_millis(1500)),
+            serde_json::json!({
+                "status": "failed",
+                "duration_secs": 1.5,
+                "candidates": 3,
+                "updated": [{
+                    "repo": "deploy",
+                    "app": "web",
+                    "image": "ghcr.io/org/web",
+                    "old": "1.0.0",
+                    "new": "1.1.0",
+                }],
+                "pruned": [],
+                "skipped": [{
+                    "repo": "deploy",
+                    "app": "api",
+                    "image": "ghcr.io/org/api",
+                    "reason": "already at 2.0.0",
+                }],
+                "ignored": [],
+                "failed": [{ "repo": "deploy", "app": "worker", "error": "no tags matched" }],
+                // Only set when there's a single repository
+                "commit": null,
+                "repos": [
+                    {
+                        "repo": "deploy",
+                        "status": "failed",
+                        "commit": commit.to_string(),
+                        "error": null,
+                    },
+                    {
+                        "repo": "infra",
+                        "status": "failed",
+                        "commit": null,
+                        "error": "can't clone",
+                    },
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn exports_where_the_candidates_stand() {
+        let address = registry::tests::serve(|target| match target {
+            "/v2/team/web/tags/list" | "/v2/team/api/tags/list" | "/v2/team/worker/tags/list" => {
+                registry::tests::page(&["1.0.0", "1.1.0"])
+            }
+            _ => (404, "{}".to_string()),
+        });
+        let overrides = |tag: &str| {
+            format!(
+                "helm:\n  parameters:\n  - name: image.tag\n    value: {}\n    forcestring: true\n",
+                tag
+            )
+        };
+        let files = ["web", "api", "worker", "db"]
+            .map(|name| {
+                let image = format!("{}/team/{}", address, name);
+                let manifest = app(name, &image, &[("web.allow-tags", "regexp:.*")]);
+                (format!("apps/{}.yaml", name), manifest)
+            })
+            .into_iter()
+            .chain([
+                (
+                    "apps/web/.argocd-source-web.yaml".to_string(),
+                    overrides("1.0.0"),
+                ),
+                (
+                    "apps/api/.argocd-source-api.yaml".to_string(),
+                    overrides("1.1.0"),
+                ),
+                (
+                    "apps/db/.argocd-source-db.yaml".to_string(),
+                    overrides("1.0.0"),
+                ),
+            ])
+            .collect::<Vec<_>>();
+
+        let mut config = registry::tests::config_for(&address);
+        plan_in(&mut config, &files).await;
+
+        let repo = &config.repositories[0].name;
+        let metrics = config.metrics.encode().unwrap();
+        let value = |series: &str, app: &str, labels: &str| {
+            let series = format!(
+                r#"image_updater_{}{{app="{}"{},image="{}/team/{}",repo="{}"}}"#,
+                series, app, labels, address, app, repo
+            );
+            metrics.lines().find_map(|line| {
+                line.strip_prefix(&series)?
+                    .strip_prefix(' ')?
+                    .parse::<i64>()
+                    .ok()
+            })
+        };
+
+        assert_eq!(
+            value("candidate_info", "web", r#",current_tag="1.0.0""#),
+            Some(1)
+        );
+        assert_eq!(
+            value("candidate_info", "api", r#",current_tag="1.1.0""#),
+            Some(1)
+        );
+        assert_eq!(
+            value("candidate_info", "worker", r#",current_tag="absent""#),
+            Some(1)
+        );
+        assert_eq!(value("image_update_available", "web", ""), Some(1));
+        assert_eq!(value("image_update_available", "api", ""), Some(0));
+        assert_eq!(value("image_update_available", "worker", ""), Some(1));
+        // The registry failed, nobody knows
+        assert_eq!(
+            value("candidate_info", "db", r#",current_tag="1.0.0""#),
+            Some(1)
+        );
+        assert_eq!(value("image_update_available", "db", ""), None);
+    }
+
+    #[test]
+    fn only_reads_the_scanned_files() {
+        let checkout = tempfile::tempdir().unwrap();
+        let manifest = application(&[("web.allow-tags", "regexp:.*")]);
+        for path in [
+            "apps/web.yaml",
+            "apps/team/api.yml",
+            "apps/notes.txt",
+            "apps/legacy/old.yaml",
+            "rendered/web.yaml",
+            "charts/web/values.yaml",
+            "root.yaml",
+            ".git/config.yaml",
+        ] {
+            let path = checkout.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, &manifest).unwrap();
+        }
+
+        let mut config = config::test_config();
+        let repo = &mut config.repositories[0];
+        repo.checkout = checkout.path().to_path_buf();
+        repo.scan = scan::ScanFilter::from_vars(|name| match name {
+            "SCAN_INCLUDE" => Some("apps/**,rendered/**".to_string()),
+            "SCAN_EXCLUDE" => Some("rendered/**,apps/legacy/**".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        let files = discover_files(&config, &config.repositories[0]).unwrap();
+
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [Path::new("apps/team/api.yml"), Path::new("apps/web.yaml")]
+        );
+        assert!(is_scanned(
+            &config.repositories[0],
+            Path::new("apps/web.yaml")
+        ));
+        assert!(!is_scanned(
+            &config.repositories[0],
+            Path::new("apps/legacy/old.yaml")
+        ));
+        assert!(!is_scanned(&config.repositories[0], Path::new("root.yaml")));
+    }
+
+    #[test]
+    fn reads_the_pull_secret() {
+        let discovery = discover_manifest(&application(&[
+            ("web.allow-tags", "regexp:.*"),
+            ("web.pull-secret", "env:WEB_CREDS"),
+        ]));
+        assert_eq!(
+            discovery.candidates[0].pull_secret,
+            Some(PullSecret::Env("WEB_CREDS".to_string()))
+        );
+
+        let discovery = discover_manifest(&application(&[
+            ("web.allow-tags", "regexp:.*"),
+            ("web.pull-secret", "secret:web"),
+        ]));
+        assert!(discovery.candidates.is_empty());
+        assert_eq!(discovery.skipped[0].reason.kind(), "invalid_annotation");
+        assert_eq!(
+            discovery.skipped[0].reason.to_string(),
+            "Invalid `pull-secret`: Unsupported pull secret: secret:web"
+        );
+    }
+
+    /// Commits everything in the checkout of `repo`, deletions included.
+    fn commit_all(repo: &Repository) -> Oid {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.update_all(["*"], None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("someone", "someone@example.com").unwrap();
+        let parents = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit().unwrap()],
+            Err(_) => vec![],
+        };
+        let parents = parents.iter().collect::<Vec<_>>();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Update",
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    fn write_app(checkout: &Path, name: &str, allow_tags: &str) {
+        let manifest = app(
+            name,
+            &format!("ghcr.io/org/{}", name),
+            &[("web.allow-tags", allow_tags)],
+        );
+        std::fs::create_dir_all(checkout.join("apps")).unwrap();
+        std::fs::write(checkout.join(format!("apps/{}.yaml", name)), manifest).unwrap();
+    }
+
+    fn discovered(config: &Config) -> Vec<(String, String)> {
+        let discovery = discover(config, &config.repositories[0]).unwrap();
+        discovery
+            .candidates
+            .into_iter()
+            .map(|candidate| (candidate.app_name, candidate.tag_filter.to_string()))
+            .collect()
+    }
+
+    /// Renames the cached candidates of `path`, to tell whether they get read
+    /// again.
+    fn mark_cached(config: &Config, path: &str, head: Oid) {
+        let cache = config.discovery_cache.as_ref().unwrap();
+        let (_, mut files) = cache.get(&config.repositories[0].name).unwrap();
+        for candidate in &mut files.get_mut(Path::new(path)).unwrap().candidates {
+            candidate.app_name = format!("{} (cached)", candidate.app_name);
+        }
+        cache.insert(&config.repositories[0].name, head, files);
+    }
+
+    #[test]
+    fn only_reads_the_files_that_changed_again() {
+        let checkout = tempfile::tempdir().unwrap();
+        let repository = Repository::init(checkout.path()).unwrap();
+        for name in ["api", "web", "worker"] {
+            write_app(checkout.path(), name, "regexp:^1\\.");
+        }
+        let first = commit_all(&repository);
+
+        let mut config = config::test_config();
+        config.discovery_cache = Some(Default::default());
+        config.repositories[0].checkout = checkout.path().to_path_buf();
+        let apps = |apps: &[(&str, &str)]| {
+            apps.iter()
+                .map(|(app, filter)| (app.to_string(), format!("the regex {}", filter)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            discovered(&config),
+            apps(&[("api", "^1\\."), ("web", "^1\\."), ("worker", "^1\\.")])
+        );
+
+        // Nothing gets read at the same commit
+        mark_cached(&config, "apps/web.yaml", first);
+        mark_cached(&config, "apps/worker.yaml", first);
+        assert_eq!(
+            discovered(&config),
+            apps(&[
+                ("api", "^1\\."),
+                ("web (cached)", "^1\\."),
+                ("worker (cached)", "^1\\.")
+            ])
+        );
+
+        write_app(checkout.path(), "web", "regexp:^2\\.");
+        write_app(checkout.path(), "db", "regexp:^1\\.");
+        std::fs::remove_file(checkout.path().join("apps/api.yaml")).unwrap();
+        std::fs::write(checkout.path().join("README.md"), "# Deploy\n").unwrap();
+        commit_all(&repository);
+        assert_eq!(
+            discovered(&config),
+            apps(&[
+                ("db", "^1\\."),
+                ("web", "^2\\."),
+                ("worker (cached)", "^1\\.")
+            ])
+        );
+    }
+
+    #[test]
+    fn reads_everything_when_the_cache_cant_be_used() {
+        let checkout = tempfile::tempdir().unwrap();
+        let repository = Repository::init(checkout.path()).unwrap();
+        write_app(checkout.path(), "web", "regexp:^1\\.");
+        let first = commit_all(&repository);
+
+        let mut config = config::test_config();
+        config.discovery_cache = Some(Default::default());
+        config.repositories[0].checkout = checkout.path().to_path_buf();
+        let names = |config: &Config| {
+            discovered(config)
+                .into_iter()
+                .map(|(app, _)| app)
+                .collect::<Vec<_>>()
+        };
+        names(&config);
+
+        // A commit the checkout doesn't have, after a force push
+        mark_cached(&config, "apps/web.yaml", Oid::from_bytes(&[1; 20]).unwrap());
+        assert_eq!(names(&config), ["web"]);
+
+        mark_cached(&config, "apps/web.yaml", first);
+        assert_eq!(names(&config), ["web (cached)"]);
+        config.discovery_cache = None;
+        assert_eq!(names(&config), ["web"]);
+    }
+
+    #[test]
+    fn skips_the_files_that_cant_be_read() {
+        let checkout = tempfile::tempdir().unwrap();
+        write_app(checkout.path(), "web", "regexp:.*");
+        let garbage = (0..=255u8).cycle().take(4096).collect::<Vec<_>>();
+        std::fs::write(checkout.path().join("apps/garbage.yaml"), garbage).unwrap();
+        // How a Windows tool exports it
+        let manifest = application(&[("web.allow-tags", "regexp:.*")]);
+        let utf16 = [0xfeff]
+            .into_iter()
+            .chain(manifest.encode_utf16())
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        std::fs::write(checkout.path().join("apps/utf16.yaml"), utf16).unwrap();
+        let huge = "# rendered\n".repeat(MAX_MANIFEST_SIZE as usize / 11 + 1);
+        std::fs::write(checkout.path().join("apps/huge.yaml"), huge).unwrap();
+
+        let mut config = config::test_config();
+        config.repositories[0].checkout = checkout.path().to_path_buf();
+        let discovery = discover(&config, &config.repositories[0]).unwrap();
+
+        assert_eq!(
+            discovery
+                .candidates
+                .iter()
+                .map(|candidate| candidate.app_name.as_str())
+                .collect::<Vec<_>>(),
+            ["web"]
+        );
+        let skipped = discovery
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.manifest.to_str().unwrap(), skipped.reason.kind()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            skipped,
+            [
+                ("apps/garbage.yaml", "unreadable"),
+                ("apps/huge.yaml", "too_large"),
+                ("apps/utf16.yaml", "unreadable"),
+            ]
+        );
+    }
+
+    fn app_names(discovery: &Discovery) -> Vec<&str> {
+        discovery
+            .candidates
+            .iter()
+            .map(|candidate| candidate.app_name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_application_in_the_third_document() {
+        let manifest = format!(
+            "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: web\n---\n---\napiVersion: v1\nkind: Service\nmetadata:\n  name: web\n---\n{}---\n",
+            application(&[("web.allow-tags", "regexp:.*")])
+        );
+        assert_eq!(app_names(&discover_manifest(&manifest)), ["web"]);
+    }
+
+    #[test]
+    fn finds_the_application_after_a_leading_separator() {
+        let manifest = format!(
+            "# Rendered by hand\n# with comments\n---\n{}",
+            application(&[("web.allow-tags", "regexp:.*")])
+        );
+        assert_eq!(app_names(&discover_manifest(&manifest)), ["web"]);
+
+        let api = app("api", "ghcr.io/org/api", &[("web.allow-tags", "regexp:.*")]);
+        let manifest = format!("---\n{}", api);
+        let manifest = format!(
+            "{}---\n{}",
+            manifest,
+            application(&[("web.allow-tags", "regexp:.*")])
+        );
+        assert_eq!(app_names(&discover_manifest(&manifest)), ["api", "web"]);
+    }
+
+    #[test]
+    fn doesnt_split_block_scalars() {
+        let manifest = application(&[
+            ("web.allow-tags", "regexp:.*"),
+            ("web.description", "--- not a separator"),
+        ]) + "    helm:\n      values: |\n        ---\n        image:\n          tag: 1.0.0\n        ---\n";
+        let discovery = discover_manifest(&manifest);
+
+        assert_eq!(app_names(&discovery), ["web"]);
+        assert!(discovery.skipped.is_empty());
+    }
+
+    #[test]
+    fn records_the_documents_that_arent_valid_yaml() {
+        let manifest = format!(
+            "apiVersion: argoproj.io/v1alpha1\nkind: Application\nmetadata: [web\n---\n{}",
+            application(&[("web.allow-tags", "regexp:.*")])
+        );
+        let discovery = discover_manifest(&manifest);
+
+        assert_eq!(app_names(&discovery), ["web"]);
+        assert_eq!(discovery.skipped.len(), 1);
+        let skipped = &discovery.skipped[0];
+        assert_eq!(skipped.manifest, Path::new("apps.yaml"));
+        assert_eq!(skipped.reason.kind(), "unparsable");
+        assert!(skipped.reason.is_actionable());
+        assert!(skipped
+            .reason
+            .to_string()
+            .starts_with("It isn't valid YAML: "));
+    }
+
+    #[test]
+    fn records_the_documents_that_arent_applications() {
+        let manifest = format!(
+            "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: web\n---\n- a list\n---\n{}",
+            application(&[("web.allow-tags", "regexp:.*")])
+        );
+        let discovery = discover_manifest(&manifest);
+
+        assert_eq!(app_names(&discovery), ["web"]);
+        let reasons: Vec<_> = discovery
+            .skipped
+            .iter()
+            .map(|skipped| skipped.reason.to_string())
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                "It's a ConfigMap, not an Application",
+                "It isn't an Application"
+            ]
+        );
+        assert!(discovery
+            .skipped
+            .iter()
+            .all(|skipped| !skipped.reason.is_actionable()));
+    }
+
+    /// A server triggering runs for a checkout holding the `web` app, which
+    /// only takes the `1.x` tags.
+    async fn trigger_client(checkout: &Path) -> (rocket::local::asynchronous::Client, Arc<Jobs>) {
+        let repository = Repository::init(checkout).unwrap();
+        write_app(checkout, "web", "regexp:^1\\.");
+        commit_all(&repository);
+
+        let mut config = config::test_config();
+        config.secrets = vec!["secret".to_string()];
+        config.repositories[0].checkout = checkout.to_path_buf();
+        let (jobs, _) = Jobs::new();
+        let jobs = Arc::new(jobs);
+        let rocket = rocket::build()
+            .manage(Arc::new(config))
+            .manage(jobs.clone())
+            .manage(Throttle::new(None, Duration::from_secs(60), Instant::now()))
+            .manage(Arc::new(RunLock::default()))
+            .mount("/", routes![trigger]);
+
+        let client = rocket::local::asynchronous::Client::untracked(rocket)
+            .await
+            .unwrap();
+        (client, jobs)
+    }
+
+    async fn push(client: &rocket::local::asynchronous::Client, image: &str) -> (Status, String) {
+        let (name, tag) = image.rsplit_once(':').unwrap();
+        let package = name.rsplit('/').next().unwrap();
+        let payload = serde_json::json!({
+            "action": "published",
+            "package": {
+                "name": package,
+                "namespace": "org",
+                "package_type": "CONTAINER",
+                "owner": { "login": "org" },
+                "package_version": {
+                    "package_url": image,
+                    "container_metadata": { "tag": { "name": tag } },
+                },
+            },
+        });
+        let response = client
+            .post("/update")
+            .header(ContentType::JSON)
+            .header(rocket::http::Header::new("X-Secret", "secret"))
+            .header(rocket::http::Header::new("X-GitHub-Event", "package"))
+            .body(payload.to_string())
+            .dispatch()
+            .await;
+
+        (response.status(), response.into_string().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn doesnt_run_for_tags_no_candidate_takes() {
+        let checkout = tempfile::tempdir().unwrap();
+        let (client, jobs) = trigger_client(checkout.path()).await;
+
+        assert_eq!(
+            push(&client, "ghcr.io/org/web:2.0.0").await,
+            (Status::Ok, "no matching candidates".to_string())
+        );
+        assert_eq!(
+            push(&client, "ghcr.io/org/worker:1.0.0").await,
+            (Status::Ok, "no matching candidates".to_string())
+        );
+        assert!(jobs.state(1).is_none());
+
+        let (status, _) = push(&client, "ghcr.io/org/web:1.2.0").await;
+        assert_eq!(status, Status::Accepted);
+        assert!(jobs.state(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn runs_when_the_candidates_cant_be_told() {
+        // Never fetched
+        let checkout = tempfile::tempdir().unwrap();
+        let (client, jobs) = trigger_client(checkout.path()).await;
+        std::fs::remove_dir_all(checkout.path().join(".git")).unwrap();
+
+        let (status, _) = push(&client, "ghcr.io/org/web:2.0.0").await;
+        assert_eq!(status, Status::Accepted);
+        assert!(jobs.state(1).is_some());
+    }
 }
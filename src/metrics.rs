@@ -0,0 +1,488 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+/// What gets exported on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    runs_started: IntCounter,
+    runs_succeeded: IntCounter,
+    runs_failed: IntCounter,
+    candidates_processed: IntCounterVec,
+    candidates_updated: IntCounterVec,
+    candidates_skipped: IntCounterVec,
+    candidates_errored: IntCounterVec,
+    pushes: IntCounter,
+    push_failures: IntCounter,
+    registry_requests: HistogramVec,
+    circuit_open: IntGaugeVec,
+    last_success: Gauge,
+    candidate_info: IntGaugeVec,
+    update_available: IntGaugeVec,
+    candidates_not_exported: IntGaugeVec,
+    /// How many candidates get their own series, the ones coming after them
+    /// only being counted in `candidates_not_exported`.
+    max_candidates: usize,
+    /// The tag of each exported candidate by repository, app and image, to
+    /// drop its series once it moves on.
+    exported: Mutex<BTreeMap<(String, String, String), String>>,
+}
+
+/// Where a candidate stands, as found while planning a run.
+pub struct CandidateVersion {
+    pub app: String,
+    pub image: String,
+    /// The tag in the repository, `absent` when there's none yet.
+    pub current: String,
+    /// Unknown when the registry couldn't be asked.
+    pub update_available: Option<bool>,
+}
+
+impl Metrics {
+    pub fn new(max_candidates: usize) -> Result<Self> {
+        let registry = Registry::new_custom(Some("image_updater".to_string()), None)?;
+        let counter = |name: &str, help: &str| -> Result<IntCounter> {
+            let counter = IntCounter::new(name, help)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let by_app = |name: &str, help: &str| -> Result<IntCounterVec> {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["app"])?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+
+        let registry_requests = HistogramVec::new(
+            HistogramOpts::new(
+                "registry_request_duration_seconds",
+                "How long the registry requests took",
+            ),
+            &["host"],
+        )?;
+        registry.register(Box::new(registry_requests.clone()))?;
+        let last_success = Gauge::new(
+            "last_successful_run_timestamp_seconds",
+            "When the last run without failures finished",
+        )?;
+        registry.register(Box::new(last_success.clone()))?;
+        let gauges = |name: &str, help: &str, labels: &[&str]| -> Result<IntGaugeVec> {
+            let gauge = IntGaugeVec::new(Opts::new(name, help), labels)?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+
+        Ok(Self {
+            candidate_info: gauges(
+                "candidate_info",
+                "The tag each candidate is at, always 1",
+                &["repo", "app", "image", "current_tag"],
+            )?,
+            update_available: gauges(
+                "image_update_available",
+                "Whether the registry has a tag the candidate would be updated to",
+                &["repo", "app", "image"],
+            )?,
+            circuit_open: gauges(
+                "registry_circuit_open",
+                "Whether the candidates of the registry host are skipped, it failing lately",
+                &["host"],
+            )?,
+            candidates_not_exported: gauges(
+                "candidates_not_exported",
+                "Candidates left out of the per candidate metrics, past MAX_CANDIDATE_METRICS",
+                &["repo"],
+            )?,
+            max_candidates,
+            exported: Mutex::default(),
+            runs_started: counter("runs_started_total", "Runs started")?,
+            runs_succeeded: counter("runs_succeeded_total", "Runs finished without failures")?,
+            runs_failed: counter("runs_failed_total", "Runs that failed, entirely or not")?,
+            candidates_processed: by_app("candidates_processed_total", "Candidates looked at")?,
+            candidates_updated: by_app("candidates_updated_total", "Candidates updated")?,
+            candidates_skipped: by_app(
+                "candidates_skipped_total",
+                "Candidates already up to date",
+            )?,
+            candidates_errored: by_app("candidates_errored_total", "Candidates that failed")?,
+            pushes: counter("git_pushes_total", "Pushes to the remote")?,
+            push_failures: counter("git_push_failures_total", "Pushes the remote refused")?,
+            registry_requests,
+            last_success,
+            registry,
+        })
+    }
+
+    pub fn run_started(&self) {
+        self.runs_started.inc();
+    }
+
+    pub fn run_finished(&self, succeeded: bool) {
+        if !succeeded {
+            self.runs_failed.inc();
+            return;
+        }
+
+        self.runs_succeeded.inc();
+        self.last_success
+            .set(chrono::Utc::now().timestamp_millis() as f64 / 1000.0);
+    }
+
+    /// Counts the candidates of a run by app, the ones that neither got
+    /// updated nor failed being already up to date.
+    pub fn candidates<'a>(
+        &self,
+        processed: impl IntoIterator<Item = &'a str>,
+        updated: impl IntoIterator<Item = &'a str>,
+        errored: impl IntoIterator<Item = &'a str>,
+    ) {
+        let mut skipped = HashMap::<&str, u64>::new();
+        for app_name in processed {
+            self.candidates_processed
+                .with_label_values(&[app_name])
+                .inc();
+            *skipped.entry(app_name).or_default() += 1;
+        }
+        for app_name in updated {
+            self.candidates_updated.with_label_values(&[app_name]).inc();
+            skipped
+                .entry(app_name)
+                .and_modify(|count| *count = count.saturating_sub(1));
+        }
+        for app_name in errored {
+            self.candidates_errored.with_label_values(&[app_name]).inc();
+            skipped
+                .entry(app_name)
+                .and_modify(|count| *count = count.saturating_sub(1));
+        }
+
+        for (app_name, count) in skipped {
+            self.candidates_skipped
+                .with_label_values(&[app_name])
+                .inc_by(count);
+        }
+    }
+
+    /// Exports where the candidates of a repository stand. When `complete`,
+    /// they're all of its candidates and the ones missing are gone. Past
+    /// `max_candidates` series, new candidates are only counted until older
+    /// ones go away.
+    pub fn candidate_versions(&self, repo: &str, versions: Vec<CandidateVersion>, complete: bool) {
+        let mut exported = self.exported.lock().unwrap();
+        if complete {
+            let gone = exported
+                .keys()
+                .filter(|(exported_repo, app, image)| {
+                    exported_repo == repo
+                        && !versions
+                            .iter()
+                            .any(|version| version.app == *app && version.image == *image)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in gone {
+                let current = exported.remove(&key).unwrap_or_default();
+                let (repo, app, image) = &key;
+                let _ = self
+                    .candidate_info
+                    .remove_label_values(&[repo, app, image, &current]);
+                let _ = self
+                    .update_available
+                    .remove_label_values(&[repo, app, image]);
+            }
+        }
+
+        let mut not_exported = 0;
+        for version in versions {
+            let key = (repo.to_string(), version.app, version.image);
+            match exported.get(&key) {
+                Some(current) if *current != version.current => {
+                    let _ = self
+                        .candidate_info
+                        .remove_label_values(&[repo, &key.1, &key.2, current]);
+                }
+                Some(_) => {}
+                None if exported.len() >= self.max_candidates => {
+                    not_exported += 1;
+                    continue;
+                }
+                None => {}
+            }
+
+            let (_, app, image) = &key;
+            self.candidate_info
+                .with_label_values(&[repo, app, image, &version.current])
+                .set(1);
+            if let Some(update_available) = version.update_available {
+                self.update_available
+                    .with_label_values(&[repo, app, image])
+                    .set(update_available.into());
+            }
+            exported.insert(key, version.current);
+        }
+
+        if not_exported > 0 {
+            log::warn!(
+                "Not exporting the metrics of {} candidates of {}, past MAX_CANDIDATE_METRICS ({})",
+                not_exported,
+                repo,
+                self.max_candidates
+            );
+        }
+        self.candidates_not_exported
+            .with_label_values(&[repo])
+            .set(not_exported);
+    }
+
+    pub fn pushed(&self, succeeded: bool) {
+        self.pushes.inc();
+        if !succeeded {
+            self.push_failures.inc();
+        }
+    }
+
+    pub fn registry_request(&self, host: &str, duration: Duration) {
+        self.registry_requests
+            .with_label_values(&[host])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn circuit(&self, host: &str, open: bool) {
+        self.circuit_open
+            .with_label_values(&[host])
+            .set(open.into());
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of `series`, as exported.
+    fn value(metrics: &Metrics, series: &str) -> Option<f64> {
+        metrics
+            .encode()
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.parse().ok())
+    }
+
+    #[test]
+    fn counts_the_runs() {
+        let metrics = Metrics::new(10).unwrap();
+        metrics.run_started();
+        metrics.run_finished(false);
+        assert_eq!(
+            value(
+                &metrics,
+                "image_updater_last_successful_run_timestamp_seconds"
+            ),
+            Some(0.0)
+        );
+
+        metrics.run_started();
+        metrics.run_finished(true);
+        assert_eq!(
+            value(&metrics, "image_updater_runs_started_total"),
+            Some(2.0)
+        );
+        assert_eq!(
+            value(&metrics, "image_updater_runs_succeeded_total"),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(&metrics, "image_updater_runs_failed_total"),
+            Some(1.0)
+        );
+        let last_success = value(
+            &metrics,
+            "image_updater_last_successful_run_timestamp_seconds",
+        );
+        assert!(last_success.unwrap() > 1_700_000_000.0);
+    }
+
+    #[test]
+    fn counts_the_candidates_by_app() {
+        let metrics = Metrics::new(10).unwrap();
+        metrics.candidates(["web", "web", "web", "worker"], ["web"], ["web", "worker"]);
+
+        for (series, count) in [
+            (
+                r#"image_updater_candidates_processed_total{app="web"}"#,
+                3.0,
+            ),
+            (r#"image_updater_candidates_updated_total{app="web"}"#, 1.0),
+            (r#"image_updater_candidates_errored_total{app="web"}"#, 1.0),
+            (r#"image_updater_candidates_skipped_total{app="web"}"#, 1.0),
+            (
+                r#"image_updater_candidates_processed_total{app="worker"}"#,
+                1.0,
+            ),
+            (
+                r#"image_updater_candidates_errored_total{app="worker"}"#,
+                1.0,
+            ),
+            (
+                r#"image_updater_candidates_skipped_total{app="worker"}"#,
+                0.0,
+            ),
+        ] {
+            assert_eq!(value(&metrics, series), Some(count), "{series}");
+        }
+        assert_eq!(
+            value(
+                &metrics,
+                r#"image_updater_candidates_updated_total{app="worker"}"#
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn counts_the_pushes_and_registry_requests() {
+        let metrics = Metrics::new(10).unwrap();
+        metrics.pushed(true);
+        metrics.pushed(false);
+        metrics.registry_request("ghcr.io", Duration::from_millis(30));
+        metrics.registry_request("ghcr.io", Duration::from_millis(70));
+
+        assert_eq!(value(&metrics, "image_updater_git_pushes_total"), Some(2.0));
+        assert_eq!(
+            value(&metrics, "image_updater_git_push_failures_total"),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(
+                &metrics,
+                r#"image_updater_registry_request_duration_seconds_count{host="ghcr.io"}"#
+            ),
+            Some(2.0)
+        );
+        let seconds = value(
+            &metrics,
+            r#"image_updater_registry_request_duration_seconds_sum{host="ghcr.io"}"#,
+        );
+        assert!((seconds.unwrap() - 0.1).abs() < 1e-9);
+        assert!(metrics
+            .encode()
+            .unwrap()
+            .contains("# TYPE image_updater_registry_request_duration_seconds histogram"));
+    }
+
+    fn version(app: &str, current: &str, update_available: Option<bool>) -> CandidateVersion {
+        CandidateVersion {
+            app: app.to_string(),
+            image: format!("ghcr.io/org/{}", app),
+            current: current.to_string(),
+            update_available,
+        }
+    }
+
+    fn info(app: &str, current: &str) -> String {
+        format!(
+            r#"image_updater_candidate_info{{app="{app}",current_tag="{current}",image="ghcr.io/org/{app}",repo="deploy"}}"#
+        )
+    }
+
+    fn available(app: &str) -> String {
+        format!(
+            r#"image_updater_image_update_available{{app="{app}",image="ghcr.io/org/{app}",repo="deploy"}}"#
+        )
+    }
+
+    #[test]
+    fn exports_the_candidate_versions() {
+        let metrics = Metrics::new(10).unwrap();
+        metrics.candidate_versions(
+            "deploy",
+            vec![
+                version("web", "1.0.0", Some(true)),
+                version("api", "absent", Some(false)),
+                version("db", "2.0.0", None),
+            ],
+            true,
+        );
+
+        assert_eq!(value(&metrics, &info("web", "1.0.0")), Some(1.0));
+        assert_eq!(value(&metrics, &available("web")), Some(1.0));
+        assert_eq!(value(&metrics, &info("api", "absent")), Some(1.0));
+        assert_eq!(value(&metrics, &available("api")), Some(0.0));
+        assert_eq!(value(&metrics, &info("db", "2.0.0")), Some(1.0));
+        assert_eq!(value(&metrics, &available("db")), None);
+    }
+
+    #[test]
+    fn drops_the_series_of_previous_tags() {
+        let metrics = Metrics::new(10).unwrap();
+        metrics.candidate_versions("deploy", vec![version("web", "1.0.0", Some(true))], true);
+        metrics.candidate_versions("deploy", vec![version("web", "1.1.0", Some(false))], true);
+
+        assert_eq!(value(&metrics, &info("web", "1.0.0")), None);
+        assert_eq!(value(&metrics, &info("web", "1.1.0")), Some(1.0));
+        assert_eq!(value(&metrics, &available("web")), Some(0.0));
+    }
+
+    #[test]
+    fn drops_the_candidates_gone_after_a_complete_run() {
+        let metrics = Metrics::new(10).unwrap();
+        let both = || {
+            vec![
+                version("web", "1.0.0", Some(false)),
+                version("api", "1.0.0", Some(false)),
+            ]
+        };
+        metrics.candidate_versions("deploy", both(), true);
+
+        // A run for a single app says nothing about the others
+        metrics.candidate_versions("deploy", vec![version("web", "1.0.0", Some(false))], false);
+        assert_eq!(value(&metrics, &info("api", "1.0.0")), Some(1.0));
+
+        metrics.candidate_versions("deploy", vec![version("web", "1.0.0", Some(false))], true);
+        assert_eq!(value(&metrics, &info("api", "1.0.0")), None);
+        assert_eq!(value(&metrics, &available("api")), None);
+        assert_eq!(value(&metrics, &info("web", "1.0.0")), Some(1.0));
+    }
+
+    #[test]
+    fn caps_the_exported_candidates() {
+        let metrics = Metrics::new(2).unwrap();
+        let versions = |apps: &[&str]| {
+            apps.iter()
+                .map(|app| version(app, "1.0.0", Some(false)))
+                .collect()
+        };
+        let not_exported = r#"image_updater_candidates_not_exported{repo="deploy"}"#;
+
+        metrics.candidate_versions("deploy", versions(&["a", "b", "c"]), true);
+        assert_eq!(value(&metrics, &info("a", "1.0.0")), Some(1.0));
+        assert_eq!(value(&metrics, &info("b", "1.0.0")), Some(1.0));
+        assert_eq!(value(&metrics, &info("c", "1.0.0")), None);
+        assert_eq!(value(&metrics, not_exported), Some(1.0));
+
+        // The exported ones keep their series, new ones take the free spots
+        metrics.candidate_versions("deploy", versions(&["b", "c", "d"]), true);
+        assert_eq!(value(&metrics, &info("a", "1.0.0")), None);
+        assert_eq!(value(&metrics, &info("b", "1.0.0")), Some(1.0));
+        assert_eq!(value(&metrics, &info("c", "1.0.0")), Some(1.0));
+        assert_eq!(value(&metrics, &info("d", "1.0.0")), None);
+        assert_eq!(value(&metrics, not_exported), Some(1.0));
+
+        metrics.candidate_versions("deploy", versions(&["b", "c"]), true);
+        assert_eq!(value(&metrics, not_exported), Some(0.0));
+    }
+}
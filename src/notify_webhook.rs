@@ -0,0 +1,228 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config_file;
+
+/// How many times a report gets posted before giving up on it.
+const ATTEMPTS: u32 = 3;
+/// The delay before the first retry, doubling for each one after it.
+const BACKOFF: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts the report of each run to `NOTIFY_URL`.
+#[derive(Clone)]
+pub struct NotifyWebhook {
+    url: reqwest::Url,
+    /// Signs the reports in `X-Image-Updater-Signature-256` when set.
+    secret: Option<String>,
+    client: reqwest::Client,
+}
+
+impl NotifyWebhook {
+    /// Enabled with `NOTIFY_URL`, the reports being signed with `NOTIFY_SECRET`
+    /// if it's set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = config_file::var("NOTIFY_URL") else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            url: url.parse().context("NOTIFY_URL isn't a valid URL")?,
+            secret: config_file::var("NOTIFY_SECRET").ok(),
+            client: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+        }))
+    }
+
+    /// Posts the report in the background, retrying a few times when the
+    /// receiver fails. Failing to deliver it is only logged.
+    pub fn send(&self, report: serde_json::Value) -> tokio::task::JoinHandle<()> {
+        let webhook = self.clone();
+        let body = report.to_string();
+
+        tokio::spawn(async move {
+            for attempt in 1..=ATTEMPTS {
+                let e = match webhook.post(&body).await {
+                    Ok(()) => return,
+                    Err(e) => e,
+                };
+
+                // The receiver won't change its mind about a bad request
+                if attempt == ATTEMPTS || e.status().is_some_and(|s| s.is_client_error()) {
+                    log::warn!("Failed to send the run report, giving up: {}", e);
+                    return;
+                }
+                let delay = BACKOFF * 2u32.pow(attempt - 1);
+                log::info!(
+                    "Failed to send the run report ({}/{}), retrying in {:?}: {}",
+                    attempt,
+                    ATTEMPTS,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+            }
+        })
+    }
+
+    async fn post(&self, body: &str) -> Result<(), reqwest::Error> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::USER_AGENT, "image-updater");
+        if let Some(secret) = &self.secret {
+            request = request.header("X-Image-Updater-Signature-256", sign(secret, body));
+        }
+
+        // The URL can hold a token, it stays out of the logs
+        request
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map(drop)
+            .map_err(reqwest::Error::without_url)
+    }
+}
+
+/// The `sha256=` prefixed hex HMAC-SHA256 of the body, the way GitHub signs
+/// its webhooks.
+fn sign(secret: &str, body: &str) -> String {
+    // HMAC takes keys of any size
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        sync::{Arc, Mutex},
+    };
+
+    /// A request the receiver got, its lowercased headers and its body.
+    type Received = (Vec<(String, String)>, String);
+
+    /// A receiver answering with `statuses` in turn, keeping what it's sent.
+    fn receiver(statuses: &'static [u16]) -> (NotifyWebhook, Arc<Mutex<Vec<Received>>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(vec![]));
+        let requests = received.clone();
+        std::thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut headers = vec![];
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(": ") else {
+                        break;
+                    };
+                    headers.push((name.to_lowercase(), value.to_string()));
+                }
+                let length = headers
+                    .iter()
+                    .find(|(name, _)| name == "content-length")
+                    .map_or(0, |(_, length)| length.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                requests
+                    .lock()
+                    .unwrap()
+                    .push((headers, String::from_utf8(body).unwrap()));
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+
+        let webhook = NotifyWebhook {
+            url: url.parse().unwrap(),
+            secret: None,
+            client: reqwest::Client::new(),
+        };
+        (webhook, received)
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn signs_like_github() {
+        // GitHub's example, from its documentation
+        assert_eq!(
+            sign("It's a Secret to Everybody", "Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_the_signed_reports() {
+        let (mut webhook, received) = receiver(&[200]);
+        webhook.secret = Some("secret".to_string());
+        let report = serde_json::json!({ "status": "succeeded", "updated": [] });
+        webhook.send(report.clone()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let [(headers, body)] = received.as_slice() else {
+            panic!("Expected a single request, got {}", received.len());
+        };
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(body).unwrap(),
+            report
+        );
+        assert_eq!(header(headers, "content-type"), Some("application/json"));
+        assert_eq!(
+            header(headers, "x-image-updater-signature-256"),
+            Some(sign("secret", body).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn doesnt_sign_without_a_secret() {
+        let (webhook, received) = receiver(&[200]);
+        webhook.send(serde_json::json!({})).await.unwrap();
+
+        let (headers, _) = &received.lock().unwrap()[0];
+        assert_eq!(header(headers, "x-image-updater-signature-256"), None);
+    }
+
+    #[tokio::test]
+    async fn retries_when_the_receiver_fails() {
+        let (webhook, received) = receiver(&[503, 200]);
+        webhook.send(serde_json::json!({})).await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_bad_requests() {
+        let (webhook, received) = receiver(&[400, 200]);
+        webhook.send(serde_json::json!({})).await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
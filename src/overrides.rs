@@ -1,13 +1,30 @@
 use serde::{Deserialize, Serialize};
+use serde_yaml::Mapping;
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct Overrides {
+    #[serde(default, skip_serializing_if = "HelmOverride::is_empty")]
     pub helm: HelmOverride,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kustomize: Option<KustomizeOverride>,
+    #[serde(flatten)]
+    pub extra: Mapping,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct HelmOverride {
+    #[serde(default)]
     pub parameters: ParametersOverride,
+    #[serde(flatten)]
+    pub extra: Mapping,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct KustomizeOverride {
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(flatten)]
+    pub extra: Mapping,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -17,5 +34,187 @@ pub struct ParametersOverride(pub Vec<Parameter>);
 pub struct Parameter {
     pub name: String,
     pub value: String,
-    pub forcestring: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forcestring: Option<bool>,
+    #[serde(flatten)]
+    pub extra: Mapping,
+}
+
+impl HelmOverride {
+    fn is_empty(&self) -> bool {
+        self.parameters.0.is_empty() && self.extra.is_empty()
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .0
+            .iter()
+            .find(|parameter| parameter.name == name)
+            .map(|parameter| parameter.value.as_str())
+    }
+
+    /// Sets the parameter `name` to `value`, appending it if it doesn't exist
+    /// yet.
+    pub fn set_parameter(&mut self, name: &str, value: &str) {
+        let mut found = false;
+        for parameter in &mut self.parameters.0 {
+            if parameter.name == name {
+                parameter.value = value.to_string();
+                found = true;
+            }
+        }
+
+        if !found {
+            self.parameters.0.push(Parameter {
+                name: name.to_string(),
+                value: value.to_string(),
+                forcestring: Some(true),
+                extra: Mapping::new(),
+            });
+        }
+    }
+
+    /// Removes the parameters matched by `prune`, returning their names.
+    pub fn prune_parameters(&mut self, prune: impl Fn(&Parameter) -> bool) -> Vec<String> {
+        let mut pruned = vec![];
+        self.parameters.0.retain(|parameter| {
+            if prune(parameter) {
+                pruned.push(parameter.name.clone());
+                return false;
+            }
+            true
+        });
+
+        pruned
+    }
+}
+
+impl KustomizeOverride {
+    /// Returns the `images` entry overriding `name`.
+    pub fn image(&self, name: &str) -> Option<&str> {
+        self.images
+            .iter()
+            .find(|entry| image_name(entry) == name)
+            .map(String::as_str)
+    }
+
+    /// Replaces the `images` entry overriding `name`, appending `entry` if there
+    /// was none.
+    pub fn set_image(&mut self, name: &str, entry: &str) {
+        match self
+            .images
+            .iter_mut()
+            .find(|image| image_name(image) == name)
+        {
+            Some(image) => *image = entry.to_string(),
+            None => self.images.push(entry.to_string()),
+        }
+    }
+}
+
+/// Returns the image a `[old=]repo:tag[@digest]` kustomize entry applies to.
+fn image_name(entry: &str) -> &str {
+    if let Some((name, _)) = entry.split_once('=') {
+        return name;
+    }
+
+    let image = entry.split_once('@').map_or(entry, |(image, _)| image);
+    crate::split_tag(image).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVERRIDES: &str = "\
+helm:
+  parameters:
+  - name: image.tag
+    value: 1.2.3
+    forcestring: true
+  - name: replicas
+    value: '3'
+  valueFiles:
+  - values-prod.yaml
+  releaseName: web
+kustomize:
+  images:
+  - nginx:1.27
+  namePrefix: prod-
+plugin:
+  name: argocd-vault-plugin
+  env:
+  - name: AVP_TYPE
+    value: vault
+  - name: NESTED
+    value:
+      list:
+      - a: 1
+        b:
+        - 2
+        - 3
+";
+
+    #[test]
+    fn keeps_the_unknown_keys() {
+        let mut overrides: Overrides = serde_yaml::from_str(OVERRIDES).unwrap();
+        overrides.helm.set_parameter("image.tag", "1.3.0");
+
+        assert_eq!(
+            serde_yaml::to_string(&overrides).unwrap(),
+            OVERRIDES.replace("1.2.3", "1.3.0")
+        );
+    }
+
+    #[test]
+    fn appends_missing_parameters() {
+        let mut overrides: Overrides = serde_yaml::from_str(OVERRIDES).unwrap();
+        overrides.helm.set_parameter("sidecar.tag", "2.0");
+
+        let expected = OVERRIDES.replace(
+            "    value: '3'\n",
+            "    value: '3'\n  - name: sidecar.tag\n    value: '2.0'\n    forcestring: true\n",
+        );
+        assert_eq!(serde_yaml::to_string(&overrides).unwrap(), expected);
+    }
+
+    #[test]
+    fn parses_parameters_without_forcestring() {
+        let content = "helm:\n  parameters:\n  - name: image.tag\n    value: \"1.2.3\"\n";
+        let mut overrides: Overrides = serde_yaml::from_str(content).unwrap();
+        assert_eq!(overrides.helm.parameter("image.tag"), Some("1.2.3"));
+        assert_eq!(overrides.helm.parameters.0[0].forcestring, None);
+
+        overrides.helm.set_parameter("image.tag", "1.3.0");
+        assert_eq!(
+            serde_yaml::to_string(&overrides).unwrap(),
+            "helm:\n  parameters:\n  - name: image.tag\n    value: 1.3.0\n"
+        );
+    }
+
+    #[test]
+    fn finds_kustomize_images_by_name() {
+        let kustomize = KustomizeOverride {
+            images: vec![
+                "ghcr.io/org/web:1.0.0".to_string(),
+                "worker=ghcr.io/org/worker:2.0.0".to_string(),
+                "registry:5000/api@sha256:abcd".to_string(),
+            ],
+            extra: Mapping::new(),
+        };
+
+        assert_eq!(
+            kustomize.image("ghcr.io/org/web"),
+            Some("ghcr.io/org/web:1.0.0")
+        );
+        assert_eq!(
+            kustomize.image("worker"),
+            Some("worker=ghcr.io/org/worker:2.0.0")
+        );
+        assert_eq!(
+            kustomize.image("registry:5000/api"),
+            Some("registry:5000/api@sha256:abcd")
+        );
+        assert_eq!(kustomize.image("ghcr.io/org/worker"), None);
+    }
 }
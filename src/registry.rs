@@ -0,0 +1,2035 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use oci_client::{
+    client::{Certificate, CertificateEncoding, ClientConfig, ClientProtocol},
+    config::ConfigFile,
+    errors::{OciDistributionError, OciErrorCode},
+    manifest::OciManifest,
+    secrets::RegistryAuth,
+    Client, Reference,
+};
+use serde::Deserialize;
+
+use crate::{
+    cache::TagCache,
+    config::Config,
+    config_file,
+    filter::TagFilter,
+    strategy::{parse_version, UpdateStrategy},
+    Candidate,
+};
+
+/// Basic auth credentials keyed by registry host (including the port, if any).
+#[derive(Clone, Debug, Default)]
+pub struct RegistryCredentials(HashMap<String, (String, String)>);
+
+impl RegistryCredentials {
+    /// Parses a list like `ghcr.io=user:token,registry.internal:5000=robot:token`.
+    pub fn parse(credentials: &str) -> Result<Self> {
+        let mut parsed = Self::default();
+        for entry in credentials.split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (host, userpass) = entry
+                .split_once('=')
+                .with_context(|| format!("Missing `=` in registry credentials for {}", entry))?;
+            let (username, password) = userpass
+                .split_once(':')
+                .with_context(|| format!("Missing `:` in registry credentials for {}", host))?;
+            parsed.insert(host, username, password);
+        }
+
+        Ok(parsed)
+    }
+
+    /// Reads the `auths` section of a Docker `config.json`, as found in
+    /// `dockerconfigjson` pull secrets.
+    pub fn from_docker_config(path: &Path) -> Result<Self> {
+        let path = if path.is_dir() {
+            path.join("config.json")
+        } else {
+            path.to_path_buf()
+        };
+        let docker_config: DockerConfig = serde_json::from_str(
+            &std::fs::read_to_string(&path).with_context(|| format!("Reading {:?}", path))?,
+        )
+        .with_context(|| format!("Parsing {:?}", path))?;
+
+        let mut parsed = Self::default();
+        for (server, entry) in docker_config.auths {
+            // Keys can be full URLs, Docker Hub's one being `https://index.docker.io/v1/`
+            let host = server
+                .trim_start_matches("https://")
+                .trim_start_matches("http://")
+                .split('/')
+                .next()
+                .unwrap_or_default();
+
+            if let Some(identity_token) = entry.identitytoken {
+                // Registries issuing identity tokens accept them as the
+                // password of a placeholder user.
+                let username = entry
+                    .username
+                    .unwrap_or_else(|| "00000000-0000-0000-0000-000000000000".to_string());
+                parsed.insert(host, &username, &identity_token);
+            } else if let Some(auth) = entry.auth {
+                let decoded = base64::engine::general_purpose::STANDARD
+                    .decode(auth.trim())
+                    .ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())
+                    .with_context(|| format!("Invalid `auth` for {} in {:?}", server, path))?;
+                let (username, password) = decoded
+                    .split_once(':')
+                    .with_context(|| format!("Invalid `auth` for {} in {:?}", server, path))?;
+                parsed.insert(host, username, password);
+            } else if let (Some(username), Some(password)) = (entry.username, entry.password) {
+                parsed.insert(host, &username, &password);
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn insert(&mut self, host: &str, username: &str, password: &str) {
+        self.0.insert(
+            normalize_host(host).to_string(),
+            (username.to_string(), password.to_string()),
+        );
+    }
+
+    /// Merges `other` into `self`, with `other`'s entries taking precedence.
+    pub fn extend(&mut self, other: Self) {
+        self.0.extend(other.0);
+    }
+
+    /// Returns the auth to use for `host`, falling back to anonymous access when
+    /// no credentials were configured for it.
+    pub fn auth_for(&self, host: &str) -> RegistryAuth {
+        match self.0.get(normalize_host(host)) {
+            Some((username, password)) => RegistryAuth::Basic(username.clone(), password.clone()),
+            None => RegistryAuth::Anonymous,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// Proxies used to reach registries. `REGISTRY_*` variables take precedence
+/// over the standard ones so that registry traffic can be routed differently
+/// from the rest of the process.
+#[derive(Clone, Debug, Default)]
+pub struct ProxySettings {
+    pub https_proxy: Option<String>,
+    pub http_proxy: Option<String>,
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| config_file::var(name).ok())
+    }
+
+    /// Reads the settings from the variables `var` returns, the updater's own
+    /// winning over the standard ones.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let first_set = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| var(name).filter(|v| !v.is_empty()))
+        };
+
+        Self {
+            https_proxy: first_set(&["REGISTRY_HTTPS_PROXY", "HTTPS_PROXY", "https_proxy"]),
+            http_proxy: first_set(&["REGISTRY_HTTP_PROXY", "HTTP_PROXY", "http_proxy"]),
+            no_proxy: first_set(&["REGISTRY_NO_PROXY", "NO_PROXY", "no_proxy"])
+                .map(|no_proxy| {
+                    no_proxy
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|entry| !entry.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns whether `host` (optionally with a port) is excluded from
+    /// proxying. Entries match the host itself and all of its subdomains.
+    pub fn bypasses(&self, host: &str) -> bool {
+        let hostname = host.split(':').next().unwrap_or(host);
+        self.no_proxy.iter().any(|entry| {
+            let domain = entry.trim_start_matches('.');
+            entry == "*"
+                || entry == host
+                || hostname == domain
+                || hostname.ends_with(&format!(".{}", domain))
+        })
+    }
+}
+
+/// Per registry TLS settings.
+#[derive(Clone, Debug, Default)]
+pub struct TlsSettings {
+    ca_certificates: HashMap<String, Vec<Certificate>>,
+    insecure: Vec<String>,
+}
+
+impl TlsSettings {
+    /// Reads `REGISTRY_CA_CERTS` (`host=/path/to/ca.pem,...`) and
+    /// `INSECURE_REGISTRIES` (`host,host:port,...`). CA files are loaded and
+    /// validated right away so that a broken file is reported at startup.
+    pub fn from_env() -> Result<Self> {
+        let mut settings = Self::default();
+
+        if let Ok(ca_certs) = config_file::var("REGISTRY_CA_CERTS") {
+            for entry in ca_certs.split(',').map(str::trim) {
+                if entry.is_empty() {
+                    continue;
+                }
+
+                let (host, path) = entry
+                    .split_once('=')
+                    .with_context(|| format!("Missing `=` in REGISTRY_CA_CERTS for {}", entry))?;
+                let certificates = load_certificates(Path::new(path))
+                    .with_context(|| format!("Invalid CA certificates for {} in {}", host, path))?;
+                settings
+                    .ca_certificates
+                    .entry(normalize_host(host).to_string())
+                    .or_default()
+                    .extend(certificates);
+            }
+        }
+
+        if let Ok(insecure) = config_file::var("INSECURE_REGISTRIES") {
+            settings.insecure = insecure
+                .split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .map(|host| normalize_host(host).to_string())
+                .collect();
+        }
+
+        Ok(settings)
+    }
+
+    fn apply(&self, host: &str, client_config: &mut ClientConfig) {
+        let host = normalize_host(host);
+        if let Some(certificates) = self.ca_certificates.get(host) {
+            client_config
+                .extra_root_certificates
+                .extend(certificates.iter().cloned());
+        }
+
+        if self.insecure.iter().any(|insecure| insecure == host) {
+            client_config.protocol = ClientProtocol::Http;
+        }
+    }
+}
+
+/// Loads every certificate of a PEM bundle, making sure the OCI client accepts
+/// them.
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let bundle = std::fs::read_to_string(path)?;
+    let certificates = bundle
+        .split_inclusive("-----END CERTIFICATE-----")
+        .filter(|pem| pem.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|pem| Certificate {
+            encoding: CertificateEncoding::Pem,
+            data: pem.trim().as_bytes().to_vec(),
+        })
+        .collect::<Vec<_>>();
+
+    if certificates.is_empty() {
+        bail!("No PEM certificate found");
+    }
+
+    Client::try_from(ClientConfig {
+        extra_root_certificates: certificates.clone(),
+        ..Default::default()
+    })?;
+
+    Ok(certificates)
+}
+
+/// Builds an OCI client configured for talking to `host`.
+pub fn client_for(config: &Config, host: &str) -> Client {
+    Client::new(client_config(config, host))
+}
+
+/// The proxy and TLS settings of the OCI client for `host`.
+pub fn client_config(config: &Config, host: &str) -> ClientConfig {
+    let mut client_config = ClientConfig::default();
+    if !config.registry_proxy.bypasses(host) {
+        client_config.https_proxy = config.registry_proxy.https_proxy.clone();
+        client_config.http_proxy = config.registry_proxy.http_proxy.clone();
+    }
+    config.registry_tls.apply(host, &mut client_config);
+
+    client_config
+}
+
+/// Value of a `pull-secret` annotation.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PullSecret {
+    /// Always pull anonymously, even if credentials exist for the registry.
+    None,
+    /// `env:NAME`, the `username:password` in the `NAME` environment variable.
+    /// Only its name is kept, the credentials get read when they're needed.
+    Env(String),
+}
+
+impl FromStr for PullSecret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(var) = s.strip_prefix("env:") {
+            if var.is_empty() {
+                bail!("`env:` needs the name of an environment variable");
+            }
+            return Ok(Self::Env(var.to_string()));
+        }
+
+        match s {
+            "none" => Ok(Self::None),
+            _ => bail!("Unsupported pull secret: {}", s),
+        }
+    }
+}
+
+/// Value of a `require-signature` annotation, who has to have signed the
+/// selected tag with cosign.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SignatureRequirement {
+    /// A PEM public key, for the signatures made with `cosign sign --key`.
+    PublicKey(String),
+    /// The identity Fulcio certified for a keyless signature, an email or the
+    /// URI of a CI workflow, and the OIDC issuer it got it from, in
+    /// `signature-issuer`.
+    Keyless { identity: String, issuer: String },
+}
+
+impl SignatureRequirement {
+    pub fn parse(value: &str, issuer: Option<&str>) -> Result<Self> {
+        let value = value.trim();
+        if value.starts_with("-----BEGIN") {
+            if issuer.is_some() {
+                bail!("`signature-issuer` is only for keyless signatures, not public keys");
+            }
+            return Ok(Self::PublicKey(value.to_string()));
+        }
+
+        if value.is_empty() {
+            bail!("Needs a PEM public key or a keyless identity");
+        }
+        let issuer = issuer.context("A keyless identity needs a `signature-issuer`")?;
+        Ok(Self::Keyless {
+            identity: value.to_string(),
+            issuer: issuer.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for SignatureRequirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PublicKey(_) => write!(f, "the public key"),
+            Self::Keyless { identity, issuer } => write!(f, "{} from {}", identity, issuer),
+        }
+    }
+}
+
+/// One of the `platforms` a candidate has to be built for, like `linux/arm64`
+/// or `linux/arm/v7`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Platform {
+    /// Parses a comma separated list like `linux/amd64,linux/arm64`.
+    pub fn parse_list(platforms: &str) -> Result<Vec<Self>> {
+        platforms
+            .split(',')
+            .map(str::trim)
+            .filter(|platform| !platform.is_empty())
+            .map(Self::from_str)
+            .collect()
+    }
+
+    fn join(platforms: &[Self]) -> String {
+        platforms
+            .iter()
+            .map(Self::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Whether an image built for `built` runs on this platform, any variant
+    /// doing when none is asked for.
+    fn is_met_by(&self, built: &Platform) -> bool {
+        self.os == built.os
+            && self.architecture == built.architecture
+            && (self.variant.is_none() || self.variant == built.variant)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('/');
+        let (Some(os), Some(architecture), variant, None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            bail!("Expected os/architecture[/variant], got {}", s);
+        };
+        if os.is_empty() || architecture.is_empty() || variant == Some("") {
+            bail!("Expected os/architecture[/variant], got {}", s);
+        }
+
+        Ok(Self {
+            os: os.to_string(),
+            architecture: architecture.to_string(),
+            variant: variant.map(str::to_string),
+        })
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum number of tags (newest first) whose manifest gets inspected when
+/// looking for one built for the `platforms` of a candidate.
+const PLATFORMS_MAX_TAGS: usize = 10;
+
+/// The platforms the tags are built for, each of them getting inspected once
+/// per run.
+#[derive(Default)]
+pub struct PlatformChecks(Mutex<HashMap<String, Vec<Platform>>>);
+
+impl PlatformChecks {
+    /// Drops the newest of the sorted `tags` until one is built for all of
+    /// `required`, returning the rest and the ones dropped.
+    async fn filter(
+        &self,
+        config: &Config,
+        client: &Client,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        mut tags: Vec<String>,
+        required: &[Platform],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        let mut dropped = vec![];
+        while let Some(tag) = tags.last() {
+            if dropped.len() == PLATFORMS_MAX_TAGS {
+                break;
+            }
+
+            let tagged = Reference::with_tag(
+                reference.registry().to_string(),
+                reference.repository().to_string(),
+                tag.to_string(),
+            );
+            let built = match self.platforms(config, client, &tagged, auth).await {
+                Ok(built) => built,
+                Err(e) => {
+                    log::warn!(
+                        "Couldn't fetch the platforms of {}: {:#}. Skipping.",
+                        tagged,
+                        e
+                    );
+                    vec![]
+                }
+            };
+            let missing = required
+                .iter()
+                .filter(|platform| !built.iter().any(|built| platform.is_met_by(built)))
+                .cloned()
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                return Ok((tags, dropped));
+            }
+
+            log::info!(
+                "Passing over {}, it isn't built for {}",
+                tag,
+                Platform::join(&missing)
+            );
+            dropped.extend(tags.pop());
+        }
+
+        match dropped.first() {
+            Some(newest) => Err(MissingPlatforms {
+                platforms: required.to_vec(),
+                newest: newest.clone(),
+                checked: dropped.len(),
+            }
+            .into()),
+            None => Ok((tags, dropped)),
+        }
+    }
+
+    /// The platforms of the manifests an index lists, or the one of the config
+    /// of a single manifest.
+    async fn platforms(
+        &self,
+        config: &Config,
+        client: &Client,
+        tagged: &Reference,
+        auth: &RegistryAuth,
+    ) -> Result<Vec<Platform>> {
+        let key = tagged.whole();
+        if let Some(platforms) = self.0.lock().unwrap().get(&key) {
+            return Ok(platforms.clone());
+        }
+
+        let (manifest, _) =
+            with_timeout(config, tagged, client.pull_manifest(tagged, auth)).await?;
+        let platforms = match manifest {
+            OciManifest::ImageIndex(index) => index
+                .manifests
+                .into_iter()
+                .filter_map(|entry| entry.platform)
+                .map(|platform| Platform {
+                    os: platform.os.to_string(),
+                    architecture: platform.architecture.to_string(),
+                    variant: platform.variant,
+                })
+                .collect(),
+            OciManifest::Image(_) => {
+                let (_, _, image_config) = with_timeout(
+                    config,
+                    tagged,
+                    client.pull_manifest_and_config(tagged, auth),
+                )
+                .await?;
+                vec![serde_json::from_str(&image_config).context("Invalid image config")?]
+            }
+        };
+        self.0.lock().unwrap().insert(key, platforms.clone());
+
+        Ok(platforms)
+    }
+}
+
+/// Picks the auth to use when talking to the registry hosting `candidate`.
+pub async fn select_auth(config: &Config, candidate: &Candidate) -> Result<RegistryAuth> {
+    match &candidate.pull_secret {
+        Some(PullSecret::None) => return Ok(RegistryAuth::Anonymous),
+        Some(PullSecret::Env(var)) => return env_auth(var),
+        None => {}
+    }
+
+    let reference = Reference::from_str(&candidate.image)?;
+    let host = reference.registry();
+    let auth = config.registry_credentials.auth_for(host);
+
+    #[cfg(feature = "ecr")]
+    if matches!(auth, RegistryAuth::Anonymous) {
+        if let Some(region) = crate::ecr::ecr_region(host) {
+            return config.ecr_tokens.auth_for(host, region).await;
+        }
+    }
+
+    Ok(auth)
+}
+
+/// The credentials of an `env:` pull secret. Failing only the candidate using
+/// it, without ever saying what the variable holds.
+fn env_auth(var: &str) -> Result<RegistryAuth> {
+    let credentials = match std::env::var(var) {
+        Ok(credentials) if !credentials.is_empty() => credentials,
+        Ok(_) => bail!("The `pull-secret` environment variable {} is empty", var),
+        Err(_) => bail!("The `pull-secret` environment variable {} isn't set", var),
+    };
+    let Some((username, password)) = credentials.split_once(':') else {
+        bail!(
+            "The `pull-secret` environment variable {} isn't `username:password`",
+            var
+        );
+    };
+
+    Ok(RegistryAuth::Basic(
+        username.to_string(),
+        password.to_string(),
+    ))
+}
+
+/// The tags matching are all younger than the `min-age` of the candidate, it
+/// stays as it is until one is old enough.
+#[derive(Clone, Debug)]
+pub struct Soaking {
+    pub newest: String,
+    pub min_age: Duration,
+}
+
+impl std::fmt::Display for Soaking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No tag is older than {:?} yet, the newest being {}",
+            self.min_age, self.newest
+        )
+    }
+}
+
+impl std::error::Error for Soaking {}
+
+/// None of the newest tags matching is built for all the `platforms` of the
+/// candidate, it stays as it is until one is.
+#[derive(Clone, Debug)]
+pub struct MissingPlatforms {
+    pub platforms: Vec<Platform>,
+    pub newest: String,
+    pub checked: usize,
+}
+
+impl std::fmt::Display for MissingPlatforms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "None of the newest {} tags is built for {}, the newest being {}",
+            self.checked,
+            Platform::join(&self.platforms),
+            self.newest
+        )
+    }
+}
+
+impl std::error::Error for MissingPlatforms {}
+
+/// The registry answered with a 429 during this run.
+#[derive(Clone, Debug)]
+pub struct RateLimited {
+    pub host: String,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limited by {}", self.host)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Whether the manifests of the tags about to be written exist, with
+/// `VERIFY_MANIFESTS`, and whether they're signed, with `require-signature`.
+/// Each of them gets checked once per run.
+#[derive(Default)]
+pub struct ManifestChecks {
+    exists: Mutex<HashMap<String, bool>>,
+    /// Why they aren't signed, `None` when they are.
+    #[cfg(feature = "cosign")]
+    unsigned: Mutex<HashMap<(String, SignatureRequirement), Option<String>>>,
+    #[cfg(feature = "cosign")]
+    signatures: crate::cosign::Signatures,
+}
+
+impl ManifestChecks {
+    /// `tag` being a selected one, which can be pinned to a digest.
+    pub async fn exists(&self, config: &Config, candidate: &Candidate, tag: &str) -> Result<bool> {
+        let reference = selected_reference(candidate, tag)?;
+        let key = reference.whole();
+        if let Some(exists) = self.exists.lock().unwrap().get(&key) {
+            return Ok(*exists);
+        }
+
+        let auth = select_auth(config, candidate).await?;
+        let client = client_for(config, reference.registry());
+        let exists = match with_timeout(
+            config,
+            &reference,
+            client.fetch_manifest_digest(&reference, &auth),
+        )
+        .await
+        {
+            Ok(_) => true,
+            Err(e) if is_not_found(&e) => false,
+            Err(e) => return Err(e),
+        };
+        self.exists.lock().unwrap().insert(key, exists);
+
+        Ok(exists)
+    }
+
+    /// Returns why `tag` isn't signed as `requirement` wants, if it isn't.
+    #[cfg(feature = "cosign")]
+    pub async fn unsigned(
+        &self,
+        config: &Config,
+        candidate: &Candidate,
+        tag: &str,
+        requirement: &SignatureRequirement,
+    ) -> Result<Option<String>> {
+        let reference = selected_reference(candidate, tag)?;
+        let key = (reference.whole(), requirement.clone());
+        if let Some(unsigned) = self.unsigned.lock().unwrap().get(&key) {
+            return Ok(unsigned.clone());
+        }
+
+        let auth = select_auth(config, candidate).await?;
+        let client_config = client_config(config, reference.registry());
+        // It takes a few requests, the timeout is for all of them
+        let unsigned = tokio::time::timeout(
+            config.registry_timeout,
+            self.signatures
+                .verify(&client_config, &auth, &key.0, requirement),
+        )
+        .await
+        .map_err(|_| RegistryTimeout {
+            url: reference.whole(),
+        })??;
+        self.unsigned.lock().unwrap().insert(key, unsigned.clone());
+
+        Ok(unsigned)
+    }
+
+    #[cfg(not(feature = "cosign"))]
+    pub async fn unsigned(
+        &self,
+        _config: &Config,
+        _candidate: &Candidate,
+        _tag: &str,
+        _requirement: &SignatureRequirement,
+    ) -> Result<Option<String>> {
+        Ok(Some(
+            "This build can't verify signatures, enable the cosign feature".to_string(),
+        ))
+    }
+}
+
+/// The reference of a selected tag, which can be pinned to a digest.
+fn selected_reference(candidate: &Candidate, tag: &str) -> Result<Reference> {
+    let image = Reference::from_str(&candidate.image)?;
+    let (registry, repository) = (image.registry().to_string(), image.repository().to_string());
+    Ok(match tag.split_once('@') {
+        Some((_, digest)) => Reference::with_digest(registry, repository, digest.to_string()),
+        None => Reference::with_tag(registry, repository, tag.to_string()),
+    })
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<OciDistributionError>() {
+        Some(OciDistributionError::ServerError { code: 404, .. })
+        | Some(OciDistributionError::ImageManifestNotFoundError(_)) => true,
+        Some(OciDistributionError::RegistryError { envelope, .. }) => envelope
+            .errors
+            .iter()
+            .any(|error| error.code == OciErrorCode::ManifestUnknown),
+        _ => false,
+    }
+}
+
+/// Hosts that rate limited us during the current run. The OCI client doesn't
+/// expose `Retry-After`, so a host stays in cooldown until the run ends.
+#[derive(Default)]
+pub struct RateLimits(Mutex<HashSet<String>>);
+
+impl RateLimits {
+    pub fn is_limited(&self, host: &str) -> bool {
+        self.0.lock().unwrap().contains(normalize_host(host))
+    }
+
+    pub fn mark(&self, host: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(normalize_host(host).to_string());
+    }
+}
+
+pub fn is_rate_limited(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<OciDistributionError>() {
+        Some(OciDistributionError::ServerError { code: 429, .. }) => true,
+        Some(OciDistributionError::RegistryError { envelope, .. }) => envelope
+            .errors
+            .iter()
+            .any(|error| error.code == OciErrorCode::Toomanyrequests),
+        _ => false,
+    }
+}
+
+/// Whether the registry couldn't be reached or answered, as opposed to it
+/// answering with something that doesn't suit.
+pub fn is_unavailable(e: &anyhow::Error) -> bool {
+    if e.is::<RegistryTimeout>() {
+        return true;
+    }
+
+    match e.downcast_ref::<OciDistributionError>() {
+        Some(OciDistributionError::RequestError(_)) => true,
+        Some(OciDistributionError::ServerError { code, .. }) => *code >= 500,
+        _ => false,
+    }
+}
+
+/// Returns whether the registry rejected our credentials.
+#[cfg(feature = "ecr")]
+pub fn is_unauthorized(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<OciDistributionError>(),
+        Some(
+            OciDistributionError::UnauthorizedError { .. }
+                | OciDistributionError::AuthenticationFailure(_)
+                | OciDistributionError::ServerError { code: 401, .. }
+        )
+    )
+}
+
+/// Docker Hub goes by many names, map them all to the one its API is served
+/// from.
+pub fn normalize_host(host: &str) -> &str {
+    match host {
+        "docker.io" | "index.docker.io" => "registry-1.docker.io",
+        host => host,
+    }
+}
+
+/// A registry call that didn't complete within `REGISTRY_TIMEOUT_SECS`.
+#[derive(Clone, Debug)]
+pub struct RegistryTimeout {
+    pub url: String,
+}
+
+impl std::fmt::Display for RegistryTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timed out talking to the registry for {}", self.url)
+    }
+}
+
+impl std::error::Error for RegistryTimeout {}
+
+async fn with_timeout<T, E>(
+    config: &Config,
+    reference: &Reference,
+    call: impl Future<Output = std::result::Result<T, E>>,
+) -> Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let start = Instant::now();
+    let result = tokio::time::timeout(config.registry_timeout, call).await;
+    config
+        .metrics
+        .registry_request(reference.registry(), start.elapsed());
+
+    match result {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(RegistryTimeout {
+            url: reference.whole(),
+        }
+        .into()),
+    }
+}
+
+/// The tag selected for a candidate.
+pub struct Selection {
+    pub tag: String,
+    /// The newer prerelease passed over, without `allow-prerelease`.
+    pub skipped_prerelease: Option<String>,
+    /// The newer tags passed over, not being built for all the `platforms`.
+    pub without_platforms: Vec<String>,
+}
+
+pub async fn get_latest_tag_for_candidate(
+    config: &Config,
+    candidate: &Candidate,
+    auth: &RegistryAuth,
+    cache: Option<&TagCache>,
+    platforms: &PlatformChecks,
+) -> Result<Selection> {
+    log::info!("Getting latest tag for candidate: {}", candidate.image);
+    let filter = &candidate.tag_filter;
+    let reference = Reference::from_str(&candidate.image)?;
+    let client = client_for(config, reference.registry());
+
+    if let Some(pinned_tag) = &candidate.pinned_tag {
+        let reference = Reference::with_tag(
+            reference.registry().to_string(),
+            reference.repository().to_string(),
+            pinned_tag.clone(),
+        );
+        let digest = with_timeout(
+            config,
+            &reference,
+            client.fetch_manifest_digest(&reference, auth),
+        )
+        .await?;
+        return Ok(Selection {
+            tag: format!("{}@{}", pinned_tag, digest),
+            skipped_prerelease: None,
+            without_platforms: vec![],
+        });
+    }
+
+    let tags = match cache.and_then(|cache| cache.get(&candidate.image)) {
+        Some(tags) => {
+            log::debug!("Using cached tags for {}", candidate.image);
+            tags
+        }
+        None => {
+            let tags = list_all_tags(config, &client, &reference, auth).await?;
+            if let Some(cache) = cache {
+                cache.insert(&candidate.image, tags.clone());
+            }
+            tags
+        }
+    };
+
+    let tags = tags
+        .into_iter()
+        .filter(|name| filter.matches(name))
+        .filter(|name| {
+            !candidate
+                .ignore_tags
+                .iter()
+                .any(|ignored| ignored.matches(name))
+        })
+        .collect::<Vec<_>>();
+
+    let tags = match filter {
+        TagFilter::Semver(_) => UpdateStrategy::Semver.sort_tags(tags, &candidate.tag_date_format),
+        // Like `release-(.*)-bullseye`, the tags get ordered by what's captured
+        TagFilter::Regexp(re) if re.captures_len() > 1 => {
+            let versions = tags
+                .into_iter()
+                .map(|tag| (filter.version(&tag).unwrap_or(&tag).to_string(), tag))
+                .collect();
+            candidate
+                .strategy
+                .sort_versions(versions, &candidate.tag_date_format)
+        }
+        TagFilter::Regexp(_) | TagFilter::Globs(_) => candidate
+            .strategy
+            .sort_tags(tags, &candidate.tag_date_format),
+    };
+
+    // Only what gets read as a semantic version can be told to be a
+    // prerelease: to the other strategies the `-alpine` of `1.25.3-alpine`
+    // names a variant
+    let is_prerelease = |tag: &str| {
+        let version = match filter {
+            TagFilter::Regexp(re) if re.captures_len() > 1 => filter.version(tag),
+            TagFilter::Semver(_) => Some(tag),
+            _ if candidate.strategy == UpdateStrategy::Semver => Some(tag),
+            _ => None,
+        };
+        version
+            .and_then(parse_version)
+            .is_some_and(|version| !version.pre.is_empty())
+    };
+    let (tags, skipped_prerelease) = match candidate.allow_prerelease {
+        true => (tags, None),
+        false => {
+            let skipped = tags.last().filter(|tag| is_prerelease(tag)).cloned();
+            let tags = tags
+                .into_iter()
+                .filter(|tag| !is_prerelease(tag))
+                .collect::<Vec<_>>();
+            (tags, skipped)
+        }
+    };
+    let (tags, without_platforms) = match candidate.platforms.is_empty() {
+        true => (tags, vec![]),
+        false => {
+            platforms
+                .filter(
+                    config,
+                    &client,
+                    &reference,
+                    auth,
+                    tags,
+                    &candidate.platforms,
+                )
+                .await?
+        }
+    };
+
+    let newest = tags.last().cloned();
+    let now = Utc::now();
+    let latest = match (candidate.strategy, candidate.min_age) {
+        (UpdateStrategy::NewestBuild, min_age) => {
+            find_newest_build(config, &client, &reference, auth, tags, min_age, now).await
+        }
+        (_, Some(min_age)) => {
+            find_soaked(config, &client, &reference, auth, tags, min_age, now).await
+        }
+        (_, None) => tags.last().cloned(),
+    };
+
+    let tag = match (latest, newest, candidate.min_age) {
+        (Some(tag), _, _) => tag,
+        (None, Some(newest), Some(min_age)) => return Err(Soaking { newest, min_age }.into()),
+        (None, _, _) => bail!("No tags matched {} for {}", filter, candidate.app_name),
+    };
+    if let Some(skipped) = &skipped_prerelease {
+        log::info!("Passing over prerelease {} for {}", skipped, tag);
+    }
+
+    Ok(Selection {
+        tag,
+        // The newest builds aren't the last ones by name
+        skipped_prerelease: skipped_prerelease
+            .filter(|_| candidate.strategy != UpdateStrategy::NewestBuild),
+        without_platforms,
+    })
+}
+
+/// Lists the first tags of `image`, to find out whether the registry can be
+/// reached with the configured credentials.
+pub async fn list_some_tags(config: &Config, image: &str) -> Result<Vec<String>> {
+    let reference = Reference::from_str(image)?;
+    let host = reference.registry();
+    let auth = config.registry_credentials.auth_for(host);
+    #[cfg(feature = "ecr")]
+    let auth = match (&auth, crate::ecr::ecr_region(host)) {
+        (RegistryAuth::Anonymous, Some(region)) => config.ecr_tokens.auth_for(host, region).await?,
+        _ => auth,
+    };
+
+    let client = client_for(config, host);
+    let tags = with_timeout(
+        config,
+        &reference,
+        client.list_tags(&reference, &auth, Some(10), None),
+    )
+    .await?;
+
+    Ok(tags.tags)
+}
+
+/// Lists the tags of `reference`, following pagination until the registry runs
+/// out of tags or `MAX_TAGS_PER_REPO` have been collected.
+async fn list_all_tags(
+    config: &Config,
+    client: &Client,
+    reference: &Reference,
+    auth: &RegistryAuth,
+) -> Result<Vec<String>> {
+    let max_tags = config.max_tags_per_repo;
+    let mut tags = with_timeout(
+        config,
+        reference,
+        client.list_tags(reference, auth, None, None),
+    )
+    .await?
+    .tags;
+
+    while tags.len() < max_tags {
+        let last = tags.last().cloned();
+        let page = match with_timeout(
+            config,
+            reference,
+            client.list_tags(reference, auth, None, last.as_deref()),
+        )
+        .await
+        {
+            Ok(response) => response.tags,
+            // Some registries answer the page after the last one with a 404 or
+            // a null tag list, keep what we got so far. Any other failure would
+            // have the latest tag picked out of part of them.
+            Err(e) if is_past_last_page(&e) => {
+                log::debug!("Stopping tag pagination for {}: {}", reference, e);
+                break;
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "Failed to list the tags of {} after {}",
+                    reference,
+                    last.unwrap_or_default()
+                )))
+            }
+        };
+
+        if page.is_empty() || page.last() == last.as_ref() {
+            break;
+        }
+        tags.extend(page);
+    }
+
+    if tags.len() >= max_tags {
+        log::warn!(
+            "{} has at least {} tags, ignoring the rest",
+            reference,
+            max_tags
+        );
+        tags.truncate(max_tags);
+    }
+
+    Ok(tags)
+}
+
+/// Whether the tag page asked for doesn't exist, which is how some registries
+/// answer for the page after the last one.
+fn is_past_last_page(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<OciDistributionError>() {
+        Some(OciDistributionError::ServerError { code: 404, .. }) => true,
+        Some(OciDistributionError::RegistryError { envelope, .. }) => {
+            !envelope.errors.is_empty()
+                && envelope.errors.iter().all(|error| {
+                    matches!(
+                        error.code,
+                        OciErrorCode::NameUnknown | OciErrorCode::NotFound
+                    )
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Maximum number of tags (newest by name first) whose config gets inspected by
+/// the newest-build strategy.
+const NEWEST_BUILD_MAX_TAGS: usize = 20;
+/// Maximum number of in-flight config fetches for the newest-build strategy.
+const NEWEST_BUILD_CONCURRENCY: usize = 5;
+
+async fn find_newest_build(
+    config: &Config,
+    client: &Client,
+    reference: &Reference,
+    auth: &RegistryAuth,
+    tags: Vec<String>,
+    min_age: Option<Duration>,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let mut builds = futures::stream::iter(tags.into_iter().rev().take(NEWEST_BUILD_MAX_TAGS))
+        .map(|tag| async move {
+            let created = build_time(config, client, reference, auth, &tag).await?;
+            Some((created, tag))
+        })
+        .buffer_unordered(NEWEST_BUILD_CONCURRENCY)
+        .filter_map(|build| async { build })
+        .collect::<Vec<_>>()
+        .await;
+
+    builds.sort();
+    builds
+        .into_iter()
+        .rev()
+        .find(|(created, _)| min_age.is_none_or(|min_age| is_soaked(*created, min_age, now)))
+        .map(|(_, tag)| tag)
+}
+
+/// The newest of the sorted `tags` that was built at least `min_age` before
+/// `now`, looking at as many of them as the newest-build strategy does.
+async fn find_soaked(
+    config: &Config,
+    client: &Client,
+    reference: &Reference,
+    auth: &RegistryAuth,
+    tags: Vec<String>,
+    min_age: Duration,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    for tag in tags.into_iter().rev().take(NEWEST_BUILD_MAX_TAGS) {
+        let Some(created) = build_time(config, client, reference, auth, &tag).await else {
+            continue;
+        };
+        if is_soaked(created, min_age, now) {
+            return Some(tag);
+        }
+        log::info!(
+            "Passing over {}, built at {} which is less than {:?} ago",
+            tag,
+            created,
+            min_age
+        );
+    }
+
+    None
+}
+
+fn is_soaked(created: DateTime<Utc>, min_age: Duration, now: DateTime<Utc>) -> bool {
+    now.signed_duration_since(created)
+        .to_std()
+        .is_ok_and(|age| age >= min_age)
+}
+
+/// When the image tagged `tag` was built, according to the `created` field of
+/// its config.
+async fn build_time(
+    config: &Config,
+    client: &Client,
+    reference: &Reference,
+    auth: &RegistryAuth,
+    tag: &str,
+) -> Option<DateTime<Utc>> {
+    let tagged = Reference::with_tag(
+        reference.registry().to_string(),
+        reference.repository().to_string(),
+        tag.to_string(),
+    );
+    let image_config = match with_timeout(
+        config,
+        &tagged,
+        client.pull_manifest_and_config(&tagged, auth),
+    )
+    .await
+    {
+        Ok((_, _, image_config)) => image_config,
+        Err(e) => {
+            log::warn!("Couldn't fetch the config of {}: {}. Skipping.", tagged, e);
+            return None;
+        }
+    };
+    match serde_json::from_str::<ConfigFile>(&image_config) {
+        Ok(ConfigFile {
+            created: Some(created),
+            ..
+        }) => Some(created),
+        Ok(_) => {
+            log::warn!("{} has no creation date. Skipping.", tagged);
+            None
+        }
+        Err(e) => {
+            log::warn!("Couldn't parse the config of {}: {}. Skipping.", tagged, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        sync::{
+            atomic::{self, AtomicUsize},
+            Arc,
+        },
+    };
+
+    use sha2::Digest;
+
+    use super::*;
+    use crate::{config::test_config, filter::IgnoredTag};
+
+    /// Answers the requests of the registry with `respond`, which gets their
+    /// path and query, returning the address it listens on.
+    pub(crate) fn serve(respond: impl Fn(&str) -> (u16, String) + Send + Sync + 'static) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let respond = Arc::new(respond);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let respond = respond.clone();
+                std::thread::spawn(move || {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request = String::new();
+                    reader.read_line(&mut request).unwrap();
+                    let mut header = String::new();
+                    while reader.read_line(&mut header).unwrap() > 2 {
+                        header.clear();
+                    }
+
+                    let mut request = request.split(' ');
+                    let method = request.next().unwrap_or_default();
+                    let target = request.next().unwrap_or_default();
+                    let (status, body) = match target {
+                        "/v2/" => (200, "{}".to_string()),
+                        target => respond(target),
+                    };
+                    // Like the registries, for the manifests
+                    let digest = format!("sha256:{:x}", sha2::Sha256::digest(&body));
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nDocker-Content-Digest: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        digest,
+                        if method == "HEAD" { "" } else { &body }
+                    )
+                    .unwrap();
+                });
+            }
+        });
+
+        address
+    }
+
+    /// The test configuration, talking plain HTTP to the registry at
+    /// `address`.
+    pub(crate) fn config_for(address: &str) -> Config {
+        let mut config = test_config();
+        config.registry_tls.insecure = vec![address.to_string()];
+        config
+    }
+
+    pub(crate) fn page(tags: &[&str]) -> (u16, String) {
+        let tags = serde_json::to_string(tags).unwrap();
+        (200, format!(r#"{{"name":"team/web","tags":{}}}"#, tags))
+    }
+
+    /// Lists the tags of `team/web`, answering the first page with 1.0.0 and
+    /// 1.1.0 and the next ones with `next`.
+    async fn list(
+        next: impl Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    ) -> Result<Vec<String>> {
+        let address = serve(move |target| match target {
+            "/v2/team/web/tags/list" => page(&["1.0.0", "1.1.0"]),
+            target => next(target),
+        });
+        let client = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+        let reference = Reference::from_str(&format!("{}/team/web", address)).unwrap();
+
+        list_all_tags(
+            &test_config(),
+            &client,
+            &reference,
+            &RegistryAuth::Anonymous,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn follows_the_pages() {
+        let tags = list(|target| match target {
+            "/v2/team/web/tags/list?last=1.1.0" => page(&["1.2.0"]),
+            _ => (404, String::new()),
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(tags, ["1.0.0", "1.1.0", "1.2.0"]);
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_second_page_failing() {
+        let e = list(|_| (500, "oops".to_string())).await.unwrap_err();
+
+        assert!(is_unavailable(&e), "{:#}", e);
+        assert!(format!("{:#}", e).contains("after 1.1.0"), "{:#}", e);
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_second_page_rate_limited() {
+        let e = list(|_| (429, String::new())).await.unwrap_err();
+
+        assert!(is_rate_limited(&e), "{:#}", e);
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_second_page_denied() {
+        let envelope = r#"{"errors":[{"code":"DENIED","message":"no"}]}"#;
+        let e = list(|_| (403, envelope.to_string())).await.unwrap_err();
+
+        assert!(
+            matches!(
+                e.downcast_ref::<OciDistributionError>(),
+                Some(OciDistributionError::RegistryError { .. })
+            ),
+            "{:#}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_missing_page() {
+        let tags = list(|_| (404, String::new())).await.unwrap();
+        assert_eq!(tags, ["1.0.0", "1.1.0"]);
+
+        let envelope = r#"{"errors":[{"code":"NAME_UNKNOWN","message":"no"}]}"#;
+        let tags = list(|_| (404, envelope.to_string())).await.unwrap();
+        assert_eq!(tags, ["1.0.0", "1.1.0"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_an_empty_page() {
+        let tags = list(|_| page(&[])).await.unwrap();
+        assert_eq!(tags, ["1.0.0", "1.1.0"]);
+
+        let null = r#"{"name":"team/web","tags":null}"#;
+        let tags = list(|_| (200, null.to_string())).await.unwrap();
+        assert_eq!(tags, ["1.0.0", "1.1.0"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_last_tag_repeating() {
+        let tags = list(|_| page(&["1.1.0"])).await.unwrap();
+
+        assert_eq!(tags, ["1.0.0", "1.1.0"]);
+    }
+
+    /// Selects the tag for `candidate` among `tags`, as if the registry had
+    /// listed them.
+    async fn select(candidate: &Candidate, tags: &[&str]) -> Result<String> {
+        let cache = TagCache::new(Duration::from_secs(60));
+        cache.insert(
+            &candidate.image,
+            tags.iter().map(|tag| tag.to_string()).collect(),
+        );
+
+        let selection = get_latest_tag_for_candidate(
+            &test_config(),
+            candidate,
+            &RegistryAuth::Anonymous,
+            Some(&cache),
+            &PlatformChecks::default(),
+        )
+        .await?;
+        Ok(selection.tag)
+    }
+
+    #[tokio::test]
+    async fn selects_within_semver_constraints() {
+        let tags = [
+            "1.3.9",
+            "1.4.0",
+            "1.4.10",
+            "1.10.0",
+            "2.3.1",
+            "2.4.0",
+            "latest",
+            "sha-abcdef",
+        ];
+
+        let candidate = Candidate::test("ghcr.io/org/web", "semver:^1.4");
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "1.10.0");
+        let candidate = Candidate::test("ghcr.io/org/web", "semver:~2.3");
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "2.3.1");
+        let candidate = Candidate::test("ghcr.io/org/web", "semver:>=1.0, <1.5");
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "1.4.10");
+    }
+
+    #[tokio::test]
+    async fn names_the_constraint_nothing_matched() {
+        let candidate = Candidate::test("ghcr.io/org/web", "semver:^3");
+        let e = select(&candidate, &["1.0.0", "2.0.0", "latest"])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "No tags matched the semver constraint ^3 for web"
+        );
+    }
+
+    #[tokio::test]
+    async fn selects_the_digest_of_the_pinned_tag() {
+        let manifest =
+            r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json"}"#;
+        let digest = format!("sha256:{:x}", sha2::Sha256::digest(manifest));
+        let address = serve(move |target| match target {
+            "/v2/team/web/manifests/stable" => (200, manifest.to_string()),
+            _ => (404, "{}".to_string()),
+        });
+
+        let config = config_for(&address);
+        let candidate = Candidate {
+            strategy: UpdateStrategy::Digest,
+            pinned_tag: Some("stable".to_string()),
+            ..Candidate::test(&format!("{}/team/web", address), "")
+        };
+        let selection = get_latest_tag_for_candidate(
+            &config,
+            &candidate,
+            &RegistryAuth::Anonymous,
+            None,
+            &PlatformChecks::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(selection.tag, format!("stable@{}", digest));
+    }
+
+    /// Serves `team/web` with the images built at the `created` dates, by tag,
+    /// the ones without a date having no config. Counts the manifest pulls.
+    fn serve_builds(
+        created: &'static [(&'static str, Option<&'static str>)],
+        pulls: Arc<AtomicUsize>,
+    ) -> String {
+        let config = |created: &str| {
+            format!(
+                r#"{{"created":"{}","architecture":"amd64","os":"linux","rootfs":{{"type":"layers","diff_ids":[]}}}}"#,
+                created
+            )
+        };
+        let digest = |content: &str| format!("sha256:{:x}", sha2::Sha256::digest(content));
+
+        serve(move |target| {
+            let tags = created.iter().map(|(tag, _)| *tag).collect::<Vec<_>>();
+            if target == "/v2/team/web/tags/list" {
+                return page(&tags);
+            }
+            if let Some(tag) = target.strip_prefix("/v2/team/web/manifests/") {
+                pulls.fetch_add(1, atomic::Ordering::SeqCst);
+                let Some((_, Some(created))) = created.iter().find(|(name, _)| *name == tag) else {
+                    return (404, "{}".to_string());
+                };
+                let config = config(created);
+                let manifest = format!(
+                    r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":{}}},"layers":[]}}"#,
+                    digest(&config),
+                    config.len()
+                );
+                return (200, manifest);
+            }
+            if let Some(blob) = target.strip_prefix("/v2/team/web/blobs/") {
+                for (_, created) in created {
+                    if let Some(config) = created.map(config) {
+                        if digest(&config) == blob {
+                            return (200, config);
+                        }
+                    }
+                }
+            }
+            (404, "{}".to_string())
+        })
+    }
+
+    async fn select_newest_build(address: &str, allow_tags: &str) -> Result<String> {
+        let config = config_for(address);
+        let candidate = Candidate {
+            strategy: UpdateStrategy::NewestBuild,
+            ..Candidate::test(&format!("{}/team/web", address), allow_tags)
+        };
+
+        let selection = get_latest_tag_for_candidate(
+            &config,
+            &candidate,
+            &RegistryAuth::Anonymous,
+            None,
+            &PlatformChecks::default(),
+        )
+        .await?;
+        Ok(selection.tag)
+    }
+
+    #[tokio::test]
+    async fn selects_the_newest_build() {
+        let address = serve_builds(
+            &[
+                ("build-aaa", Some("2024-06-03T10:00:00Z")),
+                ("build-bbb", None),
+                ("build-ccc", Some("2024-06-01T10:00:00Z")),
+                ("build-ddd", Some("2024-06-02T10:00:00Z")),
+                ("latest", Some("2024-06-04T10:00:00Z")),
+            ],
+            Arc::default(),
+        );
+
+        assert_eq!(
+            select_newest_build(&address, "build-*").await.unwrap(),
+            "build-aaa"
+        );
+    }
+
+    #[tokio::test]
+    async fn inspects_the_last_builds_by_name_only() {
+        static BUILDS: std::sync::LazyLock<Vec<(&str, Option<&str>)>> =
+            std::sync::LazyLock::new(|| {
+                (10..40)
+                    .map(|build| {
+                        let tag: &str = format!("build-{}", build).leak();
+                        let created: &str = format!("2024-06-{}T10:00:00Z", 40 - build).leak();
+                        (tag, (build < 30).then_some(created))
+                    })
+                    .collect()
+            });
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let address = serve_builds(&BUILDS, pulls.clone());
+
+        // The ones built first are past the 20 newest by name
+        assert_eq!(
+            select_newest_build(&address, "build-*").await.unwrap(),
+            "build-20"
+        );
+        assert_eq!(pulls.load(atomic::Ordering::SeqCst), NEWEST_BUILD_MAX_TAGS);
+    }
+
+    #[tokio::test]
+    async fn passes_over_ignored_tags() {
+        let tags = ["1.0.0", "1.1.0", "1.2.0", "1.2.1-broken"];
+
+        let candidate = Candidate {
+            ignore_tags: IgnoredTag::parse_list("1.2.0").unwrap(),
+            ..Candidate::test("ghcr.io/org/web", "semver:*")
+        };
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "1.1.0");
+
+        let candidate = Candidate {
+            ignore_tags: IgnoredTag::parse_list("regexp:-broken$, 1.2.0").unwrap(),
+            ..Candidate::test("ghcr.io/org/web", "1.*")
+        };
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "1.1.0");
+    }
+
+    #[tokio::test]
+    async fn sorts_by_the_captured_version() {
+        let tags = [
+            "release-1.9.0-bullseye",
+            "release-1.42.0-bookworm",
+            "release-1.42.0-bullseye",
+            "release-1.5.0-trixie",
+        ];
+        let candidate = Candidate {
+            strategy: UpdateStrategy::Semver,
+            ..Candidate::test(
+                "ghcr.io/org/web",
+                r"regexp:^release-(?<version>[\d.]+)-(bullseye|bookworm|trixie)$",
+            )
+        };
+        // The same versions are ordered by their whole tags
+        assert_eq!(
+            select(&candidate, &tags).await.unwrap(),
+            "release-1.42.0-bullseye"
+        );
+
+        // By name the suffix would win, `zeta-5` coming after `alpha-10`
+        let tags = ["build-alpha-10", "build-zeta-5", "build-beta-7"];
+        let candidate = Candidate::test("ghcr.io/org/web", r"regexp:^build-[a-z]+-(\d+)$");
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "build-alpha-10");
+        let candidate = Candidate::test("ghcr.io/org/web", r"regexp:^build-[a-z]+-\d+$");
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "build-zeta-5");
+    }
+
+    #[tokio::test]
+    async fn only_passes_over_the_semver_prereleases() {
+        // The suffix of a variant isn't a prerelease
+        let tags = ["1.25.1-alpine", "1.25.3-alpine", "1.25.2-alpine", "1.25.3"];
+        let candidate = Candidate::test("docker.io/library/nginx", r"regexp:^1\.25\.\d+-alpine$");
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "1.25.3-alpine");
+        let candidate = Candidate::test("docker.io/library/redis", "7.2-*");
+        let tags = ["7.2-bullseye", "7.2-bookworm"];
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "7.2-bullseye");
+
+        // Nor is one outside of what's captured
+        let tags = [
+            "release-1.0.0-bookworm",
+            "release-1.1.0-bookworm",
+            "release-1.2.0-rc.1-bookworm",
+        ];
+        let candidate = Candidate::test(
+            "ghcr.io/org/web",
+            r"regexp:^release-(?<version>[\w.-]+)-bookworm$",
+        );
+        assert_eq!(
+            select(&candidate, &tags).await.unwrap(),
+            "release-1.1.0-bookworm"
+        );
+
+        let tags = ["1.0.0", "1.1.0", "1.2.0-rc.1"];
+        let candidate = Candidate {
+            strategy: UpdateStrategy::Semver,
+            ..Candidate::test("ghcr.io/org/web", "regexp:.*")
+        };
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "1.1.0");
+        let candidate = Candidate::test("ghcr.io/org/web", "semver:>=1.0.0-0");
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "1.1.0");
+    }
+
+    #[tokio::test]
+    async fn sorts_by_the_whole_tag_without_a_capture() {
+        let tags = ["v1.2.0", "nightly", "v1.10.0"];
+        let allow_tags = r"regexp:^(?:v(?<version>\d+\.\d+\.\d+)|nightly)$";
+
+        let candidate = Candidate {
+            strategy: UpdateStrategy::Semver,
+            ..Candidate::test("ghcr.io/org/web", allow_tags)
+        };
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "v1.10.0");
+        assert!(select(&candidate, &["nightly"]).await.is_err());
+
+        // By name, the versions come after the rest
+        let candidate = Candidate::test("ghcr.io/org/web", allow_tags);
+        assert_eq!(select(&candidate, &tags).await.unwrap(), "v1.10.0");
+        assert_eq!(select(&candidate, &["nightly"]).await.unwrap(), "nightly");
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn tells_the_soaked_builds() {
+        let min_age = Duration::from_secs(30 * 60);
+        let now = at("2024-06-04T10:30:00Z");
+
+        assert!(is_soaked(at("2024-06-04T09:59:00Z"), min_age, now));
+        assert!(is_soaked(at("2024-06-04T10:00:00Z"), min_age, now));
+        assert!(!is_soaked(at("2024-06-04T10:00:01Z"), min_age, now));
+        // Built in the future, as far as the clocks go
+        assert!(!is_soaked(at("2024-06-04T11:00:00Z"), min_age, now));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_newest_soaked_tag() {
+        let address = serve_builds(
+            &[
+                ("1.0.0", Some("2024-06-01T10:00:00Z")),
+                ("1.1.0", Some("2024-06-04T09:00:00Z")),
+                ("1.2.0", None),
+                ("1.3.0", Some("2024-06-04T10:20:00Z")),
+            ],
+            Arc::default(),
+        );
+        let config = config_for(&address);
+        let reference = Reference::from_str(&format!("{}/team/web", address)).unwrap();
+        let client = client_for(&config, reference.registry());
+        let soaked = |min_age: u64, now: &str| {
+            let (config, client, reference) = (&config, &client, &reference);
+            let tags = ["1.0.0", "1.1.0", "1.2.0", "1.3.0"]
+                .map(String::from)
+                .to_vec();
+            let (min_age, now) = (Duration::from_secs(min_age * 60), at(now));
+            async move {
+                let auth = RegistryAuth::Anonymous;
+                find_soaked(config, client, reference, &auth, tags, min_age, now).await
+            }
+        };
+
+        assert_eq!(
+            soaked(5, "2024-06-04T10:30:00Z").await.as_deref(),
+            Some("1.3.0")
+        );
+        // Without a creation date, it can't be told to be old enough
+        assert_eq!(
+            soaked(30, "2024-06-04T10:30:00Z").await.as_deref(),
+            Some("1.1.0")
+        );
+        assert_eq!(
+            soaked(60 * 24, "2024-06-04T10:30:00Z").await.as_deref(),
+            Some("1.0.0")
+        );
+        assert_eq!(soaked(60 * 24, "2024-06-02T09:00:00Z").await, None);
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_tag_to_soak() {
+        let address = serve_builds(
+            &[
+                ("1.0.0", Some("2024-06-01T10:00:00Z")),
+                ("1.1.0", Some("2999-01-01T00:00:00Z")),
+            ],
+            Arc::default(),
+        );
+        let candidate = Candidate {
+            min_age: Some(Duration::from_secs(30 * 60)),
+            ..Candidate::test(&format!("{}/team/web", address), "semver:*")
+        };
+        let select = |candidate: Candidate| {
+            let config = config_for(&address);
+            async move {
+                get_latest_tag_for_candidate(
+                    &config,
+                    &candidate,
+                    &RegistryAuth::Anonymous,
+                    None,
+                    &PlatformChecks::default(),
+                )
+                .await
+            }
+        };
+
+        assert_eq!(select(candidate.clone()).await.unwrap().tag, "1.0.0");
+
+        let candidate = Candidate {
+            tag_filter: TagFilter::parse("semver:^1.1", false).unwrap(),
+            ..candidate
+        };
+        let e = select(candidate).await.err().unwrap();
+        let soaking = e.downcast_ref::<Soaking>().unwrap();
+        assert_eq!(soaking.newest, "1.1.0");
+    }
+
+    fn basic(username: &str, password: &str) -> RegistryAuth {
+        RegistryAuth::Basic(username.to_string(), password.to_string())
+    }
+
+    #[test]
+    fn parses_registry_credentials() {
+        let credentials =
+            RegistryCredentials::parse("ghcr.io=user:token, registry.internal:5000=robot:to:ken,")
+                .unwrap();
+
+        assert_eq!(credentials.auth_for("ghcr.io"), basic("user", "token"));
+        assert_eq!(
+            credentials.auth_for("registry.internal:5000"),
+            basic("robot", "to:ken")
+        );
+        assert!(RegistryCredentials::parse("ghcr.io:user:token").is_err());
+        assert!(RegistryCredentials::parse("ghcr.io=token").is_err());
+    }
+
+    #[test]
+    fn matches_hosts_with_their_port() {
+        let credentials = RegistryCredentials::parse("registry.internal:5000=robot:token").unwrap();
+
+        assert_eq!(
+            credentials.auth_for("registry.internal"),
+            RegistryAuth::Anonymous
+        );
+        assert_eq!(
+            credentials.auth_for("registry.internal:5001"),
+            RegistryAuth::Anonymous
+        );
+        assert_eq!(credentials.auth_for("quay.io"), RegistryAuth::Anonymous);
+    }
+
+    #[test]
+    fn matches_the_names_of_docker_hub() {
+        let credentials = RegistryCredentials::parse("docker.io=user:token").unwrap();
+
+        for host in ["docker.io", "index.docker.io", "registry-1.docker.io"] {
+            assert_eq!(
+                credentials.auth_for(host),
+                basic("user", "token"),
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn selects_the_credentials_of_the_registry() {
+        let mut config = test_config();
+        config.registry_credentials =
+            RegistryCredentials::parse("ghcr.io=user:token,quay.io=robot:secret").unwrap();
+
+        let auth = |image: &str| {
+            let candidate = Candidate::test(image, "");
+            let config = &config;
+            async move { select_auth(config, &candidate).await.unwrap() }
+        };
+        assert_eq!(auth("ghcr.io/org/web").await, basic("user", "token"));
+        assert_eq!(auth("quay.io/org/web").await, basic("robot", "secret"));
+        assert_eq!(auth("redis").await, RegistryAuth::Anonymous);
+    }
+
+    fn proxy_settings(vars: &[(&str, &str)]) -> ProxySettings {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        ProxySettings::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn prefers_the_registry_proxy() {
+        let proxy = proxy_settings(&[
+            ("HTTPS_PROXY", "http://ambient:3128"),
+            ("REGISTRY_HTTPS_PROXY", "http://registry:3128"),
+            ("https_proxy", "http://lowercase:3128"),
+            ("http_proxy", "http://plain:3128"),
+            ("REGISTRY_NO_PROXY", ""),
+            ("NO_PROXY", "internal"),
+        ]);
+
+        assert_eq!(proxy.https_proxy.as_deref(), Some("http://registry:3128"));
+        assert_eq!(proxy.http_proxy.as_deref(), Some("http://plain:3128"));
+        assert_eq!(proxy.no_proxy, ["internal"]);
+        assert!(proxy_settings(&[]).https_proxy.is_none());
+    }
+
+    #[test]
+    fn bypasses_the_excluded_hosts() {
+        let proxy = proxy_settings(&[("NO_PROXY", "internal.corp, .example.com,registry:5000")]);
+
+        assert!(proxy.bypasses("internal.corp"));
+        assert!(proxy.bypasses("internal.corp:5000"));
+        assert!(proxy.bypasses("registry.internal.corp"));
+        assert!(proxy.bypasses("example.com") && proxy.bypasses("ghcr.example.com"));
+        assert!(proxy.bypasses("registry:5000"));
+        assert!(!proxy.bypasses("registry:5001"));
+        assert!(!proxy.bypasses("notinternal.corp"));
+        assert!(!proxy.bypasses("ghcr.io"));
+        assert!(proxy_settings(&[("NO_PROXY", "*")]).bypasses("ghcr.io"));
+    }
+
+    #[test]
+    fn proxies_the_hosts_that_arent_excluded() {
+        let mut config = test_config();
+        config.registry_proxy = proxy_settings(&[
+            ("HTTPS_PROXY", "http://proxy:3128"),
+            ("NO_PROXY", "registry.internal"),
+        ]);
+
+        let proxied = client_config(&config, "ghcr.io");
+        assert_eq!(proxied.https_proxy.as_deref(), Some("http://proxy:3128"));
+        let direct = client_config(&config, "registry.internal:5000");
+        assert_eq!(direct.https_proxy, None);
+    }
+
+    /// A `dockerconfigjson` pull secret, as mounted in a pod.
+    fn docker_config() -> tempfile::TempDir {
+        let encoded =
+            |credentials: &str| base64::engine::general_purpose::STANDARD.encode(credentials);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("config.json"),
+            format!(
+                r#"{{
+                    "auths": {{
+                        "https://index.docker.io/v1/": {{ "auth": "{}" }},
+                        "ghcr.io": {{ "auth": "{}" }},
+                        "registry.internal:5000": {{ "username": "robot", "password": "secret" }},
+                        "myregistry.azurecr.io": {{ "identitytoken": "token" }},
+                        "quay.io": {{}}
+                    }},
+                    "credsStore": "desktop"
+                }}"#,
+                encoded("hub-user:hub-password"),
+                encoded("docker-user:docker-token"),
+            ),
+        )
+        .unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn reads_docker_configs() {
+        let dir = docker_config();
+        let credentials = RegistryCredentials::from_docker_config(dir.path()).unwrap();
+        let from_file =
+            RegistryCredentials::from_docker_config(&dir.path().join("config.json")).unwrap();
+
+        for credentials in [credentials, from_file] {
+            assert_eq!(
+                credentials.auth_for("registry-1.docker.io"),
+                basic("hub-user", "hub-password")
+            );
+            assert_eq!(
+                credentials.auth_for("ghcr.io"),
+                basic("docker-user", "docker-token")
+            );
+            assert_eq!(
+                credentials.auth_for("registry.internal:5000"),
+                basic("robot", "secret")
+            );
+            assert_eq!(
+                credentials.auth_for("myregistry.azurecr.io"),
+                basic("00000000-0000-0000-0000-000000000000", "token")
+            );
+            assert_eq!(credentials.auth_for("quay.io"), RegistryAuth::Anonymous);
+        }
+    }
+
+    #[test]
+    fn rejects_broken_docker_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+
+        std::fs::write(&path, r#"{"auths": {"ghcr.io": {"auth": "not base64!"}}}"#).unwrap();
+        assert!(RegistryCredentials::from_docker_config(&path).is_err());
+        std::fs::write(&path, r#"{"auths": {"ghcr.io": {"auth": "dXNlcg=="}}}"#).unwrap();
+        assert!(RegistryCredentials::from_docker_config(&path).is_err());
+        std::fs::write(&path, "auths: {}").unwrap();
+        assert!(RegistryCredentials::from_docker_config(&path).is_err());
+        assert!(RegistryCredentials::from_docker_config(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn prefers_the_configured_credentials_to_the_docker_config() {
+        // The way `Config::from_env` merges them
+        let mut credentials =
+            RegistryCredentials::from_docker_config(docker_config().path()).unwrap();
+        credentials.extend(RegistryCredentials::parse("ghcr.io=env-user:env-token").unwrap());
+
+        assert_eq!(
+            credentials.auth_for("ghcr.io"),
+            basic("env-user", "env-token")
+        );
+        assert_eq!(
+            credentials.auth_for("docker.io"),
+            basic("hub-user", "hub-password")
+        );
+        assert_eq!(credentials.auth_for("gcr.io"), RegistryAuth::Anonymous);
+    }
+
+    #[test]
+    fn parses_pull_secrets() {
+        assert_eq!(
+            "env:WEB_CREDS".parse::<PullSecret>().unwrap(),
+            PullSecret::Env("WEB_CREDS".to_string())
+        );
+        assert_eq!("none".parse::<PullSecret>().unwrap(), PullSecret::None);
+        assert!("env:".parse::<PullSecret>().is_err());
+        assert!("secret:web".parse::<PullSecret>().is_err());
+        assert!("".parse::<PullSecret>().is_err());
+    }
+
+    #[tokio::test]
+    async fn uses_the_pull_secret_of_the_candidate() {
+        std::env::set_var("IMAGE_UPDATER_TEST_PULL_SECRET", "robot:pass:word");
+        let mut config = test_config();
+        config.registry_credentials = RegistryCredentials::parse("ghcr.io=user:token").unwrap();
+        let auth = |pull_secret: Option<PullSecret>| {
+            let candidate = Candidate {
+                pull_secret,
+                ..Candidate::test("ghcr.io/org/web", "")
+            };
+            let config = &config;
+            async move { select_auth(config, &candidate).await.unwrap() }
+        };
+
+        assert_eq!(
+            auth(Some(PullSecret::Env(
+                "IMAGE_UPDATER_TEST_PULL_SECRET".to_string()
+            )))
+            .await,
+            basic("robot", "pass:word")
+        );
+        assert_eq!(auth(Some(PullSecret::None)).await, RegistryAuth::Anonymous);
+        assert_eq!(auth(None).await, basic("user", "token"));
+    }
+
+    #[test]
+    fn fails_on_unusable_pull_secrets() {
+        std::env::set_var("IMAGE_UPDATER_TEST_EMPTY_PULL_SECRET", "");
+        std::env::set_var("IMAGE_UPDATER_TEST_TOKEN_PULL_SECRET", "hunter2");
+
+        for (var, problem) in [
+            ("IMAGE_UPDATER_TEST_UNSET_PULL_SECRET", "isn't set"),
+            ("IMAGE_UPDATER_TEST_EMPTY_PULL_SECRET", "is empty"),
+            (
+                "IMAGE_UPDATER_TEST_TOKEN_PULL_SECRET",
+                "isn't `username:password`",
+            ),
+        ] {
+            let e = format!("{:#}", env_auth(var).unwrap_err());
+            assert_eq!(
+                e,
+                format!("The `pull-secret` environment variable {} {}", var, problem)
+            );
+            assert!(!e.contains("hunter2"));
+        }
+    }
+}
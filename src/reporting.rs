@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use sentry::{integrations::anyhow::capture_anyhow, protocol, ClientInitGuard, Level};
+
+use crate::{config_file, status::Run};
+
+/// Reports the failures to Sentry when `SENTRY_DSN` is set, the panics
+/// included. The guard flushes what's left to send when dropped.
+pub fn init() -> Result<Option<ClientInitGuard>> {
+    let Ok(dsn) = config_file::var("SENTRY_DSN") else {
+        return Ok(None);
+    };
+
+    let mut options = sentry::ClientOptions::default();
+    options.dsn = Some(dsn.parse().context("SENTRY_DSN isn't a valid DSN")?);
+    options.release = sentry::release_name!();
+    options.environment = config_file::var("SENTRY_ENVIRONMENT").ok().map(Into::into);
+    let guard = sentry::init(options);
+    log::info!("Reporting the failures to Sentry");
+
+    Ok(Some(guard))
+}
+
+/// A candidate that couldn't be updated.
+pub fn candidate_failure(app: &str, image: Option<&str>, host: Option<&str>, e: &anyhow::Error) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("app", app);
+            if let Some(image) = image {
+                scope.set_tag("image", image);
+            }
+            if let Some(host) = host {
+                scope.set_tag("registry_host", host);
+            }
+        },
+        || capture_anyhow(e),
+    );
+}
+
+/// The run failing as a whole, and the repositories that couldn't be updated
+/// at all, along with what `/status` says about the run.
+pub fn run_failures(run: &Run) {
+    let context = match serde_json::to_value(run) {
+        Ok(serde_json::Value::Object(run)) => protocol::Context::Other(run.into_iter().collect()),
+        _ => return,
+    };
+
+    let failures = run
+        .error
+        .iter()
+        .map(|error| (None, format!("Image update run failed: {}", error)))
+        .chain(run.repos.iter().filter_map(|repo| {
+            let error = repo.error.as_ref()?;
+            Some((
+                Some(&repo.repo),
+                format!("Failed to update {}: {}", repo.repo, error),
+            ))
+        }));
+    for (repo, message) in failures {
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("trigger", &run.trigger);
+                if let Some(repo) = repo {
+                    scope.set_tag("repo", repo);
+                }
+                scope.set_context("run", context.clone());
+            },
+            || sentry::capture_message(&message, Level::Error),
+        );
+    }
+}
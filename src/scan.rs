@@ -0,0 +1,156 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+
+use crate::config_file;
+
+const OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    // `*` stays within a directory, `**` goes across them
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Which files of a checkout get read when looking for Applications, from the
+/// comma separated globs of `SCAN_INCLUDE` and `SCAN_EXCLUDE`, like `apps/**`.
+/// They're matched against paths relative to the root of the repository. A
+/// file has to match one of the includes, when there are some, and none of
+/// the excludes: excluding wins. `.git` is never looked at.
+#[derive(Clone, Default)]
+pub struct ScanFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// The directories that can't hold anything but excluded files, like
+    /// `rendered` for `rendered/**`, so that they don't even get walked.
+    excluded_dirs: Vec<Pattern>,
+}
+
+impl ScanFilter {
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(|name| config_file::var(name).ok())
+    }
+
+    /// Reads the globs from the variables `var` returns.
+    pub(crate) fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let exclude = patterns(&var, "SCAN_EXCLUDE")?;
+        let excluded_dirs = exclude
+            .iter()
+            .filter_map(|pattern| pattern.as_str().strip_suffix("/**"))
+            .map(Pattern::new)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            include: patterns(&var, "SCAN_INCLUDE")?,
+            exclude,
+            excluded_dirs,
+        })
+    }
+
+    /// Whether the file at `path`, relative to the root, gets read.
+    pub fn matches(&self, path: &Path) -> bool {
+        (self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches_path_with(path, OPTIONS)))
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches_path_with(path, OPTIONS))
+    }
+
+    /// Whether the directory at `path`, relative to the root, gets walked.
+    pub fn walks(&self, path: &Path) -> bool {
+        path.file_name().is_none_or(|name| name != ".git")
+            && !self
+                .excluded_dirs
+                .iter()
+                .any(|pattern| pattern.matches_path_with(path, OPTIONS))
+    }
+}
+
+fn patterns(var: impl Fn(&str) -> Option<String>, name: &str) -> Result<Vec<Pattern>> {
+    let Some(patterns) = var(name) else {
+        return Ok(vec![]);
+    };
+
+    patterns
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            Pattern::new(pattern).with_context(|| format!("Invalid {} glob {}", name, pattern))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &str, exclude: &str) -> ScanFilter {
+        ScanFilter::from_vars(|name| match name {
+            "SCAN_INCLUDE" => Some(include.to_string()),
+            "SCAN_EXCLUDE" => Some(exclude.to_string()),
+            _ => None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn scans_everything_by_default() {
+        let filter = ScanFilter::from_vars(|_| None).unwrap();
+        assert!(filter.matches(Path::new("apps/web.yaml")));
+        assert!(filter.matches(Path::new("rendered/web.yaml")));
+        assert!(filter.walks(Path::new("rendered")));
+        assert!(!filter.walks(Path::new(".git")));
+        assert!(!filter.walks(Path::new("vendor/chart/.git")));
+    }
+
+    #[test]
+    fn excluding_wins() {
+        let filter = filter(
+            "apps/**, envs/*.yaml",
+            "rendered/**,apps/legacy/**,**/secret.yaml",
+        );
+
+        for scanned in ["apps/web.yaml", "apps/team/web.yaml", "envs/prod.yaml"] {
+            assert!(filter.matches(Path::new(scanned)), "{scanned}");
+        }
+        for skipped in [
+            "web.yaml",
+            // `*` stays within a directory
+            "envs/prod/web.yaml",
+            "rendered/web.yaml",
+            "apps/legacy/web.yaml",
+            "apps/team/secret.yaml",
+        ] {
+            assert!(!filter.matches(Path::new(skipped)), "{skipped}");
+        }
+    }
+
+    #[test]
+    fn doesnt_walk_the_excluded_directories() {
+        let filter = filter("", "rendered/**,charts/**/templates/**,**/secret.yaml");
+
+        assert!(!filter.walks(Path::new("rendered")));
+        assert!(!filter.walks(Path::new("charts/web/templates")));
+        assert!(filter.walks(Path::new("charts/web")));
+        assert!(filter.walks(Path::new("apps")));
+        assert!(filter.walks(Path::new("renderer")));
+    }
+
+    #[test]
+    fn rejects_invalid_globs() {
+        let e =
+            ScanFilter::from_vars(|name| (name == "SCAN_INCLUDE").then(|| "apps/[".to_string()))
+                .err()
+                .unwrap();
+        assert!(
+            e.to_string()
+                .starts_with("Invalid SCAN_INCLUDE glob apps/["),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::{config::RepoConfig, config_file};
+
+/// How long posting a message can take, not to hold the next run back.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts how the runs went to a Slack incoming webhook.
+pub struct Slack {
+    webhook_url: reqwest::Url,
+    /// Whether to post about runs that changed nothing and had no errors too.
+    notify_always: bool,
+    client: reqwest::Client,
+}
+
+impl Slack {
+    /// Enabled with `SLACK_WEBHOOK_URL`, `SLACK_NOTIFY_ALWAYS=true` posting
+    /// about every run.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(webhook_url) = config_file::var("SLACK_WEBHOOK_URL") else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            webhook_url: webhook_url
+                .parse()
+                .context("SLACK_WEBHOOK_URL isn't a valid URL")?,
+            notify_always: config_file::var("SLACK_NOTIFY_ALWAYS").is_ok_and(|v| v == "true"),
+            client: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+        }))
+    }
+
+    /// Posts the updates of a run, and its failures in a message of their own.
+    /// `run` is the summary of the run as `/update` reports it, or why it
+    /// couldn't run at all. Failing to post is only logged.
+    pub async fn notify(&self, repositories: &[RepoConfig], run: Result<&Value, String>) {
+        let messages = match run {
+            Ok(summary) => {
+                let updates = updates_message(repositories, summary);
+                let failures = failures_message(summary);
+                match (updates, failures) {
+                    (None, None) if self.notify_always => {
+                        vec![
+                            ":white_check_mark: Image update run done, nothing to update"
+                                .to_string(),
+                        ]
+                    }
+                    (updates, failures) => updates.into_iter().chain(failures).collect(),
+                }
+            }
+            Err(error) => vec![format!(":x: *Image update run failed*\n{}", error)],
+        };
+
+        for message in messages {
+            if let Err(e) = self.post(&message).await {
+                log::warn!("Failed to notify Slack: {:#}", e);
+            }
+        }
+    }
+
+    async fn post(&self, text: &str) -> Result<()> {
+        // The URL is the secret, it stays out of the logs
+        self.client
+            .post(self.webhook_url.clone())
+            .json(&json!({ "text": text }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(reqwest::Error::without_url)?;
+
+        Ok(())
+    }
+}
+
+/// The images that got updated, grouped by repository along with the commit
+/// they went in.
+fn updates_message(repositories: &[RepoConfig], summary: &Value) -> Option<String> {
+    let updated = entries(summary, "updated");
+    if updated.is_empty() {
+        return None;
+    }
+
+    let mut lines = vec![match updated.len() {
+        1 => ":rocket: *Updated 1 image*".to_string(),
+        count => format!(":rocket: *Updated {} images*", count),
+    }];
+    for repo in entries(summary, "repos") {
+        let name = repo["repo"].as_str().unwrap_or_default();
+        let changes = updated
+            .iter()
+            .filter(|update| update["repo"].as_str() == Some(name))
+            .map(|update| {
+                format!(
+                    "• {}: `{}` {} → {}",
+                    text(&update["app"]),
+                    text(&update["image"]),
+                    update["old"].as_str().unwrap_or("absent"),
+                    text(&update["new"])
+                )
+            })
+            .collect::<Vec<_>>();
+        if changes.is_empty() {
+            continue;
+        }
+
+        let url = repositories
+            .iter()
+            .find(|known| known.name == name)
+            .map(|known| known.url.as_str());
+        let commit = match (repo["commit"].as_str(), url) {
+            (Some(commit), Some(url)) => match commit_url(url, commit) {
+                Some(commit_url) => format!(" in <{}|{:.7}>", commit_url, commit),
+                None => format!(" in `{:.7}`", commit),
+            },
+            (Some(commit), None) => format!(" in `{:.7}`", commit),
+            (None, _) => String::new(),
+        };
+        lines.push(format!("*{}*{}", name, commit));
+        lines.extend(changes);
+    }
+
+    Some(lines.join("\n"))
+}
+
+/// The candidates that couldn't be updated, and the repositories that
+/// couldn't be updated at all.
+fn failures_message(summary: &Value) -> Option<String> {
+    let mut failures = entries(summary, "failed")
+        .iter()
+        .map(|failed| {
+            format!(
+                "• {} {}: {}",
+                text(&failed["repo"]),
+                text(&failed["app"]),
+                text(&failed["error"])
+            )
+        })
+        .collect::<Vec<_>>();
+    failures.extend(
+        entries(summary, "repos")
+            .iter()
+            .filter(|repo| !repo["error"].is_null())
+            .map(|repo| format!("• {}: {}", text(&repo["repo"]), text(&repo["error"]))),
+    );
+    if failures.is_empty() {
+        return None;
+    }
+
+    let mut lines = vec![match failures.len() {
+        1 => ":x: *1 image update failed*".to_string(),
+        count => format!(":x: *{} image updates failed*", count),
+    }];
+    lines.extend(failures);
+
+    Some(lines.join("\n"))
+}
+
+/// Where the commit can be seen on the forge hosting the repository, for the
+/// remotes that have a host.
+fn commit_url(repository_url: &str, commit: &str) -> Option<String> {
+    let trimmed = repository_url.trim_end_matches('/');
+    let trimmed = trimmed.strip_suffix(".git").unwrap_or(trimmed);
+    let (host, path) = match trimmed.split_once("://") {
+        Some(("https" | "http" | "ssh", rest)) => rest.split_once('/')?,
+        Some(_) => return None,
+        // The scp-like syntax, as in `git@github.com:owner/repo.git`
+        None => trimmed.split_once(':')?,
+    };
+    let host = host.rsplit('@').next()?;
+    // The SSH port has nothing to do with the web one
+    let host = match repository_url.starts_with("ssh://") {
+        true => host.split(':').next()?,
+        false => host,
+    };
+    if host.is_empty() || path.is_empty() {
+        return None;
+    }
+
+    Some(format!("https://{}/{}/commit/{}", host, path, commit))
+}
+
+fn entries<'a>(summary: &'a Value, key: &str) -> &'a [Value] {
+    summary[key]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or_default()
+}
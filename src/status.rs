@@ -0,0 +1,61 @@
+use std::{collections::VecDeque, sync::RwLock};
+
+use serde::Serialize;
+
+/// The outcome of the last runs, most recent first.
+pub struct RunHistory {
+    capacity: usize,
+    runs: RwLock<VecDeque<Run>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Run {
+    pub started_at: String,
+    pub duration_secs: f64,
+    /// What triggered the run, like `POST /update`.
+    pub trigger: String,
+    pub candidates: usize,
+    pub updated: Vec<String>,
+    /// The commit that got pushed, when there's a single repository.
+    pub commit: Option<String>,
+    pub repos: Vec<RepoRun>,
+    pub errors: Vec<RunError>,
+    /// Why the whole run failed, if it did.
+    pub error: Option<String>,
+}
+
+/// How the run went for one of the repositories.
+#[derive(Clone, Serialize)]
+pub struct RepoRun {
+    pub repo: String,
+    pub commit: Option<String>,
+    /// Why the repository couldn't be updated at all, if it couldn't.
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RunError {
+    pub repo: String,
+    pub app: String,
+    pub error: String,
+}
+
+impl RunHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            runs: RwLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Adds a run, forgetting the oldest one when there's too many already.
+    pub fn record(&self, run: Run) {
+        let mut runs = self.runs.write().unwrap();
+        runs.push_front(run);
+        runs.truncate(self.capacity);
+    }
+
+    pub fn runs(&self) -> Vec<Run> {
+        self.runs.read().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,322 @@
+use std::{cmp::Ordering, str::FromStr};
+
+use anyhow::{bail, Result};
+use chrono::{
+    format::{parse_and_remainder, Parsed, StrftimeItems},
+    NaiveDateTime,
+};
+use semver::Version;
+
+/// How the `calver` strategy reads tags without a `tag-date-format`.
+pub const DEFAULT_DATE_FORMAT: &str = "%Y.%m.%d";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum UpdateStrategy {
+    /// Sort tags alphanumerically and pick the last one.
+    #[default]
+    Name,
+    /// Parse tags as semantic versions and pick the highest one.
+    Semver,
+    /// Track the manifest digest of a single mutable tag.
+    Digest,
+    /// Pick the most recently built image according to its config's `created`
+    /// field.
+    NewestBuild,
+    /// Parse tags as dates, like `2024.06.18-3`, and pick the latest one.
+    Calver,
+}
+
+impl FromStr for UpdateStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "name" | "alphabetical" => Ok(Self::Name),
+            "semver" => Ok(Self::Semver),
+            "digest" => Ok(Self::Digest),
+            "newest-build" | "latest" => Ok(Self::NewestBuild),
+            "calver" | "date" => Ok(Self::Calver),
+            _ => bail!("Unknown update strategy: {}", s),
+        }
+    }
+}
+
+impl UpdateStrategy {
+    /// Sorts `tags` from oldest to newest, dropping the ones that can't be
+    /// ordered with this strategy. `date_format` is the one of the `calver`
+    /// strategy.
+    pub fn sort_tags(&self, tags: Vec<String>, date_format: &str) -> Vec<String> {
+        match self {
+            Self::Name | Self::Digest | Self::NewestBuild => {
+                let mut tags = tags;
+                tags.sort_by(|a, b| alphanumeric_sort::compare_path(a, b));
+                tags
+            }
+            Self::Semver | Self::Calver => {
+                let versions = tags.into_iter().map(|tag| (tag.clone(), tag)).collect();
+                self.sort_versions(versions, date_format)
+            }
+        }
+    }
+
+    /// Like `sort_tags`, for tags paired with the versions embedded in them:
+    /// they're ordered by those, the tags only breaking ties. The `name`
+    /// strategy puts the semantic versions last, in order.
+    pub fn sort_versions(&self, tags: Vec<(String, String)>, date_format: &str) -> Vec<String> {
+        match self {
+            Self::Name | Self::Digest | Self::NewestBuild => sort_by_version(tags, |version| {
+                Some((parse_version(version), Name(version.to_string())))
+            }),
+            Self::Semver => sort_by_version(tags, parse_version),
+            Self::Calver => sort_by_version(tags, |version| {
+                let date = parse_date(version, date_format);
+                if date.is_none() {
+                    log::debug!("Ignoring {}, it isn't a {} date", version, date_format);
+                }
+                date
+            }),
+        }
+    }
+}
+
+/// Orders alphanumerically, `1.9` coming before `1.10`.
+#[derive(PartialEq, Eq)]
+struct Name(String);
+
+impl Ord for Name {
+    fn cmp(&self, other: &Self) -> Ordering {
+        alphanumeric_sort::compare_path(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Sorts the tags by the key of their version, dropping the ones without.
+fn sort_by_version<K: Ord>(
+    tags: Vec<(String, String)>,
+    key: impl Fn(&str) -> Option<K>,
+) -> Vec<String> {
+    let mut keyed = tags
+        .into_iter()
+        .filter_map(|(version, tag)| Some((key(&version)?, tag)))
+        .collect::<Vec<_>>();
+    keyed.sort_by(|(a, a_tag), (b, b_tag)| {
+        a.cmp(b)
+            .then_with(|| alphanumeric_sort::compare_path(a_tag, b_tag))
+    });
+
+    keyed.into_iter().map(|(_, tag)| tag).collect()
+}
+
+/// Checks that a `tag-date-format` is one chrono can parse dates with.
+pub fn validate_date_format(format: &str) -> Result<()> {
+    if StrftimeItems::new(format).parse().is_err() {
+        bail!("Invalid date format: {}", format);
+    }
+
+    Ok(())
+}
+
+/// Parses a tag as a date in `format`, possibly followed by a build number
+/// like the `3` of `2024.06.18-3`. The tags without one come first.
+fn parse_date(tag: &str, format: &str) -> Option<(NaiveDateTime, Option<u64>)> {
+    let mut parsed = Parsed::new();
+    let rest = parse_and_remainder(&mut parsed, tag, StrftimeItems::new(format)).ok()?;
+    let date = parsed.to_naive_date().ok()?;
+    let time = parsed.to_naive_time().unwrap_or_default();
+
+    let build = match rest.strip_prefix(['-', '.', '_', '+']) {
+        Some(build) => Some(build.parse().ok()?),
+        None if rest.is_empty() => None,
+        None => return None,
+    };
+
+    Some((date.and_time(time), build))
+}
+
+/// Parses a tag as a semantic version, tolerating a leading `v`.
+pub fn parse_version(tag: &str) -> Option<Version> {
+    Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+/// Returns whether moving from `current` to `new` would go back in versions.
+/// Tags that don't parse as versions can't be compared and are never considered
+/// a downgrade.
+pub fn is_downgrade(current: &str, new: &str) -> bool {
+    match (parse_version(current.trim()), parse_version(new.trim())) {
+        (Some(current), Some(new)) => new < current,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    fn latest(strategy: UpdateStrategy, candidates: &[&str]) -> Option<String> {
+        strategy
+            .sort_tags(tags(candidates), DEFAULT_DATE_FORMAT)
+            .pop()
+    }
+
+    #[test]
+    fn parses_strategies() {
+        assert_eq!(
+            "semver".parse::<UpdateStrategy>().unwrap(),
+            UpdateStrategy::Semver
+        );
+        assert_eq!(
+            "name".parse::<UpdateStrategy>().unwrap(),
+            UpdateStrategy::Name
+        );
+        assert!("highest".parse::<UpdateStrategy>().is_err());
+    }
+
+    #[test]
+    fn sorts_semantic_versions() {
+        assert_eq!(
+            latest(UpdateStrategy::Semver, &["1.10.0", "1.9.0"]).as_deref(),
+            Some("1.10.0")
+        );
+        assert_eq!(
+            latest(UpdateStrategy::Semver, &["v1.9.0", "v1.10.0", "v1.2.0"]).as_deref(),
+            Some("v1.10.0")
+        );
+    }
+
+    #[test]
+    fn sorts_prereleases_first() {
+        assert_eq!(
+            UpdateStrategy::Semver.sort_tags(
+                tags(&["1.2.0", "1.2.0-rc.1", "1.2.0-beta.2", "1.1.0"]),
+                DEFAULT_DATE_FORMAT
+            ),
+            tags(&["1.1.0", "1.2.0-beta.2", "1.2.0-rc.1", "1.2.0"])
+        );
+    }
+
+    #[test]
+    fn drops_tags_that_arent_versions() {
+        assert_eq!(
+            UpdateStrategy::Semver.sort_tags(
+                tags(&["latest", "1.0.0", "sha-abcdef", "v0.9.1", "main"]),
+                DEFAULT_DATE_FORMAT
+            ),
+            tags(&["v0.9.1", "1.0.0"])
+        );
+        assert_eq!(
+            latest(UpdateStrategy::Semver, &["latest", "sha-abcdef"]),
+            None
+        );
+    }
+
+    #[test]
+    fn detects_downgrades() {
+        assert!(is_downgrade("1.10.2", "1.9.9"));
+        assert!(is_downgrade("v2.0.0", "1.99.0"));
+        assert!(is_downgrade("1.2.0", "1.2.0-rc.1"));
+        assert!(!is_downgrade("1.9.9", "1.10.2"));
+        assert!(!is_downgrade("1.2.0", "1.2.0"));
+    }
+
+    #[test]
+    fn doesnt_compare_tags_that_arent_versions() {
+        assert!(!is_downgrade("sha-bbbbbb", "sha-aaaaaa"));
+        assert!(!is_downgrade("1.10.2", "latest"));
+        assert!(!is_downgrade("main-2024", "1.0.0"));
+    }
+
+    fn sorted_dates(candidates: &[&str], date_format: &str) -> Vec<String> {
+        UpdateStrategy::Calver.sort_tags(tags(candidates), date_format)
+    }
+
+    #[test]
+    fn sorts_dates_across_years() {
+        assert_eq!(
+            sorted_dates(
+                &[
+                    "2024.01.02",
+                    "2023.12.31-2",
+                    "2024.01.01",
+                    "2023.12.31",
+                    "2023.2.1"
+                ],
+                DEFAULT_DATE_FORMAT
+            ),
+            tags(&[
+                "2023.2.1",
+                "2023.12.31",
+                "2023.12.31-2",
+                "2024.01.01",
+                "2024.01.02"
+            ])
+        );
+        assert_eq!(
+            sorted_dates(&["20240101.1", "20231231.12", "20231231.3"], "%Y%m%d"),
+            tags(&["20231231.3", "20231231.12", "20240101.1"])
+        );
+    }
+
+    #[test]
+    fn sorts_single_and_double_digit_months() {
+        assert_eq!(
+            sorted_dates(
+                &[
+                    "2024.10.1",
+                    "2024.9.30",
+                    "2024.09.18",
+                    "2024.1.5",
+                    "2024.06.18-3"
+                ],
+                DEFAULT_DATE_FORMAT
+            ),
+            tags(&[
+                "2024.1.5",
+                "2024.06.18-3",
+                "2024.09.18",
+                "2024.9.30",
+                "2024.10.1"
+            ])
+        );
+        assert_eq!(
+            sorted_dates(
+                &["2024.10.01", "2024.9.30", "2024.09.1"],
+                DEFAULT_DATE_FORMAT
+            ),
+            tags(&["2024.09.1", "2024.9.30", "2024.10.01"])
+        );
+    }
+
+    #[test]
+    fn drops_tags_that_arent_dates() {
+        assert_eq!(
+            sorted_dates(
+                &[
+                    "latest",
+                    "2024.13.01",
+                    "2024.02.30",
+                    "2024.06.18-rc",
+                    "2024.06.18x",
+                    "2024.06.18"
+                ],
+                DEFAULT_DATE_FORMAT
+            ),
+            tags(&["2024.06.18"])
+        );
+    }
+
+    #[test]
+    fn validates_date_formats() {
+        assert!(validate_date_format("%Y.%m.%d").is_ok());
+        assert!(validate_date_format("%Y%m%d-%H%M").is_ok());
+        assert!(validate_date_format("%Y.%").is_err());
+    }
+}
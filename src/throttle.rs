@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Keeps a misbehaving sender from triggering runs over and over, both by
+/// limiting how many triggers are accepted and by ignoring the deliveries that
+/// were already seen.
+pub struct Throttle {
+    /// Triggers accepted per minute, without limit when unset.
+    per_minute: Option<u32>,
+    delivery_ttl: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// What's left of the token bucket, refilled continuously.
+    tokens: f64,
+    refilled_at: Instant,
+    /// The job each recent delivery id got, and when it was seen.
+    deliveries: HashMap<String, (Instant, u64)>,
+}
+
+pub enum Admission {
+    Queued(u64),
+    /// The delivery was already seen, and got this job.
+    Duplicate(u64),
+    Limited,
+}
+
+impl Throttle {
+    pub fn new(per_minute: Option<u32>, delivery_ttl: Duration, now: Instant) -> Self {
+        Self {
+            per_minute,
+            delivery_ttl,
+            state: Mutex::new(State {
+                tokens: per_minute.unwrap_or_default() as f64,
+                refilled_at: now,
+                deliveries: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Calls `enqueue` at `now` unless the delivery is a duplicate or there were
+    /// too many triggers lately, duplicates not counting against the limit.
+    pub fn admit(
+        &self,
+        delivery: Option<&str>,
+        now: Instant,
+        enqueue: impl FnOnce() -> u64,
+    ) -> Admission {
+        let mut state = self.state.lock().unwrap();
+        let ttl = self.delivery_ttl;
+        state
+            .deliveries
+            .retain(|_, (seen_at, _)| now.saturating_duration_since(*seen_at) < ttl);
+        if let Some((_, job)) = delivery.and_then(|delivery| state.deliveries.get(delivery)) {
+            return Admission::Duplicate(*job);
+        }
+
+        if let Some(per_minute) = self.per_minute {
+            let per_minute = per_minute as f64;
+            let elapsed = now.saturating_duration_since(state.refilled_at);
+            state.tokens =
+                (state.tokens + elapsed.as_secs_f64() * per_minute / 60.0).min(per_minute);
+            state.refilled_at = now;
+            if state.tokens < 1.0 {
+                return Admission::Limited;
+            }
+            state.tokens -= 1.0;
+        }
+
+        let job = enqueue();
+        if let Some(delivery) = delivery {
+            state.deliveries.insert(delivery.to_string(), (now, job));
+        }
+
+        Admission::Queued(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Admits a trigger at `seconds` past `start`, numbering the jobs in order.
+    fn admit(
+        throttle: &Throttle,
+        jobs: &mut u64,
+        start: Instant,
+        seconds: u64,
+        delivery: Option<&str>,
+    ) -> Admission {
+        throttle.admit(delivery, start + Duration::from_secs(seconds), || {
+            *jobs += 1;
+            *jobs
+        })
+    }
+
+    #[test]
+    fn limits_the_triggers() {
+        let start = Instant::now();
+        let throttle = Throttle::new(Some(2), Duration::from_secs(600), start);
+        let mut jobs = 0;
+
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 0, None),
+            Admission::Queued(1)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 0, None),
+            Admission::Queued(2)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 1, None),
+            Admission::Limited
+        ));
+        // A token every 30 seconds
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 29, None),
+            Admission::Limited
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 30, None),
+            Admission::Queued(3)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 31, None),
+            Admission::Limited
+        ));
+        // The bucket doesn't fill past the limit
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 3600, None),
+            Admission::Queued(4)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 3600, None),
+            Admission::Queued(5)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 3600, None),
+            Admission::Limited
+        ));
+        assert_eq!(jobs, 5);
+    }
+
+    #[test]
+    fn doesnt_limit_without_a_limit() {
+        let start = Instant::now();
+        let throttle = Throttle::new(None, Duration::from_secs(600), start);
+        let mut jobs = 0;
+
+        for _ in 0..100 {
+            assert!(matches!(
+                admit(&throttle, &mut jobs, start, 0, None),
+                Admission::Queued(_)
+            ));
+        }
+        assert_eq!(jobs, 100);
+    }
+
+    #[test]
+    fn ignores_the_deliveries_already_seen() {
+        let start = Instant::now();
+        let throttle = Throttle::new(None, Duration::from_secs(600), start);
+        let mut jobs = 0;
+
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 0, Some("a")),
+            Admission::Queued(1)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 10, Some("a")),
+            Admission::Duplicate(1)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 20, Some("b")),
+            Admission::Queued(2)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 599, Some("a")),
+            Admission::Duplicate(1)
+        ));
+        // Forgotten once their time is up
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 600, Some("a")),
+            Admission::Queued(3)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 601, Some("a")),
+            Admission::Duplicate(3)
+        ));
+        assert_eq!(jobs, 3);
+    }
+
+    #[test]
+    fn doesnt_count_the_duplicates_against_the_limit() {
+        let start = Instant::now();
+        let throttle = Throttle::new(Some(1), Duration::from_secs(600), start);
+        let mut jobs = 0;
+
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 0, Some("a")),
+            Admission::Queued(1)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 0, Some("a")),
+            Admission::Duplicate(1)
+        ));
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 0, Some("b")),
+            Admission::Limited
+        ));
+        // A limited delivery isn't remembered, its retry goes through
+        assert!(matches!(
+            admit(&throttle, &mut jobs, start, 60, Some("b")),
+            Admission::Queued(2)
+        ));
+    }
+}
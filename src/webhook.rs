@@ -0,0 +1,357 @@
+use serde::Deserialize;
+
+use crate::{normalize_image, split_tag};
+
+/// The image tag a registry's push webhook is about.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PushedImage {
+    /// The image, without its tag.
+    pub image: String,
+    pub tags: Vec<String>,
+}
+
+/// A GitHub `package` or `registry_package` event, the field being named after
+/// the event.
+#[derive(Deserialize)]
+struct GithubEvent {
+    package: Option<GithubPackage>,
+    registry_package: Option<GithubPackage>,
+}
+
+#[derive(Deserialize)]
+struct GithubPackage {
+    name: String,
+    namespace: Option<String>,
+    package_type: Option<String>,
+    owner: Option<GithubOwner>,
+    package_version: Option<GithubPackageVersion>,
+}
+
+#[derive(Deserialize)]
+struct GithubOwner {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GithubPackageVersion {
+    /// Like `ghcr.io/owner/name:tag`.
+    package_url: Option<String>,
+    container_metadata: Option<GithubContainerMetadata>,
+}
+
+#[derive(Deserialize)]
+struct GithubContainerMetadata {
+    tag: Option<GithubTag>,
+}
+
+#[derive(Deserialize)]
+struct GithubTag {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct DockerHubEvent {
+    push_data: DockerHubPushData,
+    repository: DockerHubRepository,
+}
+
+#[derive(Deserialize)]
+struct DockerHubPushData {
+    tag: String,
+}
+
+#[derive(Deserialize)]
+struct DockerHubRepository {
+    /// Like `namespace/name`, Docker Hub being implied.
+    repo_name: String,
+}
+
+#[derive(Deserialize)]
+struct HarborEvent {
+    #[serde(rename = "type", alias = "event_type")]
+    event_type: String,
+    event_data: HarborEventData,
+}
+
+#[derive(Deserialize)]
+struct HarborEventData {
+    resources: Vec<HarborResource>,
+}
+
+#[derive(Deserialize)]
+struct HarborResource {
+    tag: Option<String>,
+    /// Like `harbor.example.com/project/name:tag`.
+    resource_url: String,
+}
+
+impl PushedImage {
+    /// Finds out what got pushed from the JSON body of a webhook, `None`
+    /// meaning the payload isn't one we know about.
+    pub fn parse(github_event: Option<&str>, body: &[u8]) -> Option<Self> {
+        match github_event {
+            Some("package" | "registry_package") => Self::from_github(body),
+            Some(_) => None,
+            // Docker Hub and Harbor don't say what they're sending
+            None => Self::from_docker_hub(body).or_else(|| Self::from_harbor(body)),
+        }
+    }
+
+    fn from_github(body: &[u8]) -> Option<Self> {
+        let event: GithubEvent = serde_json::from_slice(body)
+            .inspect_err(|e| log::warn!("Couldn't parse the GitHub package event: {}", e))
+            .ok()?;
+        let package = event.package.or(event.registry_package)?;
+        // `registry_package` events predating GHCR call them docker packages
+        if package.package_type.as_deref().is_some_and(|package_type| {
+            !package_type.eq_ignore_ascii_case("container")
+                && !package_type.eq_ignore_ascii_case("docker")
+        }) {
+            return None;
+        }
+
+        let version = package.package_version?;
+        // Untagged versions are only there for the tagged ones to point to
+        let tag = version.container_metadata?.tag?.name;
+        if tag.is_empty() {
+            return None;
+        }
+
+        let image = match version.package_url {
+            Some(url) => split_tag(&url).0.to_string(),
+            None => {
+                let owner = package
+                    .namespace
+                    .or(package.owner.map(|owner| owner.login))?;
+                format!("ghcr.io/{}/{}", owner, package.name)
+            }
+        };
+
+        Some(Self {
+            image: image.to_lowercase(),
+            tags: vec![tag],
+        })
+    }
+
+    fn from_docker_hub(body: &[u8]) -> Option<Self> {
+        let event: DockerHubEvent = serde_json::from_slice(body).ok()?;
+
+        Some(Self {
+            image: format!("docker.io/{}", event.repository.repo_name),
+            tags: vec![event.push_data.tag],
+        })
+    }
+
+    fn from_harbor(body: &[u8]) -> Option<Self> {
+        let event: HarborEvent = serde_json::from_slice(body).ok()?;
+        if event.event_type != "PUSH_ARTIFACT" {
+            return None;
+        }
+
+        // All the resources are tags of the same artifact
+        let mut image = None;
+        let mut tags = vec![];
+        for resource in event.event_data.resources {
+            let Some(tag) = resource.tag.filter(|tag| !tag.is_empty()) else {
+                continue;
+            };
+            let url = resource.resource_url;
+            let url = url.split_once('@').map_or(url.as_str(), |(url, _)| url);
+            image.get_or_insert_with(|| split_tag(url).0.to_string());
+            tags.push(tag);
+        }
+
+        Some(Self {
+            image: image?,
+            tags,
+        })
+    }
+
+    /// Whether `url` points to the pushed image, whatever its tag is and
+    /// whether the registry is spelled out or not.
+    pub fn is_image(&self, url: &str) -> bool {
+        match (
+            normalize_image(split_tag(url).0),
+            normalize_image(&self.image),
+        ) {
+            (Ok(image), Ok(pushed)) => image == pushed,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for PushedImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.image, self.tags.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A trimmed `package` event GitHub sends when a container is published.
+    const GITHUB_PACKAGE: &str = r#"{
+        "action": "published",
+        "package": {
+            "id": 1234,
+            "name": "web",
+            "namespace": "org",
+            "ecosystem": "CONTAINER",
+            "package_type": "CONTAINER",
+            "owner": { "login": "org", "type": "Organization" },
+            "package_version": {
+                "id": 5678,
+                "version": "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+                "package_url": "ghcr.io/org/web:1.2.0",
+                "container_metadata": {
+                    "tag": { "name": "1.2.0", "digest": "sha256:0123456789abcdef" },
+                    "labels": {},
+                    "manifest": {}
+                }
+            }
+        },
+        "repository": { "full_name": "org/web" },
+        "sender": { "login": "octocat" }
+    }"#;
+
+    /// The older `registry_package` event, without a `package_url`.
+    const GITHUB_REGISTRY_PACKAGE: &str = r#"{
+        "action": "published",
+        "registry_package": {
+            "name": "Worker",
+            "namespace": "Org",
+            "package_type": "docker",
+            "package_version": {
+                "container_metadata": { "tag": { "name": "v2" } }
+            }
+        }
+    }"#;
+
+    fn pushed(image: &str, tags: &[&str]) -> PushedImage {
+        PushedImage {
+            image: image.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_github_package_events() {
+        assert_eq!(
+            PushedImage::parse(Some("package"), GITHUB_PACKAGE.as_bytes()),
+            Some(pushed("ghcr.io/org/web", &["1.2.0"]))
+        );
+        assert_eq!(
+            PushedImage::parse(Some("registry_package"), GITHUB_REGISTRY_PACKAGE.as_bytes()),
+            Some(pushed("ghcr.io/org/worker", &["v2"]))
+        );
+    }
+
+    #[test]
+    fn ignores_the_other_github_events() {
+        let npm =
+            GITHUB_PACKAGE.replace(r#""package_type": "CONTAINER""#, r#""package_type": "npm""#);
+        let untagged = GITHUB_PACKAGE.replace(r#""name": "1.2.0""#, r#""name": """#);
+
+        assert_eq!(PushedImage::parse(Some("package"), npm.as_bytes()), None);
+        assert_eq!(
+            PushedImage::parse(Some("package"), untagged.as_bytes()),
+            None
+        );
+        assert_eq!(
+            PushedImage::parse(Some("push"), GITHUB_PACKAGE.as_bytes()),
+            None
+        );
+        assert_eq!(PushedImage::parse(Some("package"), b"not json"), None);
+    }
+
+    #[test]
+    fn matches_the_pushed_image() {
+        let web = pushed("ghcr.io/org/web", &["1.2.0"]);
+
+        assert!(web.is_image("ghcr.io/org/web"));
+        assert!(web.is_image("ghcr.io/org/web:1.0.0"));
+        assert!(!web.is_image("ghcr.io/org/web-worker"));
+        assert!(!web.is_image("ghcr.io/other/web"));
+        assert!(!web.is_image("docker.io/org/web"));
+    }
+
+    /// A trimmed Docker Hub push webhook.
+    const DOCKER_HUB_PUSH: &str = r#"{
+        "callback_url": "https://registry.hub.docker.com/u/org/web/hook/abcdef/",
+        "push_data": {
+            "pushed_at": 1760000000,
+            "pusher": "org",
+            "tag": "1.3.0"
+        },
+        "repository": {
+            "name": "web",
+            "namespace": "org",
+            "owner": "org",
+            "repo_name": "org/web",
+            "repo_url": "https://hub.docker.com/r/org/web",
+            "status": "Active"
+        }
+    }"#;
+
+    /// A trimmed Harbor `PUSH_ARTIFACT` webhook, for an artifact with two tags.
+    const HARBOR_PUSH: &str = r#"{
+        "type": "PUSH_ARTIFACT",
+        "occur_at": 1760000000,
+        "operator": "robot$ci",
+        "event_data": {
+            "resources": [
+                {
+                    "digest": "sha256:0123456789abcdef",
+                    "tag": "1.3.0",
+                    "resource_url": "harbor.example.com/org/web:1.3.0"
+                },
+                {
+                    "digest": "sha256:0123456789abcdef",
+                    "tag": "latest",
+                    "resource_url": "harbor.example.com/org/web:latest"
+                }
+            ],
+            "repository": {
+                "name": "web",
+                "namespace": "org",
+                "repo_full_name": "org/web",
+                "repo_type": "private"
+            }
+        }
+    }"#;
+
+    #[test]
+    fn parses_docker_hub_pushes() {
+        let web = PushedImage::parse(None, DOCKER_HUB_PUSH.as_bytes()).unwrap();
+        assert_eq!(web, pushed("docker.io/org/web", &["1.3.0"]));
+        assert!(web.is_image("org/web:1.2.0"));
+        assert!(web.is_image("registry-1.docker.io/org/web"));
+
+        let official =
+            DOCKER_HUB_PUSH.replace(r#""repo_name": "org/web""#, r#""repo_name": "nginx""#);
+        let nginx = PushedImage::parse(None, official.as_bytes()).unwrap();
+        assert!(nginx.is_image("nginx:1.25"));
+        assert!(nginx.is_image("docker.io/library/nginx"));
+    }
+
+    #[test]
+    fn parses_harbor_pushes() {
+        let web = PushedImage::parse(None, HARBOR_PUSH.as_bytes()).unwrap();
+        assert_eq!(
+            web,
+            pushed("harbor.example.com/org/web", &["1.3.0", "latest"])
+        );
+        assert!(web.is_image("harbor.example.com/org/web:1.2.0"));
+
+        let deleted = HARBOR_PUSH.replace("PUSH_ARTIFACT", "DELETE_ARTIFACT");
+        assert_eq!(PushedImage::parse(None, deleted.as_bytes()), None);
+    }
+
+    #[test]
+    fn doesnt_scope_unknown_payloads() {
+        for body in ["", "{}", r#"{"ref": "refs/heads/main"}"#, "image=web"] {
+            assert_eq!(PushedImage::parse(None, body.as_bytes()), None, "{body}");
+        }
+    }
+}
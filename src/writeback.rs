@@ -0,0 +1,1009 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    overrides::{Overrides, Parameter},
+    split_tag,
+    strategy::is_downgrade,
+    yaml_edit, Candidate,
+};
+
+const TARGET_REVISION: [&str; 3] = ["spec", "source", "targetRevision"];
+const PARAMETERS: [&str; 4] = ["spec", "source", "helm", "parameters"];
+
+/// Which file the selected tag gets written to, from the app's
+/// `write-back-target` annotation.
+#[derive(Clone, Debug, Default)]
+pub enum WriteBackTarget {
+    /// The `.argocd-source-<app>.yaml` override file next to the app's sources.
+    #[default]
+    Overrides,
+    /// A helm values file, relative to the app's path or to the root of the
+    /// repository when it starts with a `/`.
+    HelmValues(String),
+    /// `spec.source.helm.parameters` in the Application manifest itself.
+    Application,
+}
+
+impl FromStr for WriteBackTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "application" => Ok(Self::Application),
+            None if s == "helmvalues" => Ok(Self::HelmValues("values.yaml".to_string())),
+            Some(("helmvalues", path)) if !path.is_empty() => {
+                Ok(Self::HelmValues(path.to_string()))
+            }
+            _ => bail!("Unknown write-back target: {}", s),
+        }
+    }
+}
+
+/// Where the selected tag gets written in the app's override file.
+#[derive(Clone, Debug)]
+pub enum WriteTarget {
+    /// `helm.parameters`, the tag going into `image_tag` and optionally the
+    /// image repository into `image_name`.
+    Helm {
+        image_tag: String,
+        image_name: Option<String>,
+    },
+    /// `kustomize.images`, replacing the entry for `image_name`.
+    Kustomize { image_name: String },
+    /// `spec.source.targetRevision` of the Application the candidate was found
+    /// in.
+    ChartRevision,
+}
+
+/// Something the updater changed in the repository, along with the file it
+/// wrote relative to the repository's root.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// A candidate's tag got updated.
+    Tag {
+        app_name: String,
+        image: String,
+        old_tag: Option<String>,
+        new_tag: String,
+        path: PathBuf,
+    },
+    /// A stale parameter got removed from an app's override file.
+    Pruned {
+        app_name: String,
+        parameter: String,
+        path: PathBuf,
+    },
+}
+
+impl Change {
+    fn tag(candidate: &Candidate, path: &Path, old_tag: Option<String>, new_tag: &str) -> Self {
+        Self::Tag {
+            app_name: candidate.app_name.clone(),
+            image: split_tag(&candidate.url).0.to_string(),
+            old_tag,
+            new_tag: new_tag.to_string(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Parses a change back from a commit message line, the way it's
+    /// displayed. Its path isn't part of it and is left empty.
+    pub fn parse(line: &str) -> Option<Self> {
+        let (app_name, change) = line.split_once(": ")?;
+        // Application names are lowercase DNS names, which tells trailers like
+        // `Signed-off-by: ...` apart
+        if app_name.is_empty()
+            || !app_name
+                .bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-' || c == b'.')
+        {
+            return None;
+        }
+
+        if let Some(parameter) = change.strip_prefix("pruned stale parameter ") {
+            return Some(Self::Pruned {
+                app_name: app_name.to_string(),
+                parameter: parameter.to_string(),
+                path: PathBuf::new(),
+            });
+        }
+
+        let (old_tag, new_tag, image) = match change.split(' ').collect::<Vec<_>>()[..] {
+            [image, old_tag, "->", new_tag] => (Some(old_tag), new_tag, image),
+            [image, new_tag] => (None, new_tag, image),
+            _ => return None,
+        };
+
+        Some(Self::Tag {
+            app_name: app_name.to_string(),
+            image: image.to_string(),
+            old_tag: old_tag.map(str::to_string),
+            new_tag: new_tag.to_string(),
+            path: PathBuf::new(),
+        })
+    }
+
+    pub fn app_name(&self) -> &str {
+        match self {
+            Self::Tag { app_name, .. } | Self::Pruned { app_name, .. } => app_name,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Tag { path, .. } | Self::Pruned { path, .. } => path,
+        }
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tag {
+                app_name,
+                image,
+                old_tag: Some(old_tag),
+                new_tag,
+                ..
+            } => write!(f, "{}: {} {} -> {}", app_name, image, old_tag, new_tag),
+            Self::Tag {
+                app_name,
+                image,
+                old_tag: None,
+                new_tag,
+                ..
+            } => write!(f, "{}: {} {}", app_name, image, new_tag),
+            Self::Pruned {
+                app_name,
+                parameter,
+                ..
+            } => write!(f, "{}: pruned stale parameter {}", app_name, parameter),
+        }
+    }
+}
+
+pub fn update_tag_for_candidate(
+    repo_path: &Path,
+    candidate: &Candidate,
+    tag: &str,
+) -> Result<Option<Change>> {
+    if let WriteTarget::ChartRevision = candidate.target {
+        return update_chart_revision(repo_path, &candidate.manifest, candidate, tag);
+    }
+
+    match &candidate.write_back {
+        WriteBackTarget::Overrides => update_overrides(repo_path, candidate, tag),
+        WriteBackTarget::HelmValues(values_file) => {
+            let values_file = helm_values_file(candidate, values_file);
+            update_helm_values(repo_path, &values_file, candidate, tag)
+        }
+        WriteBackTarget::Application => {
+            update_application(repo_path, &candidate.manifest, candidate, tag)
+        }
+    }
+}
+
+/// Reads the tag currently written for the candidate, without changing
+/// anything.
+pub fn current_tag(repo_path: &Path, candidate: &Candidate) -> Result<Option<String>> {
+    let read = |file: &Path| {
+        let path = repo_path.join(file);
+        match path.exists() {
+            true => {
+                std::fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))
+            }
+            false => Ok(String::new()),
+        }
+    };
+
+    match (&candidate.target, &candidate.write_back) {
+        (WriteTarget::ChartRevision, _) => {
+            let content = read(&candidate.manifest)?;
+            Ok(find_application(&content, &candidate.app_name)
+                .and_then(|document| yaml_edit::get_scalar(&document, &TARGET_REVISION)))
+        }
+        (WriteTarget::Helm { image_tag, .. }, WriteBackTarget::Overrides) => {
+            let overrides = read_overrides(
+                &repo_path.join(overrides_file(&candidate.path, &candidate.app_name)),
+            )?;
+            Ok(overrides.helm.parameter(image_tag).map(str::to_string))
+        }
+        (WriteTarget::Kustomize { image_name }, WriteBackTarget::Overrides) => {
+            let overrides = read_overrides(
+                &repo_path.join(overrides_file(&candidate.path, &candidate.app_name)),
+            )?;
+            Ok(overrides
+                .kustomize
+                .as_ref()
+                .and_then(|kustomize| kustomize.image(image_name))
+                .map(|entry| kustomize_image_tag(entry).unwrap_or_default().to_string()))
+        }
+        (WriteTarget::Helm { image_tag, .. }, WriteBackTarget::HelmValues(values_file)) => {
+            let content = read(&helm_values_file(candidate, values_file))?;
+            let values: serde_yaml::Value = serde_yaml::from_str(&content)?;
+            let tag_path = image_tag.split('.').collect::<Vec<_>>();
+            Ok(yaml_edit::get_scalar(&values, &tag_path))
+        }
+        (WriteTarget::Helm { image_tag, .. }, WriteBackTarget::Application) => {
+            let content = read(&candidate.manifest)?;
+            Ok(find_application(&content, &candidate.app_name)
+                .and_then(|document| yaml_edit::get_parameter(&document, &PARAMETERS, image_tag)))
+        }
+        (WriteTarget::Kustomize { .. }, _) => {
+            bail!("Kustomize images can only be written to the override file")
+        }
+    }
+}
+
+/// Whether writing `tag` over `current` would change anything, the same way
+/// `update_tag_for_candidate` decides it.
+pub fn would_change(candidate: &Candidate, current: Option<&str>, tag: &str) -> bool {
+    should_write(candidate, current, tag) && !current.is_some_and(|current| same_tag(current, tag))
+}
+
+/// Resolves a `write-back-target` values file, relative to the app's path or to
+/// the root of the repository when it starts with `/`.
+fn helm_values_file(candidate: &Candidate, values_file: &str) -> PathBuf {
+    match values_file.strip_prefix('/') {
+        Some(values_file) => PathBuf::from(values_file),
+        None => Path::new(&candidate.path).join(values_file),
+    }
+}
+
+fn overrides_file(path: &str, app_name: &str) -> PathBuf {
+    Path::new(path).join(format!(".argocd-source-{}.yaml", app_name))
+}
+
+fn read_overrides(overrides_path: &Path) -> Result<Overrides> {
+    if !overrides_path.exists() {
+        return Ok(Overrides::default());
+    }
+
+    Ok(serde_yaml::from_str(&std::fs::read_to_string(
+        overrides_path,
+    )?)?)
+}
+
+fn update_overrides(repo_path: &Path, candidate: &Candidate, tag: &str) -> Result<Option<Change>> {
+    let overrides_file = overrides_file(&candidate.path, &candidate.app_name);
+    let overrides_path = repo_path.join(&overrides_file);
+    let mut current_overrides = read_overrides(&overrides_path)?;
+
+    let (image_name, _) = split_tag(&candidate.url);
+    let (has_changed, old_tag) = match &candidate.target {
+        WriteTarget::Helm {
+            image_tag,
+            image_name: helm_image_name,
+        } => {
+            let current = current_overrides
+                .helm
+                .parameter(image_tag)
+                .map(str::to_string);
+            if !should_write(candidate, current.as_deref(), tag) {
+                return Ok(None);
+            }
+            let mut has_changed = !current
+                .as_deref()
+                .is_some_and(|current| same_tag(current, tag));
+            if has_changed {
+                current_overrides.helm.set_parameter(image_tag, tag);
+            }
+
+            if let Some(helm_image_name) = helm_image_name {
+                if current_overrides.helm.parameter(helm_image_name) != Some(image_name) {
+                    log::info!("Setting {} to {}", helm_image_name, image_name);
+                    current_overrides
+                        .helm
+                        .set_parameter(helm_image_name, image_name);
+                    has_changed = true;
+                }
+            }
+
+            (has_changed, current)
+        }
+        WriteTarget::Kustomize {
+            image_name: kustomize_image_name,
+        } => {
+            let kustomize = current_overrides
+                .kustomize
+                .get_or_insert_with(Default::default);
+            let current = kustomize.image(kustomize_image_name);
+            let current_tag = current.map(|entry| kustomize_image_tag(entry).unwrap_or_default());
+            if !should_write(candidate, current_tag, tag) {
+                return Ok(None);
+            }
+            let current_tag = current_tag.map(str::to_string);
+
+            let entry = if kustomize_image_name == image_name {
+                format!("{}:{}", image_name, tag)
+            } else {
+                format!("{}={}:{}", kustomize_image_name, image_name, tag)
+            };
+            let has_changed = !current.is_some_and(|current| same_tag(current, &entry));
+            if has_changed {
+                kustomize.set_image(kustomize_image_name, &entry);
+            }
+
+            (has_changed, current_tag)
+        }
+        WriteTarget::ChartRevision => {
+            bail!("Chart revisions are written to the Application manifest")
+        }
+    };
+
+    if !has_changed || !write_overrides(&overrides_path, &current_overrides)? {
+        return Ok(None);
+    }
+
+    Ok(Some(Change::tag(candidate, &overrides_file, old_tag, tag)))
+}
+
+/// Removes the parameters the updater wrote in the app's override file that
+/// don't belong to any of its candidates anymore, like the ones of an image
+/// dropped from the `image-list`.
+pub fn prune_parameters(
+    repo_path: &Path,
+    app_name: &str,
+    path: &str,
+    managed: &HashSet<String>,
+) -> Result<Vec<Change>> {
+    let overrides_file = overrides_file(path, app_name);
+    let overrides_path = repo_path.join(&overrides_file);
+    if !overrides_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut current_overrides: Overrides =
+        serde_yaml::from_str(&std::fs::read_to_string(&overrides_path)?)?;
+    let pruned = current_overrides.helm.prune_parameters(|parameter| {
+        is_updater_parameter(parameter) && !managed.contains(&parameter.name)
+    });
+    if pruned.is_empty() {
+        return Ok(vec![]);
+    }
+
+    for name in &pruned {
+        log::info!("Pruning stale parameter {} from {:?}", name, overrides_path);
+    }
+    write_overrides(&overrides_path, &current_overrides)?;
+
+    Ok(pruned
+        .into_iter()
+        .map(|parameter| Change::Pruned {
+            app_name: app_name.to_string(),
+            parameter,
+            path: overrides_file.clone(),
+        })
+        .collect())
+}
+
+/// Writes an override file, unless it already has the same content. Parameters
+/// keep their order and values that look like numbers or booleans are always
+/// quoted the same way, so that a run that doesn't change anything doesn't
+/// produce a diff.
+fn write_overrides(overrides_path: &Path, overrides: &Overrides) -> Result<bool> {
+    let serialized = serde_yaml::to_string(overrides)?;
+    if let Ok(existing) = std::fs::read_to_string(overrides_path) {
+        let existing = serde_yaml::from_str::<serde_yaml::Value>(&existing)?;
+        if existing == serde_yaml::from_str::<serde_yaml::Value>(&serialized)? {
+            log::debug!("{:?} didn't change, not rewriting it", overrides_path);
+            return Ok(false);
+        }
+    }
+
+    if let Some(parent) = overrides_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(overrides_path, serialized)?;
+
+    Ok(true)
+}
+
+/// Whether a parameter looks like one the updater wrote: it always forces them
+/// to strings and they point at an image's tag, name or repository.
+fn is_updater_parameter(parameter: &Parameter) -> bool {
+    let last_segment = parameter.name.rsplit('.').next().unwrap_or_default();
+    parameter.forcestring == Some(true) && ["tag", "name", "repository"].contains(&last_segment)
+}
+
+/// Sets the dotted keys from `helm.image-tag` and `helm.image-name` directly in
+/// a values file, leaving the rest of it untouched.
+fn update_helm_values(
+    repo_path: &Path,
+    values_file: &Path,
+    candidate: &Candidate,
+    tag: &str,
+) -> Result<Option<Change>> {
+    let WriteTarget::Helm {
+        image_tag,
+        image_name: helm_image_name,
+    } = &candidate.target
+    else {
+        bail!(
+            "{} writes to helm values but doesn't have a `helm.image-tag`",
+            candidate.url
+        );
+    };
+
+    let values_path = &repo_path.join(values_file);
+    let mut values = if values_path.exists() {
+        std::fs::read_to_string(values_path)
+            .with_context(|| format!("Failed to read {:?}", values_path))?
+    } else {
+        String::new()
+    };
+    let parsed: serde_yaml::Value = serde_yaml::from_str(&values)
+        .with_context(|| format!("Failed to parse {:?}", values_path))?;
+
+    let tag_path = image_tag.split('.').collect::<Vec<_>>();
+    let current = yaml_edit::get_scalar(&parsed, &tag_path);
+    if !should_write(candidate, current.as_deref(), tag) {
+        return Ok(None);
+    }
+
+    let mut has_changed = false;
+    if !current
+        .as_deref()
+        .is_some_and(|current| same_tag(current, tag))
+    {
+        values = yaml_edit::set_scalar(&values, &tag_path, tag)
+            .with_context(|| format!("Failed to set {} in {:?}", image_tag, values_path))?;
+        has_changed = true;
+    }
+
+    if let Some(helm_image_name) = helm_image_name {
+        let (image_name, _) = split_tag(&candidate.url);
+        let name_path = helm_image_name.split('.').collect::<Vec<_>>();
+        if yaml_edit::get_scalar(&parsed, &name_path).as_deref() != Some(image_name) {
+            log::info!("Setting {} to {}", helm_image_name, image_name);
+            values = yaml_edit::set_scalar(&values, &name_path, image_name).with_context(|| {
+                format!("Failed to set {} in {:?}", helm_image_name, values_path)
+            })?;
+            has_changed = true;
+        }
+    }
+
+    if !has_changed {
+        return Ok(None);
+    }
+
+    if let Some(parent) = values_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(values_path, values)?;
+
+    Ok(Some(Change::tag(candidate, values_file, current, tag)))
+}
+
+/// Bumps `spec.source.targetRevision` in the Application manifest the candidate
+/// was found in.
+fn update_chart_revision(
+    repo_path: &Path,
+    manifest_file: &Path,
+    candidate: &Candidate,
+    tag: &str,
+) -> Result<Option<Change>> {
+    let manifest = &repo_path.join(manifest_file);
+    let content = std::fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read {:?}", manifest))?;
+    let is_app = |document: &serde_yaml::Value| is_application(document, &candidate.app_name);
+    let current = find_application(&content, &candidate.app_name)
+        .and_then(|document| yaml_edit::get_scalar(&document, &TARGET_REVISION));
+    if !should_write(candidate, current.as_deref(), tag) {
+        return Ok(None);
+    }
+    if current
+        .as_deref()
+        .is_some_and(|current| same_tag(current, tag))
+    {
+        return Ok(None);
+    }
+
+    let edited = yaml_edit::set_scalar_in(&content, is_app, &TARGET_REVISION, tag)
+        .with_context(|| format!("Failed to set the targetRevision in {:?}", manifest))?;
+    std::fs::write(manifest, edited)?;
+
+    Ok(Some(Change::tag(candidate, manifest_file, current, tag)))
+}
+
+/// Upserts the helm parameters in the Application manifest the candidate was
+/// found in, only touching that Application's document.
+fn update_application(
+    repo_path: &Path,
+    manifest_file: &Path,
+    candidate: &Candidate,
+    tag: &str,
+) -> Result<Option<Change>> {
+    let WriteTarget::Helm {
+        image_tag,
+        image_name: helm_image_name,
+    } = &candidate.target
+    else {
+        bail!(
+            "{} writes to the Application but doesn't have a `helm.image-tag`",
+            candidate.url
+        );
+    };
+
+    let manifest = &repo_path.join(manifest_file);
+    let mut content = std::fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read {:?}", manifest))?;
+    let Some(application) = find_application(&content, &candidate.app_name) else {
+        bail!("Couldn't find {} in {:?}", candidate.app_name, manifest);
+    };
+    let is_app = |document: &serde_yaml::Value| is_application(document, &candidate.app_name);
+
+    let current = yaml_edit::get_parameter(&application, &PARAMETERS, image_tag);
+    if !should_write(candidate, current.as_deref(), tag) {
+        return Ok(None);
+    }
+
+    let mut has_changed = false;
+    if !current
+        .as_deref()
+        .is_some_and(|current| same_tag(current, tag))
+    {
+        content = yaml_edit::set_parameter_in(&content, is_app, &PARAMETERS, image_tag, tag)
+            .with_context(|| format!("Failed to set {} in {:?}", image_tag, manifest))?;
+        has_changed = true;
+    }
+
+    if let Some(helm_image_name) = helm_image_name {
+        let (image_name, _) = split_tag(&candidate.url);
+        let current_name = yaml_edit::get_parameter(&application, &PARAMETERS, helm_image_name);
+        if current_name.as_deref() != Some(image_name) {
+            log::info!("Setting {} to {}", helm_image_name, image_name);
+            content = yaml_edit::set_parameter_in(
+                &content,
+                is_app,
+                &PARAMETERS,
+                helm_image_name,
+                image_name,
+            )
+            .with_context(|| format!("Failed to set {} in {:?}", helm_image_name, manifest))?;
+            has_changed = true;
+        }
+    }
+
+    if !has_changed {
+        return Ok(None);
+    }
+    std::fs::write(manifest, content)?;
+
+    Ok(Some(Change::tag(candidate, manifest_file, current, tag)))
+}
+
+fn is_application(document: &serde_yaml::Value, app_name: &str) -> bool {
+    document.get("kind").and_then(serde_yaml::Value::as_str) == Some("Application")
+        && document
+            .get("metadata")
+            .and_then(|metadata| metadata.get("name"))
+            .and_then(serde_yaml::Value::as_str)
+            == Some(app_name)
+}
+
+/// Finds the document of the Application named `app_name` in `content`.
+fn find_application(content: &str, app_name: &str) -> Option<serde_yaml::Value> {
+    serde_yaml::Deserializer::from_str(content)
+        .filter_map(|document| serde_yaml::Value::deserialize(document).ok())
+        .find(|document| is_application(document, app_name))
+}
+
+/// Checks whether moving from `current` to `tag` is allowed, logging what's
+/// about to happen.
+fn should_write(candidate: &Candidate, current: Option<&str>, tag: &str) -> bool {
+    match current {
+        Some(current) if !candidate.allow_downgrade && is_downgrade(current, tag) => {
+            log::warn!(
+                "Refusing to downgrade {} from {} to {}",
+                candidate.url,
+                current,
+                tag
+            );
+            false
+        }
+        Some(current) if same_tag(current, tag) => true,
+        Some(_) => {
+            log::info!(
+                "Updating existing override for {} to {}",
+                candidate.url,
+                tag
+            );
+            true
+        }
+        None => {
+            log::info!("Creating new override for {} with {}", candidate.url, tag);
+            true
+        }
+    }
+}
+
+/// Extracts the tag of a `[old=]repo:tag[@digest]` kustomize image entry, keeping
+/// the digest attached like the digest strategy writes it.
+fn kustomize_image_tag(entry: &str) -> Option<&str> {
+    let image = entry.split_once('=').map_or(entry, |(_, image)| image);
+    let name_end = image.find('@').unwrap_or(image.len());
+    let (name, _) = split_tag(&image[..name_end]);
+
+    image.get(name.len() + 1..)
+}
+
+/// Compares two override values, ignoring surrounding whitespace and the case of
+/// digests so that a cosmetic difference doesn't register as a change.
+fn same_tag(current: &str, new: &str) -> bool {
+    let normalize = |value: &str| match value.trim().split_once('@') {
+        Some((tag, digest)) => format!("{}@{}", tag, digest.to_ascii_lowercase()),
+        None => value.trim().to_string(),
+    };
+
+    normalize(current) == normalize(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVERRIDES: &str = "apps/web/.argocd-source-web.yaml";
+
+    /// Writes `tag` for `candidate` in a checkout holding `files`, returning the
+    /// change and the checkout.
+    fn write(
+        files: &[(&str, &str)],
+        candidate: &Candidate,
+        tag: &str,
+    ) -> (Option<Change>, tempfile::TempDir) {
+        let checkout = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = checkout.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        let change = update_tag_for_candidate(checkout.path(), candidate, tag).unwrap();
+        (change, checkout)
+    }
+
+    fn read(checkout: &tempfile::TempDir, path: &str) -> String {
+        std::fs::read_to_string(checkout.path().join(path)).unwrap()
+    }
+
+    #[test]
+    fn compares_tags_without_cosmetic_differences() {
+        assert!(same_tag(" 1.2.3\n", "1.2.3"));
+        assert!(same_tag("latest@sha256:ABCDEF", "latest@sha256:abcdef"));
+        assert!(!same_tag("latest@sha256:abcdef", "latest@sha256:abcdee"));
+        assert!(!same_tag("stable@sha256:abcdef", "latest@sha256:abcdef"));
+    }
+
+    #[test]
+    fn writes_digests() {
+        let candidate = Candidate::test("ghcr.io/org/web", "");
+        let current = "helm:\n  parameters:\n  - name: image.tag\n    value: latest@sha256:aaaa\n    forcestring: true\n";
+
+        let (change, checkout) = write(&[(OVERRIDES, current)], &candidate, "latest@sha256:bbbb");
+        assert!(change.is_some());
+        assert_eq!(read(&checkout, OVERRIDES), current.replace("aaaa", "bbbb"));
+    }
+
+    #[test]
+    fn doesnt_rewrite_the_same_digest() {
+        let candidate = Candidate::test("ghcr.io/org/web", "");
+        let current =
+            "helm:\n  parameters:\n  - name: image.tag\n    value: ' latest@sha256:ABCD '\n";
+
+        let (change, checkout) = write(&[(OVERRIDES, current)], &candidate, "latest@sha256:abcd");
+        assert!(change.is_none());
+        assert_eq!(read(&checkout, OVERRIDES), current);
+    }
+
+    fn parameters(tag: &str) -> String {
+        format!(
+            "helm:\n  parameters:\n  - name: image.tag\n    value: {}\n    forcestring: true\n",
+            tag
+        )
+    }
+
+    #[test]
+    fn refuses_to_downgrade() {
+        let candidate = Candidate::test("ghcr.io/org/web", "");
+        let (change, checkout) = write(&[(OVERRIDES, &parameters("1.10.2"))], &candidate, "1.9.9");
+        assert!(change.is_none());
+        assert_eq!(read(&checkout, OVERRIDES), parameters("1.10.2"));
+    }
+
+    #[test]
+    fn downgrades_when_allowed() {
+        let candidate = Candidate {
+            allow_downgrade: true,
+            ..Candidate::test("ghcr.io/org/web", "")
+        };
+        let (change, checkout) = write(&[(OVERRIDES, &parameters("1.10.2"))], &candidate, "1.9.9");
+        assert!(change.is_some());
+        assert_eq!(read(&checkout, OVERRIDES), parameters("1.9.9"));
+    }
+
+    #[test]
+    fn writes_tags_that_arent_versions() {
+        let candidate = Candidate::test("ghcr.io/org/web", "");
+        let (change, checkout) = write(
+            &[(OVERRIDES, &parameters("sha-bbbbbb"))],
+            &candidate,
+            "sha-aaaaaa",
+        );
+        assert!(change.is_some());
+        assert_eq!(read(&checkout, OVERRIDES), parameters("sha-aaaaaa"));
+    }
+
+    fn kustomize_candidate(url: &str, image_name: &str) -> Candidate {
+        Candidate {
+            target: WriteTarget::Kustomize {
+                image_name: image_name.to_string(),
+            },
+            ..Candidate::test(url, "")
+        }
+    }
+
+    #[test]
+    fn writes_helm_and_kustomize_images_side_by_side() {
+        let checkout = tempfile::tempdir().unwrap();
+        let helm = Candidate::test("ghcr.io/org/api", "");
+        let kustomize = kustomize_candidate("ghcr.io/org/web", "ghcr.io/org/web");
+        let renamed = kustomize_candidate("ghcr.io/org/worker", "worker");
+
+        for (candidate, tag) in [(&helm, "1.0.0"), (&kustomize, "2.0.0"), (&renamed, "3.0.0")] {
+            let change = update_tag_for_candidate(checkout.path(), candidate, tag).unwrap();
+            assert!(change.is_some());
+        }
+        assert_eq!(
+            read(&checkout, OVERRIDES),
+            "\
+helm:
+  parameters:
+  - name: image.tag
+    value: 1.0.0
+    forcestring: true
+kustomize:
+  images:
+  - ghcr.io/org/web:2.0.0
+  - worker=ghcr.io/org/worker:3.0.0
+"
+        );
+    }
+
+    #[test]
+    fn replaces_the_kustomize_image_of_the_repository() {
+        let current = "\
+kustomize:
+  images:
+  - ghcr.io/org/other:1.0.0
+  - ghcr.io/org/web:1.0.0@sha256:abcd
+  namePrefix: prod-
+";
+        let candidate = kustomize_candidate("ghcr.io/org/web", "ghcr.io/org/web");
+
+        assert_eq!(
+            current_tag(checkout_with(current).path(), &candidate)
+                .unwrap()
+                .as_deref(),
+            Some("1.0.0@sha256:abcd")
+        );
+        let (change, checkout) = write(&[(OVERRIDES, current)], &candidate, "1.1.0");
+        assert!(change.is_some());
+        assert_eq!(
+            read(&checkout, OVERRIDES),
+            current.replace("1.0.0@sha256:abcd", "1.1.0")
+        );
+    }
+
+    fn checkout_with(overrides: &str) -> tempfile::TempDir {
+        let checkout = tempfile::tempdir().unwrap();
+        let path = checkout.path().join(OVERRIDES);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, overrides).unwrap();
+        checkout
+    }
+
+    fn values_candidate(values_file: &str, image_tag: &str) -> Candidate {
+        Candidate {
+            target: WriteTarget::Helm {
+                image_tag: image_tag.to_string(),
+                image_name: None,
+            },
+            write_back: values_file.parse().unwrap(),
+            ..Candidate::test("ghcr.io/org/web", "")
+        }
+    }
+
+    #[test]
+    fn parses_write_back_targets() {
+        let parse = |s: &str| {
+            s.parse::<WriteBackTarget>()
+                .map(|target| format!("{:?}", target))
+        };
+
+        assert_eq!(parse("helmvalues").unwrap(), r#"HelmValues("values.yaml")"#);
+        assert_eq!(
+            parse("helmvalues:/env/prod.yaml").unwrap(),
+            r#"HelmValues("/env/prod.yaml")"#
+        );
+        assert_eq!(parse("application").unwrap(), "Application");
+        assert!(parse("helmvalues:").is_err());
+        assert!(parse("git").is_err());
+    }
+
+    #[test]
+    fn sets_nested_helm_values() {
+        let values = "\
+# Deployed everywhere
+replicas: 2
+web:
+  image:
+    repository: ghcr.io/org/web # Public
+    tag: 1.0.0
+  resources: {}
+";
+        let candidate = values_candidate("helmvalues", "web.image.tag");
+        let (change, checkout) = write(&[("apps/web/values.yaml", values)], &candidate, "1.1.0");
+
+        assert!(change.is_some());
+        assert_eq!(
+            read(&checkout, "apps/web/values.yaml"),
+            values.replace("tag: 1.0.0", "tag: 1.1.0")
+        );
+    }
+
+    #[test]
+    fn creates_the_missing_helm_values() {
+        let values = "replicas: 2\n";
+        let candidate = values_candidate("helmvalues:/env/prod.yaml", "web.image.tag");
+        let (_, checkout) = write(&[("env/prod.yaml", values)], &candidate, "1.10");
+        assert_eq!(
+            read(&checkout, "env/prod.yaml"),
+            "replicas: 2\nweb:\n  image:\n    tag: '1.10'\n"
+        );
+
+        let (change, checkout) = write(&[], &candidate, "1.1.0");
+        assert!(change.is_some());
+        assert_eq!(
+            read(&checkout, "env/prod.yaml"),
+            "web:\n  image:\n    tag: 1.1.0\n"
+        );
+    }
+
+    #[test]
+    fn wont_set_helm_values_in_lists() {
+        let checkout = checkout_with("");
+        let values = "web:\n  image:\n  - tag: 1.0.0\n";
+        let path = checkout.path().join("apps/web/values.yaml");
+        std::fs::write(&path, values).unwrap();
+
+        let candidate = values_candidate("helmvalues", "web.image.tag");
+        assert!(update_tag_for_candidate(checkout.path(), &candidate, "1.1.0").is_err());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), values);
+    }
+
+    /// Whether the checkout has changes git would commit.
+    fn is_dirty(repository: &git2::Repository) -> bool {
+        !repository.statuses(None).unwrap().is_empty()
+    }
+
+    #[test]
+    fn a_second_run_doesnt_change_anything() {
+        let current = "\
+helm:
+  parameters:
+  - name: replicas
+    value: '3'
+  - name: debug
+    value: 'true'
+  - name: image.tag
+    value: '1.10'
+    forcestring: true
+  valueFiles:
+  - values-prod.yaml
+";
+        let checkout = checkout_with(current);
+        let repository = git2::Repository::init(checkout.path()).unwrap();
+        let commit = || {
+            let mut index = repository.index().unwrap();
+            index
+                .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+                .unwrap();
+            index.write().unwrap();
+            let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = git2::Signature::now("test", "test@example.com").unwrap();
+            let parents = repository
+                .head()
+                .ok()
+                .map(|head| head.peel_to_commit().unwrap());
+            repository
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    "Update",
+                    &tree,
+                    &parents.iter().collect::<Vec<_>>(),
+                )
+                .unwrap();
+        };
+        commit();
+
+        let candidate = Candidate {
+            target: WriteTarget::Helm {
+                image_tag: "image.tag".to_string(),
+                image_name: Some("image.repository".to_string()),
+            },
+            ..Candidate::test("ghcr.io/org/web", "")
+        };
+        assert!(update_tag_for_candidate(checkout.path(), &candidate, "2.0")
+            .unwrap()
+            .is_some());
+        commit();
+        let written = read(&checkout, OVERRIDES);
+        assert!(written.contains("value: '2.0'") && written.contains("value: 'true'"));
+
+        assert!(update_tag_for_candidate(checkout.path(), &candidate, "2.0")
+            .unwrap()
+            .is_none());
+        assert!(!is_dirty(&repository));
+    }
+
+    #[test]
+    fn parses_the_changes_back() {
+        let changes = [
+            Change::Tag {
+                app_name: "web".to_string(),
+                image: "ghcr.io/org/web".to_string(),
+                old_tag: Some("1.0.0".to_string()),
+                new_tag: "1.1.0".to_string(),
+                path: PathBuf::new(),
+            },
+            Change::Tag {
+                app_name: "web-2.staging".to_string(),
+                image: "redis".to_string(),
+                old_tag: None,
+                new_tag: "7.2@sha256:0123".to_string(),
+                path: PathBuf::new(),
+            },
+            Change::Pruned {
+                app_name: "api".to_string(),
+                parameter: "worker.image.tag".to_string(),
+                path: PathBuf::new(),
+            },
+        ];
+
+        for change in changes {
+            let line = change.to_string();
+            assert_eq!(Change::parse(&line), Some(change), "{line}");
+        }
+        assert_eq!(
+            Change::parse("web: ghcr.io/org/web 1.0.0 -> 1.1.0")
+                .unwrap()
+                .to_string(),
+            "web: ghcr.io/org/web 1.0.0 -> 1.1.0"
+        );
+    }
+
+    #[test]
+    fn doesnt_parse_other_lines() {
+        for line in [
+            "Signed-off-by: Someone <someone@example.com>",
+            "Update 2 images",
+            "",
+            ": ghcr.io/org/web 1.1.0",
+            "web: ghcr.io/org/web 1.0.0 to 1.1.0",
+            "web:ghcr.io/org/web 1.1.0",
+        ] {
+            assert_eq!(Change::parse(line), None, "{line}");
+        }
+    }
+}
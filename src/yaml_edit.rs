@@ -0,0 +1,686 @@
+//! Surgical edits of block style YAML documents.
+//!
+//! Going through `serde_yaml` to rewrite a user's file loses comments, quoting
+//! and formatting. The functions here only touch the lines holding the value
+//! being changed and then check, by parsing both versions, that nothing else
+//! was affected.
+
+use anyhow::{bail, Context, Result};
+use serde_yaml::{Mapping, Value};
+
+/// What to do at the end of the key path.
+enum Leaf<'a> {
+    /// Set the scalar to this value.
+    Scalar(&'a str),
+    /// Set the `value` of the `{name, value}` entry with this name in a list,
+    /// appending it with `forceString` when there's none, like ArgoCD's helm
+    /// parameters.
+    Parameter { name: &'a str, value: &'a str },
+}
+
+/// Sets the scalar at the dotted `path` (e.g. `image.tag`) to `value`,
+/// creating intermediate mappings when they don't exist.
+pub fn set_scalar(document: &str, path: &[&str], value: &str) -> Result<String> {
+    set_scalar_in(document, |_| true, path, value)
+}
+
+/// Same as [`set_scalar`] but for a file that can contain multiple documents,
+/// editing the one matched by `select`.
+pub fn set_scalar_in(
+    content: &str,
+    select: impl Fn(&Value) -> bool,
+    path: &[&str],
+    value: &str,
+) -> Result<String> {
+    edit(content, select, path, &Leaf::Scalar(value))
+}
+
+/// Upserts the `name` entry of the parameter list at `path` (e.g.
+/// `spec.source.helm.parameters`) in the document matched by `select`.
+pub fn set_parameter_in(
+    content: &str,
+    select: impl Fn(&Value) -> bool,
+    path: &[&str],
+    name: &str,
+    value: &str,
+) -> Result<String> {
+    edit(content, select, path, &Leaf::Parameter { name, value })
+}
+
+/// Returns the string at the dotted `path`, if there's one.
+pub fn get_scalar(document: &Value, path: &[&str]) -> Option<String> {
+    let mut current = document;
+    for key in path {
+        current = current.as_mapping()?.get(*key)?;
+    }
+
+    as_string(current)
+}
+
+/// Returns the `value` of the `name` entry in the parameter list at `path`.
+pub fn get_parameter(document: &Value, path: &[&str], name: &str) -> Option<String> {
+    let mut current = document;
+    for key in path {
+        current = current.as_mapping()?.get(*key)?;
+    }
+
+    current
+        .as_sequence()?
+        .iter()
+        .find(|entry| entry.get("name").and_then(Value::as_str) == Some(name))
+        .and_then(|entry| as_string(entry.get("value")?))
+}
+
+fn as_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn edit(
+    content: &str,
+    select: impl Fn(&Value) -> bool,
+    path: &[&str],
+    leaf: &Leaf,
+) -> Result<String> {
+    let mut lines = content.lines().map(str::to_string).collect::<Vec<_>>();
+
+    let matching = document_ranges(&lines)
+        .into_iter()
+        .filter(|&(start, end)| {
+            let document = lines[start..end].join("\n");
+            serde_yaml::from_str(&document).is_ok_and(|parsed| select(&parsed))
+        })
+        .collect::<Vec<_>>();
+    let (start, end) = match matching[..] {
+        [range] => range,
+        [] => bail!("Couldn't find the document to edit"),
+        _ => bail!("Multiple documents match, refusing to pick one"),
+    };
+
+    let original = lines[start..end].join("\n");
+    set_in_range(&mut lines, start, end, 0, path, leaf)?;
+    let edited_end = end + lines.len() - content.lines().count();
+    verify(&original, &lines[start..edited_end].join("\n"), path, leaf)?;
+
+    let mut edited = lines.join("\n");
+    edited.push('\n');
+
+    Ok(edited)
+}
+
+/// Returns the line ranges of the documents in a file, excluding the `---`
+/// separators.
+fn document_ranges(lines: &[String]) -> Vec<(usize, usize)> {
+    let mut ranges = vec![];
+    let mut start = 0;
+    for (idx, line) in lines.iter().enumerate() {
+        let is_separator = line
+            .strip_prefix("---")
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t']));
+        if is_separator {
+            ranges.push((start, idx));
+            start = idx + 1;
+        }
+    }
+    ranges.push((start, lines.len()));
+
+    // Skip empty documents, like the one before a leading `---`, unless there's
+    // nothing else to edit
+    let with_content = ranges
+        .iter()
+        .copied()
+        .filter(|&(start, end)| lines[start..end].iter().any(|line| !is_ignorable(line)))
+        .collect::<Vec<_>>();
+    if with_content.is_empty() {
+        ranges.split_off(ranges.len() - 1)
+    } else {
+        with_content
+    }
+}
+
+/// Renders `value` as a YAML string scalar, quoting it when it would otherwise
+/// be read as a number, a boolean, null...
+fn render_scalar(value: &str) -> Result<String> {
+    Ok(serde_yaml::to_string(&Value::String(value.to_string()))?
+        .trim_end()
+        .to_string())
+}
+
+/// A `key: value` line.
+struct KeyLine<'a> {
+    indent: usize,
+    key: &'a str,
+    /// Byte offset of the value in the line.
+    value_start: usize,
+    /// Byte offset of the end of the value, before any trailing comment.
+    value_end: usize,
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn is_ignorable(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty() || trimmed.starts_with('#')
+}
+
+fn is_list_item(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    trimmed == "-" || trimmed.starts_with("- ")
+}
+
+fn parse_key_line(line: &str) -> Option<KeyLine<'_>> {
+    let indent = indent_of(line);
+    let rest = &line[indent..];
+    if rest.starts_with('-') || rest.starts_with('#') {
+        return None;
+    }
+
+    let (key, after_key) = match rest.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let end = rest[1..].find(quote)? + 1;
+            (&rest[1..end], end + 1)
+        }
+        _ => {
+            let end = rest
+                .find(": ")
+                .or_else(|| rest.ends_with(':').then(|| rest.len() - 1))?;
+            (&rest[..end], end)
+        }
+    };
+
+    if !rest[after_key..].starts_with(':') {
+        return None;
+    }
+
+    let value_start = indent + after_key + 1;
+    let value = &line[value_start..];
+    let leading = value.len() - value.trim_start().len();
+    let value_start = value_start + leading;
+    let value_end = value_start + scalar_len(&line[value_start..]);
+
+    Some(KeyLine {
+        indent,
+        key,
+        value_start,
+        value_end,
+    })
+}
+
+/// Length of the scalar at the start of `value`, excluding a trailing comment.
+fn scalar_len(value: &str) -> usize {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (idx, c) in value.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') if idx == 0 => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '#') if previous == ' ' => return value[..idx].trim_end().len(),
+            _ => {}
+        }
+        previous = c;
+    }
+
+    value.trim_end().len()
+}
+
+/// Returns the index one past the last line belonging to the block opened by
+/// the line at `start`, whose keys are at `indent`.
+fn block_end(lines: &[String], start: usize, end: usize, indent: usize) -> usize {
+    let mut last_content = start + 1;
+    for (idx, line) in lines.iter().enumerate().take(end).skip(start + 1) {
+        if is_ignorable(line) {
+            continue;
+        }
+
+        // Lists can be at the same indentation as their key
+        let line_indent = indent_of(line);
+        let compact_sequence = line_indent == indent && is_list_item(line);
+        if line_indent < indent || (line_indent == indent && !compact_sequence) {
+            break;
+        }
+        last_content = idx + 1;
+    }
+
+    last_content
+}
+
+/// Returns the index one past the last line with content in `start..end`, or
+/// `start` if there's none.
+fn content_end(lines: &[String], start: usize, end: usize) -> usize {
+    (start..end)
+        .rev()
+        .find(|idx| !is_ignorable(&lines[*idx]))
+        .map_or(start, |idx| idx + 1)
+}
+
+/// Applies `leaf` at `path` within the mapping whose keys are at `indent`,
+/// spanning `lines[start..end]`.
+fn set_in_range(
+    lines: &mut Vec<String>,
+    start: usize,
+    end: usize,
+    indent: usize,
+    path: &[&str],
+    leaf: &Leaf,
+) -> Result<()> {
+    let (key, rest) = path.split_first().context("Empty key path")?;
+
+    for idx in start..end {
+        let Some(key_line) = parse_key_line(&lines[idx]) else {
+            continue;
+        };
+        if key_line.indent != indent || key_line.key != *key {
+            continue;
+        }
+
+        let current = lines[idx][key_line.value_start..key_line.value_end].to_string();
+        let child_end = block_end(lines, idx, end, indent);
+        let first_child = (idx + 1..child_end).find(|child| !is_ignorable(&lines[*child]));
+
+        match (rest, leaf) {
+            ([], Leaf::Parameter { name, value }) => {
+                return set_parameter(lines, idx, child_end, first_child, name, value);
+            }
+            ([], Leaf::Scalar(value)) => {
+                if first_child.is_some() || current.starts_with(['|', '>', '{', '[']) {
+                    bail!("`{}` isn't a plain scalar", key);
+                }
+
+                let line = &lines[idx];
+                let mut edited = line[..key_line.value_start].to_string();
+                if key_line.value_start == line.len() {
+                    edited.push(' ');
+                }
+                edited.push_str(&render_scalar(value)?);
+                edited.push_str(&line[key_line.value_end..]);
+                lines[idx] = edited;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        match current.as_str() {
+            "" | "{}" | "~" | "null" => {}
+            _ => bail!("`{}` isn't a mapping", key),
+        }
+
+        if let Some(first_child) = first_child {
+            if is_list_item(&lines[first_child]) {
+                bail!("`{}` is a list", key);
+            }
+            let child_indent = indent_of(&lines[first_child]);
+            return set_in_range(lines, idx + 1, child_end, child_indent, rest, leaf);
+        }
+
+        // An empty mapping, drop the inline `{}`/`null` and add the children
+        clear_inline_value(lines, idx);
+        let new_lines = key_lines(indent + 2, rest, leaf)?;
+        lines.splice(idx + 1..idx + 1, new_lines);
+        return Ok(());
+    }
+
+    // Append after the last line with content so that trailing comments stay at
+    // the end of the block.
+    let insert_at = content_end(lines, start, end);
+    let new_lines = key_lines(indent, path, leaf)?;
+    lines.splice(insert_at..insert_at, new_lines);
+
+    Ok(())
+}
+
+/// Upserts the `name` entry of the list opened by the key at `idx`.
+fn set_parameter(
+    lines: &mut Vec<String>,
+    idx: usize,
+    child_end: usize,
+    first_child: Option<usize>,
+    name: &str,
+    value: &str,
+) -> Result<()> {
+    let key_line = parse_key_line(&lines[idx]).context("Not a key")?;
+    let current = &lines[idx][key_line.value_start..key_line.value_end];
+
+    let Some(first_child) = first_child else {
+        match current {
+            "" | "[]" | "~" | "null" => {}
+            _ => bail!("`{}` isn't a list", key_line.key),
+        }
+
+        let indent = key_line.indent;
+        clear_inline_value(lines, idx);
+        lines.splice(idx + 1..idx + 1, parameter_lines(indent, name, value)?);
+        return Ok(());
+    };
+
+    if !current.is_empty() || !is_list_item(&lines[first_child]) {
+        bail!("`{}` isn't a list", key_line.key);
+    }
+
+    let dash_indent = indent_of(&lines[first_child]);
+    let items = (first_child..child_end)
+        .filter(|item| indent_of(&lines[*item]) == dash_indent && is_list_item(&lines[*item]))
+        .collect::<Vec<_>>();
+
+    for (position, &item_start) in items.iter().enumerate() {
+        let item_end = items.get(position + 1).copied().unwrap_or(child_end);
+        let item_end = content_end(lines, item_start, item_end);
+
+        // Look at the entry as a mapping by blanking out its dash
+        let mut item = lines[item_start..item_end].to_vec();
+        item[0].replace_range(dash_indent..dash_indent + 1, " ");
+        let content_indent = indent_of(&item[0]);
+
+        let name_matches = item.iter().any(|line| {
+            parse_key_line(line).is_some_and(|key_line| {
+                key_line.indent == content_indent
+                    && key_line.key == "name"
+                    && serde_yaml::from_str::<String>(
+                        &line[key_line.value_start..key_line.value_end],
+                    )
+                    .is_ok_and(|entry_name| entry_name == name)
+            })
+        });
+        if !name_matches {
+            continue;
+        }
+
+        let item_len = item.len();
+        set_in_range(
+            &mut item,
+            0,
+            item_len,
+            content_indent,
+            &["value"],
+            &Leaf::Scalar(value),
+        )?;
+        item[0].replace_range(dash_indent..dash_indent + 1, "-");
+        lines.splice(item_start..item_end, item);
+        return Ok(());
+    }
+
+    let insert_at = content_end(lines, first_child, child_end);
+    lines.splice(
+        insert_at..insert_at,
+        parameter_lines(dash_indent, name, value)?,
+    );
+
+    Ok(())
+}
+
+/// Drops the inline value of the key at `idx`, like `{}` or `null`, before
+/// adding children to it.
+fn clear_inline_value(lines: &mut [String], idx: usize) {
+    let Some(key_line) = parse_key_line(&lines[idx]) else {
+        return;
+    };
+
+    let line = &lines[idx];
+    lines[idx] = format!(
+        "{}{}",
+        line[..key_line.value_start].trim_end(),
+        &line[key_line.value_end..]
+    );
+}
+
+/// Renders the lines creating `path`, starting at `indent`.
+fn key_lines(indent: usize, path: &[&str], leaf: &Leaf) -> Result<Vec<String>> {
+    let mut new_lines = vec![];
+    for (depth, key) in path.iter().enumerate() {
+        let padding = " ".repeat(indent + depth * 2);
+        match leaf {
+            Leaf::Scalar(value) if depth == path.len() - 1 => {
+                new_lines.push(format!(
+                    "{}{}: {}",
+                    padding,
+                    render_scalar(key)?,
+                    render_scalar(value)?
+                ));
+            }
+            _ => new_lines.push(format!("{}{}:", padding, render_scalar(key)?)),
+        }
+    }
+
+    if let Leaf::Parameter { name, value } = leaf {
+        let indent = indent + (path.len() - 1) * 2;
+        new_lines.extend(parameter_lines(indent, name, value)?);
+    }
+
+    Ok(new_lines)
+}
+
+fn parameter_lines(indent: usize, name: &str, value: &str) -> Result<Vec<String>> {
+    let padding = " ".repeat(indent);
+    Ok(vec![
+        format!("{}- name: {}", padding, render_scalar(name)?),
+        format!("{}  value: {}", padding, render_scalar(value)?),
+        format!("{}  forceString: true", padding),
+    ])
+}
+
+/// Makes sure `edited` only differs from `original` by what `leaf` changes at
+/// `path`.
+fn verify(original: &str, edited: &str, path: &[&str], leaf: &Leaf) -> Result<()> {
+    let mut expected: Value = serde_yaml::from_str(original)?;
+    let mut current = &mut expected;
+    for (depth, key) in path.iter().enumerate() {
+        if current.is_null() {
+            *current = Value::Mapping(Mapping::new());
+        }
+        let Value::Mapping(mapping) = current else {
+            bail!("`{}` isn't a mapping", path[..depth].join("."));
+        };
+        current = mapping
+            .entry(Value::String(key.to_string()))
+            .or_insert(Value::Null);
+    }
+
+    match leaf {
+        Leaf::Scalar(value) => *current = Value::String(value.to_string()),
+        Leaf::Parameter { name, value } => {
+            if current.is_null() {
+                *current = Value::Sequence(vec![]);
+            }
+            let Value::Sequence(entries) = current else {
+                bail!("`{}` isn't a list", path.join("."));
+            };
+
+            let entry = entries
+                .iter_mut()
+                .filter_map(Value::as_mapping_mut)
+                .find(|entry| entry.get("name").and_then(Value::as_str) == Some(*name));
+            match entry {
+                Some(entry) => {
+                    entry.insert("value".into(), Value::String(value.to_string()));
+                }
+                None => {
+                    let mut entry = Mapping::new();
+                    entry.insert("name".into(), Value::String(name.to_string()));
+                    entry.insert("value".into(), Value::String(value.to_string()));
+                    entry.insert("forceString".into(), Value::Bool(true));
+                    entries.push(Value::Mapping(entry));
+                }
+            }
+        }
+    }
+
+    let edited: Value = serde_yaml::from_str(edited).context("Edited YAML doesn't parse")?;
+    if edited != expected {
+        bail!("Couldn't safely edit `{}`", path.join("."));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_app(document: &Value) -> bool {
+        document.get("kind").and_then(Value::as_str) == Some("Application")
+    }
+
+    #[test]
+    fn only_changes_the_value() {
+        let values = "\
+# The web app
+image:
+  repository: ghcr.io/org/web # where it's pulled from
+  tag: \"1.0.0\"   # bumped by the updater
+replicas: 2
+";
+        assert_eq!(
+            set_scalar(values, &["image", "tag"], "1.1.0").unwrap(),
+            "\
+# The web app
+image:
+  repository: ghcr.io/org/web # where it's pulled from
+  tag: 1.1.0   # bumped by the updater
+replicas: 2
+"
+        );
+    }
+
+    #[test]
+    fn quotes_what_isnt_a_string_otherwise() {
+        let values = "image:\n  tag: latest\n";
+        assert_eq!(
+            set_scalar(values, &["image", "tag"], "1.10").unwrap(),
+            "image:\n  tag: '1.10'\n"
+        );
+        assert_eq!(
+            set_scalar(values, &["image", "tag"], "true").unwrap(),
+            "image:\n  tag: 'true'\n"
+        );
+    }
+
+    #[test]
+    fn creates_the_missing_keys() {
+        assert_eq!(
+            set_scalar("replicas: 2\n# The end\n", &["image", "tag"], "1.0.0").unwrap(),
+            "replicas: 2\nimage:\n  tag: 1.0.0\n# The end\n"
+        );
+        assert_eq!(
+            set_scalar("image: {}\n", &["image", "tag"], "1.0.0").unwrap(),
+            "image:\n  tag: 1.0.0\n"
+        );
+        assert_eq!(
+            set_scalar("image:\n  repository: web\n", &["image", "tag"], "1.0.0").unwrap(),
+            "image:\n  repository: web\n  tag: 1.0.0\n"
+        );
+    }
+
+    #[test]
+    fn refuses_what_isnt_a_scalar() {
+        for values in [
+            "image:\n  tag: |\n    1.0.0\n",
+            "image:\n  tag:\n    name: 1.0.0\n",
+            "image: web\n",
+            "image:\n- tag: 1.0.0\n",
+        ] {
+            assert!(
+                set_scalar(values, &["image", "tag"], "1.1.0").is_err(),
+                "{values}"
+            );
+        }
+    }
+
+    #[test]
+    fn edits_the_selected_document() {
+        let content = "\
+---
+kind: ConfigMap
+spec:
+  targetRevision: main
+---
+kind: Application
+spec:
+  targetRevision: 1.0.0 # the chart
+";
+        let edited = set_scalar_in(content, is_app, &["spec", "targetRevision"], "1.1.0").unwrap();
+        assert_eq!(edited, content.replace("1.0.0 #", "1.1.0 #"));
+
+        let twice = format!("{}---\n{}", content, content);
+        assert!(set_scalar_in(&twice, is_app, &["spec", "targetRevision"], "1.1.0").is_err());
+        assert!(set_scalar_in(content, |_| false, &["spec", "targetRevision"], "1.1.0").is_err());
+    }
+
+    #[test]
+    fn upserts_parameters() {
+        let parameters = ["spec", "source", "helm", "parameters"];
+        let application = "\
+kind: Application
+spec:
+  source:
+    helm:
+      parameters:
+      - name: image.tag
+        value: 1.0.0 # pinned
+        forceString: true
+";
+        assert_eq!(
+            set_parameter_in(application, is_app, &parameters, "image.tag", "1.1.0").unwrap(),
+            application.replace("1.0.0 #", "1.1.0 #")
+        );
+        assert_eq!(
+            set_parameter_in(application, is_app, &parameters, "worker.tag", "2.0").unwrap(),
+            format!(
+                "{}      - name: worker.tag\n        value: '2.0'\n        forceString: true\n",
+                application
+            )
+        );
+        assert_eq!(
+            set_parameter_in(
+                "kind: Application\nspec:\n  source:\n    path: web\n",
+                is_app,
+                &parameters,
+                "image.tag",
+                "1.0.0"
+            )
+            .unwrap(),
+            "\
+kind: Application
+spec:
+  source:
+    path: web
+    helm:
+      parameters:
+      - name: image.tag
+        value: 1.0.0
+        forceString: true
+"
+        );
+    }
+
+    #[test]
+    fn reads_values() {
+        let document: Value = serde_yaml::from_str(
+            "image:\n  tag: 42\n  pull: true\nparameters:\n- name: image.tag\n  value: 2.0.0\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_scalar(&document, &["image", "tag"]).as_deref(),
+            Some("42")
+        );
+        assert_eq!(
+            get_scalar(&document, &["image", "pull"]).as_deref(),
+            Some("true")
+        );
+        assert_eq!(get_scalar(&document, &["image"]), None);
+        assert_eq!(get_scalar(&document, &["image", "name"]), None);
+        assert_eq!(
+            get_parameter(&document, &["parameters"], "image.tag").as_deref(),
+            Some("2.0.0")
+        );
+        assert_eq!(
+            get_parameter(&document, &["parameters"], "worker.tag"),
+            None
+        );
+    }
+}